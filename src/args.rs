@@ -0,0 +1,804 @@
+//! Command-line options of the subcommands and the value types they parse into.
+
+use anyhow::Result;
+use clap::{Args, FromArgMatches, ValueEnum};
+use shine_rs::StereoMode;
+use std::ffi::OsString;
+use windows::Win32::Media::Audio::{eCommunications, eConsole, eMultimedia, ERole};
+use wasapi::{Direction, Role};
+
+use crate::capture::{DEFAULT_BUFFER_MS, DEFAULT_EVENT_TIMEOUT_MS, DEFAULT_SKIP_WARMUP_MS};
+use crate::control::{parse_hotkey, Hotkey};
+use crate::dsp::{
+    parse_channel_gain, parse_channel_map, parse_compress, parse_eq_band, ChannelMap, Compression,
+    EqBand,
+};
+use crate::time::{parse_duration, parse_start_at, StartAt};
+
+/// Options of `ARec list`.
+#[derive(Args, Debug)]
+pub struct ListArgs {
+    /// Which endpoints to list: playback devices (loopback) or microphones
+    #[arg(short, long, value_enum, default_value_t = Source::Loopback)]
+    pub(crate) source: Source,
+
+    /// Print the devices as a JSON array (for scripts) instead of text
+    #[arg(long)]
+    pub(crate) json: bool,
+
+    /// Also show each device's mix format, speakers, and the MP3 bitrates it allows
+    #[arg(long)]
+    pub(crate) formats: bool,
+
+    /// Which default device to show and mark with `*`: the console, multimedia or
+    /// communications (calls) default
+    #[arg(long, value_enum, default_value_t = DeviceRole::Console)]
+    pub(crate) role: DeviceRole,
+}
+
+/// Options of `ARec probe`.
+#[derive(Args, Debug)]
+pub struct ProbeArgs {
+    /// Probe a playback device (loopback) or a microphone / capture endpoint
+    #[arg(short, long, value_enum, default_value_t = Source::Loopback)]
+    pub(crate) source: Source,
+
+    /// Select device by substring match on friendly name (case-insensitive)
+    #[arg(short, long, conflicts_with_all = ["device_index", "device_id"])]
+    pub(crate) device: Option<String>,
+
+    /// Select device by the [index] shown by `list`
+    #[arg(long, conflicts_with = "device_id")]
+    pub(crate) device_index: Option<u32>,
+
+    /// Select device by exact endpoint ID (as shown by `list`)
+    #[arg(long)]
+    pub(crate) device_id: Option<String>,
+
+    /// Which default device to probe when no device is selected: the console, multimedia
+    /// or communications default
+    #[arg(long, value_enum, default_value_t = DeviceRole::Console, conflicts_with_all = ["device", "device_index", "device_id"])]
+    pub(crate) role: DeviceRole,
+
+    /// Print the result as one JSON object (for scripts) instead of text
+    #[arg(long)]
+    pub(crate) json: bool,
+}
+
+/// Settings of a recording: the options of `ARec record`. Build them from command-line
+/// style arguments with [`RecordArgs::parse_from`].
+#[derive(Args, Clone, Debug)]
+pub struct RecordArgs {
+    /// Read defaults for any of these options from a TOML file (command-line flags win)
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+
+    /// Capture speaker output (loopback) or a microphone / capture endpoint
+    #[arg(short, long, value_enum, default_value_t = Source::Loopback)]
+    pub(crate) source: Source,
+
+    /// Output file path; may contain {date}, {time}, {device} and {n}. Repeat to record
+    /// several outputs at once
+    /// [default: output.mp3, output.wav, output.flac or output.pcm, depending on --format]
+    #[arg(short, long)]
+    pub(crate) out: Vec<String>,
+
+    /// Put the output files in this directory (created if missing); without --out they're
+    /// named {device}-{date}-{time}.<ext>, and relative --out paths are taken from it
+    #[arg(long, value_name = "DIR")]
+    pub(crate) out_dir: Option<String>,
+
+    /// Output file format; the Nth --format goes with the Nth --out, and outputs without
+    /// one use the last format given [default: mp3]
+    #[arg(short, long, value_enum)]
+    pub(crate) format: Vec<OutputFormat>,
+
+    /// Record duration: seconds, MM:SS, HH:MM:SS or 1h30m / 5m / 90s (0 = until Ctrl+C)
+    #[arg(short = 't', long, visible_alias = "seconds", value_name = "DURATION", default_value_t = 10, value_parser = parse_duration)]
+    pub(crate) duration: u64,
+
+    /// Count down this many seconds on stderr before capture starts (not part of --duration)
+    #[arg(long, default_value_t = 0)]
+    pub(crate) countdown: u64,
+
+    /// Ring the console bell when capture starts
+    #[arg(long)]
+    pub(crate) beep: bool,
+
+    /// Wait until this local time before capturing: HH:MM[:SS] or YYYY-MM-DDTHH:MM[:SS]
+    #[arg(long, value_name = "TIME", value_parser = parse_start_at, conflicts_with = "countdown")]
+    pub(crate) start_at: Option<StartAt>,
+
+    /// If the --start-at time of day has already passed, wait for it tomorrow instead of failing
+    #[arg(long, requires = "start_at")]
+    pub(crate) start_next_day: bool,
+
+    /// Select device by substring match on friendly name (case-insensitive).
+    /// If omitted, uses the default playback device (or default microphone with --source mic).
+    #[arg(short, long, conflicts_with_all = ["device_index", "device_id"])]
+    pub(crate) device: Option<String>,
+
+    /// Select device by the [index] shown by `list`
+    #[arg(long, conflicts_with = "device_id")]
+    pub(crate) device_index: Option<u32>,
+
+    /// Select device by exact endpoint ID (as shown by `list`)
+    #[arg(long)]
+    pub(crate) device_id: Option<String>,
+
+    /// Record only this program's audio (with its child processes), by process ID or
+    /// executable name (1234, firefox or firefox.exe), instead of everything the device
+    /// plays. Needs Windows 10 version 2004 or later
+    #[arg(long, value_name = "PID|NAME", conflicts_with_all = ["device", "device_index", "device_id", "pick", "follow_default", "reconnect", "exclusive"])]
+    pub(crate) process: Option<String>,
+
+    /// Record everything played except this program (with its child processes), e.g. a
+    /// chat or notification app; a PID or executable name as for --process, which it
+    /// can't be combined with. Needs Windows 10 version 2004 or later
+    #[arg(long, value_name = "PID|NAME", conflicts_with_all = ["process", "device", "device_index", "device_id", "pick", "follow_default", "reconnect", "exclusive"])]
+    pub(crate) exclude_process: Option<String>,
+
+    /// Stop the recording when this process exits (a PID or executable name as for
+    /// --process), e.g. with --process to record a program from launch to close
+    #[arg(long, value_name = "PID|NAME")]
+    pub(crate) until_process_exits: Option<String>,
+
+    /// Choose the device from a menu of active devices (arrow keys or number, Enter to record)
+    #[arg(long, conflicts_with_all = ["device", "device_index", "device_id", "follow_default"])]
+    pub(crate) pick: bool,
+
+    /// Which default device to record when no device is selected (and to follow with
+    /// --follow-default or --reconnect): the console, multimedia or communications default
+    #[arg(long, value_enum, default_value_t = DeviceRole::Console, conflicts_with_all = ["device", "device_index", "device_id"])]
+    pub(crate) role: DeviceRole,
+
+    /// Move to the new default device when Windows switches it, continuing the same file
+    #[arg(long, conflicts_with_all = ["device", "device_index", "device_id"])]
+    pub(crate) follow_default: bool,
+
+    /// When the device goes away (unplugged, disabled), wait for a default device and keep recording on it
+    #[arg(long)]
+    pub(crate) reconnect: bool,
+
+    /// MP3 bitrate in kbps (must be supported by shine_rs) [default: 192]
+    #[arg(short, long)]
+    pub(crate) kbps: Option<u32>,
+
+    /// Use the closest supported bitrate (with a warning) when --kbps isn't one shine_rs supports
+    #[arg(long)]
+    pub(crate) allow_nearest_bitrate: bool,
+
+    /// MP3 variable bitrate quality 0-9 (0 = best, 9 = smallest). Overrides --kbps.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=9))]
+    pub(crate) vbr: Option<u8>,
+
+    /// MP3 channel mode; default is mono for one channel, stereo otherwise
+    #[arg(long, value_enum)]
+    pub(crate) stereo_mode: Option<ChannelMode>,
+
+    /// Set the copyright bit in every MP3 frame header
+    #[arg(long)]
+    pub(crate) mp3_copyright: bool,
+
+    /// Mark the MP3 as a copy by clearing the original bit in its frame headers
+    #[arg(long)]
+    pub(crate) mp3_copy: bool,
+
+    /// Emphasis declared in the MP3 frame headers; ARec doesn't apply it, so use it only
+    /// for audio that is already pre-emphasized
+    #[arg(long, value_enum, value_name = "EMPHASIS", default_value_t = Mp3Emphasis::None)]
+    pub(crate) mp3_emphasis: Mp3Emphasis,
+
+    /// FLAC compression level 0-8 (0 = fastest, 8 = smallest) [default: 5]
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(u8).range(0..=8))]
+    pub(crate) flac_level: Option<u8>,
+
+    /// Write raw PCM little-endian (the default)
+    #[arg(long, conflicts_with = "pcm_be")]
+    pub(crate) pcm_le: bool,
+
+    /// Write raw PCM big-endian
+    #[arg(long)]
+    pub(crate) pcm_be: bool,
+
+    /// Downmix devices with more than two channels to stereo (the default)
+    #[arg(long, default_value_t = true, conflicts_with = "no_downmix")]
+    downmix_to_stereo: bool,
+
+    /// Keep every channel of a device with more than two channels (WAV, FLAC and raw PCM;
+    /// MP3 holds at most two, so use --channel-map to pick them)
+    #[arg(long, conflicts_with = "channel_map")]
+    pub(crate) no_downmix: bool,
+
+    /// Downmix to a single channel, leaving the LFE channel out (see --mono-coeffs)
+    #[arg(long, conflicts_with_all = ["no_downmix", "channel_map"])]
+    pub(crate) mono: bool,
+
+    /// Weight of each device channel in the --mono downmix, e.g. 1,1,0.7,0,0.5,0.5
+    /// (scaled down if they add up to more than 1, so full scale can't overflow)
+    #[arg(long, value_name = "W,W,...", value_delimiter = ',', allow_hyphen_values = true, requires = "mono")]
+    pub(crate) mono_coeffs: Vec<f32>,
+
+    /// Listen to the first second of a two-channel device before recording and, when both
+    /// channels carry the same audio, record one channel (warns if it turns stereo later)
+    #[arg(long, conflicts_with_all = ["mono", "channel_map", "stereo_mode", "start_at", "stereo_width", "swap_lr", "invert_phase"])]
+    pub(crate) auto_mono: bool,
+
+    /// Resample to this rate (Hz) before encoding, e.g. when the device rate isn't supported by MP3
+    #[arg(long)]
+    pub(crate) resample: Option<u32>,
+
+    /// Gain applied to all captured audio before any other processing (dB; negative attenuates)
+    #[arg(long, value_name = "DB", allow_hyphen_values = true)]
+    pub(crate) gain: Option<f32>,
+
+    /// Device channels (0-based) that feed the stereo output, e.g. L=2,R=3; replaces the downmix
+    #[arg(long, value_name = "SPEC", value_parser = parse_channel_map)]
+    pub(crate) channel_map: Option<ChannelMap>,
+
+    /// Gain for one device channel (0-based) before downmix, e.g. 2=-3; repeatable
+    #[arg(long, value_name = "N=DB", value_parser = parse_channel_gain, allow_hyphen_values = true)]
+    pub(crate) gain_ch: Vec<(usize, f32)>,
+
+    /// Stereo width after downmix: 0 = mono, 1 = unchanged, up to 2 = side signal doubled
+    #[arg(long, value_name = "WIDTH")]
+    pub(crate) stereo_width: Option<f32>,
+
+    /// Swap the left and right output channels
+    #[arg(long)]
+    pub(crate) swap_lr: bool,
+
+    /// Invert the polarity of the left, right or both output channels
+    #[arg(long, value_enum, value_name = "CHANNEL", ignore_case = true)]
+    pub(crate) invert_phase: Option<PhaseInvert>,
+
+    /// Dither applied when converting float capture to 16-bit (tpdf also requests float capture)
+    #[arg(long, value_enum, default_value_t = DitherMode::None)]
+    pub(crate) dither: DitherMode,
+
+    /// High-pass filter cutoff (Hz) applied to every device channel, e.g. 20 to remove DC offset
+    #[arg(long, value_name = "HZ")]
+    pub(crate) highpass: Option<f32>,
+
+    /// Low-pass filter cutoff (Hz) applied to every device channel, e.g. 11000 for 64 kbps MP3
+    /// [default: automatic for MP3 below 128 kbps; 0 = off]
+    #[arg(long, value_name = "HZ")]
+    pub(crate) lowpass: Option<f32>,
+
+    /// EQ band applied to every device channel, e.g. f=100,g=-6,q=1.0 or
+    /// type=highshelf,f=8000,g=3; repeatable, the bands run in order
+    #[arg(long, value_name = "SPEC", value_parser = parse_eq_band, allow_hyphen_values = true)]
+    pub(crate) eq: Vec<EqBand>,
+
+    /// Compress the output above a threshold, e.g. 3:-20 for 3:1 above -20 dBFS, to even out
+    /// loud and quiet passages
+    #[arg(long, value_name = "RATIO:DBFS", value_parser = parse_compress, allow_hyphen_values = true)]
+    pub(crate) compress: Option<Compression>,
+
+    /// Time the --compress gain takes to come down when the level rises (ms)
+    #[arg(long, value_name = "MS", default_value_t = 10, requires = "compress")]
+    pub(crate) compress_attack: u32,
+
+    /// Time the --compress gain takes to recover when the level falls (ms)
+    #[arg(long, value_name = "MS", default_value_t = 200, requires = "compress")]
+    pub(crate) compress_release: u32,
+
+    /// Gain applied after --compress to make up for the reduction (dB)
+    #[arg(long, value_name = "DB", default_value_t = 0.0, allow_hyphen_values = true, requires = "compress")]
+    pub(crate) compress_makeup: f32,
+
+    /// Limit peaks to this ceiling in dBFS (e.g. -1), with a short lookahead so loud parts don't clip
+    #[arg(long, value_name = "DBFS", allow_hyphen_values = true)]
+    pub(crate) limiter: Option<f32>,
+
+    /// Noise gate: turn audio that stays below this level (dBFS, e.g. -50) down by 40 dB
+    #[arg(long, value_name = "DBFS", allow_hyphen_values = true)]
+    pub(crate) gate: Option<f32>,
+
+    /// Time the --gate takes to open once the audio rises above the threshold (ms)
+    #[arg(long, value_name = "MS", default_value_t = 5, requires = "gate")]
+    pub(crate) gate_attack: u32,
+
+    /// Time the --gate takes to close once the audio stays below the threshold (ms)
+    #[arg(long, value_name = "MS", default_value_t = 150, requires = "gate")]
+    pub(crate) gate_release: u32,
+
+    /// Also capture the microphone matching this substring and mix it into the loopback audio
+    #[arg(long, value_name = "SUBSTRING")]
+    pub(crate) mix_mic: Option<String>,
+
+    /// Gain applied to the loopback stream when mixing (dB)
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true, requires = "mix_mic")]
+    pub(crate) loopback_gain: f32,
+
+    /// Gain applied to the microphone stream when mixing (dB)
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true, requires = "mix_mic")]
+    pub(crate) mic_gain: f32,
+
+    /// Insert silence where the device reports dropped audio, keeping the file in step with wall-clock time
+    #[arg(long)]
+    pub(crate) fill_gaps: bool,
+
+    /// Pad or drop frames so the recording's length follows the system clock (for syncing with video)
+    #[arg(long)]
+    pub(crate) drift_correct: bool,
+
+    /// Stop with an error once more than this many drops (glitches, short reads, event timeouts) were seen
+    #[arg(long)]
+    pub(crate) max_drops: Option<u64>,
+
+    /// WASAPI capture buffer length in milliseconds (larger survives system load, smaller reacts faster)
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_BUFFER_MS, value_parser = clap::value_parser!(u32).range(1..=1000))]
+    pub(crate) buffer_ms: u32,
+
+    /// How long one wait for a capture event lasts (ms) before the stop conditions, the
+    /// control pipe and the status line are checked again; no data is fine, not an error
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_EVENT_TIMEOUT_MS, value_parser = clap::value_parser!(u32).range(10..=60_000))]
+    pub(crate) event_timeout_ms: u32,
+
+    /// Discard the first MS milliseconds of captured audio, after the stream starts and
+    /// after each reconnect or device switch, for drivers whose first packets hold stale
+    /// data or a click (0 keeps everything)
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_SKIP_WARMUP_MS, value_parser = clap::value_parser!(u32).range(0..=10_000))]
+    pub(crate) skip_warmup: u32,
+
+    /// Capture in WASAPI exclusive mode in a format the device takes natively (mic only; loopback stays shared)
+    #[arg(long)]
+    pub(crate) exclusive: bool,
+
+    /// Don't draw the live level meter (stderr); print the plain once-per-second status instead
+    #[arg(long)]
+    pub(crate) no_meter: bool,
+
+    /// Progress while recording: text (the meter or status line) or json (a JSON line on
+    /// stderr 4 times a second with elapsed time, bytes written, peak and drops)
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = ProgressFormat::Text)]
+    pub(crate) progress: ProgressFormat,
+
+    /// Print an N-band spectrum of the output (dBFS per band, log-spaced from 20 Hz) as a
+    /// JSON line on stderr 4 times a second, for visualizers; turns the meter off
+    #[arg(long, value_name = "BANDS", value_parser = clap::value_parser!(u32).range(1..=64))]
+    pub(crate) spectrum: Option<u32>,
+
+    /// Named pipe (e.g. \\.\pipe\arec) that takes stop, status, split, pause, resume and
+    /// spectrum commands from another program and answers each with a JSON line
+    #[arg(long, value_name = "NAME")]
+    pub(crate) control_pipe: Option<String>,
+
+    /// Don't pause and resume the recording with the space bar
+    #[arg(long)]
+    pub(crate) no_pause_key: bool,
+
+    /// Global hotkey (e.g. Ctrl+Alt+R) that starts and stops writing even when the
+    /// console isn't focused; the recording waits, paused, for the first press
+    #[arg(long, value_name = "COMBO", value_parser = parse_hotkey)]
+    pub(crate) hotkey: Option<Hotkey>,
+
+    /// Suppress progress output; only the end-of-recording summary is printed
+    #[arg(short, long)]
+    pub(crate) quiet: bool,
+
+    // No summary either, for `selftest`, which reports on its own
+    #[arg(skip)]
+    pub(crate) no_summary: bool,
+
+    /// Print the end-of-recording summary as one JSON line
+    #[arg(long)]
+    pub(crate) json: bool,
+
+    /// Hash the recorded 16-bit samples (before encoding, so every format gives the same
+    /// digest) and report it in the summary and sidecar
+    #[arg(long, value_enum, value_name = "KIND")]
+    pub(crate) checksum: Option<ChecksumKind>,
+
+    /// Also write <out>.json next to each output file once it is finished: device, format,
+    /// times, levels, drops, encoder settings and split/track boundaries
+    #[arg(long)]
+    pub(crate) sidecar: bool,
+
+    /// Stop after this many seconds of continuous silence (see --silence-threshold)
+    #[arg(long, value_name = "SECS")]
+    pub(crate) silence_timeout: Option<u64>,
+
+    /// Level below which audio counts as silence for --silence-timeout, --trim-silence, --vox
+    /// and --split-on-silence (dBFS)
+    #[arg(long, value_name = "DBFS", default_value_t = -60.0, allow_hyphen_values = true)]
+    pub(crate) silence_threshold: f32,
+
+    /// Replace existing output files without asking, or instead of picking a new name
+    /// (out-1.mp3, ...) for an --out with {n}, {date} or {time}
+    #[arg(long)]
+    pub(crate) overwrite: bool,
+
+    /// Continue an existing --out file (WAV, MP3 or raw PCM) instead of starting a new one,
+    /// e.g. to resume an interrupted session; its format must match this recording
+    #[arg(long, conflicts_with_all = ["overwrite", "split_seconds", "split_mb", "split_on_silence", "cue", "normalize_peak", "trim_silence"])]
+    pub(crate) append: bool,
+
+    #[command(flatten)]
+    pub(crate) tags: TagArgs,
+
+    /// Start a new output file every N seconds (out-000.mp3, out-001.mp3, ...)
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) split_seconds: Option<u64>,
+
+    /// Start a new output file once the current one reaches this size (MiB)
+    #[arg(long, value_name = "MIB", value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) split_mb: Option<u64>,
+
+    /// Start a new output file (track) after each silence of --silence-min (see --silence-threshold)
+    #[arg(long)]
+    pub(crate) split_on_silence: bool,
+
+    /// Silence that ends a track with --split-on-silence (ms)
+    #[arg(long, value_name = "MS", default_value_t = 2000, requires = "split_on_silence")]
+    pub(crate) silence_min: u32,
+
+    /// Write a CUE sheet listing the split files, or the tracks of one file with --cue-markers
+    #[arg(long, value_name = "FILE")]
+    pub(crate) cue: Option<String>,
+
+    /// With --cue, record one file and mark the --split-seconds / --split-on-silence
+    /// boundaries as tracks instead of starting new files
+    #[arg(long, requires = "cue")]
+    pub(crate) cue_markers: bool,
+
+    /// Run this command for each finished file (every segment), with {file} replaced by its path
+    #[arg(long, value_name = "COMMAND")]
+    pub(crate) on_complete: Option<String>,
+
+    /// Also stream MP3 to an Icecast server: http://[user[:password]@]host[:port][/mount]
+    #[arg(long, value_name = "URL")]
+    pub(crate) icecast: Option<String>,
+
+    /// Icecast mount point (overrides the URL path)
+    #[arg(long, value_name = "MOUNT", requires = "icecast")]
+    pub(crate) icecast_mount: Option<String>,
+
+    /// Icecast source password (overrides the one in the URL)
+    #[arg(long, value_name = "PASSWORD", requires = "icecast")]
+    pub(crate) icecast_password: Option<String>,
+
+    /// Serve the live MP3 stream over HTTP on this address (e.g. :8080) to any number of listeners
+    #[arg(long, value_name = "ADDRESS")]
+    pub(crate) serve: Option<String>,
+
+    /// Drop leading and trailing silence (below --silence-threshold) from the file (WAV only)
+    #[arg(long)]
+    pub(crate) trim_silence: bool,
+
+    /// Audio to keep before the first and after the last sound with --trim-silence (ms)
+    #[arg(long, value_name = "MS", default_value_t = 100, requires = "trim_silence")]
+    pub(crate) trim_padding: u32,
+
+    /// Flush the output to disk every SECS (and update the WAV header sizes) so a crash loses at most that much (0 = only at the end)
+    #[arg(long, value_name = "SECS", default_value_t = 5, visible_alias = "wav-header-interval")]
+    pub(crate) flush_interval: u64,
+
+    /// Voice-activated recording: only write audio while it is above --silence-threshold
+    #[arg(long)]
+    pub(crate) vox: bool,
+
+    /// Keep writing this long after the audio drops below the threshold with --vox (ms)
+    #[arg(long, value_name = "MS", default_value_t = 1000, requires = "vox")]
+    pub(crate) vox_hang: u32,
+
+    /// Write the active stretches of a --vox recording to this JSON file
+    #[arg(long, value_name = "FILE", requires = "vox")]
+    pub(crate) vox_log: Option<String>,
+
+    /// Also write this much audio from before each --vox trigger, from a ring buffer (ms)
+    #[arg(long, value_name = "MS", requires = "vox")]
+    pub(crate) preroll: Option<u32>,
+
+    /// Fade the start of the recording in from silence over this many milliseconds
+    #[arg(long, value_name = "MS")]
+    pub(crate) fade_in: Option<u32>,
+
+    /// Fade the end of the recording out to silence over this many milliseconds
+    #[arg(long, value_name = "MS")]
+    pub(crate) fade_out: Option<u32>,
+
+    /// Scale the recording so its peak hits this level in dBFS, e.g. -1 (WAV and FLAC only)
+    #[arg(long, value_name = "DBFS", allow_hyphen_values = true)]
+    pub(crate) normalize_peak: Option<f32>,
+}
+
+// Metadata written as an ID3v2.3 tag at the start of MP3 files, or a VORBIS_COMMENT block in FLAC
+#[derive(Args, Clone, Debug)]
+pub(crate) struct TagArgs {
+    /// Title tag (MP3 and FLAC)
+    #[arg(long)]
+    pub(crate) title: Option<String>,
+
+    /// Artist tag (MP3 and FLAC)
+    #[arg(long)]
+    pub(crate) artist: Option<String>,
+
+    /// Album tag (MP3 and FLAC)
+    #[arg(long)]
+    pub(crate) album: Option<String>,
+
+    /// Year tag (MP3 and FLAC)
+    #[arg(long, value_parser = clap::value_parser!(u16).range(0..=9999))]
+    pub(crate) year: Option<u16>,
+
+    /// Comment tag (MP3 and FLAC)
+    #[arg(long)]
+    pub(crate) comment: Option<String>,
+
+    /// Genre tag, free text or a numeric ID3v1 genre like "(12)" (MP3 and FLAC)
+    #[arg(long)]
+    pub(crate) genre: Option<String>,
+
+    /// Fill title/artist/album from the system "now playing" media session (per file when splitting)
+    #[arg(long)]
+    pub(crate) auto_tags: bool,
+}
+
+impl RecordArgs {
+    /// Parses `record` options (without the program and subcommand names), e.g.
+    /// `RecordArgs::parse_from(["--seconds", "30", "--format", "flac"])`.
+    /// Errors are clap's usage messages.
+    pub fn parse_from<I, T>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let command = Self::augment_args(clap::Command::new("record"));
+        let argv = std::iter::once(OsString::from("record")).chain(args.into_iter().map(Into::into));
+        let matches = command.try_get_matches_from(argv)?;
+        Ok(Self::from_arg_matches(&matches)?)
+    }
+
+    // Whether any --split-* option is set
+    pub(crate) fn has_split(&self) -> bool {
+        self.split_seconds.is_some() || self.split_mb.is_some() || self.split_on_silence
+    }
+
+    // Whether the output is cut into several files (--cue-markers keeps one)
+    pub(crate) fn splits(&self) -> bool {
+        self.has_split() && !self.cue_markers
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Source {
+    /// Loopback capture of a render (playback) endpoint
+    Loopback,
+    /// Normal capture from a microphone / capture endpoint
+    Mic,
+}
+
+impl Source {
+    // Endpoint direction to enumerate; loopback opens a capture stream on a render endpoint.
+    pub(crate) fn direction(self) -> Direction {
+        match self {
+            Source::Loopback => Direction::Render,
+            Source::Mic => Direction::Capture,
+        }
+    }
+
+    pub(crate) fn kind(self) -> &'static str {
+        match self {
+            Source::Loopback => "render",
+            Source::Mic => "capture",
+        }
+    }
+}
+
+// Windows keeps a separate default device per role; Sound settings sets console and
+// multimedia together, while chat apps follow the communications default.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DeviceRole {
+    /// The default device (what get_default_device and most programs use)
+    Console,
+    /// The default device for music and movies
+    Multimedia,
+    /// The default communications device (calls and chat)
+    Communications,
+}
+
+impl DeviceRole {
+    pub(crate) const ALL: [DeviceRole; 3] =
+        [DeviceRole::Console, DeviceRole::Multimedia, DeviceRole::Communications];
+
+    pub(crate) fn role(self) -> Role {
+        match self {
+            DeviceRole::Console => Role::Console,
+            DeviceRole::Multimedia => Role::Multimedia,
+            DeviceRole::Communications => Role::Communications,
+        }
+    }
+
+    // IMMNotificationClient reports default changes per ERole
+    pub(crate) fn erole(self) -> ERole {
+        match self {
+            DeviceRole::Console => eConsole,
+            DeviceRole::Multimedia => eMultimedia,
+            DeviceRole::Communications => eCommunications,
+        }
+    }
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            DeviceRole::Console => "console",
+            DeviceRole::Multimedia => "multimedia",
+            DeviceRole::Communications => "communications",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    /// MP3 via shine_rs (lossy)
+    Mp3,
+    /// Canonical 16-bit PCM WAV (lossless)
+    Wav,
+    /// 16-bit FLAC (lossless, compressed)
+    Flac,
+    /// Raw interleaved 16-bit samples without a header
+    Pcm,
+}
+
+impl OutputFormat {
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Mp3 => "mp3",
+            OutputFormat::Wav => "wav",
+            OutputFormat::Flac => "flac",
+            OutputFormat::Pcm => "pcm",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DitherMode {
+    /// Round to the nearest 16-bit value
+    None,
+    /// Triangular-PDF dither (+/-1 LSB) before rounding
+    Tpdf,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ProgressFormat {
+    /// The live level meter, or the status line with --no-meter
+    Text,
+    /// One JSON object per line on stderr, for a parent process
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ChecksumKind {
+    /// SHA-256, for telling recordings apart
+    Sha256,
+    /// CRC-32 (IEEE), quicker and shorter for a rough check
+    Crc32,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PhaseInvert {
+    /// Left channel only
+    #[value(name = "L")]
+    Left,
+    /// Right channel only
+    #[value(name = "R")]
+    Right,
+    /// Both channels
+    Both,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Mp3Emphasis {
+    /// No emphasis
+    None,
+    /// 50/15 microseconds (as on CDs)
+    #[value(name = "50-15")]
+    Us50_15,
+    /// CCITT J.17
+    Ccitt,
+}
+
+impl Mp3Emphasis {
+    // The frame header's two emphasis bits (0b10 is reserved)
+    pub(crate) fn bits(self) -> u8 {
+        match self {
+            Mp3Emphasis::None => 0b00,
+            Mp3Emphasis::Us50_15 => 0b01,
+            Mp3Emphasis::Ccitt => 0b11,
+        }
+    }
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Mp3Emphasis::None => "none",
+            Mp3Emphasis::Us50_15 => "50/15 us",
+            Mp3Emphasis::Ccitt => "CCITT J.17",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ChannelMode {
+    /// Independent left/right channels
+    Stereo,
+    /// Joint stereo (can save bits on similar channels at low bitrates)
+    Joint,
+    /// Single channel (needs a one-channel target)
+    Mono,
+    /// Two unrelated mono channels
+    Dual,
+}
+
+impl ChannelMode {
+    // --stereo-mode, or the mode that matches the channel count.
+    pub(crate) fn resolve(requested: Option<ChannelMode>, channels: usize) -> ChannelMode {
+        requested.unwrap_or(if channels == 1 {
+            ChannelMode::Mono
+        } else {
+            ChannelMode::Stereo
+        })
+    }
+
+    pub(crate) fn stereo_mode(self) -> StereoMode {
+        match self {
+            ChannelMode::Stereo => StereoMode::Stereo,
+            ChannelMode::Joint => StereoMode::JointStereo,
+            ChannelMode::Mono => StereoMode::Mono,
+            ChannelMode::Dual => StereoMode::DualChannel,
+        }
+    }
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            ChannelMode::Stereo => "stereo",
+            ChannelMode::Joint => "joint stereo",
+            ChannelMode::Mono => "mono",
+            ChannelMode::Dual => "dual channel",
+        }
+    }
+}
+
+pub(crate) const DEFAULT_KBPS: u32 = 192;
+
+pub(crate) const DEFAULT_FLAC_LEVEL: u8 = 5;
+
+/// Options of `ARec selftest`.
+#[derive(Args, Debug)]
+pub struct SelfTestArgs {
+    /// Test speaker output (loopback) or the default microphone
+    #[arg(short, long, value_enum, default_value_t = Source::Loopback)]
+    pub(crate) source: Source,
+
+    /// Lowest peak level in dBFS that counts as audio
+    #[arg(long, value_name = "DBFS", default_value_t = -50.0, allow_hyphen_values = true)]
+    pub(crate) floor: f32,
+}
+
+/// Options of `ARec bench`.
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Seconds of synthetic audio to encode
+    #[arg(short = 't', long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) seconds: u64,
+
+    /// MP3 bitrate in kbps
+    #[arg(short, long, default_value_t = DEFAULT_KBPS)]
+    pub(crate) kbps: u32,
+
+    /// MP3 variable bitrate quality 0-9 instead of --kbps
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=9))]
+    pub(crate) vbr: Option<u8>,
+
+    /// Output format to encode
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Mp3)]
+    pub(crate) format: OutputFormat,
+
+    /// Sample rate of the synthetic audio in Hz
+    #[arg(long, default_value_t = 48_000)]
+    pub(crate) rate: u32,
+
+    /// Channels of the synthetic audio
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(1..=2))]
+    pub(crate) channels: u8,
+}
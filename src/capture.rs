@@ -0,0 +1,1579 @@
+//! Capture sources: the WASAPI device or process stream, the `--mix-mic` microphone, and
+//! decoding packets to 16-bit samples.
+
+use anyhow::{anyhow, bail, Context, Result};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+use windows::{
+    core::PCWSTR,
+    Wdk::System::SystemServices::RtlGetVersion,
+    Win32::{
+        Foundation::{CloseHandle, PROPERTYKEY},
+        Media::Audio::{
+            eCapture, eRender, EDataFlow, ERole, IMMDeviceEnumerator, IMMNotificationClient,
+            IMMNotificationClient_Impl, MMDeviceEnumerator, AUDCLNT_E_DEVICE_INVALIDATED,
+            AUDCLNT_E_RESOURCES_INVALIDATED, AUDCLNT_E_SERVICE_NOT_RUNNING, DEVICE_STATE,
+        },
+        System::{
+            Com::{CoCreateInstance, CLSCTX_ALL},
+            Diagnostics::ToolHelp::{
+                CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W,
+                TH32CS_SNAPPROCESS,
+            },
+            SystemInformation::OSVERSIONINFOW,
+        },
+    },
+};
+use wasapi::{
+    AudioCaptureClient, AudioClient, Device, DeviceEnumerator, Direction, Handle, SampleType,
+    StreamMode, WasapiError, WaveFormat,
+};
+
+use crate::args::{DeviceRole, DitherMode, RecordArgs, Source};
+use crate::dsp::{db_to_gain, standard_channel_mask, LinearResampler, TpdfDither};
+use crate::record::stop_requested;
+use crate::Console;
+
+// Upper bound for one --fill-gaps insert, in case the device position jumps wildly
+pub(crate) const MAX_GAP_FILL_SECS: u64 = 10;
+
+// --auto-mono listens this long, and takes left and right this close (in 16-bit steps,
+// about -54 dBFS) as the same audio
+const AUTO_MONO_PROBE: Duration = Duration::from_secs(1);
+
+const AUTO_MONO_TOLERANCE: i32 = 64;
+
+/// Format of interleaved 16-bit audio: what a [`CaptureSource`] delivers, and what a
+/// custom sink receives after the downmix, resampling and other processing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamFormat {
+    pub sample_rate: u32,
+    pub channels: usize,
+    /// Speaker positions of the channels (`SPEAKER_*` bits of WAVEFORMATEXTENSIBLE)
+    pub channel_mask: u32,
+}
+
+/// Where a recording's audio comes from: the WASAPI device `record` selects, or a source
+/// given to [`Recorder::source`], such as a [`ReplaySource`] in tests. Calls come from the
+/// recording thread in this order: `format`, `open`, `start`, `read` (with `reopen` or
+/// `follow_default` in between), `stop`.
+pub trait CaptureSource {
+    /// Name for messages and the `{device}` token of `--out`
+    fn name(&self) -> String;
+
+    /// Format of the samples `read` delivers; the settings are checked against it
+    fn format(&self) -> StreamFormat;
+
+    /// Prepares capture once the settings have been checked, before any `--start-at` wait
+    fn open(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn stop(&mut self) {}
+
+    /// Appends the next block of interleaved samples to `out`, waiting up to about a second
+    /// (`--event-timeout-ms` for a device) for it; [`CaptureRead::Idle`] when none came is
+    /// not an error. An error ends the recording, unless it is a lost device and
+    /// `--reconnect` is set, in which case `reopen` is tried.
+    fn read(&mut self, out: &mut Vec<i16>) -> Result<CaptureRead>;
+
+    /// After `read` lost the device: tries once to continue on the default device and
+    /// returns its name, or `None` to be asked again a second later
+    fn reopen(&mut self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Called before each `read`: with `--follow-default`, moves to a new default device
+    /// and returns its name. An error ends the recording.
+    fn follow_default(&mut self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Float samples read so far that were at or beyond full scale (|value| >= 1.0) and
+    /// were clamped when converted to 16 bits; always 0 for integer sources
+    fn float_overs(&self) -> u64 {
+        0
+    }
+
+    /// Identifier of the device, if it has one (the WASAPI endpoint ID), for `--sidecar`
+    fn id(&self) -> Option<String> {
+        None
+    }
+}
+
+/// What one [`CaptureSource::read`] produced
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureRead {
+    /// `frames` frames were captured and appended. `lost_frames` is set when audio was lost
+    /// before them (with `--fill-gaps`, that many frames of silence go in front), and `short`
+    /// when fewer frames arrived than were announced. `timestamp` is the system clock time
+    /// of the first captured frame (from any fixed origin); without it `--drift-correct`
+    /// and the drift measurement skip the block.
+    Block {
+        frames: u64,
+        lost_frames: Option<u64>,
+        short: bool,
+        timestamp: Option<Duration>,
+    },
+    /// Nothing arrived in time. `expected` when the source should never pause (a
+    /// microphone); the summary counts those as event timeouts.
+    Idle { expected: bool },
+    /// The source has no more audio; the recording ends with stop reason `end of source`
+    Ended,
+}
+
+/// A [`CaptureSource`] that plays back fixed samples in 10 ms blocks, as fast as they are
+/// read, then ends. With it a recording runs through the whole pipeline (processing,
+/// encoders, files) without an audio device.
+///
+/// ```no_run
+/// let tone: Vec<i16> = (0..48_000 * 2)
+///     .map(|i| (((i / 2) as f32 * 0.05).sin() * 8000.0) as i16)
+///     .collect();
+/// let source = arec::ReplaySource::new(tone, 2, 48_000);
+/// let config = arec::RecordArgs::parse_from(["--seconds", "0", "--format", "wav", "--out", "tone.wav"])?;
+/// arec::Recorder::new(config).source(source).start()?.wait()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct ReplaySource {
+    format: StreamFormat,
+    samples: Vec<i16>,
+    position: usize,
+}
+
+impl ReplaySource {
+    /// Interleaved `samples` with `channels` channels at `sample_rate`, and the standard
+    /// speaker layout for that channel count
+    pub fn new(samples: Vec<i16>, channels: usize, sample_rate: u32) -> Self {
+        Self {
+            format: StreamFormat {
+                sample_rate,
+                channels,
+                channel_mask: standard_channel_mask(channels),
+            },
+            samples,
+            position: 0,
+        }
+    }
+
+    /// Replaces the speaker layout, e.g. to test the downmix of an unusual channel mask
+    pub fn channel_mask(mut self, channel_mask: u32) -> Self {
+        self.format.channel_mask = channel_mask;
+        self
+    }
+}
+
+impl CaptureSource for ReplaySource {
+    fn name(&self) -> String {
+        "replay".to_string()
+    }
+
+    fn format(&self) -> StreamFormat {
+        self.format
+    }
+
+    fn read(&mut self, out: &mut Vec<i16>) -> Result<CaptureRead> {
+        let block = (self.format.sample_rate as usize / 100).max(1) * self.format.channels;
+        let end = (self.position + block).min(self.samples.len());
+        if end == self.position {
+            return Ok(CaptureRead::Ended);
+        }
+        out.extend_from_slice(&self.samples[self.position..end]);
+        let frames = ((end - self.position) / self.format.channels) as u64;
+        self.position = end;
+        Ok(CaptureRead::Block {
+            frames,
+            lost_frames: None,
+            short: false,
+            timestamp: None,
+        })
+    }
+}
+
+// Microphone stream mixed into the loopback capture (--mix-mic).
+// The mic is read without blocking whenever loopback data arrives, converted to the
+// loopback rate/channel layout, and queued; each loopback packet consumes as many
+// queued mic frames as it can, so a short mic tick just leaves the rest for later.
+struct MicMixer {
+    audio_client: AudioClient,
+    capture: AudioCaptureClient,
+    _event: Handle, // event mode requires a handle, but the loopback event drives the loop
+    sample_format: CaptureSampleFormat,
+    mic_channels: usize,
+    mix_channels: usize,
+    resampler: Option<LinearResampler>,
+    raw_buf: Vec<u8>,
+    pcm_buf: Vec<i16>,
+    resample_buf: Vec<i16>,
+    pending: VecDeque<i16>, // mix_channels interleaved, at the loopback rate
+    max_pending: usize,
+    loopback_gain: f32,
+    mic_gain: f32,
+    dither: Option<TpdfDither>,
+    float_overs: u64,
+}
+
+impl MicMixer {
+    fn open(
+        enumerator: &DeviceEnumerator,
+        needle: &str,
+        mix_rate: u32,
+        mix_channels: usize,
+        args: &RecordArgs,
+        console: Console,
+    ) -> Result<Self> {
+        let selector = DeviceSelector::Substring(needle);
+        let device = select_device(enumerator, Direction::Capture, selector, args.role)?;
+        let name = device
+            .get_friendlyname()
+            .unwrap_or_else(|_| "<unknown>".to_string());
+        info!(console, "Mixing microphone: {name}");
+
+        let audio_client = device.get_iaudioclient()?;
+        let mic_format = audio_client.get_mixformat()?;
+        let mic_rate = mic_format.get_samplespersec();
+        let mic_channels = mic_format.get_nchannels() as usize;
+
+        let dither = args.dither == DitherMode::Tpdf;
+        let activate = || Ok(device.get_iaudioclient()?);
+        let (audio_client, sample_format) = initialize_capture_client(
+            &activate,
+            audio_client,
+            &mic_format,
+            args.buffer_ms,
+            dither,
+            console,
+        )
+        .context("microphone stream")?;
+        let capture = audio_client.get_audiocaptureclient()?;
+        let event = audio_client.set_get_eventhandle()?;
+
+        let resampler = if mic_rate != mix_rate {
+            info!(console, "Resampling microphone {mic_rate} Hz -> {mix_rate} Hz");
+            Some(LinearResampler::new(mic_channels, mic_rate, mix_rate))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            audio_client,
+            capture,
+            _event: event,
+            sample_format,
+            mic_channels,
+            mix_channels,
+            resampler,
+            raw_buf: Vec::new(),
+            pcm_buf: Vec::new(),
+            resample_buf: Vec::new(),
+            pending: VecDeque::new(),
+            // Keep at most ~1s of mic audio queued; older frames are dropped
+            max_pending: mix_rate as usize * mix_channels,
+            loopback_gain: db_to_gain(args.loopback_gain),
+            mic_gain: db_to_gain(args.mic_gain),
+            dither: dither.then(TpdfDither::new),
+            float_overs: 0,
+        })
+    }
+
+    fn start(&self) -> Result<()> {
+        self.audio_client.start_stream()?;
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<()> {
+        self.audio_client.stop_stream()?;
+        Ok(())
+    }
+
+    // Read every packet the mic has ready (never blocks) into the pending queue.
+    fn drain(&mut self) -> Result<()> {
+        let bytes_per_frame = self.mic_channels * self.sample_format.bytes_per_sample();
+        loop {
+            let next = self.capture.get_next_packet_size()?;
+            let Some(frames_available) = next else { break; };
+            if frames_available == 0 {
+                break;
+            }
+
+            self.raw_buf.clear();
+            self.raw_buf.resize(frames_available as usize * bytes_per_frame, 0u8);
+            let (frames_read, info) = self
+                .capture
+                .read_from_device(&mut self.raw_buf)
+                .context("read_from_device (microphone) failed")?;
+            if frames_read == 0 {
+                break;
+            }
+
+            self.pcm_buf.clear();
+            if info.flags.silent {
+                self.pcm_buf.resize(frames_read as usize * self.mic_channels, 0);
+            } else {
+                let used_bytes = frames_read as usize * bytes_per_frame;
+                self.float_overs += decode_samples_into(
+                    self.sample_format,
+                    &self.raw_buf[..used_bytes],
+                    &mut self.pcm_buf,
+                    self.dither.as_mut(),
+                );
+            }
+
+            if let Some(resampler) = &mut self.resampler {
+                self.resample_buf.clear();
+                resampler.process_into(&self.pcm_buf, &mut self.resample_buf);
+                std::mem::swap(&mut self.pcm_buf, &mut self.resample_buf);
+            }
+
+            // Map mic channels onto the loopback layout: mono feeds the front pair,
+            // otherwise channel c goes to channel c (extra mic channels are dropped).
+            for frame in self.pcm_buf.chunks_exact(self.mic_channels) {
+                for ch in 0..self.mix_channels {
+                    let s = if self.mic_channels == 1 {
+                        if ch < 2 { frame[0] } else { 0 }
+                    } else {
+                        frame.get(ch).copied().unwrap_or(0)
+                    };
+                    self.pending.push_back(s);
+                }
+            }
+        }
+
+        if self.pending.len() > self.max_pending {
+            // Drop whole frames so the queue stays channel-aligned
+            let excess = (self.pending.len() - self.max_pending).div_ceil(self.mix_channels)
+                * self.mix_channels;
+            self.pending.drain(..excess);
+        }
+        Ok(())
+    }
+
+    // Sum queued mic frames into a loopback buffer (mix_channels interleaved).
+    // Frames with no mic data yet just get the loopback gain.
+    fn mix_into(&mut self, pcm: &mut [i16]) {
+        for s in pcm.iter_mut() {
+            let mut v = *s as f32 * self.loopback_gain;
+            if let Some(m) = self.pending.pop_front() {
+                v += m as f32 * self.mic_gain;
+            }
+            *s = v.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+}
+
+// Capture buffer length (--buffer-ms default), and the longer one tried for drivers
+// that refuse short buffers
+pub(crate) const DEFAULT_BUFFER_MS: u32 = 20;
+
+const FALLBACK_BUFFER_MS: u32 = 100;
+
+// --event-timeout-ms default: a silent loopback device still gets the loop round once a second
+pub(crate) const DEFAULT_EVENT_TIMEOUT_MS: u32 = 1000;
+
+// --skip-warmup default: long enough for the stale first packet of the drivers that click,
+// short enough not to lose the start of what is played
+pub(crate) const DEFAULT_SKIP_WARMUP_MS: u32 = 50;
+
+// Shared, event-driven. Autoconvert lets the audio engine convert from endpoint format if needed.
+fn capture_mode(buffer_ms: u32) -> StreamMode {
+    StreamMode::EventsShared {
+        autoconvert: true,
+        buffer_duration_hns: buffer_ms as i64 * 10_000, // 100 ns units
+    }
+}
+
+// Initialize a CAPTURE stream. On a RENDER endpoint wasapi sets the loopback flag;
+// on a capture endpoint (mic) this is a normal capture stream.
+// Drivers differ in what they accept, so work down a ladder until one works: i16 in the
+// device channel count (we downmix later), then the native mix format if it is 32-bit
+// float (converted to i16 ourselves), first with a `buffer_ms` buffer and then with
+// FALLBACK_BUFFER_MS. With `prefer_float` (--dither) float is tried first, so the
+// conversion to 16 bits happens here, where it can be dithered. Each failure is logged;
+// the last error is returned if all fail. `activate` makes the fresh client each retry needs.
+pub(crate) fn initialize_capture_client(
+    activate: &dyn Fn() -> Result<AudioClient>,
+    audio_client: AudioClient,
+    mix: &WaveFormat,
+    buffer_ms: u32,
+    prefer_float: bool,
+    console: Console,
+) -> Result<(AudioClient, CaptureSampleFormat)> {
+    let desired = WaveFormat::new(
+        16,               // storebits
+        16,               // validbits
+        &SampleType::Int, // i16
+        mix.get_samplespersec() as usize,
+        mix.get_nchannels() as usize,
+        None,
+    );
+    let mut formats = vec![(CaptureSampleFormat::I16, &desired)];
+    if is_f32_format(mix) {
+        let native = (CaptureSampleFormat::F32, mix);
+        if prefer_float {
+            formats.insert(0, native);
+        } else {
+            formats.push(native);
+        }
+    }
+
+    log::debug!("Mix format: {}", wave_format_label(mix));
+    let mut fresh = Some(audio_client);
+    let mut last_err = None;
+    let mut attempts = 0;
+    let mut buffers_ms = vec![buffer_ms];
+    if buffer_ms < FALLBACK_BUFFER_MS {
+        buffers_ms.push(FALLBACK_BUFFER_MS);
+    }
+    for buffer_ms in buffers_ms {
+        for &(format, wave) in &formats {
+            attempts += 1;
+            log::debug!("Attempt {attempts}: {} capture with a {buffer_ms} ms buffer", format.label());
+            // A client that failed Initialize can't be reused, so each retry activates a new one.
+            let mut client = match fresh.take() {
+                Some(client) => client,
+                None => activate()?,
+            };
+            match client.initialize_client(wave, &Direction::Capture, &capture_mode(buffer_ms)) {
+                Ok(()) => {
+                    if attempts > 1 {
+                        let label = format.label();
+                        info!(console, "Capturing as {label} with a {buffer_ms} ms buffer");
+                    }
+                    return Ok((client, format));
+                }
+                Err(e) => {
+                    let label = format.label();
+                    info!(console, "Capture as {label} with a {buffer_ms} ms buffer rejected: {e}");
+                    last_err = Some(e);
+                }
+            }
+        }
+    }
+    let err = last_err.expect("at least one format is tried");
+    Err(err).context(format!("initialize_client failed after {attempts} attempts"))
+}
+
+// The capture stream `record` uses: exclusive with --exclusive on a capture endpoint,
+// otherwise shared through the fallback ladder, on the device or the process loopback
+// stream of --process or --exclude-process.
+fn initialize_record_client(
+    device: &Device,
+    process: Option<&ProcessTarget>,
+    audio_client: AudioClient,
+    mix: &WaveFormat,
+    args: &RecordArgs,
+    console: Console,
+) -> Result<(AudioClient, CaptureSampleFormat)> {
+    if args.exclusive && args.source == Source::Mic {
+        initialize_exclusive_capture_client(device, audio_client, mix, args.buffer_ms, console)
+    } else {
+        let activate = || match process {
+            Some(target) => target.activate(),
+            None => Ok(device.get_iaudioclient()?),
+        };
+        initialize_capture_client(
+            &activate,
+            audio_client,
+            mix,
+            args.buffer_ms,
+            args.dither == DitherMode::Tpdf,
+            console,
+        )
+    }
+}
+
+// --exclusive: open the capture endpoint exclusively, in a format the hardware takes as is
+// (no engine mixing or conversion). Candidates keep the mix rate and channel count so the
+// rest of the pipeline is unchanged: the device's native format first, then float, 32-,
+// 24-in-32-, packed 24- and 16-bit integer. The period is `buffer_ms`, raised to the
+// device minimum and aligned to 128 bytes as HD Audio drivers require. Exclusive mode
+// can't suggest a closest format, so an error names the device's native format instead.
+fn initialize_exclusive_capture_client(
+    device: &Device,
+    mut audio_client: AudioClient,
+    mix: &WaveFormat,
+    buffer_ms: u32,
+    console: Console,
+) -> Result<(AudioClient, CaptureSampleFormat)> {
+    let rate = mix.get_samplespersec() as usize;
+    let channels = mix.get_nchannels() as usize;
+    let native = device.get_device_format().ok();
+    let mut candidates: Vec<WaveFormat> = native
+        .iter()
+        .filter(|f| f.get_samplespersec() as usize == rate && f.get_nchannels() as usize == channels)
+        .cloned()
+        .collect();
+    for (store, valid, sample_type) in [
+        (32, 32, SampleType::Float),
+        (32, 32, SampleType::Int),
+        (32, 24, SampleType::Int),
+        (24, 24, SampleType::Int),
+        (16, 16, SampleType::Int),
+    ] {
+        candidates.push(WaveFormat::new(store, valid, &sample_type, rate, channels, None));
+    }
+
+    let mut rejected: Vec<String> = Vec::new();
+    for candidate in &candidates {
+        let label = wave_format_label(candidate);
+        let Some(format) = CaptureSampleFormat::from_wave_format(candidate) else {
+            continue;
+        };
+        if rejected.contains(&label) {
+            continue;
+        }
+        log::debug!("Exclusive mode: trying {label}");
+        let Ok(wave) = audio_client.is_supported_exclusive_with_quirks(candidate) else {
+            info!(console, "Exclusive mode: {label} not supported");
+            rejected.push(label);
+            continue;
+        };
+        let period_hns =
+            audio_client.calculate_aligned_period_near(buffer_ms as i64 * 10_000, Some(128), &wave)?;
+        audio_client
+            .initialize_client(&wave, &Direction::Capture, &StreamMode::EventsExclusive { period_hns })
+            .with_context(|| format!("exclusive initialize_client ({label})"))?;
+        info!(console,
+            "Exclusive mode: {label}, {:.1} ms period",
+            period_hns as f64 / 10_000.0
+        );
+        return Ok((audio_client, format));
+    }
+    let native = native.map_or_else(|| "unknown".to_string(), |f| wave_format_label(&f));
+    bail!(
+        "exclusive mode: the device accepts none of {}; its native format is {native}. \
+         Record without --exclusive to let Windows convert",
+        rejected.join("; ")
+    )
+}
+
+// e.g. "48000 Hz, 2 channels, 24-bit PCM in 32-bit"
+fn wave_format_label(fmt: &WaveFormat) -> String {
+    let store = fmt.get_bitspersample();
+    let valid = fmt.get_validbitspersample();
+    let kind = if matches!(fmt.get_subformat(), Ok(SampleType::Float)) {
+        "float"
+    } else {
+        "PCM"
+    };
+    let container = if store != valid {
+        format!(" in {store}-bit")
+    } else {
+        String::new()
+    };
+    let channels = fmt.get_nchannels();
+    format!(
+        "{} Hz, {channels} channel{}, {valid}-bit {kind}{container}",
+        fmt.get_samplespersec(),
+        if channels == 1 { "" } else { "s" }
+    )
+}
+
+// Open and start a capture stream on another device for --follow-default or --reconnect,
+// in the original mix format so the rest of the pipeline doesn't change.
+fn start_capture_stream(
+    device: &Device,
+    mix: &WaveFormat,
+    args: &RecordArgs,
+    console: Console,
+) -> Result<WasapiStream> {
+    let (client, format) =
+        initialize_record_client(device, None, device.get_iaudioclient()?, mix, args, console)?;
+    let stream = WasapiStream::new(client, format)?;
+    stream.client.start_stream()?;
+    Ok(stream)
+}
+
+// An initialized capture stream and what reading it needs
+struct WasapiStream {
+    client: AudioClient,
+    capture: AudioCaptureClient,
+    event: Handle,
+    sample_format: CaptureSampleFormat,
+}
+
+impl WasapiStream {
+    fn new(client: AudioClient, sample_format: CaptureSampleFormat) -> Result<Self> {
+        Ok(Self {
+            capture: client.get_audiocaptureclient()?,
+            event: client.set_get_eventhandle()?,
+            client,
+            sample_format,
+        })
+    }
+}
+
+// The CaptureSource behind `record`: the selected device in event-driven mode, with the
+// --mix-mic stream summed in, --follow-default and --reconnect moving to another device,
+// and lost audio detected from the device position (and filled with --fill-gaps).
+pub(crate) struct WasapiSource {
+    args: RecordArgs,
+    console: Console,
+    enumerator: DeviceEnumerator,
+    device: Device,
+    mix: WaveFormat,
+    // --process or --exclude-process: the program captured alone, or left out
+    process: Option<ProcessTarget>,
+    // The activated client until `open` initializes it into `stream`
+    client: Option<AudioClient>,
+    stream: Option<WasapiStream>,
+    default_watcher: Option<DefaultDeviceWatcher>,
+    mic_mixer: Option<MicMixer>,
+    // Raw bytes of the current packet
+    raw_buf: Vec<u8>,
+    // Dither state for the float -> i16 conversion (unused for i16 capture)
+    dither: Option<TpdfDither>,
+    // Device position (in frames) the next packet should start at; None before the first
+    // read and after a switch
+    next_device_pos: Option<u64>,
+    // When the device was lost, and the silence (frames) owed for a switch
+    lost_at: Option<Instant>,
+    switch_gap_frames: u64,
+    // Float samples clamped at full scale (the microphone keeps its own count)
+    float_overs: u64,
+}
+
+impl WasapiSource {
+    pub(crate) fn new(
+        enumerator: DeviceEnumerator,
+        device: Device,
+        process: Option<ProcessTarget>,
+        args: &RecordArgs,
+        console: Console,
+    ) -> Result<Self> {
+        // wasapi 0.22: get_iaudioclient (not get_audioclient)
+        let mut client = device.get_iaudioclient()?;
+        let mix = client.get_mixformat()?;
+        // A process loopback client has no mix format of its own; Windows converts the
+        // program's audio to the default device's
+        if let Some(target) = &process {
+            client = target.activate()?;
+        }
+        Ok(Self {
+            args: args.clone(),
+            console,
+            enumerator,
+            device,
+            mix,
+            process,
+            client: Some(client),
+            stream: None,
+            default_watcher: None,
+            mic_mixer: None,
+            raw_buf: Vec::new(),
+            dither: (args.dither == DitherMode::Tpdf).then(TpdfDither::new),
+            next_device_pos: None,
+            lost_at: None,
+            switch_gap_frames: 0,
+            float_overs: 0,
+        })
+    }
+
+    fn stream(&mut self) -> &mut WasapiStream {
+        self.stream.as_mut().expect("capture stream is opened before use")
+    }
+
+    // A new stream is in the original mix format, so only the device position restarts.
+    // The time without a device becomes silence (capped like a glitch gap).
+    fn switch_to(&mut self, device: Device, stream: WasapiStream, since: Instant) -> String {
+        let rate = self.mix.get_samplespersec() as u64;
+        self.switch_gap_frames +=
+            ((since.elapsed().as_secs_f64() * rate as f64) as u64).min(MAX_GAP_FILL_SECS * rate);
+        self.stream = Some(stream);
+        self.next_device_pos = None;
+        let name = device
+            .get_friendlyname()
+            .unwrap_or_else(|_| "<unknown>".to_string());
+        self.device = device;
+        name
+    }
+
+    fn read_packet(&mut self, out: &mut Vec<i16>) -> Result<CaptureRead> {
+        let channels = self.mix.get_nchannels() as usize;
+        let rate = self.mix.get_samplespersec() as u64;
+        let source = self.args.source;
+        let timeout_ms = self.args.event_timeout_ms;
+
+        // Packets left over from the last event are read first. Loopback delivers no
+        // packets while nothing is playing, so a wait timeout just means "no data yet".
+        let mut waited = false;
+        let frames_available = loop {
+            let stream = self.stream();
+            if let Some(frames) = stream.capture.get_next_packet_size().context("get_next_packet_size failed")?
+                && frames > 0
+            {
+                break frames;
+            }
+            if waited {
+                return Ok(CaptureRead::Idle { expected: false });
+            }
+            match stream.event.wait_for_event(timeout_ms) {
+                Ok(()) => waited = true,
+                Err(WasapiError::EventTimeout) => {
+                    return Ok(CaptureRead::Idle {
+                        expected: source == Source::Mic,
+                    });
+                }
+                Err(e) => return Err(anyhow::Error::from(e).context("wait_for_event failed")),
+            }
+        };
+
+        let bytes_per_frame = channels * self.stream().sample_format.bytes_per_sample();
+        let needed = frames_available as usize * bytes_per_frame;
+        // Reuse raw_buf (no new allocation each time)
+        let mut raw_buf = std::mem::take(&mut self.raw_buf);
+        raw_buf.clear();
+        raw_buf.resize(needed, 0u8);
+        let read = self.stream().capture.read_from_device(&mut raw_buf);
+        let (frames_read, info) = match read {
+            Ok(read) => read,
+            Err(e) => {
+                self.raw_buf = raw_buf;
+                return Err(anyhow::Error::from(e).context("read_from_device failed"));
+            }
+        };
+        if frames_read == 0 {
+            self.raw_buf = raw_buf;
+            return Ok(CaptureRead::Idle { expected: false });
+        }
+
+        // A discontinuity means audio was lost before this packet. The gap is the packet's
+        // device position minus where the previous packet ended. The first packet is often
+        // flagged just because the stream started, so it isn't counted.
+        let lost_frames = match self.next_device_pos {
+            Some(expected) if info.flags.data_discontinuity => {
+                Some(info.index.saturating_sub(expected).min(MAX_GAP_FILL_SECS * rate))
+            }
+            _ => None,
+        };
+        log::trace!(
+            "Packet: {frames_read} of {frames_available} frames at device position {}{}{}{}",
+            info.index,
+            if info.flags.silent { ", silent" } else { "" },
+            if info.flags.data_discontinuity { ", discontinuity" } else { "" },
+            if info.flags.timestamp_error { ", timestamp error" } else { "" }
+        );
+        self.next_device_pos = Some(info.index + frames_read as u64);
+
+        // --fill-gaps: silence for the lost frames goes in front of this packet,
+        // as does the time a --follow-default switch took
+        let start = out.len();
+        if self.args.fill_gaps
+            && let Some(gap) = lost_frames
+        {
+            out.resize(out.len() + gap as usize * channels, 0);
+        }
+        if self.switch_gap_frames > 0 {
+            out.resize(out.len() + self.switch_gap_frames as usize * channels, 0);
+            self.switch_gap_frames = 0;
+        }
+
+        // A packet flagged silent is silence whatever the buffer holds
+        if info.flags.silent {
+            out.resize(out.len() + frames_read as usize * channels, 0);
+        } else {
+            let used_bytes = frames_read as usize * bytes_per_frame;
+            let sample_format = self.stream().sample_format;
+            self.float_overs += decode_samples_into(
+                sample_format,
+                &raw_buf[..used_bytes],
+                out,
+                self.dither.as_mut(),
+            );
+        }
+        self.raw_buf = raw_buf;
+
+        // Sum in whatever the mic has delivered so far (before resample/downmix). A mic
+        // problem isn't a lost loopback device, so it is reported as a plain error.
+        if let Some(mic) = &mut self.mic_mixer {
+            mic.drain()
+                .map_err(|e| anyhow!("microphone capture failed: {e:#}"))?;
+            mic.mix_into(&mut out[start..]);
+        }
+
+        // The QPC time of the packet's first frame, in 100 ns units
+        Ok(CaptureRead::Block {
+            frames: frames_read as u64,
+            lost_frames,
+            short: frames_read < frames_available,
+            timestamp: (!info.flags.timestamp_error).then(|| Duration::from_nanos(info.timestamp * 100)),
+        })
+    }
+}
+
+impl CaptureSource for WasapiSource {
+    fn name(&self) -> String {
+        if let Some(target) = &self.process {
+            let but = if target.exclude { "All but " } else { "" };
+            return format!("{but}{} (PID {})", target.name, target.pid);
+        }
+        self.device
+            .get_friendlyname()
+            .unwrap_or_else(|_| "<unknown>".to_string())
+    }
+
+    fn format(&self) -> StreamFormat {
+        StreamFormat {
+            sample_rate: self.mix.get_samplespersec(),
+            channels: self.mix.get_nchannels() as usize,
+            channel_mask: self.mix.get_dwchannelmask(),
+        }
+    }
+
+    fn open(&mut self) -> Result<()> {
+        let client = self.client.take().expect("capture stream is opened once");
+        let (client, sample_format) = initialize_record_client(
+            &self.device,
+            self.process.as_ref(),
+            client,
+            &self.mix,
+            &self.args,
+            self.console,
+        )?;
+        let rate = self.mix.get_samplespersec();
+        // A process loopback client reports a meaningless buffer size
+        if self.process.is_none() {
+            let buffer_frames = client.get_buffer_size()?;
+            info!(self.console,
+                "Capture buffer: {buffer_frames} frames ({:.1} ms at {rate} Hz)",
+                buffer_frames as f64 * 1000.0 / rate as f64
+            );
+        }
+        log::info!(
+            "Capture stream opened on {}: {} samples, mix format {}",
+            self.name(),
+            sample_format.label(),
+            wave_format_label(&self.mix)
+        );
+        self.stream = Some(WasapiStream::new(client, sample_format)?);
+
+        // --follow-default: Windows tells us when the default device for this source changes
+        if self.args.follow_default {
+            self.default_watcher =
+                Some(DefaultDeviceWatcher::register(self.args.source.direction(), self.args.role)?);
+        }
+
+        // Optional second capture stream (microphone) summed into the loopback audio
+        if let Some(needle) = self.args.mix_mic.as_deref() {
+            self.mic_mixer = Some(MicMixer::open(
+                &self.enumerator,
+                needle,
+                rate,
+                self.mix.get_nchannels() as usize,
+                &self.args,
+                self.console,
+            )?);
+        }
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.stream().client.start_stream()?;
+        if let Some(mic) = &self.mic_mixer {
+            mic.start()?;
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        let client = &self.stream().client;
+        let _ = client.stop_stream();
+        // Drop what's still buffered, so a restart (after --auto-mono's check) begins fresh
+        let _ = client.reset_stream();
+        if let Some(mic) = &self.mic_mixer {
+            let _ = mic.stop();
+        }
+    }
+
+    fn read(&mut self, out: &mut Vec<i16>) -> Result<CaptureRead> {
+        let read = self.read_packet(out);
+        if read.is_err() && self.lost_at.is_none() {
+            self.lost_at = Some(Instant::now());
+        }
+        read
+    }
+
+    fn reopen(&mut self) -> Result<Option<String>> {
+        let (direction, role) = (self.args.source.direction(), self.args.role.role());
+        let device = match self.enumerator.get_default_device_for_role(&direction, &role) {
+            Ok(device) => device,
+            Err(e) => {
+                log::debug!("Reconnect: no default device yet: {e}");
+                return Ok(None);
+            }
+        };
+        let stream = match start_capture_stream(&device, &self.mix, &self.args, self.console) {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::debug!("Reconnect: can't open the default device yet: {e:#}");
+                return Ok(None);
+            }
+        };
+        let since = self.lost_at.take().unwrap_or_else(Instant::now);
+        Ok(Some(self.switch_to(device, stream, since)))
+    }
+
+    // The new stream is opened in the original mix format (autoconvert adapts the new
+    // device to it), so everything after the decode stays as it is. Without a default
+    // device (everything unplugged) the current one is kept.
+    fn follow_default(&mut self) -> Result<Option<String>> {
+        if !self.default_watcher.as_ref().is_some_and(|watcher| watcher.changed()) {
+            return Ok(None);
+        }
+        let switch_start = Instant::now();
+        let (direction, role) = (self.args.source.direction(), self.args.role.role());
+        let Ok(device) = self.enumerator.get_default_device_for_role(&direction, &role) else {
+            return Ok(None);
+        };
+        if device.get_id().unwrap_or_default() == self.device.get_id().unwrap_or_default() {
+            log::debug!("Default device change notified, but it is still the recorded device");
+            return Ok(None);
+        }
+        // The old device may already be gone, so a failed stop doesn't matter
+        let _ = self.stream().client.stop_stream();
+        let stream = start_capture_stream(&device, &self.mix, &self.args, self.console)?;
+        Ok(Some(self.switch_to(device, stream, switch_start)))
+    }
+
+    fn float_overs(&self) -> u64 {
+        self.float_overs + self.mic_mixer.as_ref().map_or(0, |mic| mic.float_overs)
+    }
+
+    fn id(&self) -> Option<String> {
+        if self.process.is_some() {
+            return None;
+        }
+        self.device.get_id().ok()
+    }
+}
+
+// Process loopback capture arrived in Windows 10 version 2004
+const PROCESS_LOOPBACK_MIN_BUILD: u32 = 19041;
+
+// A --process or --exclude-process target: the program whose audio (with that of its
+// child processes) is captured alone, or everything but, through process loopback
+// instead of the endpoint's mix
+pub(crate) struct ProcessTarget {
+    pid: u32,
+    name: String,
+    exclude: bool,
+}
+
+impl ProcessTarget {
+    // The process as found by find_process, on a Windows version with process loopback
+    pub(crate) fn find(spec: &str, exclude: bool, console: Console) -> Result<Self> {
+        let option = if exclude { "--exclude-process" } else { "--process" };
+        let build = windows_build();
+        if build < PROCESS_LOOPBACK_MIN_BUILD {
+            bail!(
+                "{option} needs Windows 10 version 2004 (build {PROCESS_LOOPBACK_MIN_BUILD}) \
+                 or later; this is build {build}. Record the whole device instead"
+            );
+        }
+        let (pid, name) = find_process(spec, option, console)?;
+        Ok(Self { pid, name, exclude })
+    }
+
+    // wasapi activates the client through ActivateAudioInterfaceAsync with
+    // AUDIOCLIENT_ACTIVATION_PARAMS, in include-tree or exclude-tree mode
+    fn activate(&self) -> Result<AudioClient> {
+        AudioClient::new_application_loopback_client(self.pid, !self.exclude)
+            .with_context(|| format!("process loopback activation for PID {} failed", self.pid))
+    }
+}
+
+// The PID and executable name of the process `option` names: a PID, or an executable
+// name with or without `.exe` (case-insensitive). Of several processes with the name, the
+// one whose parent has another name is taken: browsers and games start helpers under the
+// same name, and a process tree includes them.
+pub(crate) fn find_process(spec: &str, option: &str, console: Console) -> Result<(u32, String)> {
+    let processes = running_processes()?;
+    if let Ok(pid) = spec.parse::<u32>() {
+        let Some((_, _, name)) = processes.into_iter().find(|(id, _, _)| *id == pid) else {
+            bail!("{option}: no process has PID {pid}");
+        };
+        return Ok((pid, name));
+    }
+    let wanted = spec.to_lowercase();
+    let matches_name = |exe: &str| {
+        let exe = exe.to_lowercase();
+        exe == wanted || exe.strip_suffix(".exe") == Some(wanted.as_str())
+    };
+    let named: Vec<&(u32, u32, String)> =
+        processes.iter().filter(|(_, _, exe)| matches_name(exe)).collect();
+    let roots: Vec<&(u32, u32, String)> = named
+        .iter()
+        .copied()
+        .filter(|(_, parent, _)| !named.iter().any(|(pid, _, _)| pid == parent))
+        .collect();
+    match roots.as_slice() {
+        [] => bail!("{option}: no running program is named {spec}"),
+        [(pid, _, name)] => {
+            if named.len() > 1 {
+                info!(console, "{name}: {} processes; using the tree from PID {pid}", named.len());
+            }
+            Ok((*pid, name.clone()))
+        }
+        _ => {
+            let pids: Vec<String> = roots.iter().map(|(pid, _, _)| pid.to_string()).collect();
+            bail!(
+                "{option}: {} separate programs are named {spec} (PIDs {}); give the PID",
+                roots.len(),
+                pids.join(", ")
+            )
+        }
+    }
+}
+
+// (PID, parent PID, executable name) of every running process
+fn running_processes() -> Result<Vec<(u32, u32, String)>> {
+    let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) }
+        .context("CreateToolhelp32Snapshot failed")?;
+    let mut entry = PROCESSENTRY32W {
+        dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32,
+        ..Default::default()
+    };
+    let mut processes = Vec::new();
+    let mut more = unsafe { Process32FirstW(snapshot, &mut entry) }.is_ok();
+    while more {
+        let exe = &entry.szExeFile;
+        let len = exe.iter().position(|&c| c == 0).unwrap_or(exe.len());
+        processes.push((
+            entry.th32ProcessID,
+            entry.th32ParentProcessID,
+            String::from_utf16_lossy(&exe[..len]),
+        ));
+        more = unsafe { Process32NextW(snapshot, &mut entry) }.is_ok();
+    }
+    let _ = unsafe { CloseHandle(snapshot) };
+    Ok(processes)
+}
+
+// The Windows build number. RtlGetVersion reports the real version whatever the program's
+// manifest declares, unlike GetVersionExW.
+fn windows_build() -> u32 {
+    let mut info = OSVERSIONINFOW {
+        dwOSVersionInfoSize: std::mem::size_of::<OSVERSIONINFOW>() as u32,
+        ..Default::default()
+    };
+    let _ = unsafe { RtlGetVersion(&mut info) };
+    info.dwBuildNumber
+}
+
+// Errors WASAPI reports once the endpoint is gone: unplugged or disabled device, a format
+// change in Sound settings, or a restart of the audio service.
+pub(crate) fn is_device_lost(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<WasapiError>() {
+        Some(WasapiError::Windows(e)) => [
+            AUDCLNT_E_DEVICE_INVALIDATED,
+            AUDCLNT_E_RESOURCES_INVALIDATED,
+            AUDCLNT_E_SERVICE_NOT_RUNNING,
+        ]
+        .contains(&e.code()),
+        _ => false,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CaptureSampleFormat {
+    I16,
+    F32,
+    // Packed 3-byte samples (exclusive mode only)
+    I24,
+    // 4-byte integer samples, 32 or 24 valid bits left-justified (exclusive mode only)
+    I32,
+}
+
+impl CaptureSampleFormat {
+    fn bytes_per_sample(self) -> usize {
+        match self {
+            CaptureSampleFormat::I16 => 2,
+            CaptureSampleFormat::F32 | CaptureSampleFormat::I32 => 4,
+            CaptureSampleFormat::I24 => 3,
+        }
+    }
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            CaptureSampleFormat::I16 => "16-bit PCM",
+            CaptureSampleFormat::F32 => "32-bit float",
+            CaptureSampleFormat::I24 => "24-bit PCM",
+            CaptureSampleFormat::I32 => "32-bit PCM",
+        }
+    }
+
+    // The decoder for a wave format, if there is one
+    fn from_wave_format(fmt: &WaveFormat) -> Option<Self> {
+        match (fmt.get_subformat().ok()?, fmt.get_bitspersample()) {
+            (SampleType::Float, 32) => Some(CaptureSampleFormat::F32),
+            (SampleType::Int, 16) => Some(CaptureSampleFormat::I16),
+            (SampleType::Int, 24) => Some(CaptureSampleFormat::I24),
+            (SampleType::Int, 32) => Some(CaptureSampleFormat::I32),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn is_f32_format(fmt: &WaveFormat) -> bool {
+    matches!(fmt.get_subformat(), Ok(SampleType::Float)) && fmt.get_bitspersample() == 32
+}
+
+// Returns how many float samples were at or beyond full scale (|value| >= 1.0) and got
+// clamped; integer formats can't go over
+fn decode_samples_into(
+    format: CaptureSampleFormat,
+    bytes: &[u8],
+    out: &mut Vec<i16>,
+    dither: Option<&mut TpdfDither>,
+) -> u64 {
+    match format {
+        CaptureSampleFormat::I16 => decode_i16_into(bytes, out),
+        CaptureSampleFormat::F32 => return decode_f32_to_i16_into(bytes, out, dither),
+        CaptureSampleFormat::I24 => decode_wide_to_i16_into(bytes, 3, out, dither),
+        CaptureSampleFormat::I32 => decode_wide_to_i16_into(bytes, 4, out, dither),
+    }
+    0
+}
+
+// Grows `out` once and fills the new tail in a single bounds-check-free pass, which the
+// compiler vectorizes. A trailing odd byte (never a whole sample) is ignored.
+fn decode_i16_into(bytes: &[u8], out: &mut Vec<i16>) {
+    let start = out.len();
+    out.resize(start + bytes.len() / 2, 0);
+    for (dst, chunk) in out[start..].iter_mut().zip(bytes.chunks_exact(2)) {
+        *dst = i16::from_le_bytes([chunk[0], chunk[1]]);
+    }
+}
+
+fn decode_f32_to_i16_into(
+    bytes: &[u8],
+    out: &mut Vec<i16>,
+    dither: Option<&mut TpdfDither>,
+) -> u64 {
+    out.reserve(bytes.len() / 4);
+    let samples = bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+    let overs = samples.clone().filter(|s| s.abs() >= 1.0).count() as u64;
+    match dither {
+        Some(dither) => out.extend(samples.map(|s| f32_to_i16_dithered(s, dither.next()))),
+        None => out.extend(samples.map(f32_to_i16)),
+    }
+    overs
+}
+
+// 24- and 32-bit integer samples (little-endian, `width` bytes). Without dither the top 16
+// bits are kept, so 16-bit material padded by the driver comes through bit for bit; with
+// dither the sample goes through the float path.
+fn decode_wide_to_i16_into(
+    bytes: &[u8],
+    width: usize,
+    out: &mut Vec<i16>,
+    dither: Option<&mut TpdfDither>,
+) {
+    out.reserve(bytes.len() / width);
+    // Sign-extend into an i32 with the sample in the top bits
+    let samples = bytes.chunks_exact(width).map(|chunk| {
+        let mut le = [0u8; 4];
+        le[4 - width..].copy_from_slice(chunk);
+        i32::from_le_bytes(le)
+    });
+    match dither {
+        Some(dither) => out.extend(
+            samples.map(|s| f32_to_i16_dithered(s as f32 / 2_147_483_648.0, dither.next())),
+        ),
+        None => out.extend(samples.map(|s| (s >> 16) as i16)),
+    }
+}
+
+fn f32_to_i16(s: f32) -> i16 {
+    // Float PCM is nominally [-1.0, 1.0] but can overshoot; clamp before scaling.
+    // NaN maps to 0 via the saturating `as` cast.
+    (s.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+}
+
+// Same, with `noise` (in LSBs) added before rounding; the `as` cast saturates at the rails
+fn f32_to_i16_dithered(s: f32, noise: f32) -> i16 {
+    (s.clamp(-1.0, 1.0) * i16::MAX as f32 + noise).round() as i16
+}
+
+// --follow-default: an IMMNotificationClient registered with the system device enumerator.
+// Windows calls it on its own thread; a default-device change for the watched direction
+// is passed to the capture loop over a channel.
+#[windows::core::implement(IMMNotificationClient)]
+struct DefaultDeviceNotifier {
+    flow: EDataFlow,
+    role: ERole,
+    changed: Sender<()>,
+}
+
+impl IMMNotificationClient_Impl for DefaultDeviceNotifier_Impl {
+    fn OnDefaultDeviceChanged(
+        &self,
+        flow: EDataFlow,
+        role: ERole,
+        _default_device_id: &PCWSTR,
+    ) -> windows::core::Result<()> {
+        // Each role reports separately; only the --role one is followed
+        if flow == self.flow && role == self.role {
+            let _ = self.changed.try_send(());
+        }
+        Ok(())
+    }
+
+    fn OnDeviceStateChanged(&self, _device_id: &PCWSTR, _new_state: DEVICE_STATE) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnDeviceAdded(&self, _device_id: &PCWSTR) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnDeviceRemoved(&self, _device_id: &PCWSTR) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnPropertyValueChanged(&self, _device_id: &PCWSTR, _key: &PROPERTYKEY) -> windows::core::Result<()> {
+        Ok(())
+    }
+}
+
+// Keeps the notifier registered for as long as it lives.
+struct DefaultDeviceWatcher {
+    enumerator: IMMDeviceEnumerator,
+    client: IMMNotificationClient,
+    changed: Receiver<()>,
+}
+
+impl DefaultDeviceWatcher {
+    fn register(direction: Direction, role: DeviceRole) -> Result<Self> {
+        let flow = match direction {
+            Direction::Render => eRender,
+            Direction::Capture => eCapture,
+        };
+        let (tx, rx) = bounded::<()>(1);
+        // SAFETY: COM is initialized for this thread (initialize_mta in main).
+        let enumerator: IMMDeviceEnumerator =
+            unsafe { CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL) }
+                .context("failed to create the device enumerator for --follow-default")?;
+        let client: IMMNotificationClient =
+            DefaultDeviceNotifier { flow, role: role.erole(), changed: tx }.into();
+        // SAFETY: `client` is a valid COM object and stays alive until it is unregistered in drop.
+        unsafe { enumerator.RegisterEndpointNotificationCallback(&client) }
+            .context("failed to register for default device changes")?;
+        Ok(Self {
+            enumerator,
+            client,
+            changed: rx,
+        })
+    }
+
+    // True once after a change; several quick changes collapse into one
+    fn changed(&self) -> bool {
+        self.changed.try_recv().is_ok()
+    }
+}
+
+impl Drop for DefaultDeviceWatcher {
+    fn drop(&mut self) {
+        // SAFETY: unregisters the callback registered in `register`.
+        let _ = unsafe { self.enumerator.UnregisterEndpointNotificationCallback(&self.client) };
+    }
+}
+
+// How the user picked a device on the command line.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum DeviceSelector<'a> {
+    Default,
+    Substring(&'a str),
+    Index(u32),
+    Id(&'a str),
+}
+
+impl<'a> DeviceSelector<'a> {
+    // --device-index, then --device-id, then --device (clap keeps them exclusive)
+    pub(crate) fn from_options(
+        index: Option<u32>,
+        id: Option<&'a str>,
+        substring: Option<&'a str>,
+    ) -> Self {
+        if let Some(index) = index {
+            DeviceSelector::Index(index)
+        } else if let Some(id) = id {
+            DeviceSelector::Id(id)
+        } else if let Some(needle) = substring {
+            DeviceSelector::Substring(needle)
+        } else {
+            DeviceSelector::Default
+        }
+    }
+}
+
+pub(crate) fn select_device(
+    enumerator: &DeviceEnumerator,
+    direction: Direction,
+    selector: DeviceSelector,
+    role: DeviceRole,
+) -> Result<Device> {
+    let collection = enumerator.get_device_collection(&direction)?;
+    let count = collection.get_nbr_devices()?;
+    log::debug!("{count} active {direction} device(s), selecting by {selector:?}");
+
+    match selector {
+        DeviceSelector::Default => {
+            Ok(enumerator.get_default_device_for_role(&direction, &role.role())?)
+        }
+        DeviceSelector::Index(index) => {
+            if index >= count {
+                bail!("--device-index {index} out of range: {count} {direction} device(s) (see `list`)");
+            }
+            Ok(collection.get_device_at_index(index)?)
+        }
+        DeviceSelector::Id(id) => {
+            for i in 0..count {
+                let dev = collection.get_device_at_index(i)?;
+                if dev.get_id().is_ok_and(|dev_id| dev_id == id) {
+                    return Ok(dev);
+                }
+            }
+            bail!("No {direction} device has id: {id}");
+        }
+        DeviceSelector::Substring(needle) => {
+            let needle = needle.to_lowercase();
+            let mut matches = Vec::new();
+            for i in 0..count {
+                let dev = collection.get_device_at_index(i)?;
+                let name = dev.get_friendlyname().unwrap_or_default();
+                log::debug!("[{i}] {name}");
+                if name.to_lowercase().contains(&needle) {
+                    matches.push((i, name, dev));
+                }
+            }
+
+            match matches.len() {
+                0 => bail!("No {direction} device matched substring: {needle}"),
+                1 => Ok(matches.pop().expect("one match").2),
+                _ => {
+                    let list: Vec<String> = matches
+                        .iter()
+                        .map(|(i, name, _)| format!("  [{i}] {name}"))
+                        .collect();
+                    bail!(
+                        "{} {direction} devices matched substring: {needle}\n{}\n\
+                         Use a more specific --device, or --device-index / --device-id",
+                        matches.len(),
+                        list.join("\n")
+                    );
+                }
+            }
+        }
+    }
+}
+
+// --auto-mono: reads about AUTO_MONO_PROBE of audio (waiting at most twice as long for it)
+// and tells whether both channels matched throughout. Silence proves nothing, so it counts
+// as stereo. None means Ctrl+C stopped the check.
+pub(crate) fn probe_mono(
+    source: &mut dyn CaptureSource,
+    rate: u32,
+    stop_rx: &Receiver<()>,
+    console: Console,
+) -> Result<Option<bool>> {
+    let wanted = rate as u64 * AUTO_MONO_PROBE.as_millis() as u64 / 1000;
+    let started = Instant::now();
+    let mut block = Vec::new();
+    let (mut frames, mut same, mut heard) = (0, true, false);
+    source.start()?;
+    while frames < wanted && started.elapsed() < AUTO_MONO_PROBE * 2 {
+        if stop_requested(stop_rx) {
+            source.stop();
+            return Ok(None);
+        }
+        block.clear();
+        match source.read(&mut block) {
+            Ok(CaptureRead::Block { frames: n, .. }) => {
+                frames += n;
+                same &= channels_match(&block);
+                heard |= block.iter().any(|&s| i32::from(s).abs() > AUTO_MONO_TOLERANCE);
+            }
+            Ok(CaptureRead::Idle { .. }) => {}
+            Ok(CaptureRead::Ended) => break,
+            Err(e) => {
+                source.stop();
+                return Err(e.context("--auto-mono couldn't read the source"));
+            }
+        }
+    }
+    source.stop();
+    let mono = same && heard;
+    if mono {
+        info!(console, "Auto mono: both channels carry the same audio; recording one channel");
+    } else if heard {
+        info!(console, "Auto mono: the channels differ; recording stereo");
+    } else {
+        info!(
+            console,
+            "Auto mono: nothing played in the first second to compare; recording stereo"
+        );
+    }
+    Ok(Some(mono))
+}
+
+// Whether every frame of two-channel samples has left and right within AUTO_MONO_TOLERANCE
+pub(crate) fn channels_match(samples: &[i16]) -> bool {
+    samples
+        .chunks_exact(2)
+        .all(|frame| (i32::from(frame[0]) - i32::from(frame[1])).abs() <= AUTO_MONO_TOLERANCE)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dsp::TpdfDither;
+
+    use super::{
+        decode_f32_to_i16_into, decode_i16_into, decode_wide_to_i16_into, f32_to_i16,
+        f32_to_i16_dithered,
+    };
+
+    #[test]
+    fn f32_to_i16_scales_full_scale_to_the_rails() {
+        assert_eq!(f32_to_i16(1.0), i16::MAX);
+        assert_eq!(f32_to_i16(-1.0), -i16::MAX);
+        assert_eq!(f32_to_i16(0.0), 0);
+        assert_eq!(f32_to_i16(0.5), 16384);
+    }
+
+    #[test]
+    fn f32_to_i16_clamps_out_of_range_samples() {
+        assert_eq!(f32_to_i16(1.5), i16::MAX);
+        assert_eq!(f32_to_i16(-7.0), -i16::MAX);
+        assert_eq!(f32_to_i16(f32::INFINITY), i16::MAX);
+        assert_eq!(f32_to_i16(f32::NEG_INFINITY), -i16::MAX);
+        assert_eq!(f32_to_i16(f32::NAN), 0);
+    }
+
+    #[test]
+    fn f32_to_i16_rounds_to_the_nearest_step() {
+        let lsb = 1.0 / i16::MAX as f32;
+        assert_eq!(f32_to_i16(1.4 * lsb), 1);
+        assert_eq!(f32_to_i16(1.6 * lsb), 2);
+        assert_eq!(f32_to_i16(-1.4 * lsb), -1);
+        assert_eq!(f32_to_i16(-1.6 * lsb), -2);
+        assert_eq!(f32_to_i16(0.4 * lsb), 0);
+    }
+
+    // Arbitrary but repeatable bytes, from a xorshift generator
+    fn noise_bytes(len: usize) -> Vec<u8> {
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 32) as u8
+            })
+            .collect()
+    }
+
+    // Each decoder appends after what is already in `out`, like the capture loop's buffer
+    fn prefilled() -> Vec<i16> {
+        vec![7, -7]
+    }
+
+    #[test]
+    fn i16_decoding_matches_a_per_sample_loop() {
+        let mut bytes = noise_bytes(4000);
+        bytes.extend_from_slice(&[0x00, 0x80, 0xFF, 0x7F, 0xFF, 0xFF, 0x00, 0x00]);
+        let mut expected = prefilled();
+        for chunk in bytes.chunks_exact(2) {
+            expected.push(i16::from_le_bytes([chunk[0], chunk[1]]));
+        }
+        let mut out = prefilled();
+        decode_i16_into(&bytes, &mut out);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn wide_decoding_matches_a_per_sample_loop() {
+        for width in [3, 4] {
+            let mut bytes = noise_bytes(width * 1000);
+            // Full scale both ways, -1 LSB, and 16-bit material padded to the width
+            for sample in [i32::MIN, i32::MAX, -1, 0x1234 << 16, -0x1234 << 16] {
+                bytes.extend_from_slice(&sample.to_le_bytes()[4 - width..]);
+            }
+            let mut expected = prefilled();
+            for chunk in bytes.chunks_exact(width) {
+                let mut sample = 0i32;
+                for (i, &b) in chunk.iter().enumerate() {
+                    sample |= (b as i32) << (8 * (4 - width + i));
+                }
+                expected.push((sample >> 16) as i16);
+            }
+            let mut out = prefilled();
+            decode_wide_to_i16_into(&bytes, width, &mut out, None);
+            assert_eq!(out, expected, "{}-bit", width * 8);
+            assert_eq!(&out[out.len() - 2..], [0x1234, -0x1234]);
+        }
+    }
+
+    #[test]
+    fn float_decoding_matches_a_per_sample_loop() {
+        let mut samples: Vec<f32> = noise_bytes(4000)
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]) as f32 / 32768.0 * 3.0 - 1.5)
+            .collect();
+        samples.extend([1.0, -1.0, 0.0, f32::NAN, f32::INFINITY]);
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+
+        let mut expected = prefilled();
+        let mut expected_overs = 0;
+        for chunk in bytes.chunks_exact(4) {
+            let s = f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            if s.abs() >= 1.0 {
+                expected_overs += 1;
+            }
+            expected.push(f32_to_i16(s));
+        }
+        let mut out = prefilled();
+        let overs = decode_f32_to_i16_into(&bytes, &mut out, None);
+        assert_eq!(out, expected);
+        assert_eq!(overs, expected_overs);
+        assert!(overs > 0);
+    }
+
+    #[test]
+    fn dithered_decoding_matches_a_per_sample_loop() {
+        let samples: Vec<f32> = (0..500).map(|i| (i as f32 * 0.01).sin() * 0.001).collect();
+        let float_bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let mut noise = TpdfDither::new();
+        let expected: Vec<i16> =
+            samples.iter().map(|&s| f32_to_i16_dithered(s, noise.next())).collect();
+        let mut out = Vec::new();
+        decode_f32_to_i16_into(&float_bytes, &mut out, Some(&mut TpdfDither::new()));
+        assert_eq!(out, expected);
+
+        // The same signal as 32-bit integers takes the float path with dither
+        let int_bytes: Vec<u8> = samples
+            .iter()
+            .flat_map(|&s| ((s as f64 * 2_147_483_648.0) as i32).to_le_bytes())
+            .collect();
+        let mut noise = TpdfDither::new();
+        let expected: Vec<i16> = int_bytes
+            .chunks_exact(4)
+            .map(|chunk| {
+                let s = i32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                f32_to_i16_dithered(s as f32 / 2_147_483_648.0, noise.next())
+            })
+            .collect();
+        let mut out = Vec::new();
+        decode_wide_to_i16_into(&int_bytes, 4, &mut out, Some(&mut TpdfDither::new()));
+        assert_eq!(out, expected);
+    }
+}
@@ -0,0 +1,250 @@
+//! `--checksum` digests of the recorded samples.
+
+use crate::args::ChecksumKind;
+
+// --checksum: a running hash of the samples written, fed little-endian as the WAV data
+// chunk holds them, so a WAV, FLAC and MP3 of one recording share the digest
+pub(crate) enum Checksum {
+    Sha256(Sha256),
+    Crc32(u32),
+}
+
+impl Checksum {
+    pub(crate) fn new(kind: ChecksumKind) -> Self {
+        match kind {
+            ChecksumKind::Sha256 => Checksum::Sha256(Sha256::new()),
+            ChecksumKind::Crc32 => Checksum::Crc32(!0),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Checksum::Sha256(_) => "sha256",
+            Checksum::Crc32(_) => "crc32",
+        }
+    }
+
+    // Converted through a small block on the stack rather than a copy of the chunk
+    pub(crate) fn update(&mut self, samples: &[i16]) {
+        let mut bytes = [0u8; 128];
+        for chunk in samples.chunks(64) {
+            for (pair, s) in bytes.chunks_exact_mut(2).zip(chunk) {
+                pair.copy_from_slice(&s.to_le_bytes());
+            }
+            let bytes = &bytes[..chunk.len() * 2];
+            match self {
+                Checksum::Sha256(sha) => sha.update(bytes),
+                Checksum::Crc32(crc) => crc32_update(crc, bytes),
+            }
+        }
+    }
+
+    pub(crate) fn hex(&self) -> String {
+        match self {
+            Checksum::Sha256(sha) => sha.digest().iter().map(|b| format!("{b:02x}")).collect(),
+            Checksum::Crc32(crc) => format!("{:08x}", !crc),
+        }
+    }
+}
+
+// CRC-32 with the reflected polynomial 0xEDB88320, as in zip and PNG (the register starts
+// and ends inverted, in Checksum)
+fn crc32_update(crc: &mut u32, bytes: &[u8]) {
+    for &b in bytes {
+        *crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (*crc & 1).wrapping_neg();
+            *crc = (*crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+}
+
+// SHA-256 (FIPS 180-4) for --checksum, laid out like Md5
+#[derive(Clone)]
+pub(crate) struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5,
+    0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc,
+    0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+    0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3,
+    0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5,
+    0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+impl Sha256 {
+    fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+                0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+            ],
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        while !data.is_empty() {
+            let n = (64 - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+            if self.block_len == 64 {
+                sha256_compress(&mut self.state, &self.block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    fn digest(&self) -> [u8; 32] {
+        let mut sha = self.clone();
+        let bit_len = self.total_len.wrapping_mul(8);
+        sha.update(&[0x80]);
+        while sha.block_len != 56 {
+            sha.update(&[0]);
+        }
+        sha.update(&bit_len.to_be_bytes());
+        let mut out = [0u8; 32];
+        for (chunk, word) in out.chunks_exact_mut(4).zip(sha.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        out
+    }
+}
+
+fn sha256_compress(state: &mut [u32; 8], block: &[u8; 64]) {
+    let mut w = [0u32; 64];
+    for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(SHA256_K[i])
+            .wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+    for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::args::ChecksumKind;
+
+    use super::{crc32_update, Checksum, Sha256};
+
+    fn sha256_hex(data: &[u8]) -> String {
+        let mut sha = Sha256::new();
+        sha.update(data);
+        sha.digest().iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    fn crc32_hex(data: &[u8]) -> String {
+        let mut crc = !0;
+        crc32_update(&mut crc, data);
+        format!("{:08x}", !crc)
+    }
+
+    #[test]
+    fn sha256_matches_known_digests() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // 56 bytes: the padding no longer fits, so the digest takes a second block
+        let two_blocks = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        assert_eq!(two_blocks.len(), 56);
+        assert_eq!(
+            sha256_hex(two_blocks),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        // Fed in pieces that don't line up with the blocks
+        let mut sha = Sha256::new();
+        for piece in two_blocks.chunks(5) {
+            sha.update(piece);
+        }
+        assert_eq!(sha.digest(), {
+            let mut whole = Sha256::new();
+            whole.update(two_blocks);
+            whole.digest()
+        });
+    }
+
+    #[test]
+    fn crc32_matches_known_checks() {
+        assert_eq!(crc32_hex(b""), "00000000");
+        assert_eq!(crc32_hex(b"123456789"), "cbf43926");
+    }
+
+    #[test]
+    fn checksum_hashes_samples_as_little_endian_bytes() {
+        // 01 00 fe ff 34 12 00 80
+        let samples = [1, -2, 0x1234, i16::MIN];
+        let mut sha = Checksum::new(ChecksumKind::Sha256);
+        sha.update(&samples);
+        assert_eq!(sha.name(), "sha256");
+        assert_eq!(sha.hex(), "3334dc1a6ad80654042f4a0247e30cfdd5fba1d7facf8d65e07eed6286c2d4f5");
+        let mut crc = Checksum::new(ChecksumKind::Crc32);
+        crc.update(&samples);
+        assert_eq!(crc.name(), "crc32");
+        assert_eq!(crc.hex(), "c127fbda");
+
+        // Across the 64-sample blocks update converts in, and split over several calls
+        let long: Vec<i16> = (0..1000).map(|i| (i * 331) as i16).collect();
+        let bytes: Vec<u8> = long.iter().flat_map(|s| s.to_le_bytes()).collect();
+        for kind in [ChecksumKind::Sha256, ChecksumKind::Crc32] {
+            let mut checksum = Checksum::new(kind);
+            for piece in long.chunks(99) {
+                checksum.update(piece);
+            }
+            let expected = match kind {
+                ChecksumKind::Sha256 => sha256_hex(&bytes),
+                ChecksumKind::Crc32 => crc32_hex(&bytes),
+            };
+            assert_eq!(checksum.hex(), expected);
+        }
+    }
+}
@@ -0,0 +1,586 @@
+//! The `list`, `probe`, `selftest` and `bench` subcommands.
+
+use anyhow::{bail, Context, Result};
+use shine_rs::SUPPORTED_SAMPLE_RATES;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use wasapi::{DeviceEnumerator, Direction, WaveFormat};
+
+use crate::args::{
+    BenchArgs, ChannelMode, DeviceRole, ListArgs, OutputFormat, ProbeArgs, RecordArgs, SelfTestArgs,
+    Source, DEFAULT_FLAC_LEVEL,
+};
+use crate::capture::{
+    initialize_capture_client, is_f32_format, select_device, CaptureSampleFormat, DeviceSelector,
+    StreamFormat, DEFAULT_BUFFER_MS,
+};
+use crate::control::{ConsoleKeys, PickerKey};
+use crate::dsp::{speaker_names, stereo_downmix_matrix, to_dbfs};
+use crate::json::{json_list, json_string};
+use crate::record::{human_bytes, Recorder, RecordingHandle};
+use crate::sinks::{create_sink, pcm_sample_format, valid_bitrates};
+use crate::Console;
+
+// Endpoint id of the default device for each role, empty where a role has none
+fn default_device_ids(
+    enumerator: &DeviceEnumerator,
+    direction: &Direction,
+) -> Vec<(DeviceRole, String)> {
+    DeviceRole::ALL
+        .iter()
+        .map(|&role| {
+            let id = enumerator
+                .get_default_device_for_role(direction, &role.role())
+                .and_then(|dev| dev.get_id())
+                .unwrap_or_default();
+            (role, id)
+        })
+        .collect()
+}
+
+// "console, multimedia": the roles `id` is the default device for
+fn default_roles(defaults: &[(DeviceRole, String)], id: &str) -> Vec<&'static str> {
+    defaults
+        .iter()
+        .filter(|(_, default_id)| !default_id.is_empty() && default_id == id)
+        .map(|(role, _)| role.label())
+        .collect()
+}
+
+/// `ARec list`: print the default device and every active device of a direction.
+/// Needs COM initialized on the calling thread (`wasapi::initialize_mta`).
+pub fn list_devices(args: &ListArgs) -> Result<()> {
+    let source = args.source;
+    let enumerator = DeviceEnumerator::new()?;
+    let direction = source.direction();
+    let kind = source.kind();
+
+    let default = enumerator.get_default_device_for_role(&direction, &args.role.role())?;
+    let default_id = default.get_id().unwrap_or_default();
+    let defaults = default_device_ids(&enumerator, &direction);
+
+    if args.json {
+        return list_devices_json(&enumerator, &direction, kind, &default_id, &defaults);
+    }
+
+    if args.role == DeviceRole::Console {
+        println!("Default {kind} device (will be recorded if you don't pass --device):");
+    } else {
+        println!(
+            "Default {} {kind} device (recorded with --role {} if you don't pass --device):",
+            args.role.label(),
+            args.role.label()
+        );
+    }
+    println!(
+        "  {}",
+        default
+            .get_friendlyname()
+            .unwrap_or_else(|_| "<unknown>".to_string())
+    );
+    println!("  id: {default_id}");
+    println!();
+
+    match source {
+        Source::Loopback => println!("Render (playback) devices:"),
+        Source::Mic => println!("Capture (recording) devices:"),
+    }
+    let collection = enumerator.get_device_collection(&direction)?;
+    for (i, (name, id)) in enumerate_devices(&enumerator, &direction)?.iter().enumerate() {
+        let mark = if *id == default_id { "*" } else { " " };
+        let roles = default_roles(&defaults, id);
+        if roles.is_empty() {
+            println!("  {mark}[{i}] {name}");
+        } else {
+            println!("  {mark}[{i}] {name}  (default: {})", roles.join(", "));
+        }
+        println!("       id: {id}");
+        if args.formats {
+            let mix = collection
+                .get_device_at_index(i as u32)
+                .and_then(|dev| dev.get_iaudioclient())
+                .and_then(|client| client.get_mixformat());
+            match mix {
+                Ok(mix) => {
+                    let speakers = speaker_names(mix.get_dwchannelmask());
+                    if speakers.is_empty() {
+                        println!("       format: {}", describe_mix_format(&mix));
+                    } else {
+                        println!(
+                            "       format: {} ({})",
+                            describe_mix_format(&mix),
+                            speakers.join(" ")
+                        );
+                    }
+                    println!("       {}", mp3_support(mix.get_samplespersec()));
+                }
+                Err(e) => println!("       format: unavailable ({e})"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// "48000 Hz, 2 channels, 32-bit float" for `list --formats` and `probe`
+fn describe_mix_format(mix: &WaveFormat) -> String {
+    let channels = mix.get_nchannels();
+    format!(
+        "{} Hz, {channels} channel{}, {}-bit {}",
+        mix.get_samplespersec(),
+        if channels == 1 { "" } else { "s" },
+        mix.get_bitspersample(),
+        if is_f32_format(mix) { "float" } else { "PCM" }
+    )
+}
+
+// Which --kbps values work at the device rate, or which --resample rates to use instead
+fn mp3_support(rate: u32) -> String {
+    let join = |items: &[u32]| items.iter().map(u32::to_string).collect::<Vec<_>>().join(", ");
+    if SUPPORTED_SAMPLE_RATES.contains(&rate) {
+        format!("MP3: {rate} Hz supported; bitrates {} kbps", join(&valid_bitrates(rate)))
+    } else {
+        format!(
+            "MP3: {rate} Hz not supported; use --resample with one of {} Hz (or --format wav)",
+            join(SUPPORTED_SAMPLE_RATES)
+        )
+    }
+}
+
+// (friendly name, endpoint id) of each active device, in `list` [index] order
+fn enumerate_devices(enumerator: &DeviceEnumerator, direction: &Direction) -> Result<Vec<(String, String)>> {
+    let collection = enumerator.get_device_collection(direction)?;
+    let count = collection.get_nbr_devices()?;
+
+    let mut devices = Vec::with_capacity(count as usize);
+    for i in 0..count {
+        let dev = collection.get_device_at_index(i)?;
+        let name = dev.get_friendlyname().unwrap_or_else(|_| "<unknown>".to_string());
+        let id = dev.get_id().unwrap_or_else(|_| "<unknown>".to_string());
+        devices.push((name, id));
+    }
+    Ok(devices)
+}
+
+// `record --pick`: a menu of the devices `list` shows, starting on the default one.
+// Up/Down move the highlight, digits jump to that [index], Enter takes the highlighted
+// device and Esc or Ctrl+C cancels. Returns the [index] for DeviceSelector::Index.
+pub(crate) fn pick_device(
+    enumerator: &DeviceEnumerator,
+    source: Source,
+    role: DeviceRole,
+) -> Result<u32> {
+    let direction = source.direction();
+    let devices = enumerate_devices(enumerator, &direction)?;
+    if devices.is_empty() {
+        bail!("No active {direction} devices to pick from");
+    }
+    let default_id = enumerator
+        .get_default_device_for_role(&direction, &role.role())
+        .and_then(|dev| dev.get_id())
+        .unwrap_or_default();
+
+    let keys = ConsoleKeys::open().context("--pick needs an interactive console")?;
+    eprintln!(
+        "Pick the {} device to record (Up/Down or its number, Enter to record, Esc to cancel):",
+        source.kind()
+    );
+    let mut selected = devices.iter().position(|(_, id)| *id == default_id).unwrap_or(0);
+    let mut typed = String::new();
+    let mut drawn = false;
+    loop {
+        // Redraw the menu in place: back up over the lines drawn last time
+        if drawn {
+            eprint!("\x1b[{}A", devices.len());
+        }
+        for (i, (name, id)) in devices.iter().enumerate() {
+            let mark = if *id == default_id { "*" } else { " " };
+            if i == selected {
+                eprintln!("\r\x1b[7m> {mark}[{i}] {name}\x1b[0m\x1b[K");
+            } else {
+                eprintln!("\r  {mark}[{i}] {name}\x1b[K");
+            }
+        }
+        drawn = true;
+
+        match keys.read()? {
+            PickerKey::Up => {
+                typed.clear();
+                selected = selected.checked_sub(1).unwrap_or(devices.len() - 1);
+            }
+            PickerKey::Down => {
+                typed.clear();
+                selected = (selected + 1) % devices.len();
+            }
+            PickerKey::Digit(d) => {
+                // Several digits make one index; one that doesn't fit starts a new number
+                typed.push(d);
+                match typed.parse::<usize>() {
+                    Ok(i) if i < devices.len() => selected = i,
+                    _ => {
+                        typed = d.to_string();
+                        match typed.parse::<usize>() {
+                            Ok(i) if i < devices.len() => selected = i,
+                            _ => typed.clear(),
+                        }
+                    }
+                }
+            }
+            PickerKey::Enter => break,
+            PickerKey::Cancel => bail!("No device picked"),
+        }
+    }
+    Ok(selected as u32)
+}
+
+// One JSON object per device, including its shared-mode mix format.
+// Devices whose mix format can't be read (e.g. in use exclusively) get nulls there.
+fn list_devices_json(
+    enumerator: &DeviceEnumerator,
+    direction: &Direction,
+    kind: &str,
+    default_id: &str,
+    defaults: &[(DeviceRole, String)],
+) -> Result<()> {
+    let collection = enumerator.get_device_collection(direction)?;
+    let count = collection.get_nbr_devices()?;
+
+    let mut entries = Vec::with_capacity(count as usize);
+    for i in 0..count {
+        let dev = collection.get_device_at_index(i)?;
+        let name = dev.get_friendlyname().unwrap_or_else(|_| "<unknown>".to_string());
+        let id = dev.get_id().unwrap_or_else(|_| "<unknown>".to_string());
+        let mix = dev.get_iaudioclient().and_then(|client| client.get_mixformat()).ok();
+
+        let null = || "null".to_string();
+        let (sample_rate, channels, bits, mask, speakers, mp3_rate_ok, mp3_bitrates) = match &mix {
+            Some(m) => {
+                let rate = m.get_samplespersec();
+                let mp3_rate_ok = SUPPORTED_SAMPLE_RATES.contains(&rate);
+                let bitrates = if mp3_rate_ok { valid_bitrates(rate) } else { Vec::new() };
+                (
+                    rate.to_string(),
+                    m.get_nchannels().to_string(),
+                    m.get_bitspersample().to_string(),
+                    m.get_dwchannelmask().to_string(),
+                    json_list(speaker_names(m.get_dwchannelmask()).iter().map(|s| json_string(s))),
+                    mp3_rate_ok.to_string(),
+                    json_list(bitrates.iter().map(u32::to_string)),
+                )
+            }
+            None => (null(), null(), null(), null(), null(), null(), null()),
+        };
+
+        entries.push(format!(
+            "  {{\"index\": {i}, \"name\": {}, \"id\": {}, \"is_default\": {}, \
+             \"default_roles\": {}, \"direction\": {}, \"sample_rate\": {sample_rate}, \
+             \"channels\": {channels}, \
+             \"bits\": {bits}, \"channel_mask\": {mask}, \"speakers\": {speakers}, \
+             \"mp3_sample_rate_supported\": {mp3_rate_ok}, \"mp3_bitrates\": {mp3_bitrates}}}",
+            json_string(&name),
+            json_string(&id),
+            id == default_id,
+            json_list(default_roles(defaults, &id).iter().map(|role| json_string(role))),
+            json_string(kind),
+        ));
+    }
+
+    if entries.is_empty() {
+        println!("[]");
+    } else {
+        println!("[\n{}\n]", entries.join(",\n"));
+    }
+    Ok(())
+}
+
+/// `ARec selftest`: record one second from the default device to a temporary MP3 and
+/// report whether audio was captured and encoded. Needs COM initialized on the calling
+/// thread; the error names what failed.
+pub fn self_test(args: &SelfTestArgs) -> Result<()> {
+    let direction = args.source.direction();
+    let device = DeviceEnumerator::new()?
+        .get_default_device(&direction)
+        .with_context(|| format!("no default {} device", args.source.kind()))?;
+    let name = device.get_friendlyname().unwrap_or_else(|_| "<unknown>".to_string());
+    let path = std::env::temp_dir().join(format!("arec-selftest-{}.mp3", std::process::id()));
+    let path_arg = path.to_string_lossy().into_owned();
+    let source_arg = match args.source {
+        Source::Loopback => "loopback",
+        Source::Mic => "mic",
+    };
+    let mut config = RecordArgs::parse_from([
+        "--seconds", "1", "--source", source_arg, "--out", &path_arg, "--overwrite", "--quiet",
+        "--no-meter",
+    ])?;
+    config.no_summary = true;
+
+    // Peak and frames of what reaches the encoder, measured next to it
+    let measured = Arc::new(Mutex::new((0i32, 0u64, 0u32)));
+    let recorded = {
+        let measured = Arc::clone(&measured);
+        Recorder::new(config)
+            .on_samples(move |samples, channels, rate| {
+                let mut measured = measured.lock().expect("self-test totals");
+                let peak = samples.iter().map(|&s| (s as i32).abs()).max().unwrap_or(0);
+                measured.0 = measured.0.max(peak);
+                measured.1 += (samples.len() / channels.max(1)) as u64;
+                measured.2 = rate;
+            })
+            .start()
+            .and_then(RecordingHandle::wait)
+    };
+    let size = std::fs::metadata(&path).map_or(0, |m| m.len());
+    let _ = std::fs::remove_file(&path);
+    let (peak_abs, frames, rate) = *measured.lock().expect("self-test totals");
+
+    println!("Device: {name}");
+    recorded.context("FAIL: recording did not complete")?;
+    let peak_dbfs = to_dbfs(peak_abs as f64);
+    let peak = if peak_dbfs.is_finite() {
+        format!("{peak_dbfs:.1} dBFS")
+    } else {
+        "-inf dBFS (digital silence)".to_string()
+    };
+    println!(
+        "Captured: {:.2} s ({frames} frames at {rate} Hz)",
+        frames as f64 / rate.max(1) as f64
+    );
+    println!("Peak: {peak} (floor {:.1} dBFS)", args.floor);
+    println!("MP3: {}", human_bytes(size));
+    if frames == 0 {
+        bail!("FAIL: no audio frames were captured");
+    }
+    // The device stopped delivering (disconnected, or the audio engine stalled)
+    if frames < rate as u64 * 9 / 10 {
+        bail!("FAIL: the device delivered only part of the second");
+    }
+    if size == 0 {
+        bail!("FAIL: the MP3 file is empty");
+    }
+    if peak_dbfs < args.floor as f64 {
+        let hint = match args.source {
+            Source::Loopback => "play something on the device while the test runs",
+            Source::Mic => "speak into the microphone while the test runs",
+        };
+        if peak_dbfs.is_finite() {
+            bail!("FAIL: the peak is below the floor; {hint}, or lower --floor");
+        }
+        bail!("FAIL: only silence was captured; {hint}");
+    }
+    println!("PASS");
+    Ok(())
+}
+
+/// `ARec bench`: encode synthetic audio as fast as possible and print the throughput
+/// and realtime factor. No audio device is used.
+pub fn bench_encoder(args: &BenchArgs) -> Result<()> {
+    let (rate, channels) = (args.rate, args.channels as usize);
+    if rate == 0 {
+        bail!("--rate must be greater than 0");
+    }
+    let mut options = vec!["--kbps".to_string(), args.kbps.to_string()];
+    if let Some(quality) = args.vbr {
+        options.extend(["--vbr".to_string(), quality.to_string()]);
+    }
+    let config = RecordArgs::parse_from(options)?;
+    let mode = match (args.format, args.vbr) {
+        (OutputFormat::Mp3, vbr) => {
+            if !SUPPORTED_SAMPLE_RATES.contains(&rate) {
+                bail!("MP3 can't be encoded at {rate} Hz. Supported: {:?}", SUPPORTED_SAMPLE_RATES);
+            }
+            let channel_mode = ChannelMode::resolve(config.stereo_mode, channels).label();
+            match vbr {
+                Some(q) => format!("MP3 VBR quality {q}, {channel_mode}"),
+                None if valid_bitrates(rate).contains(&args.kbps) => {
+                    format!("MP3 CBR {} kbps, {channel_mode}", args.kbps)
+                }
+                None => bail!(
+                    "{} kbps isn't available at {rate} Hz. Valid: {:?}",
+                    args.kbps,
+                    valid_bitrates(rate)
+                ),
+            }
+        }
+        (OutputFormat::Wav, _) => "WAV 16-bit PCM".to_string(),
+        (OutputFormat::Flac, _) => format!("FLAC 16-bit, level {DEFAULT_FLAC_LEVEL}"),
+        (OutputFormat::Pcm, _) => format!("raw PCM {}", pcm_sample_format(false)),
+    };
+
+    // One second of two tones and some noise, repeated; content the encoder has to work on
+    let mut noise = 0x2545_f491u32;
+    let second: Vec<i16> = (0..rate as usize)
+        .flat_map(|i| {
+            let t = i as f64 / rate as f64;
+            let tones = 0.3 * (2.0 * std::f64::consts::PI * 440.0 * t).sin()
+                + 0.1 * (2.0 * std::f64::consts::PI * 3_000.0 * t).sin();
+            (0..channels)
+                .map(|_| {
+                    noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    let hiss = (noise >> 16) as f64 / 65_536.0 - 0.5;
+                    ((tones + 0.05 * hiss) * i16::MAX as f64) as i16
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    let path = std::env::temp_dir().join(format!(
+        "arec-bench-{}.{}",
+        std::process::id(),
+        args.format.extension()
+    ));
+    let path_arg = path.to_string_lossy().into_owned();
+    println!(
+        "Encoding {} s of synthetic {rate} Hz audio, {channels} channel{}: {mode}",
+        args.seconds,
+        if channels == 1 { "" } else { "s" }
+    );
+    let encoded = (|| -> Result<Duration> {
+        let console = Console::default();
+        let stream = StreamFormat { sample_rate: rate, channels, channel_mask: 0 };
+        let mut sink = create_sink(&config, args.format, &path_arg, stream, args.kbps, console)?;
+        let block = (rate as usize / 100).max(1) * channels;
+        let mut left = args.seconds * rate as u64 * channels as u64;
+        let started = Instant::now();
+        while left > 0 {
+            for chunk in second.chunks(block) {
+                let take = chunk.len().min(left as usize);
+                sink.write_samples(&chunk[..take])?;
+                left -= take as u64;
+                if left == 0 {
+                    break;
+                }
+            }
+        }
+        sink.finish()?;
+        Ok(started.elapsed())
+    })();
+    let size = std::fs::metadata(&path).map_or(0, |m| m.len());
+    let _ = std::fs::remove_file(&path);
+    let elapsed = encoded?.as_secs_f64().max(1e-9);
+
+    let pcm_bytes = args.seconds as f64 * rate as f64 * channels as f64 * 2.0;
+    let realtime = args.seconds as f64 / elapsed;
+    println!("Time: {elapsed:.3} s");
+    println!(
+        "Throughput: {:.2} MB/s of 16-bit PCM, {realtime:.1}x realtime",
+        pcm_bytes / elapsed / 1e6
+    );
+    println!("Output: {}", human_bytes(size));
+    if realtime < 2.0 {
+        println!(
+            "This machine has little headroom for this setting; recording may fall behind \
+             under load. A lower bitrate, WAV or FLAC is lighter."
+        );
+    }
+    Ok(())
+}
+
+/// `ARec probe`: print a device's mix format and the capture and MP3 settings that
+/// work with it. Needs COM initialized on the calling thread.
+pub fn probe_device(args: &ProbeArgs) -> Result<()> {
+    // initialize_capture_client reports a float fallback with info!; the probe output says it
+    let console = Console { quiet: true, stdout_audio: false };
+
+    let enumerator = DeviceEnumerator::new()?;
+    let selector = DeviceSelector::from_options(
+        args.device_index,
+        args.device_id.as_deref(),
+        args.device.as_deref(),
+    );
+    let device = select_device(&enumerator, args.source.direction(), selector, args.role)?;
+    let name = device.get_friendlyname().unwrap_or_else(|_| "<unknown>".to_string());
+    let id = device.get_id().unwrap_or_else(|_| "<unknown>".to_string());
+
+    let audio_client = device.get_iaudioclient()?;
+    let mix = audio_client.get_mixformat()?;
+    let rate = mix.get_samplespersec();
+    let channels = mix.get_nchannels() as usize;
+    let bits = mix.get_bitspersample();
+    let float = is_f32_format(&mix);
+    let mask = mix.get_dwchannelmask();
+    let speakers = speaker_names(mask);
+
+    // Initialize (but don't start) a capture stream the way `record` does, to see which
+    // sample format the driver accepts. A device in exclusive use fails here.
+    let activate = || Ok(device.get_iaudioclient()?);
+    let capture =
+        initialize_capture_client(&activate, audio_client, &mix, DEFAULT_BUFFER_MS, false, console)
+            .map(|(_, format)| format);
+
+    let mp3_rate_ok = SUPPORTED_SAMPLE_RATES.contains(&rate);
+    let bitrates = if mp3_rate_ok { valid_bitrates(rate) } else { Vec::new() };
+    // What `record` does with more than two channels: speaker matrix or plain averaging
+    let downmix = (channels > 2).then(|| {
+        if stereo_downmix_matrix(mask, channels).is_some() {
+            "speaker matrix"
+        } else {
+            "average"
+        }
+    });
+
+    if args.json {
+        // Bitrates at each rate --resample can convert to
+        let resample_bitrates = SUPPORTED_SAMPLE_RATES
+            .iter()
+            .map(|&r| format!("\"{r}\": {}", json_list(valid_bitrates(r).iter().map(u32::to_string))))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let capture_format = match &capture {
+            Ok(CaptureSampleFormat::I16) => json_string("i16"),
+            Ok(CaptureSampleFormat::F32) => json_string("f32"),
+            Ok(CaptureSampleFormat::I24) => json_string("i24"),
+            Ok(CaptureSampleFormat::I32) => json_string("i32"),
+            Err(_) => "null".to_string(),
+        };
+        println!(
+            "{{\"name\": {}, \"id\": {}, \"direction\": {}, \"sample_rate\": {rate}, \
+             \"channels\": {channels}, \"bits\": {bits}, \"float\": {float}, \
+             \"channel_mask\": {mask}, \"speakers\": {}, \"capture_format\": {capture_format}, \
+             \"downmix\": {}, \"mp3_sample_rate_supported\": {mp3_rate_ok}, \"mp3_bitrates\": {}, \
+             \"mp3_sample_rates\": {}, \"mp3_resample_bitrates\": {{{resample_bitrates}}}}}",
+            json_string(&name),
+            json_string(&id),
+            json_string(args.source.kind()),
+            json_list(speakers.iter().map(|s| json_string(s))),
+            downmix.map(json_string).unwrap_or_else(|| "null".to_string()),
+            json_list(bitrates.iter().map(u32::to_string)),
+            json_list(SUPPORTED_SAMPLE_RATES.iter().map(u32::to_string)),
+        );
+        return Ok(());
+    }
+
+    let join = |items: &[u32]| {
+        items.iter().map(u32::to_string).collect::<Vec<_>>().join(", ")
+    };
+    println!("Device: {name}");
+    println!("  id: {id}");
+    println!("Mix format: {}", describe_mix_format(&mix));
+    if speakers.is_empty() {
+        println!("Channel mask: none (channel positions unknown)");
+    } else {
+        println!("Channel mask: 0x{mask:x} ({})", speakers.join(", "));
+    }
+    match &capture {
+        Ok(CaptureSampleFormat::I16) if float => {
+            println!("Capture format: 16-bit PCM (converted from float by Windows)")
+        }
+        Ok(CaptureSampleFormat::I16) => println!("Capture format: 16-bit PCM"),
+        Ok(CaptureSampleFormat::F32) => {
+            println!("Capture format: 32-bit float (16-bit PCM rejected; ARec converts to 16-bit)")
+        }
+        Ok(format) => println!("Capture format: {}", format.label()),
+        Err(e) => println!("Capture format: unavailable ({e:#})"),
+    }
+    match downmix {
+        Some(how) => println!("Stereo downmix: {how}"),
+        None => println!("Stereo downmix: not needed"),
+    }
+    println!("{}", mp3_support(rate));
+    println!("MP3 bitrates with --resample:");
+    for &r in SUPPORTED_SAMPLE_RATES {
+        println!("  {r} Hz: {} kbps", join(&valid_bitrates(r)));
+    }
+    Ok(())
+}
@@ -0,0 +1,554 @@
+//! Ways to control a running recording: console keys, a global hotkey, a watched process,
+//! and the control pipe.
+
+use anyhow::{anyhow, Context, Result};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use std::{
+    fs::File,
+    io::{self, BufRead, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use windows::{
+    core::PCWSTR,
+    Win32::{
+        Foundation::{
+            CloseHandle, ERROR_BROKEN_PIPE, ERROR_PIPE_CONNECTED, HANDLE, LPARAM, WAIT_OBJECT_0,
+            WAIT_TIMEOUT, WPARAM,
+        },
+        Storage::FileSystem::{
+            ReadFile, WriteFile, FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX,
+        },
+        System::{
+            Console::{
+                GetConsoleMode, GetNumberOfConsoleInputEvents, GetStdHandle, ReadConsoleInputW,
+                SetConsoleMode, CONSOLE_MODE, ENABLE_ECHO_INPUT, ENABLE_LINE_INPUT,
+                ENABLE_PROCESSED_INPUT, ENABLE_VIRTUAL_TERMINAL_PROCESSING, INPUT_RECORD, KEY_EVENT,
+                STD_ERROR_HANDLE, STD_INPUT_HANDLE,
+            },
+            Pipes::{
+                ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_TYPE_BYTE, PIPE_WAIT,
+            },
+            Threading::{GetCurrentThreadId, OpenProcess, WaitForSingleObject, PROCESS_SYNCHRONIZE},
+        },
+        UI::{
+            Input::KeyboardAndMouse::{
+                RegisterHotKey, UnregisterHotKey, HOT_KEY_MODIFIERS, MOD_ALT, MOD_CONTROL,
+                MOD_NOREPEAT, MOD_SHIFT, MOD_WIN, VIRTUAL_KEY, VK_DOWN, VK_ESCAPE, VK_F1, VK_RETURN,
+                VK_SPACE, VK_UP,
+            },
+            WindowsAndMessaging::{
+                GetMessageW, PeekMessageW, PostThreadMessageW, MSG, PM_NOREMOVE, WM_HOTKEY, WM_QUIT,
+            },
+        },
+    },
+};
+
+use crate::capture::find_process;
+use crate::json::json_string;
+use crate::Console;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PickerKey {
+    Up,
+    Down,
+    Digit(char),
+    Enter,
+    Cancel,
+}
+
+// Console input one key at a time (no line editing or echo) for the --pick menu, and
+// escape sequences on stderr for drawing it. Both modes are put back on drop.
+pub(crate) struct ConsoleKeys {
+    input: HANDLE,
+    input_mode: CONSOLE_MODE,
+    output: Option<(HANDLE, CONSOLE_MODE)>,
+}
+
+impl ConsoleKeys {
+    pub(crate) fn open() -> Result<Self> {
+        let input = unsafe { GetStdHandle(STD_INPUT_HANDLE) }?;
+        let mut input_mode = CONSOLE_MODE::default();
+        unsafe { GetConsoleMode(input, &mut input_mode) }.context("standard input is not a console")?;
+        // Without ENABLE_PROCESSED_INPUT, Ctrl+C arrives as a key instead of ending the process
+        let raw = input_mode & !(ENABLE_LINE_INPUT | ENABLE_ECHO_INPUT | ENABLE_PROCESSED_INPUT);
+        unsafe { SetConsoleMode(input, raw) }?;
+
+        // Older consoles without escape sequence support still get the menu, drawn less tidily
+        let output = unsafe { GetStdHandle(STD_ERROR_HANDLE) }.ok().and_then(|output| {
+            let mut mode = CONSOLE_MODE::default();
+            unsafe { GetConsoleMode(output, &mut mode) }.ok()?;
+            unsafe { SetConsoleMode(output, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) }.ok()?;
+            Some((output, mode))
+        });
+
+        Ok(Self { input, input_mode, output })
+    }
+
+    // Waits for the next key the menu understands; releases and other keys are skipped
+    pub(crate) fn read(&self) -> Result<PickerKey> {
+        let mut records = [INPUT_RECORD::default()];
+        loop {
+            let mut read = 0u32;
+            unsafe { ReadConsoleInputW(self.input, &mut records, &mut read) }
+                .context("failed to read console input")?;
+            if read == 0 || u32::from(records[0].EventType) != KEY_EVENT {
+                continue;
+            }
+            let key = unsafe { records[0].Event.KeyEvent };
+            if !key.bKeyDown.as_bool() {
+                continue;
+            }
+            let ch = char::from_u32(u32::from(unsafe { key.uChar.UnicodeChar })).unwrap_or('\0');
+            let vk = VIRTUAL_KEY(key.wVirtualKeyCode);
+            if vk == VK_UP {
+                return Ok(PickerKey::Up);
+            } else if vk == VK_DOWN {
+                return Ok(PickerKey::Down);
+            } else if vk == VK_RETURN {
+                return Ok(PickerKey::Enter);
+            } else if vk == VK_ESCAPE || ch == '\u{3}' {
+                return Ok(PickerKey::Cancel);
+            } else if ch.is_ascii_digit() {
+                return Ok(PickerKey::Digit(ch));
+            }
+        }
+    }
+}
+
+// Space bar presses while recording. Pending console input is read without waiting and
+// without changing the console mode, so Ctrl+C still works; None when stdin isn't a console.
+pub(crate) struct PauseKeys {
+    input: HANDLE,
+}
+
+impl PauseKeys {
+    pub(crate) fn open() -> Option<Self> {
+        let input = unsafe { GetStdHandle(STD_INPUT_HANDLE) }.ok()?;
+        let mut mode = CONSOLE_MODE::default();
+        unsafe { GetConsoleMode(input, &mut mode) }.ok()?;
+        Some(Self { input })
+    }
+
+    // Space bar presses since the last call; other input is discarded
+    pub(crate) fn presses(&self) -> usize {
+        let mut presses = 0;
+        let mut records = [INPUT_RECORD::default()];
+        loop {
+            let mut pending = 0u32;
+            if unsafe { GetNumberOfConsoleInputEvents(self.input, &mut pending) }.is_err()
+                || pending == 0
+            {
+                return presses;
+            }
+            let mut read = 0u32;
+            if unsafe { ReadConsoleInputW(self.input, &mut records, &mut read) }.is_err()
+                || read == 0
+            {
+                return presses;
+            }
+            if u32::from(records[0].EventType) == KEY_EVENT {
+                let key = unsafe { records[0].Event.KeyEvent };
+                if key.bKeyDown.as_bool() && VIRTUAL_KEY(key.wVirtualKeyCode) == VK_SPACE {
+                    presses += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ConsoleKeys {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = SetConsoleMode(self.input, self.input_mode);
+            if let Some((output, mode)) = self.output {
+                let _ = SetConsoleMode(output, mode);
+            }
+        }
+    }
+}
+
+// --hotkey: a key with modifiers, kept as RegisterHotKey takes them
+#[derive(Clone, Debug)]
+pub(crate) struct Hotkey {
+    modifiers: HOT_KEY_MODIFIERS,
+    key: u32,
+    pub(crate) text: String,
+}
+
+pub(crate) fn parse_hotkey(spec: &str) -> Result<Hotkey, String> {
+    let parts: Vec<&str> = spec.split('+').map(str::trim).collect();
+    if parts.iter().any(|part| part.is_empty()) {
+        return Err(format!(
+            "expected modifiers and a key joined by '+', e.g. Ctrl+Alt+R, got '{spec}'"
+        ));
+    }
+    let (key_name, modifier_names) = parts.split_last().expect("split yields at least one part");
+    let mut modifiers = HOT_KEY_MODIFIERS(0);
+    for name in modifier_names {
+        modifiers |= match name.to_ascii_lowercase().as_str() {
+            "ctrl" | "control" => MOD_CONTROL,
+            "alt" => MOD_ALT,
+            "shift" => MOD_SHIFT,
+            "win" => MOD_WIN,
+            _ => return Err(format!("unknown modifier '{name}' (Ctrl, Alt, Shift, Win)")),
+        };
+    }
+    let upper = key_name.to_ascii_uppercase();
+    let function = upper
+        .strip_prefix('F')
+        .and_then(|n| n.parse::<u32>().ok())
+        .filter(|n| (1..=24).contains(n));
+    let key = match (function, upper.as_bytes()) {
+        (Some(n), _) => u32::from(VK_F1.0) + n - 1,
+        (None, [c]) if c.is_ascii_alphanumeric() => u32::from(*c),
+        (None, _) if upper == "SPACE" => u32::from(VK_SPACE.0),
+        _ => return Err(format!("unknown key '{key_name}' (A-Z, 0-9, F1-F24 or Space)")),
+    };
+    // A plain letter would be taken from every other program
+    if modifiers.0 == 0 && function.is_none() {
+        return Err(format!("'{spec}' needs a modifier (Ctrl, Alt, Shift or Win)"));
+    }
+    let mut text: Vec<&str> = [
+        (MOD_CONTROL, "Ctrl"),
+        (MOD_ALT, "Alt"),
+        (MOD_SHIFT, "Shift"),
+        (MOD_WIN, "Win"),
+    ]
+    .into_iter()
+    .filter(|(bit, _)| modifiers.0 & bit.0 != 0)
+    .map(|(_, name)| name)
+    .collect();
+    let key_text = if key == u32::from(VK_SPACE.0) { "Space".to_string() } else { upper };
+    text.push(&key_text);
+    Ok(Hotkey { modifiers, key, text: text.join("+") })
+}
+
+const HOTKEY_ID: i32 = 1;
+
+// --hotkey: RegisterHotKey on a thread of its own, whose message loop receives WM_HOTKEY
+// wherever the focus is. Presses reach the capture loop over a channel; Drop posts
+// WM_QUIT, after which the thread unregisters the hotkey.
+pub(crate) struct GlobalHotkey {
+    presses: Receiver<()>,
+    thread_id: u32,
+}
+
+impl GlobalHotkey {
+    pub(crate) fn start(hotkey: &Hotkey) -> Result<Self> {
+        let (press_tx, presses) = bounded::<()>(16);
+        let (ready_tx, ready_rx) = bounded::<Result<u32>>(1);
+        let thread_hotkey = hotkey.clone();
+        std::thread::Builder::new()
+            .name("hotkey".to_string())
+            .spawn(move || {
+                let mut msg = MSG::default();
+                // Create the thread's message queue before Drop can post to it
+                let _ = unsafe { PeekMessageW(&mut msg, None, 0, 0, PM_NOREMOVE) };
+                let modifiers = thread_hotkey.modifiers | MOD_NOREPEAT;
+                let key = thread_hotkey.key;
+                if let Err(e) = unsafe { RegisterHotKey(None, HOTKEY_ID, modifiers, key) } {
+                    let _ = ready_tx.send(Err(anyhow!(e).context(format!(
+                        "failed to register --hotkey {} (is another program using it?)",
+                        thread_hotkey.text
+                    ))));
+                    return;
+                }
+                let _ = ready_tx.send(Ok(unsafe { GetCurrentThreadId() }));
+                while unsafe { GetMessageW(&mut msg, None, 0, 0) }.0 > 0 {
+                    if msg.message == WM_HOTKEY {
+                        let _ = press_tx.try_send(());
+                    }
+                }
+                let _ = unsafe { UnregisterHotKey(None, HOTKEY_ID) };
+            })
+            .context("failed to start the hotkey thread")?;
+        let thread_id = ready_rx
+            .recv()
+            .map_err(|_| anyhow!("hotkey thread exited before registering the hotkey"))??;
+        log::info!("Global hotkey {} registered", hotkey.text);
+        Ok(Self { presses, thread_id })
+    }
+
+    // Presses since the last call
+    pub(crate) fn presses(&self) -> usize {
+        self.presses.try_iter().count()
+    }
+}
+
+impl Drop for GlobalHotkey {
+    fn drop(&mut self) {
+        let _ = unsafe { PostThreadMessageW(self.thread_id, WM_QUIT, WPARAM(0), LPARAM(0)) };
+    }
+}
+
+// --until-process-exits: a thread waits on the process handle, a second at a time so it
+// notices when the recording is over, and reports the exit over a channel
+pub(crate) struct ProcessWatch {
+    exited: Receiver<()>,
+    closed: Arc<AtomicBool>,
+}
+
+impl ProcessWatch {
+    pub(crate) fn start(spec: &str, console: Console) -> Result<Self> {
+        let (pid, name) = find_process(spec, "--until-process-exits", console)?;
+        let (exit_tx, exited) = bounded::<()>(1);
+        let (ready_tx, ready_rx) = bounded::<Result<()>>(1);
+        let closed = Arc::new(AtomicBool::new(false));
+        let thread_closed = closed.clone();
+        std::thread::Builder::new()
+            .name("process-watch".to_string())
+            .spawn(move || {
+                let process = match unsafe { OpenProcess(PROCESS_SYNCHRONIZE, false, pid) } {
+                    Ok(process) => process,
+                    Err(e) => {
+                        let context = format!("--until-process-exits: can't watch PID {pid}");
+                        let _ = ready_tx.send(Err(anyhow!(e).context(context)));
+                        return;
+                    }
+                };
+                let _ = ready_tx.send(Ok(()));
+                while !thread_closed.load(Ordering::Relaxed) {
+                    match unsafe { WaitForSingleObject(process, 1000) } {
+                        WAIT_TIMEOUT => {}
+                        WAIT_OBJECT_0 => {
+                            log::info!("Watched process {pid} exited");
+                            let _ = exit_tx.send(());
+                            break;
+                        }
+                        _ => {
+                            eprintln!("\nWarning: --until-process-exits lost track of PID {pid}");
+                            break;
+                        }
+                    }
+                }
+                let _ = unsafe { CloseHandle(process) };
+            })
+            .context("failed to start the process watch thread")?;
+        ready_rx
+            .recv()
+            .map_err(|_| anyhow!("process watch thread exited before opening the process"))??;
+        info!(console, "Stopping when {name} (PID {pid}) exits");
+        Ok(Self { exited, closed })
+    }
+
+    pub(crate) fn exited(&self) -> bool {
+        !self.exited.is_empty()
+    }
+}
+
+impl Drop for ProcessWatch {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+// A --control-pipe command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ControlCommand {
+    Stop,
+    Status,
+    Split,
+    Pause,
+    Resume,
+    Spectrum,
+}
+
+impl ControlCommand {
+    fn parse(text: &str) -> Result<Self, String> {
+        match text.to_ascii_lowercase().as_str() {
+            "stop" => Ok(Self::Stop),
+            "status" => Ok(Self::Status),
+            "split" => Ok(Self::Split),
+            "pause" => Ok(Self::Pause),
+            "resume" => Ok(Self::Resume),
+            "spectrum" => Ok(Self::Spectrum),
+            _ => Err(format!(
+                "unknown command '{text}' (stop, status, split, pause, resume, spectrum)"
+            )),
+        }
+    }
+}
+
+// A command on its way to the capture loop, which sends the JSON reply back
+pub(crate) struct ControlRequest {
+    pub(crate) command: ControlCommand,
+    pub(crate) reply: Sender<String>,
+}
+
+pub(crate) fn control_error(message: &str) -> String {
+    format!("{{\"ok\": false, \"error\": {}}}", json_string(message))
+}
+
+// --control-pipe: a named pipe server on its own thread, one client at a time. Commands
+// arrive as lines and each gets one JSON line back. The capture loop picks requests up
+// on every pass; when it has ended, requests fail and the thread exits.
+pub(crate) struct ControlPipe {
+    pub(crate) requests: Receiver<ControlRequest>,
+    path: String,
+    closed: Arc<AtomicBool>,
+}
+
+impl ControlPipe {
+    pub(crate) fn start(name: &str) -> Result<Self> {
+        // A bare name goes in the local pipe namespace
+        let path = if name.starts_with(r"\\") {
+            name.to_string()
+        } else {
+            format!(r"\\.\pipe\{name}")
+        };
+        let (request_tx, requests) = bounded::<ControlRequest>(1);
+        let (ready_tx, ready_rx) = bounded::<Result<()>>(1);
+        let closed = Arc::new(AtomicBool::new(false));
+        let thread_path = path.clone();
+        let thread_closed = closed.clone();
+        std::thread::Builder::new()
+            .name("control-pipe".to_string())
+            .spawn(move || {
+                let mut ready = Some(ready_tx);
+                loop {
+                    let pipe = match NamedPipe::create(&thread_path) {
+                        Ok(pipe) => pipe,
+                        Err(e) => {
+                            match ready.take() {
+                                Some(ready) => drop(ready.send(Err(e))),
+                                None => eprintln!("\nWarning: --control-pipe stopped: {e:#}"),
+                            }
+                            return;
+                        }
+                    };
+                    if let Some(ready) = ready.take() {
+                        let _ = ready.send(Ok(()));
+                    }
+                    if let Err(e) = pipe.accept() {
+                        eprintln!("\nWarning: --control-pipe stopped: {e:#}");
+                        return;
+                    }
+                    if thread_closed.load(Ordering::Relaxed)
+                        || !serve_control_client(pipe, &request_tx)
+                    {
+                        return;
+                    }
+                }
+            })
+            .context("failed to start the control pipe thread")?;
+        ready_rx
+            .recv()
+            .map_err(|_| anyhow!("control pipe thread exited before creating the pipe"))??;
+        log::info!("Control pipe listening on {path}");
+        Ok(Self { requests, path, closed })
+    }
+}
+
+impl Drop for ControlPipe {
+    fn drop(&mut self) {
+        // Wake the thread if it is waiting for a client, so it releases the pipe name
+        self.closed.store(true, Ordering::Relaxed);
+        let _ = File::open(&self.path);
+    }
+}
+
+// Answer one --control-pipe client until it disconnects. False once the recording is over.
+fn serve_control_client(pipe: NamedPipe, requests: &Sender<ControlRequest>) -> bool {
+    let mut reader = io::BufReader::new(pipe);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => return true,
+            Ok(_) => {}
+        }
+        let text = line.trim();
+        if text.is_empty() {
+            continue;
+        }
+        let mut recording = true;
+        let reply = match ControlCommand::parse(text) {
+            Ok(command) => {
+                let (reply_tx, reply_rx) = bounded(1);
+                let answer = requests
+                    .send(ControlRequest { command, reply: reply_tx })
+                    .ok()
+                    .and_then(|()| reply_rx.recv().ok());
+                answer.unwrap_or_else(|| {
+                    recording = false;
+                    control_error("the recording has ended")
+                })
+            }
+            Err(e) => control_error(&e),
+        };
+        if reader.get_mut().write_all(format!("{reply}\n").as_bytes()).is_err() || !recording {
+            return recording;
+        }
+    }
+}
+
+// Server end of a named pipe; closed on drop
+struct NamedPipe(HANDLE);
+
+impl NamedPipe {
+    fn create(path: &str) -> Result<Self> {
+        let wide: Vec<u16> = path.encode_utf16().chain(Some(0)).collect();
+        let handle = unsafe {
+            CreateNamedPipeW(
+                PCWSTR(wide.as_ptr()),
+                PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                1,
+                4096,
+                4096,
+                0,
+                None,
+            )
+        };
+        if handle.is_invalid() {
+            return Err(windows::core::Error::from_thread())
+                .with_context(|| format!("can't create the control pipe {path} (is it in use?)"));
+        }
+        Ok(Self(handle))
+    }
+
+    // Wait for a client; one that connected before the wait counts too
+    fn accept(&self) -> Result<()> {
+        match unsafe { ConnectNamedPipe(self.0, None) } {
+            Err(e) if e.code() != ERROR_PIPE_CONNECTED.to_hresult() => {
+                Err(e).context("waiting for a control pipe client failed")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl io::Read for NamedPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        match unsafe { ReadFile(self.0, Some(buf), Some(&mut read), None) } {
+            Ok(()) => Ok(read as usize),
+            // The client closed its end
+            Err(e) if e.code() == ERROR_BROKEN_PIPE.to_hresult() => Ok(0),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+impl Write for NamedPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        unsafe { WriteFile(self.0, Some(buf), Some(&mut written), None) }
+            .map_err(io::Error::other)?;
+        Ok(written as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for NamedPipe {
+    fn drop(&mut self) {
+        let _ = unsafe { CloseHandle(self.0) };
+    }
+}
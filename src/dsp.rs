@@ -0,0 +1,2016 @@
+//! Sample processing between capture and the encoders: resampling, downmixes, filters,
+//! dynamics and level measurement.
+
+use anyhow::Result;
+use std::{collections::VecDeque, time::Duration};
+
+use crate::args::PhaseInvert;
+use crate::capture::MAX_GAP_FILL_SECS;
+use crate::json::{dbfs_json, json_string};
+use crate::time::LocalTime;
+
+// --limiter lookahead (also the added latency) and release time
+const LIMITER_LOOKAHEAD_MS: u32 = 5;
+
+const LIMITER_RELEASE_MS: u32 = 50;
+
+pub(crate) fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+// Seed for --dither, fixed so the same input always gives the same output
+const DITHER_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+// Triangular-PDF dither noise in [-1, 1) LSB: the sum of two uniform values, from a
+// xorshift64* generator. It decorrelates the rounding error from the signal, so quiet
+// passages and fades get a faint steady hiss instead of distortion.
+pub(crate) struct TpdfDither {
+    state: u64,
+}
+
+impl TpdfDither {
+    pub(crate) fn new() -> Self {
+        Self { state: DITHER_SEED }
+    }
+
+    // Uniform in [0, 1)
+    fn uniform(&mut self) -> f32 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let bits = self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 40; // top 24 bits
+        bits as f32 / (1u64 << 24) as f32
+    }
+
+    pub(crate) fn next(&mut self) -> f32 {
+        self.uniform() + self.uniform() - 1.0
+    }
+}
+
+// Linear gain that brings `peak` (absolute i16) to `target_dbfs`. Silence stays at unity.
+pub(crate) fn normalize_gain(peak: i32, target_dbfs: f32) -> f64 {
+    if peak == 0 {
+        return 1.0;
+    }
+    let target = i16::MAX as f64 * 10f64.powf(target_dbfs as f64 / 20.0);
+    target / peak as f64
+}
+
+pub(crate) fn scale_samples(samples: &mut [i16], gain: f64) {
+    for s in samples.iter_mut() {
+        *s = (*s as f64 * gain).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+    }
+}
+
+// Adds the samples at full scale (+32767, or -32767 and below) in interleaved `samples` to
+// the per-channel `counts`
+pub(crate) fn count_clipped(samples: &[i16], counts: &mut [u64]) {
+    for frame in samples.chunks_exact(counts.len()) {
+        for (count, &s) in counts.iter_mut().zip(frame) {
+            if s.unsigned_abs() >= i16::MAX as u16 {
+                *count += 1;
+            }
+        }
+    }
+}
+
+// --compress: the ratio above the threshold
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Compression {
+    ratio: f32,
+    threshold_dbfs: f32,
+}
+
+pub(crate) fn parse_compress(spec: &str) -> Result<Compression, String> {
+    let (ratio, threshold) = spec
+        .split_once(':')
+        .ok_or_else(|| format!("expected <ratio>:<threshold dBFS>, e.g. 3:-20, got '{spec}'"))?;
+    let ratio: f32 = ratio
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{}' is not a ratio", ratio.trim()))?;
+    if !(ratio >= 1.0 && ratio.is_finite()) {
+        return Err(format!("the ratio must be 1 or more, got {ratio}"));
+    }
+    let threshold_dbfs: f32 = threshold
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{}' is not a threshold in dBFS", threshold.trim()))?;
+    if !(threshold_dbfs <= 0.0 && threshold_dbfs.is_finite()) {
+        return Err(format!("the threshold must be at or below 0 dBFS, got {threshold_dbfs}"));
+    }
+    Ok(Compression { ratio, threshold_dbfs })
+}
+
+// Single-band compressor for interleaved i16 (--compress). A peak detector follows the
+// loudest channel, rising with the attack time constant and falling with the release one;
+// all channels get the same gain so the stereo image doesn't shift. Above the threshold
+// the gain computer takes (envelope - threshold) * (1 - 1/ratio) dB off. The envelope
+// carries across blocks.
+pub(crate) struct Compressor {
+    channels: usize,
+    ratio: f32,
+    threshold_db: f32,
+    attack: f32,
+    release: f32,
+    makeup_db: f32,
+    envelope: f32,
+}
+
+impl Compressor {
+    pub(crate) fn new(
+        channels: usize,
+        compression: Compression,
+        attack_ms: u32,
+        release_ms: u32,
+        makeup_db: f32,
+        sample_rate: u32,
+    ) -> Self {
+        let coeff = |ms: u32| {
+            let frames = sample_rate as f32 * ms as f32 / 1000.0;
+            if frames < 1.0 { 0.0 } else { (-1.0 / frames).exp() }
+        };
+        Self {
+            channels,
+            ratio: compression.ratio,
+            threshold_db: compression.threshold_dbfs,
+            attack: coeff(attack_ms),
+            release: coeff(release_ms),
+            makeup_db,
+            envelope: 0.0,
+        }
+    }
+
+    pub(crate) fn process(&mut self, samples: &mut [i16]) {
+        for frame in samples.chunks_exact_mut(self.channels) {
+            let peak = frame.iter().map(|&s| (s as i32).abs()).max().unwrap_or(0) as f32
+                / i16::MAX as f32;
+            let coeff = if peak > self.envelope { self.attack } else { self.release };
+            self.envelope = peak + (self.envelope - peak) * coeff;
+
+            let level_db = 20.0 * self.envelope.max(1e-6).log10();
+            let reduction = (level_db - self.threshold_db).max(0.0) * (1.0 - 1.0 / self.ratio);
+            let gain = db_to_gain(self.makeup_db - reduction);
+            if gain != 1.0 {
+                scale_samples(frame, gain as f64);
+            }
+        }
+    }
+}
+
+// How far a closed --gate turns the audio down, and the time constant of the level it
+// follows (long enough that zero crossings of a low tone don't close it)
+const GATE_FLOOR_DB: f32 = -40.0;
+
+const GATE_DETECT_MS: u32 = 10;
+
+// Noise gate for interleaved i16 (--gate). Each channel follows its level with a peak
+// envelope and ramps its own gain between 1 and the floor: up over the attack time while
+// the level is at or above the threshold, down over the release time while it is below.
+// Envelopes and gains carry across blocks.
+pub(crate) struct NoiseGate {
+    threshold: f32,
+    floor: f32,
+    open_step: f32,
+    close_step: f32,
+    decay: f32,
+    envelope: Vec<f32>,
+    gain: Vec<f32>,
+    pub(crate) gated_frames: u64, // frames during which every channel's gate was closed
+}
+
+impl NoiseGate {
+    pub(crate) fn new(
+        channels: usize,
+        threshold_dbfs: f32,
+        attack_ms: u32,
+        release_ms: u32,
+        sample_rate: u32,
+    ) -> Self {
+        let floor = db_to_gain(GATE_FLOOR_DB);
+        let frames = |ms: u32| (sample_rate as u64 * ms as u64 / 1000).max(1) as f32;
+        Self {
+            threshold: i16::MAX as f32 * db_to_gain(threshold_dbfs),
+            floor,
+            open_step: (1.0 - floor) / frames(attack_ms),
+            close_step: (1.0 - floor) / frames(release_ms),
+            decay: (-1.0 / frames(GATE_DETECT_MS)).exp(),
+            envelope: vec![0.0; channels],
+            // Start closed, so hiss at the very start is gated too
+            gain: vec![floor; channels],
+            gated_frames: 0,
+        }
+    }
+
+    pub(crate) fn process(&mut self, samples: &mut [i16]) {
+        let channels = self.gain.len();
+        for frame in samples.chunks_exact_mut(channels) {
+            let mut all_closed = true;
+            let channel_state = self.envelope.iter_mut().zip(&mut self.gain);
+            for (s, (envelope, gain)) in frame.iter_mut().zip(channel_state) {
+                let level = (*s as f32).abs();
+                *envelope = level.max(*envelope * self.decay);
+                if *envelope >= self.threshold {
+                    all_closed = false;
+                    *gain = (*gain + self.open_step).min(1.0);
+                } else {
+                    *gain = (*gain - self.close_step).max(self.floor);
+                }
+                if *gain < 1.0 {
+                    *s = (*s as f32 * *gain).round() as i16;
+                }
+            }
+            if all_closed {
+                self.gated_frames += 1;
+            }
+        }
+    }
+}
+
+// Lookahead peak limiter for interleaved i16 (channels linked, so the stereo image holds).
+// Each frame needs gain min(1, ceiling / peak); the gain applied to the frame leaving the
+// delay line follows the smallest need within the lookahead, so it is already down when a
+// peak arrives. It falls with a fast attack (a fifth of the lookahead) and recovers with
+// LIMITER_RELEASE_MS. A final clamp catches what the attack hasn't quite reached.
+pub(crate) struct Limiter {
+    channels: usize,
+    ceiling: f32,
+    lookahead: usize,
+    attack: f32,
+    release: f32,
+    gain: f32,
+    delay: VecDeque<i16>,
+    // (frame index, needed gain) with gains ascending from the front: a sliding minimum
+    needs: VecDeque<(u64, f32)>,
+    frames_in: u64,
+    pub(crate) limited: u64, // output samples the limiter changed
+}
+
+impl Limiter {
+    pub(crate) fn new(channels: usize, ceiling_dbfs: f32, sample_rate: u32) -> Self {
+        let lookahead = (sample_rate * LIMITER_LOOKAHEAD_MS / 1000).max(1) as usize;
+        let release_frames = (sample_rate * LIMITER_RELEASE_MS / 1000) as f32;
+        Self {
+            channels,
+            ceiling: i16::MAX as f32 * db_to_gain(ceiling_dbfs),
+            lookahead,
+            attack: (-5.0 / lookahead as f32).exp(),
+            release: (-1.0 / release_frames).exp(),
+            gain: 1.0,
+            delay: VecDeque::with_capacity((lookahead + 1) * channels),
+            needs: VecDeque::new(),
+            frames_in: 0,
+            limited: 0,
+        }
+    }
+
+    pub(crate) fn process_into(&mut self, input: &[i16], out: &mut Vec<i16>) {
+        for frame in input.chunks_exact(self.channels) {
+            let peak = frame.iter().map(|&s| (s as i32).abs()).max().unwrap_or(0) as f32;
+            let need = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+            while self.needs.back().is_some_and(|&(_, g)| g >= need) {
+                self.needs.pop_back();
+            }
+            self.needs.push_back((self.frames_in, need));
+            self.frames_in += 1;
+
+            self.delay.extend(frame);
+            if self.delay.len() > self.lookahead * self.channels {
+                self.emit_frame(out);
+            }
+        }
+    }
+
+    // Everything still in the lookahead, at the end of the recording
+    pub(crate) fn flush_into(&mut self, out: &mut Vec<i16>) {
+        while !self.delay.is_empty() {
+            self.emit_frame(out);
+        }
+    }
+
+    fn emit_frame(&mut self, out: &mut Vec<i16>) {
+        // Index of the frame leaving now; needs before it no longer matter
+        let index = self.frames_in - (self.delay.len() / self.channels) as u64;
+        while self.needs.front().is_some_and(|&(i, _)| i < index) {
+            self.needs.pop_front();
+        }
+        let target = self.needs.front().map_or(1.0, |&(_, g)| g);
+        let coeff = if target < self.gain { self.attack } else { self.release };
+        self.gain = target + (self.gain - target) * coeff;
+        if target == 1.0 && self.gain > 0.9999 {
+            self.gain = 1.0; // settle instead of creeping towards unity forever
+        }
+
+        for _ in 0..self.channels {
+            let Some(s) = self.delay.pop_front() else { break };
+            let limited = (s as f32 * self.gain)
+                .round()
+                .clamp(-self.ceiling, self.ceiling) as i16;
+            if limited != s {
+                self.limited += 1;
+            }
+            out.push(limited);
+        }
+    }
+}
+
+// One-pole high-pass (RC filter) for interleaved i16: y[n] = a * (y[n-1] + x[n] - x[n-1]).
+// It removes DC with a 6 dB/octave slope below the cutoff. Each channel keeps its last
+// input and output across blocks, so block boundaries don't click.
+pub(crate) struct HighPass {
+    alpha: f32,
+    prev_in: Vec<f32>,
+    prev_out: Vec<f32>,
+}
+
+impl HighPass {
+    pub(crate) fn new(channels: usize, cutoff_hz: f32, sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff_hz);
+        let dt = 1.0 / sample_rate as f32;
+        Self {
+            alpha: rc / (rc + dt),
+            prev_in: vec![0.0; channels],
+            prev_out: vec![0.0; channels],
+        }
+    }
+
+    pub(crate) fn process(&mut self, samples: &mut [i16]) {
+        let channels = self.prev_in.len();
+        for frame in samples.chunks_exact_mut(channels) {
+            for (ch, s) in frame.iter_mut().enumerate() {
+                let x = *s as f32;
+                let y = self.alpha * (self.prev_out[ch] + x - self.prev_in[ch]);
+                self.prev_in[ch] = x;
+                self.prev_out[ch] = y;
+                *s = y.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            }
+        }
+    }
+}
+
+// Second-order Butterworth low-pass for interleaved i16 (RBJ cookbook biquad, direct
+// form I), 12 dB/octave above the cutoff. Each channel keeps its last two inputs and
+// outputs across blocks, so block boundaries don't click.
+pub(crate) struct LowPass {
+    b: [f64; 3],
+    a: [f64; 2],
+    // Per channel: x[n-1], x[n-2], y[n-1], y[n-2]
+    state: Vec<[f64; 4]>,
+}
+
+impl LowPass {
+    pub(crate) fn new(channels: usize, cutoff_hz: f32, sample_rate: u32) -> Self {
+        let w0 = 2.0 * std::f64::consts::PI * cutoff_hz as f64 / sample_rate as f64;
+        let alpha = w0.sin() / (2.0 * std::f64::consts::FRAC_1_SQRT_2);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+        let b1 = (1.0 - cos) / a0;
+        Self {
+            b: [b1 / 2.0, b1, b1 / 2.0],
+            a: [-2.0 * cos / a0, (1.0 - alpha) / a0],
+            state: vec![[0.0; 4]; channels],
+        }
+    }
+
+    pub(crate) fn process(&mut self, samples: &mut [i16]) {
+        let channels = self.state.len();
+        for frame in samples.chunks_exact_mut(channels) {
+            for (s, state) in frame.iter_mut().zip(&mut self.state) {
+                let [x1, x2, y1, y2] = *state;
+                let x = *s as f64;
+                let y = self.b[0] * x + self.b[1] * x1 + self.b[2] * x2
+                    - self.a[0] * y1
+                    - self.a[1] * y2;
+                *state = [x, x1, y, y1];
+                *s = y.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+            }
+        }
+    }
+}
+
+// One --eq band, as given on the command line
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EqType {
+    Peak,
+    LowShelf,
+    HighShelf,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct EqBand {
+    kind: EqType,
+    pub(crate) freq_hz: f32,
+    gain_db: f32,
+    q: f32,
+}
+
+// Parse `key=value` pairs: f (Hz) and g (dB) are required, q defaults to 1.0 for a peak
+// and 0.707 for a shelf, type is peak, lowshelf or highshelf (default peak)
+pub(crate) fn parse_eq_band(spec: &str) -> Result<EqBand, String> {
+    let mut kind = EqType::Peak;
+    let (mut freq, mut gain, mut q) = (None, None, None);
+    for pair in spec.split(',') {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("expected key=value, e.g. f=100,g=-6,q=1.0, got '{pair}'"))?;
+        let value = value.trim();
+        let number = || {
+            value
+                .parse::<f32>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .ok_or_else(|| format!("'{value}' is not a number for '{}'", key.trim()))
+        };
+        match key.trim() {
+            "f" => freq = Some(number()?),
+            "g" => gain = Some(number()?),
+            "q" => q = Some(number()?),
+            "type" => {
+                kind = match value.to_ascii_lowercase().as_str() {
+                    "peak" => EqType::Peak,
+                    "lowshelf" => EqType::LowShelf,
+                    "highshelf" => EqType::HighShelf,
+                    _ => {
+                        return Err(format!(
+                            "unknown band type '{value}' (peak, lowshelf, highshelf)"
+                        ));
+                    }
+                }
+            }
+            other => return Err(format!("unknown key '{other}' (f, g, q, type)")),
+        }
+    }
+    let freq_hz = freq.ok_or("missing f=<Hz>")?;
+    if freq_hz <= 0.0 {
+        return Err(format!("the frequency must be above 0 Hz, got {freq_hz}"));
+    }
+    let gain_db = gain.ok_or("missing g=<dB>")?;
+    if gain_db.abs() > 24.0 {
+        return Err(format!("the gain must be between -24 and 24 dB, got {gain_db}"));
+    }
+    let q = q.unwrap_or(if kind == EqType::Peak { 1.0 } else { std::f32::consts::FRAC_1_SQRT_2 });
+    if !(q > 0.0 && q <= 20.0) {
+        return Err(format!("q must be above 0 and at most 20, got {q}"));
+    }
+    Ok(EqBand { kind, freq_hz, gain_db, q })
+}
+
+// Chain of RBJ peaking/shelving biquads for interleaved i16 (--eq). Each sample runs
+// through every band in f64 and is rounded once; per-band, per-channel state carries
+// across blocks, so block boundaries don't click.
+pub(crate) struct Equalizer {
+    channels: usize,
+    // Per band: b0..b2 and a1, a2, normalized by a0
+    coeffs: Vec<([f64; 3], [f64; 2])>,
+    // Per band and channel: x[n-1], x[n-2], y[n-1], y[n-2]
+    state: Vec<[f64; 4]>,
+}
+
+impl Equalizer {
+    pub(crate) fn new(channels: usize, bands: &[EqBand], sample_rate: u32) -> Self {
+        let coeffs: Vec<_> = bands
+            .iter()
+            .map(|band| {
+                let a = 10f64.powf(band.gain_db as f64 / 40.0);
+                let w0 = 2.0 * std::f64::consts::PI * band.freq_hz as f64 / sample_rate as f64;
+                let (sin, cos) = w0.sin_cos();
+                let alpha = sin / (2.0 * band.q as f64);
+                let shelf = 2.0 * a.sqrt() * alpha;
+                let (b, a) = match band.kind {
+                    EqType::Peak => (
+                        [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                        [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+                    ),
+                    EqType::LowShelf => (
+                        [
+                            a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                            2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                            a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                        ],
+                        [
+                            (a + 1.0) + (a - 1.0) * cos + shelf,
+                            -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                            (a + 1.0) + (a - 1.0) * cos - shelf,
+                        ],
+                    ),
+                    EqType::HighShelf => (
+                        [
+                            a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                            -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                            a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                        ],
+                        [
+                            (a + 1.0) - (a - 1.0) * cos + shelf,
+                            2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                            (a + 1.0) - (a - 1.0) * cos - shelf,
+                        ],
+                    ),
+                };
+                ([b[0] / a[0], b[1] / a[0], b[2] / a[0]], [a[1] / a[0], a[2] / a[0]])
+            })
+            .collect();
+        Self { channels, state: vec![[0.0; 4]; coeffs.len() * channels], coeffs }
+    }
+
+    pub(crate) fn process(&mut self, samples: &mut [i16]) {
+        for frame in samples.chunks_exact_mut(self.channels) {
+            for (ch, s) in frame.iter_mut().enumerate() {
+                let mut x = *s as f64;
+                for (band, (b, a)) in self.coeffs.iter().enumerate() {
+                    let state = &mut self.state[band * self.channels + ch];
+                    let [x1, x2, y1, y2] = *state;
+                    let y = b[0] * x + b[1] * x1 + b[2] * x2 - a[0] * y1 - a[1] * y2;
+                    *state = [x, x1, y, y1];
+                    x = y;
+                }
+                *s = x.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+            }
+        }
+    }
+}
+
+// Stereo CBR bitrate -> low-pass cutoff, close to what LAME picks by default. From
+// 128 kbps up the encoder has the bits for the full band.
+const AUTO_LOWPASS: [(u32, f32); 11] = [
+    (8, 2000.0),
+    (16, 3700.0),
+    (24, 3900.0),
+    (32, 5500.0),
+    (40, 7000.0),
+    (48, 7500.0),
+    (56, 10000.0),
+    (64, 11000.0),
+    (80, 13500.0),
+    (96, 15100.0),
+    (112, 15600.0),
+];
+
+// The automatic --lowpass for an MP3 bitrate: mono gets the cutoff of twice the bitrate
+// in stereo. None when the cutoff wouldn't remove anything at this sample rate.
+pub(crate) fn auto_lowpass_hz(kbps: u32, channels: usize, sample_rate: u32) -> Option<f32> {
+    let stereo_kbps = if channels == 1 { kbps * 2 } else { kbps };
+    let (_, hz) = AUTO_LOWPASS.iter().find(|&&(max_kbps, _)| stereo_kbps <= max_kbps)?;
+    (*hz < sample_rate as f32 * 0.45).then_some(*hz)
+}
+
+// Linear-interpolating sample-rate converter for interleaved i16. State carries
+// across blocks (fractional read position + last input frame), so block
+// boundaries are seamless and each channel is interpolated independently.
+// Downsampling first runs the input through an anti-aliasing low-pass below the new
+// Nyquist, since interpolation alone folds everything above it back into the audio.
+pub(crate) struct LinearResampler {
+    channels: usize,
+    // Input frames advanced per output frame (in_rate / out_rate)
+    step: f64,
+    // Read position in input frames; 0.0 is `last`, 1.0 is the first frame of the next block
+    pos: f64,
+    last: Vec<f64>,
+    anti_alias: Option<AntiAlias>,
+    // The block being read, filtered when downsampling
+    input: Vec<f64>,
+}
+
+impl LinearResampler {
+    pub(crate) fn new(channels: usize, in_rate: u32, out_rate: u32) -> Self {
+        Self {
+            channels,
+            step: in_rate as f64 / out_rate as f64,
+            pos: 1.0,
+            last: vec![0.0; channels],
+            anti_alias: (out_rate < in_rate).then(|| {
+                AntiAlias::new(channels, ANTI_ALIAS_CUTOFF * out_rate as f64 / in_rate as f64)
+            }),
+            input: Vec::new(),
+        }
+    }
+
+    pub(crate) fn process_into(&mut self, input: &[i16], out: &mut Vec<i16>) {
+        let channels = self.channels;
+        let frames = input.len() / channels;
+        if frames == 0 {
+            return;
+        }
+        out.reserve(((frames as f64 / self.step) as usize + 1) * channels);
+        self.input.clear();
+        self.input.extend(input[..frames * channels].iter().map(|&s| s as f64));
+        if let Some(filter) = &mut self.anti_alias {
+            filter.process(&mut self.input);
+        }
+
+        // Frame i of the virtual stream: 0 = previous block's last frame, i >= 1 = input[i - 1]
+        let sample = |i: usize, ch: usize| -> f64 {
+            if i == 0 {
+                self.last[ch]
+            } else {
+                self.input[(i - 1) * channels + ch]
+            }
+        };
+
+        while (self.pos as usize) < frames {
+            let i0 = self.pos as usize;
+            let frac = self.pos - i0 as f64;
+            for ch in 0..channels {
+                let a = sample(i0, ch);
+                let b = sample(i0 + 1, ch);
+                // The filter can overshoot a little; the `as` cast saturates
+                out.push((a + (b - a) * frac).round() as i16);
+            }
+            self.pos += self.step;
+        }
+
+        self.pos -= frames as f64;
+        self.last
+            .copy_from_slice(&self.input[(frames - 1) * channels..frames * channels]);
+    }
+}
+
+// Anti-aliasing cutoff as a fraction of the output rate: 90% of the new Nyquist, so the
+// filter is well down by the time it is reached
+const ANTI_ALIAS_CUTOFF: f64 = 0.45;
+
+// Butterworth order of the anti-aliasing filter (biquads = order / 2): 96 dB per octave
+const ANTI_ALIAS_ORDER: usize = 16;
+
+// The anti-aliasing low-pass of LinearResampler: a cascade of biquads (RBJ low-pass
+// sections, direct form I) whose Qs make up one high-order Butterworth response, flat
+// below the cutoff. Runs in f64 on the input rate, with per-channel state across blocks.
+struct AntiAlias {
+    // Per section: b0, b1, b2, a1, a2
+    sections: Vec<[f64; 5]>,
+    // Per channel and section: x[n-1], x[n-2], y[n-1], y[n-2]
+    state: Vec<Vec<[f64; 4]>>,
+}
+
+impl AntiAlias {
+    // `cutoff` is a fraction of the sample rate (below 0.5)
+    fn new(channels: usize, cutoff: f64) -> Self {
+        let w0 = 2.0 * std::f64::consts::PI * cutoff;
+        let cos = w0.cos();
+        let sections: Vec<[f64; 5]> = (0..ANTI_ALIAS_ORDER / 2)
+            .map(|k| {
+                // Pole pair k of the Butterworth prototype
+                let angle =
+                    (2 * k + 1) as f64 * std::f64::consts::PI / (2 * ANTI_ALIAS_ORDER) as f64;
+                let q = 1.0 / (2.0 * angle.cos());
+                let alpha = w0.sin() / (2.0 * q);
+                let a0 = 1.0 + alpha;
+                let b1 = (1.0 - cos) / a0;
+                [b1 / 2.0, b1, b1 / 2.0, -2.0 * cos / a0, (1.0 - alpha) / a0]
+            })
+            .collect();
+        Self {
+            state: vec![vec![[0.0; 4]; sections.len()]; channels],
+            sections,
+        }
+    }
+
+    fn process(&mut self, samples: &mut [f64]) {
+        let channels = self.state.len();
+        for frame in samples.chunks_exact_mut(channels) {
+            for (s, states) in frame.iter_mut().zip(&mut self.state) {
+                let mut x = *s;
+                for (c, state) in self.sections.iter().zip(states.iter_mut()) {
+                    let [x1, x2, y1, y2] = *state;
+                    let y = c[0] * x + c[1] * x1 + c[2] * x2 - c[3] * y1 - c[4] * y2;
+                    *state = [x, x1, y, y1];
+                    x = y;
+                }
+                *s = x;
+            }
+        }
+    }
+}
+
+// WAVEFORMATEXTENSIBLE dwChannelMask speaker positions (ksmedia.h)
+const SPEAKER_FRONT_LEFT: u32 = 0x1;
+
+const SPEAKER_FRONT_RIGHT: u32 = 0x2;
+
+const SPEAKER_FRONT_CENTER: u32 = 0x4;
+
+const SPEAKER_LOW_FREQUENCY: u32 = 0x8;
+
+const SPEAKER_BACK_LEFT: u32 = 0x10;
+
+const SPEAKER_BACK_RIGHT: u32 = 0x20;
+
+const SPEAKER_FRONT_LEFT_OF_CENTER: u32 = 0x40;
+
+const SPEAKER_FRONT_RIGHT_OF_CENTER: u32 = 0x80;
+
+const SPEAKER_BACK_CENTER: u32 = 0x100;
+
+const SPEAKER_SIDE_LEFT: u32 = 0x200;
+
+const SPEAKER_SIDE_RIGHT: u32 = 0x400;
+
+// Short names of the WAVEFORMATEXTENSIBLE speaker bits, lowest bit first
+const SPEAKER_NAMES: [&str; 18] = [
+    "FL", "FR", "FC", "LFE", "BL", "BR", "FLC", "FRC", "BC", "SL", "SR", "TC", "TFL", "TFC",
+    "TFR", "TBL", "TBC", "TBR",
+];
+
+// Usual speaker layout for a channel count (0 for mono, stereo and unknown counts). These
+// are also FLAC's default channel orders.
+pub(crate) fn standard_channel_mask(channels: usize) -> u32 {
+    match channels {
+        3 => 0x7,   // FL FR FC
+        4 => 0x33,  // FL FR BL BR
+        5 => 0x37,  // FL FR FC BL BR
+        6 => 0x3F,  // 5.1: FL FR FC LFE BL BR
+        7 => 0x70F, // 6.1: FL FR FC LFE BC SL SR
+        8 => 0x63F, // 7.1: FL FR FC LFE BL BR SL SR
+        _ => 0,
+    }
+}
+
+// Channel mask -> speaker names in channel order; unknown bits show as their number
+pub(crate) fn speaker_names(channel_mask: u32) -> Vec<String> {
+    (0..32)
+        .filter(|bit| channel_mask & (1 << bit) != 0)
+        .map(|bit| match SPEAKER_NAMES.get(bit) {
+            Some(name) => name.to_string(),
+            None => format!("bit{bit}"),
+        })
+        .collect()
+}
+
+// Downmix coefficients are Q12 fixed point so they can be applied in i32 accumulators
+// (Q12 leaves enough headroom for several full-scale channels summed into one side).
+const COEFF_SHIFT: u32 = 12;
+
+const COEFF_ONE: i32 = 1 << COEFF_SHIFT;
+
+const COEFF_MINUS_3DB: i32 = 2896; // 0.7071 * 2^12
+
+// Build per-channel (left, right) coefficients for an ITU-R BS.775 style downmix:
+// L = FL + 0.707*C + 0.707*SL, R = FR + 0.707*C + 0.707*SR, LFE dropped.
+// Returns None when the mask doesn't describe every channel, so the caller can
+// fall back to plain averaging.
+pub(crate) fn stereo_downmix_matrix(channel_mask: u32, channels: usize) -> Option<Vec<[i32; 2]>> {
+    if channel_mask == 0 || channel_mask.count_ones() as usize != channels {
+        return None;
+    }
+
+    let mut matrix = Vec::with_capacity(channels);
+    // Interleaved channels appear in ascending speaker-bit order.
+    for bit in 0..32 {
+        let speaker = 1u32 << bit;
+        if channel_mask & speaker == 0 {
+            continue;
+        }
+        let coeffs = match speaker {
+            SPEAKER_FRONT_LEFT | SPEAKER_FRONT_LEFT_OF_CENTER => [COEFF_ONE, 0],
+            SPEAKER_FRONT_RIGHT | SPEAKER_FRONT_RIGHT_OF_CENTER => [0, COEFF_ONE],
+            SPEAKER_FRONT_CENTER => [COEFF_MINUS_3DB, COEFF_MINUS_3DB],
+            SPEAKER_LOW_FREQUENCY => [0, 0],
+            SPEAKER_BACK_LEFT | SPEAKER_SIDE_LEFT => [COEFF_MINUS_3DB, 0],
+            SPEAKER_BACK_RIGHT | SPEAKER_SIDE_RIGHT => [0, COEFF_MINUS_3DB],
+            SPEAKER_BACK_CENTER => [COEFF_ONE / 2, COEFF_ONE / 2],
+            // Top/height and unnamed positions are not part of BS.775; drop them.
+            _ => [0, 0],
+        };
+        matrix.push(coeffs);
+    }
+    Some(matrix)
+}
+
+pub(crate) fn downmix_matrix_to_stereo_into(
+    input: &[i16],
+    matrix: &[[i32; 2]],
+    out: &mut Vec<i16>,
+) {
+    let channels = matrix.len();
+    let frames = input.len() / channels;
+    out.reserve(frames * 2);
+
+    for f in 0..frames {
+        let base = f * channels;
+        let mut l_acc: i32 = 0;
+        let mut r_acc: i32 = 0;
+
+        for (ch, [l_coeff, r_coeff]) in matrix.iter().enumerate() {
+            let s = input[base + ch] as i32;
+            l_acc += s * l_coeff;
+            r_acc += s * r_coeff;
+        }
+
+        let l = (l_acc >> COEFF_SHIFT).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        let r = (r_acc >> COEFF_SHIFT).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+        out.push(l);
+        out.push(r);
+    }
+}
+
+pub(crate) fn downmix_n_to_stereo_into(input: &[i16], channels: usize, out: &mut Vec<i16>) {
+    let frames = input.len() / channels;
+    out.reserve(frames * 2);
+
+    for f in 0..frames {
+        let base = f * channels;
+        let mut l_acc: i32 = 0;
+        let mut r_acc: i32 = 0;
+        let mut l_n: i32 = 0;
+        let mut r_n: i32 = 0;
+
+        for ch in 0..channels {
+            let s = input[base + ch] as i32;
+            if ch % 2 == 0 {
+                l_acc += s;
+                l_n += 1;
+            } else {
+                r_acc += s;
+                r_n += 1;
+            }
+        }
+
+        let l = (l_acc / l_n.max(1)).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        let r = (r_acc / r_n.max(1)).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+        out.push(l);
+        out.push(r);
+    }
+}
+
+// Weights of each device channel in a --mono downmix: the left and right coefficients of
+// the stereo matrix, averaged, so the LFE channel (and height speakers) are left out. A
+// mask that doesn't describe every channel is replaced by the standard layout for the
+// count; without one, every channel counts the same.
+pub(crate) fn mono_downmix_weights(channel_mask: u32, channels: usize) -> Vec<f32> {
+    let mask = if channel_mask.count_ones() as usize == channels {
+        channel_mask
+    } else {
+        standard_channel_mask(channels)
+    };
+    match stereo_downmix_matrix(mask, channels) {
+        Some(matrix) => matrix
+            .iter()
+            .map(|[l, r]| (l + r) as f32 / 2.0 / COEFF_ONE as f32)
+            .collect(),
+        None => vec![1.0; channels],
+    }
+}
+
+// Q12 coefficients for downmix_to_mono_into. Weights adding up to more than 1 (in absolute
+// value) are scaled down to a sum of 1, so full-scale samples on every channel can't
+// overflow; a stereo device's default weights give (L + R) / 2.
+pub(crate) fn mono_coefficients(weights: &[f32]) -> Vec<i32> {
+    let total: f32 = weights.iter().map(|w| w.abs()).sum();
+    let scale = if total > 1.0 { 1.0 / total } else { 1.0 };
+    weights
+        .iter()
+        .map(|w| (w * scale * COEFF_ONE as f32).round() as i32)
+        .collect()
+}
+
+pub(crate) fn downmix_to_mono_into(input: &[i16], coeffs: &[i32], out: &mut Vec<i16>) {
+    let channels = coeffs.len();
+    out.reserve(input.len() / channels);
+
+    for frame in input.chunks_exact(channels) {
+        let acc: i32 = frame.iter().zip(coeffs).map(|(&s, &c)| s as i32 * c).sum();
+        out.push((acc >> COEFF_SHIFT).clamp(i16::MIN as i32, i16::MAX as i32) as i16);
+    }
+}
+
+// --channel-map: which device channel feeds each output channel
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ChannelMap {
+    pub(crate) left: usize,
+    pub(crate) right: usize,
+}
+
+pub(crate) fn parse_channel_map(spec: &str) -> Result<ChannelMap, String> {
+    let (mut left, mut right) = (None, None);
+    for part in spec.split(',') {
+        let (name, index) = part
+            .split_once('=')
+            .ok_or_else(|| format!("expected L=<index>,R=<index>, got '{part}'"))?;
+        let index: usize = index
+            .trim()
+            .parse()
+            .map_err(|_| format!("'{}' is not a channel index", index.trim()))?;
+        match name.trim() {
+            "L" | "l" => left = Some(index),
+            "R" | "r" => right = Some(index),
+            other => return Err(format!("unknown output channel '{other}' (use L and R)")),
+        }
+    }
+    match (left, right) {
+        (Some(left), Some(right)) => Ok(ChannelMap { left, right }),
+        _ => Err("both L and R must be given, e.g. L=2,R=3".to_string()),
+    }
+}
+
+pub(crate) fn parse_channel_gain(spec: &str) -> Result<(usize, f32), String> {
+    let (channel, db) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected <channel>=<dB>, got '{spec}'"))?;
+    let channel = channel
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{}' is not a channel index", channel.trim()))?;
+    let db: f32 = db
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{}' is not a gain in dB", db.trim()))?;
+    if !db.is_finite() {
+        return Err(format!("'{db}' is not a gain in dB"));
+    }
+    Ok((channel, db))
+}
+
+// Scale each channel of interleaved frames by its own linear gain (clamped to i16)
+pub(crate) fn apply_channel_gains(samples: &mut [i16], gains: &[f32]) {
+    for frame in samples.chunks_exact_mut(gains.len()) {
+        for (s, &gain) in frame.iter_mut().zip(gains) {
+            *s = (*s as f32 * gain).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+}
+
+// --stereo-width, --swap-lr and --invert-phase, applied to stereo frames after the downmix
+pub(crate) struct StereoTools {
+    width: Option<f32>,
+    swap: bool,
+    invert_left: bool,
+    invert_right: bool,
+}
+
+impl StereoTools {
+    // None when none of the options is given (or the width is 1), so the loop skips it
+    pub(crate) fn new(width: Option<f32>, swap: bool, invert: Option<PhaseInvert>) -> Option<Self> {
+        let width = width.filter(|&w| w != 1.0);
+        let (invert_left, invert_right) = match invert {
+            None => (false, false),
+            Some(PhaseInvert::Left) => (true, false),
+            Some(PhaseInvert::Right) => (false, true),
+            Some(PhaseInvert::Both) => (true, true),
+        };
+        (width.is_some() || swap || invert_left || invert_right).then_some(Self {
+            width,
+            swap,
+            invert_left,
+            invert_right,
+        })
+    }
+
+    pub(crate) fn process(&self, samples: &mut [i16]) {
+        for frame in samples.chunks_exact_mut(2) {
+            let (mut left, mut right) = (frame[0], frame[1]);
+            if let Some(width) = self.width {
+                // Mid/side: keep the mid, scale the side
+                let mid = (left as f32 + right as f32) / 2.0;
+                let side = (left as f32 - right as f32) / 2.0 * width;
+                left = (mid + side).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                right = (mid - side).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            }
+            if self.swap {
+                std::mem::swap(&mut left, &mut right);
+            }
+            if self.invert_left {
+                left = left.saturating_neg();
+            }
+            if self.invert_right {
+                right = right.saturating_neg();
+            }
+            frame[0] = left;
+            frame[1] = right;
+        }
+    }
+}
+
+pub(crate) fn remap_channels_into(
+    input: &[i16],
+    channels: usize,
+    map: &ChannelMap,
+    out: &mut Vec<i16>,
+) {
+    let frames = input.len() / channels;
+    out.reserve(frames * 2);
+
+    for frame in input.chunks_exact(channels) {
+        out.push(frame[map.left]);
+        out.push(frame[map.right]);
+    }
+}
+
+// Compares the frames a source delivers with the system clock times stamped on its blocks.
+// The drift (ppm) is measured only across blocks that follow each other without a gap.
+// With --drift-correct, the frames passed on are kept within a millisecond of the clock:
+// a frame is dropped or repeated at the end of a block, and a longer shortfall (lost
+// audio, or loopback delivering nothing while nothing plays) becomes silence. All counts
+// are frames at the source rate.
+pub(crate) struct DriftTracker {
+    rate: f64,
+    channels: usize,
+    // Timestamp of the first frame passed on, and the frames passed on since then
+    origin: Option<Duration>,
+    position: u64,
+    // The previous timestamped block: its timestamp and captured frames
+    last: Option<(Duration, u64)>,
+    device_frames: u64,
+    clock_secs: f64,
+    pub(crate) inserted: u64,
+    pub(crate) dropped: u64,
+}
+
+impl DriftTracker {
+    pub(crate) fn new(rate: u32, channels: usize) -> Self {
+        Self {
+            rate: rate as f64,
+            channels,
+            origin: None,
+            position: 0,
+            last: None,
+            device_frames: 0,
+            clock_secs: 0.0,
+            inserted: 0,
+            dropped: 0,
+        }
+    }
+
+    // `block` holds `frames` captured frames, after any silence the source put in front
+    pub(crate) fn track(
+        &mut self,
+        block: &mut Vec<i16>,
+        frames: u64,
+        timestamp: Option<Duration>,
+        correct: bool,
+    ) {
+        let channels = self.channels;
+        let appended = (block.len() / channels) as u64;
+        let lead = appended.saturating_sub(frames);
+        let Some(timestamp) = timestamp else {
+            self.position += appended;
+            return;
+        };
+
+        if let Some((last_time, last_frames)) = self.last
+            && let Some(clock) = timestamp.checked_sub(last_time)
+        {
+            let clock = clock.as_secs_f64();
+            // More than 5 ms off means a gap (or a switch), which isn't drift
+            if lead == 0 && (clock - last_frames as f64 / self.rate).abs() < 0.005 {
+                self.device_frames += last_frames;
+                self.clock_secs += clock;
+            }
+        }
+        self.last = Some((timestamp, frames));
+
+        if !correct {
+            return;
+        }
+        let origin = *self.origin.get_or_insert(timestamp);
+        let expected = timestamp.saturating_sub(origin).as_secs_f64() * self.rate;
+        // Frames ahead (+) or behind (-) the clock at the first captured frame
+        let error = (self.position + lead) as f64 - expected;
+        let tolerance = self.rate / 1000.0;
+        if error < -self.rate / 50.0 {
+            let missing = ((-error) as u64).min(MAX_GAP_FILL_SECS * self.rate as u64);
+            let at = lead as usize * channels;
+            block.splice(at..at, std::iter::repeat_n(0, missing as usize * channels));
+            self.inserted += missing;
+        } else if error < -tolerance && appended > 0 {
+            let last_frame = block[block.len() - channels..].to_vec();
+            block.extend_from_slice(&last_frame);
+            self.inserted += 1;
+        } else if error > tolerance && appended > 1 {
+            block.truncate(block.len() - channels);
+            self.dropped += 1;
+        }
+        self.position += (block.len() / channels) as u64;
+    }
+
+    // Device clock against system clock, once there is a second to compare
+    pub(crate) fn ppm(&self) -> Option<f64> {
+        (self.clock_secs >= 1.0)
+            .then(|| (self.device_frames as f64 / self.rate / self.clock_secs - 1.0) * 1e6)
+    }
+}
+
+// Voice-activated recording (--vox): decides for each chunk whether it is written and
+// keeps track of the active stretches. Positions are frames at the output rate.
+pub(crate) struct Vox {
+    channels: usize,
+    sample_rate: u32,
+    threshold_dbfs: f64,
+    hang_frames: u64,
+    pub(crate) position: u64,     // frames seen, written or not
+    pub(crate) written: u64,      // frames written
+    active_until: u64, // position the hang runs to after the last loud chunk
+    current: Option<VoxSegment>,
+    pub(crate) segments: Vec<VoxSegment>,
+    preroll: Option<PreRollBuffer>, // recent dropped audio (--preroll)
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct VoxSegment {
+    start: u64,      // recording position of the first frame written
+    end: u64,        // recording position just after the last one
+    file_start: u64, // where the stretch starts in the output
+}
+
+impl Vox {
+    pub(crate) fn new(
+        channels: usize,
+        threshold_dbfs: f32,
+        hang_ms: u32,
+        preroll_ms: Option<u32>,
+        sample_rate: u32,
+    ) -> Self {
+        let preroll = preroll_ms.map(|ms| {
+            let frames = sample_rate as usize * ms as usize / 1000;
+            PreRollBuffer::new(frames * channels)
+        });
+        Self {
+            channels,
+            sample_rate,
+            threshold_dbfs: threshold_dbfs as f64,
+            hang_frames: sample_rate as u64 * hang_ms as u64 / 1000,
+            position: 0,
+            written: 0,
+            active_until: 0,
+            current: None,
+            segments: Vec::new(),
+            preroll,
+        }
+    }
+
+    // Whether `chunk` should be written. Loud chunks are kept, and so is everything that
+    // starts within the hang time after one. Dropped chunks go into the pre-roll buffer,
+    // which is put in front of the chunk that starts the next stretch.
+    pub(crate) fn keep(&mut self, chunk: &mut Vec<i16>) -> bool {
+        let frames = (chunk.len() / self.channels) as u64;
+        let start = self.position;
+        self.position += frames;
+        if rms_dbfs(chunk) >= self.threshold_dbfs {
+            self.active_until = self.position + self.hang_frames;
+        }
+        if start >= self.active_until {
+            if let Some(segment) = self.current.take() {
+                self.segments.push(segment);
+            }
+            if let Some(preroll) = &mut self.preroll {
+                preroll.push(chunk);
+            }
+            return false;
+        }
+        if self.current.is_none() {
+            let file_start = self.written;
+            let mut start = start;
+            if let Some(preroll) = &mut self.preroll
+                && !preroll.is_empty()
+            {
+                let mut joined = Vec::with_capacity(preroll.len() + chunk.len());
+                preroll.drain_into(&mut joined);
+                let preroll_frames = (joined.len() / self.channels) as u64;
+                joined.extend_from_slice(chunk);
+                *chunk = joined;
+                start -= preroll_frames;
+                self.written += preroll_frames;
+            }
+            self.current = Some(VoxSegment {
+                start,
+                end: start,
+                file_start,
+            });
+        }
+        if let Some(segment) = &mut self.current {
+            segment.end = self.position;
+        }
+        self.written += frames;
+        true
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.current.is_some()
+    }
+
+    // Close the stretch still open when the recording stops
+    pub(crate) fn finish(&mut self) {
+        if let Some(segment) = self.current.take() {
+            self.segments.push(segment);
+        }
+    }
+
+    pub(crate) fn secs(&self, frames: u64) -> f64 {
+        frames as f64 / self.sample_rate as f64
+    }
+
+    // The --vox-log file: the recording start and each active stretch in seconds
+    pub(crate) fn to_json(&self, started: &LocalTime) -> String {
+        let secs = |frames: u64| self.secs(frames);
+        let list: Vec<String> = self
+            .segments
+            .iter()
+            .map(|s| {
+                format!(
+                    "{{\"start_secs\": {:.3}, \"end_secs\": {:.3}, \"file_secs\": {:.3}}}",
+                    secs(s.start),
+                    secs(s.end),
+                    secs(s.file_start)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"started\": {}, \"threshold_dbfs\": {:.1}, \"hang_secs\": {:.3}, \"duration_secs\": {:.3}, \
+             \"written_secs\": {:.3}, \"segments\": [{}]}}\n",
+            json_string(&started.to_string()),
+            self.threshold_dbfs,
+            secs(self.hang_frames),
+            secs(self.position),
+            secs(self.written),
+            list.join(", ")
+        )
+    }
+}
+
+// Ring buffer holding the most recent `capacity` samples (--preroll). Once full, each
+// new sample overwrites the oldest one; `drain_into` hands them out oldest first.
+struct PreRollBuffer {
+    samples: Vec<i16>,
+    capacity: usize,
+    next: usize, // write position, which is also the oldest sample once full
+}
+
+impl PreRollBuffer {
+    fn new(capacity: usize) -> Self {
+        Self {
+            samples: Vec::with_capacity(capacity),
+            capacity,
+            next: 0,
+        }
+    }
+
+    fn push(&mut self, chunk: &[i16]) {
+        if self.capacity == 0 {
+            return;
+        }
+        // Only the tail of a chunk longer than the buffer can survive
+        let chunk = &chunk[chunk.len().saturating_sub(self.capacity)..];
+        for &s in chunk {
+            if self.samples.len() < self.capacity {
+                self.samples.push(s);
+            } else {
+                self.samples[self.next] = s;
+            }
+            self.next = (self.next + 1) % self.capacity;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    // Append the buffered samples in order (oldest first) and empty the buffer
+    fn drain_into(&mut self, out: &mut Vec<i16>) {
+        if self.samples.len() == self.capacity {
+            out.extend_from_slice(&self.samples[self.next..]);
+            out.extend_from_slice(&self.samples[..self.next]);
+        } else {
+            out.extend_from_slice(&self.samples);
+        }
+        self.samples.clear();
+        self.next = 0;
+    }
+}
+
+pub(crate) fn rms_dbfs(samples: &[i16]) -> f64 {
+    let sum_sq: f64 = samples.iter().map(|&s| (s as f64) * (s as f64)).sum();
+    to_dbfs((sum_sq / samples.len().max(1) as f64).sqrt())
+}
+
+pub(crate) fn to_dbfs(level: f64) -> f64 {
+    if level <= 0.0 {
+        f64::NEG_INFINITY
+    } else {
+        20.0 * (level / i16::MAX as f64).log10()
+    }
+}
+
+// --spectrum: band levels of the mono sum of the last SPECTRUM_FFT_SIZE output frames,
+// from a Hann-windowed real FFT
+pub(crate) struct Spectrum {
+    channels: usize,
+    recent: Vec<f32>,
+    pos: usize,
+    window: Vec<f32>,
+    // FFT bin range (start..end) of each band
+    bands: Vec<(usize, usize)>,
+}
+
+const SPECTRUM_FFT_SIZE: usize = 2048;
+
+pub(crate) const SPECTRUM_INTERVAL: Duration = Duration::from_millis(250);
+
+const SPECTRUM_LOW_HZ: f64 = 20.0;
+
+impl Spectrum {
+    pub(crate) fn new(bands: u32, rate: u32, channels: usize) -> Self {
+        let n = SPECTRUM_FFT_SIZE;
+        let window = (0..n)
+            .map(|i| (0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / n as f64).cos()) as f32)
+            .collect();
+        let nyquist = rate as f64 / 2.0;
+        let bin_hz = rate as f64 / n as f64;
+        let edge =
+            |i: u32| SPECTRUM_LOW_HZ * (nyquist / SPECTRUM_LOW_HZ).powf(i as f64 / bands as f64);
+        let bands = (0..bands)
+            .map(|i| {
+                let start = ((edge(i) / bin_hz) as usize).clamp(1, n / 2 - 1);
+                let end = ((edge(i + 1) / bin_hz).ceil() as usize).clamp(start + 1, n / 2);
+                (start, end)
+            })
+            .collect();
+        Self {
+            channels,
+            recent: vec![0.0; n],
+            pos: 0,
+            window,
+            bands,
+        }
+    }
+
+    pub(crate) fn update(&mut self, samples: &[i16]) {
+        for frame in samples.chunks_exact(self.channels) {
+            let sum: i32 = frame.iter().map(|&s| s as i32).sum();
+            self.recent[self.pos] = sum as f32 / (self.channels as f32 * 32768.0);
+            self.pos = (self.pos + 1) % SPECTRUM_FFT_SIZE;
+        }
+    }
+
+    // dBFS per band: the strongest bin, scaled so a full-scale sine reads 0 dBFS
+    fn levels(&self) -> Vec<f64> {
+        let n = SPECTRUM_FFT_SIZE;
+        let windowed: Vec<f32> = (0..n)
+            .map(|i| self.recent[(self.pos + i) % n] * self.window[i])
+            .collect();
+        let magnitudes = real_fft_magnitudes(&windowed);
+        self.bands
+            .iter()
+            .map(|&(start, end)| {
+                let peak = magnitudes[start..end].iter().fold(0.0f64, |a, &m| a.max(m));
+                if peak > 0.0 {
+                    20.0 * (peak * 4.0 / n as f64).log10()
+                } else {
+                    f64::NEG_INFINITY
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        let levels: Vec<String> = self.levels().into_iter().map(dbfs_json).collect();
+        format!("[{}]", levels.join(", "))
+    }
+}
+
+// Magnitudes of bins 0..=n/2 of a real signal (power-of-two length n): the even and odd
+// samples go through one n/2-point complex FFT as real and imaginary parts, then the two
+// half spectra are separated and combined
+fn real_fft_magnitudes(x: &[f32]) -> Vec<f64> {
+    let half = x.len() / 2;
+    let mut re: Vec<f64> = x.iter().step_by(2).map(|&v| v as f64).collect();
+    let mut im: Vec<f64> = x.iter().skip(1).step_by(2).map(|&v| v as f64).collect();
+    fft_in_place(&mut re, &mut im);
+    (0..=half)
+        .map(|k| {
+            let (zr, zi) = (re[k % half], im[k % half]);
+            let (cr, ci) = (re[(half - k) % half], -im[(half - k) % half]);
+            // Even part (Z[k] + conj Z[n/2-k]) / 2, odd part -i (Z[k] - conj Z[n/2-k]) / 2
+            let (er, ei) = ((zr + cr) / 2.0, (zi + ci) / 2.0);
+            let (or, oi) = ((zi - ci) / 2.0, -(zr - cr) / 2.0);
+            let angle = -std::f64::consts::PI * k as f64 / half as f64;
+            let (wr, wi) = (angle.cos(), angle.sin());
+            let (xr, xi) = (er + wr * or - wi * oi, ei + wr * oi + wi * or);
+            (xr * xr + xi * xi).sqrt()
+        })
+        .collect()
+}
+
+// Iterative radix-2 FFT; the length must be a power of two
+fn fft_in_place(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * std::f64::consts::PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (wr, wi) = ((angle * k as f64).cos(), (angle * k as f64).sin());
+                let (a, b) = (start + k, start + k + len / 2);
+                let tr = re[b] * wr - im[b] * wi;
+                let ti = re[b] * wi + im[b] * wr;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const M3: i32 = COEFF_MINUS_3DB;
+
+    #[test]
+    fn downmix_matrix_for_5_1() {
+        let matrix = stereo_downmix_matrix(standard_channel_mask(6), 6).unwrap();
+        let expected = [[COEFF_ONE, 0], [0, COEFF_ONE], [M3, M3], [0, 0], [M3, 0], [0, M3]];
+        assert_eq!(matrix, expected);
+    }
+
+    #[test]
+    fn downmix_matrix_for_7_1() {
+        let matrix = stereo_downmix_matrix(standard_channel_mask(8), 8).unwrap();
+        let expected = [
+            [COEFF_ONE, 0],
+            [0, COEFF_ONE],
+            [M3, M3],
+            [0, 0],
+            [M3, 0],
+            [0, M3],
+            [M3, 0],
+            [0, M3],
+        ];
+        assert_eq!(matrix, expected);
+    }
+
+    #[test]
+    fn downmix_matrix_passes_stereo_through() {
+        let matrix = stereo_downmix_matrix(0x3, 2).unwrap();
+        let input = [1000, -2000, i16::MAX, i16::MIN, 0, 1];
+        let mut out = Vec::new();
+        downmix_matrix_to_stereo_into(&input, &matrix, &mut out);
+        assert_eq!(out, input);
+    }
+
+    #[test]
+    fn downmix_matrix_puts_mono_centre_on_both_sides() {
+        let matrix = stereo_downmix_matrix(SPEAKER_FRONT_CENTER, 1).unwrap();
+        assert_eq!(matrix, [[M3, M3]]);
+        let mut out = Vec::new();
+        downmix_matrix_to_stereo_into(&[10_000], &matrix, &mut out);
+        assert_eq!(out, [7070, 7070]);
+    }
+
+    #[test]
+    fn downmix_matrix_needs_a_mask_for_every_channel() {
+        assert_eq!(stereo_downmix_matrix(0, 2), None);
+        assert_eq!(stereo_downmix_matrix(0x3F, 8), None);
+        assert_eq!(stereo_downmix_matrix(standard_channel_mask(1), 1), None);
+    }
+
+    // A sine advancing `step` radians per frame, the same on all `channels`
+    fn sine(frames: usize, channels: usize, step: f64) -> Vec<i16> {
+        (0..frames * channels)
+            .map(|i| ((i / channels) as f64 * step).sin() * 10_000.0)
+            .map(|s| s as i16)
+            .collect()
+    }
+
+    // Frames out of a resampler for a second of input fed in `block`-frame pieces
+    fn resampled(in_rate: u32, out_rate: u32, block: usize) -> Vec<i16> {
+        let mut resampler = LinearResampler::new(2, in_rate, out_rate);
+        let input = sine(in_rate as usize, 2, 0.05);
+        let mut out = Vec::new();
+        for chunk in input.chunks(block * 2) {
+            resampler.process_into(chunk, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn resampler_halves_96k_to_48k() {
+        let frames = resampled(96_000, 48_000, 960).len() / 2;
+        assert!(frames.abs_diff(48_000) <= 1, "{frames} frames");
+    }
+
+    #[test]
+    fn resampler_converts_44k1_to_48k() {
+        let frames = resampled(44_100, 48_000, 441).len() / 2;
+        assert!(frames.abs_diff(48_000) <= 1, "{frames} frames");
+    }
+
+    #[test]
+    fn resampler_is_continuous_across_blocks() {
+        for (in_rate, out_rate) in [(44_100, 48_000), (96_000, 48_000)] {
+            let whole = resampled(in_rate, out_rate, in_rate as usize);
+            // Odd block sizes put the boundaries at every fractional position
+            for block in [1, 7, 333] {
+                let split = resampled(in_rate, out_rate, block);
+                assert_eq!(split.len(), whole.len());
+                let worst = split.iter().zip(&whole).map(|(a, b)| a.abs_diff(*b)).max();
+                assert!(worst <= Some(1), "{in_rate} -> {out_rate}, blocks of {block}");
+            }
+        }
+    }
+
+    #[test]
+    fn normalize_gain_brings_the_peak_to_the_target() {
+        assert!((normalize_gain(i16::MAX as i32, 0.0) - 1.0).abs() < 1e-12);
+        assert!((normalize_gain(i16::MAX as i32, -6.0) - 0.501_187).abs() < 1e-6);
+        for (peak, target) in [(1000, -1.0), (20_000, -3.0), (32_768, -0.1)] {
+            let level = peak as f64 * normalize_gain(peak, target);
+            let dbfs = 20.0 * (level / i16::MAX as f64).log10();
+            assert!((dbfs - target as f64).abs() < 1e-4, "{peak} -> {dbfs} dBFS");
+        }
+    }
+
+    #[test]
+    fn normalize_gain_leaves_silence_at_unity() {
+        assert_eq!(normalize_gain(0, -1.0), 1.0);
+        assert_eq!(normalize_gain(0, 0.0), 1.0);
+    }
+
+    fn remapped(input: &[i16], channels: usize, spec: &str) -> Vec<i16> {
+        let map = parse_channel_map(spec).unwrap();
+        let mut out = vec![-1];
+        remap_channels_into(input, channels, &map, &mut out);
+        assert_eq!(out.remove(0), -1, "appends to what is already there");
+        out
+    }
+
+    #[test]
+    fn channel_map_reorders_drops_and_duplicates() {
+        let quad = [10, 11, 12, 13, 20, 21, 22, 23];
+        assert_eq!(remapped(&quad, 4, "L=0,R=1"), [10, 11, 20, 21]);
+        assert_eq!(remapped(&quad, 4, "L=3,R=2"), [13, 12, 23, 22]);
+        assert_eq!(remapped(&quad, 4, "R=0,L=2"), [12, 10, 22, 20]);
+        assert_eq!(remapped(&quad, 4, "L=1,R=1"), [11, 11, 21, 21]);
+        assert_eq!(remapped(&[5, 6], 1, "L=0,R=0"), [5, 5, 6, 6]);
+        // A cut-off last frame is left out
+        assert_eq!(remapped(&quad[..7], 4, "L=0,R=3"), [10, 13]);
+    }
+
+    #[test]
+    fn channel_map_spec_needs_both_sides() {
+        assert!(parse_channel_map("L=2").is_err());
+        assert!(parse_channel_map("L=0,C=1").is_err());
+        assert!(parse_channel_map("L=x,R=1").is_err());
+        let map = parse_channel_map(" l = 4 , r=5").unwrap();
+        assert_eq!((map.left, map.right), (4, 5));
+    }
+
+    fn peak(samples: &[i16]) -> i32 {
+        samples.iter().map(|&s| (s as i32).abs()).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn high_pass_removes_dc() {
+        let mut filter = HighPass::new(2, 20.0, 48_000);
+        let mut dc = [10_000i16; 2 * 48_000];
+        for chunk in dc.chunks_mut(2 * 480) {
+            filter.process(chunk);
+        }
+        // A step passes at first, then decays with the 8 ms time constant
+        assert!(dc[0] > 9_900 && dc[1] > 9_900, "{:?}", &dc[..2]);
+        assert!(peak(&dc[2 * 4_800..]) <= 1, "{}", peak(&dc[2 * 4_800..]));
+        assert!(dc[dc.len() - 2..].iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn high_pass_keeps_tones_well_above_the_cutoff() {
+        let mut filter = HighPass::new(1, 20.0, 48_000);
+        // 1 kHz at 48 kHz
+        let mut tone = sine(48_000, 1, 2.0 * std::f64::consts::PI / 48.0);
+        filter.process(&mut tone);
+        let settled = peak(&tone[4_800..]);
+        assert!((9_950..=10_000).contains(&settled), "{settled}");
+    }
+
+    // Input through a limiter in `block`-frame pieces, then flushed
+    fn limited(limiter: &mut Limiter, input: &[i16], block: usize) -> Vec<i16> {
+        let mut out = Vec::new();
+        for chunk in input.chunks(block * 2) {
+            limiter.process_into(chunk, &mut out);
+        }
+        limiter.flush_into(&mut out);
+        out
+    }
+
+    #[test]
+    fn limiter_holds_the_ceiling() {
+        // -1 dBFS is 29204
+        let ceiling = (i16::MAX as f32 * db_to_gain(-1.0)) as i32;
+        let mut input: Vec<i16> = sine(48_000, 2, 0.05).iter().map(|&s| s * 3).collect();
+        // Single-sample spikes, one at the very start where there is nothing to look back on
+        input[0] = i16::MAX;
+        input[2 * 20_000 + 1] = i16::MIN;
+        input[2 * 30_000] = 25_000;
+        for block in [1, 480, 48_000] {
+            let mut limiter = Limiter::new(2, -1.0, 48_000);
+            let out = limited(&mut limiter, &input, block);
+            assert_eq!(out.len(), input.len());
+            assert!(peak(&out) <= ceiling, "{} over {ceiling}", peak(&out));
+            assert!(limiter.limited > 0);
+        }
+    }
+
+    #[test]
+    fn limiter_passes_quiet_input_unchanged() {
+        // Peaks of 10000, well under the -1 dBFS ceiling: only delayed by the lookahead
+        let input = sine(48_000, 2, 0.05);
+        let mut limiter = Limiter::new(2, -1.0, 48_000);
+        assert_eq!(limited(&mut limiter, &input, 480), input);
+        assert_eq!(limiter.limited, 0);
+    }
+
+    #[test]
+    fn db_to_gain_converts_decibels() {
+        assert_eq!(db_to_gain(0.0), 1.0);
+        assert!((db_to_gain(-6.0) - 0.501).abs() < 0.001, "{}", db_to_gain(-6.0));
+        assert!((db_to_gain(6.0) - 1.995).abs() < 0.001, "{}", db_to_gain(6.0));
+        assert!((db_to_gain(-20.0) - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn scale_samples_rounds_and_clips() {
+        let mut samples = [0, 1000, -1000, 3, i16::MAX, i16::MIN];
+        scale_samples(&mut samples, 1.0);
+        assert_eq!(samples, [0, 1000, -1000, 3, i16::MAX, i16::MIN]);
+        scale_samples(&mut samples, 0.5);
+        assert_eq!(samples, [0, 500, -500, 2, 16384, -16384]);
+        // +12 dB on loud samples saturates at the rails instead of wrapping
+        let mut loud = [20_000, -20_000, 100];
+        scale_samples(&mut loud, db_to_gain(12.0) as f64);
+        assert_eq!(loud, [i16::MAX, i16::MIN, 398]);
+    }
+
+    #[test]
+    fn dither_stays_within_one_lsb() {
+        let mut dither = TpdfDither::new();
+        let noise: Vec<f32> = (0..100_000).map(|_| dither.next()).collect();
+        assert!(noise.iter().all(|n| (-1.0..1.0).contains(n)));
+        let mean = noise.iter().sum::<f32>() / noise.len() as f32;
+        assert!(mean.abs() < 0.01, "mean {mean}");
+        // Triangular: far more values near 0 than near the edges
+        let centre = noise.iter().filter(|n| n.abs() < 0.25).count();
+        let edges = noise.iter().filter(|n| n.abs() > 0.75).count();
+        assert!(centre > 5 * edges, "{centre} near 0, {edges} near the edges");
+    }
+
+    #[test]
+    fn dither_repeats_the_same_sequence() {
+        let mut first = TpdfDither::new();
+        let mut second = TpdfDither::new();
+        let a: Vec<f32> = (0..1000).map(|_| first.next()).collect();
+        let b: Vec<f32> = (0..1000).map(|_| second.next()).collect();
+        assert_eq!(a, b);
+        // Pinned, so a change to the seed or generator shows up as changed output
+        assert_eq!(a[..4], [0.42273974, -0.37050128, 0.27371454, -0.4021997]);
+    }
+
+    fn drained(buffer: &mut PreRollBuffer) -> Vec<i16> {
+        let mut out = Vec::new();
+        buffer.drain_into(&mut out);
+        assert!(buffer.is_empty());
+        out
+    }
+
+    #[test]
+    fn preroll_keeps_the_newest_samples_in_order() {
+        let mut buffer = PreRollBuffer::new(5);
+        buffer.push(&[1, 2, 3]);
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(drained(&mut buffer), [1, 2, 3]);
+
+        // Wrapping around the end, several times over
+        for chunk in [[1, 2, 3], [4, 5, 6], [7, 8, 9]] {
+            buffer.push(&chunk);
+        }
+        assert_eq!(buffer.len(), 5);
+        assert_eq!(drained(&mut buffer), [5, 6, 7, 8, 9]);
+
+        // Exactly full, with the write position back at the start
+        buffer.push(&[1, 2, 3, 4, 5]);
+        assert_eq!(drained(&mut buffer), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn preroll_longer_chunk_keeps_its_tail() {
+        let mut buffer = PreRollBuffer::new(4);
+        buffer.push(&[1, 2]);
+        buffer.push(&[3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(drained(&mut buffer), [6, 7, 8, 9]);
+        // Refilled after a drain, it starts over
+        buffer.push(&[10]);
+        assert_eq!(drained(&mut buffer), [10]);
+
+        let mut none = PreRollBuffer::new(0);
+        none.push(&[1, 2, 3]);
+        assert!(drained(&mut none).is_empty());
+    }
+
+    fn stereo_tools(
+        width: Option<f32>,
+        swap: bool,
+        invert: Option<PhaseInvert>,
+        input: &[i16],
+    ) -> Vec<i16> {
+        let mut samples = input.to_vec();
+        StereoTools::new(width, swap, invert).unwrap().process(&mut samples);
+        samples
+    }
+
+    #[test]
+    fn stereo_tools_are_skipped_when_unused() {
+        assert!(StereoTools::new(None, false, None).is_none());
+        assert!(StereoTools::new(Some(1.0), false, None).is_none());
+    }
+
+    #[test]
+    fn stereo_width_scales_the_side_signal() {
+        let input = [1000, 0, -200, 600, 30_000, -30_000];
+        // 0 collapses to mono, 1 keeps the image, 2 doubles the side (clipping at the rails)
+        assert_eq!(stereo_tools(Some(0.0), false, None, &input), [500, 500, 200, 200, 0, 0]);
+        assert_eq!(
+            stereo_tools(Some(1.0), true, None, &input),
+            stereo_tools(None, true, None, &input)
+        );
+        assert_eq!(
+            stereo_tools(Some(2.0), false, None, &input),
+            [1500, -500, -600, 1000, i16::MAX, i16::MIN]
+        );
+    }
+
+    #[test]
+    fn swap_and_invert_act_on_the_chosen_channels() {
+        let input = [1000, -2000, i16::MIN, 5];
+        assert_eq!(stereo_tools(None, true, None, &input), [-2000, 1000, 5, i16::MIN]);
+        let invert = |which| stereo_tools(None, false, Some(which), &input);
+        assert_eq!(invert(PhaseInvert::Left), [-1000, -2000, i16::MAX, 5]);
+        assert_eq!(invert(PhaseInvert::Right), [1000, 2000, i16::MIN, -5]);
+        assert_eq!(invert(PhaseInvert::Both), [-1000, 2000, i16::MAX, -5]);
+        // The swap comes first, so the inverted channel is the one that ends up on that side
+        assert_eq!(
+            stereo_tools(None, true, Some(PhaseInvert::Left), &input),
+            [2000, 1000, -5, i16::MIN]
+        );
+    }
+
+    #[test]
+    fn mono_weights_leave_out_the_lfe() {
+        for (channels, lfe) in [(6, 3), (8, 3)] {
+            let weights = mono_downmix_weights(standard_channel_mask(channels), channels);
+            assert_eq!(weights.len(), channels);
+            assert_eq!(weights[lfe], 0.0, "{channels} channels");
+            assert_eq!(weights[..2], [0.5, 0.5]);
+            let centre = std::f32::consts::FRAC_1_SQRT_2;
+            assert!((weights[2] - centre).abs() < 0.001, "centre {}", weights[2]);
+            // The downmix coefficients are scaled to a total of one
+            let coeffs = mono_coefficients(&weights);
+            let total: i32 = coeffs.iter().sum();
+            assert!(total.abs_diff(COEFF_ONE) <= 4, "{channels} channels: {total}");
+            assert_eq!(coeffs[lfe], 0);
+        }
+        // A mask that doesn't fit the channel count falls back to the standard layout
+        assert_eq!(mono_downmix_weights(0x3, 6), mono_downmix_weights(0x3F, 6));
+    }
+
+    #[test]
+    fn mono_downmix_of_stereo_averages_left_and_right() {
+        let coeffs = mono_coefficients(&mono_downmix_weights(0x3, 2));
+        assert_eq!(coeffs, [COEFF_ONE / 2, COEFF_ONE / 2]);
+        assert_eq!(mono_coefficients(&mono_downmix_weights(0, 2)), coeffs);
+        let mut out = Vec::new();
+        downmix_to_mono_into(&[1000, 3000, -4000, 0, i16::MAX, i16::MAX], &coeffs, &mut out);
+        assert_eq!(out, [2000, -2000, i16::MAX]);
+    }
+
+    #[test]
+    fn mono_downmix_of_full_scale_surround_doesnt_overflow() {
+        let coeffs = mono_coefficients(&mono_downmix_weights(0x3F, 6));
+        let mut out = Vec::new();
+        downmix_to_mono_into(&[i16::MAX; 6], &coeffs, &mut out);
+        downmix_to_mono_into(&[i16::MIN; 6], &coeffs, &mut out);
+        assert!(out[0] > 32_700, "{out:?}");
+        assert!(out[1] < -32_700, "{out:?}");
+    }
+
+    #[test]
+    fn low_pass_keeps_dc() {
+        let mut filter = LowPass::new(2, 16_000.0, 48_000);
+        let mut dc = [10_000i16; 2 * 4_800];
+        for chunk in dc.chunks_mut(2 * 100) {
+            filter.process(chunk);
+        }
+        assert!(dc[2 * 100..].iter().all(|&s| s == 10_000), "{:?}", &dc[..8]);
+    }
+
+    #[test]
+    fn low_pass_removes_a_nyquist_tone() {
+        // +10000, -10000, ... is a tone at half the sample rate
+        let tone: Vec<i16> = (0..4_800).map(|i| [10_000, -10_000][i % 2]).collect();
+        let mut filter = LowPass::new(1, 16_000.0, 48_000);
+        let mut filtered = tone.clone();
+        filter.process(&mut filtered);
+        assert!(peak(&filtered[480..]) <= 1, "{}", peak(&filtered[480..]));
+
+        // A tone well below the cutoff passes within a fraction of a dB
+        let mut filter = LowPass::new(1, 16_000.0, 48_000);
+        let mut low = sine(4_800, 1, 2.0 * std::f64::consts::PI * 1_000.0 / 48_000.0);
+        filter.process(&mut low);
+        let level = peak(&low[480..]);
+        assert!((9_800..=10_200).contains(&level), "{level}");
+    }
+
+    #[test]
+    fn gate_opens_over_the_attack_and_closes_over_the_release() {
+        // At 1 kHz: 10 ms attack = 10 frames, 100 ms release = 100 frames. -20 dBFS is 3277.
+        let mut gate = NoiseGate::new(1, -20.0, 10, 100, 1_000);
+        let mut samples = [&[1_000i16; 50][..], &[10_000; 50], &[1_000; 200]].concat();
+        gate.process(&mut samples);
+
+        // Closed from the start: 1000 is below the threshold, turned down 40 dB
+        assert!(samples[..50].iter().all(|&s| s == 10), "{:?}", &samples[..50]);
+        // Opens over ten frames, then passes unchanged
+        assert!(samples[50..60].windows(2).all(|w| w[0] < w[1]), "{:?}", &samples[50..60]);
+        assert!(samples[59..100].iter().all(|&s| s == 10_000), "{:?}", &samples[50..100]);
+        // The level follows the drop with a 10 ms time constant, so the gate stays open
+        // about 12 frames, then closes over the 100-frame release
+        assert!(samples[100..110].iter().all(|&s| s == 1_000), "{:?}", &samples[100..120]);
+        let closing = &samples[112..212];
+        assert!(closing.windows(2).all(|w| w[0] >= w[1]), "{closing:?}");
+        assert_eq!(closing[0], 980, "{closing:?}");
+        assert!((490..=510).contains(&closing[49]), "{closing:?}");
+        assert!(samples[211..].iter().all(|&s| s == 10), "{:?}", &samples[200..220]);
+        // Closed while the level was below the threshold: the first 50 and the last 188
+        assert!(gate.gated_frames.abs_diff(50 + 188) <= 1, "{}", gate.gated_frames);
+    }
+
+    #[test]
+    fn gate_keeps_its_state_across_blocks() {
+        let input = [&[1_000i16; 50][..], &[10_000; 50], &[1_000; 200]].concat();
+        let mut whole_gate = NoiseGate::new(2, -20.0, 10, 100, 1_000);
+        let mut whole: Vec<i16> = input.iter().flat_map(|&s| [s, -s]).collect();
+        let mut blocks = whole.clone();
+        whole_gate.process(&mut whole);
+        let mut block_gate = NoiseGate::new(2, -20.0, 10, 100, 1_000);
+        for chunk in blocks.chunks_mut(2 * 7) {
+            block_gate.process(chunk);
+        }
+        assert_eq!(blocks, whole);
+        assert_eq!(block_gate.gated_frames, whole_gate.gated_frames);
+    }
+
+    fn dbfs_of(sample: i16) -> f32 {
+        20.0 * (sample as f32 / i16::MAX as f32).log10()
+    }
+
+    #[test]
+    fn compressor_follows_its_static_curve() {
+        let compression = parse_compress("4:-20").unwrap();
+        // 0 ms attack and release: the envelope is the peak of each frame
+        let mut compressor = Compressor::new(1, compression, 0, 0, 0.0, 48_000);
+        for input_db in [-40.0, -20.0, -12.0, -4.0, 0.0] {
+            let level = (i16::MAX as f32 * db_to_gain(input_db)).round() as i16;
+            let mut samples = [level, -level];
+            compressor.process(&mut samples);
+            // 4:1 above -20 dBFS, untouched below it
+            let expected = if input_db > -20.0 {
+                -20.0 + (input_db + 20.0) / 4.0
+            } else {
+                input_db
+            };
+            let output_db = dbfs_of(samples[0]);
+            assert!((output_db - expected).abs() < 0.05, "{input_db} dB in, {output_db} dB out");
+            assert_eq!(samples[1], -samples[0]);
+        }
+
+        // Makeup gain on top
+        let mut compressor = Compressor::new(1, compression, 0, 0, 6.0, 48_000);
+        let mut samples = [1_000];
+        compressor.process(&mut samples);
+        assert_eq!(samples, [1_995]);
+    }
+
+    #[test]
+    fn compressor_attacks_and_releases_gradually() {
+        let compression = parse_compress("4:-20").unwrap();
+        // At 1 kHz: a 10-frame attack and a 100-frame release time constant
+        let mut compressor = Compressor::new(2, compression, 10, 100, 0.0, 1_000);
+        let loud = (i16::MAX as f32 * db_to_gain(-4.0)) as i16;
+        let quiet = (i16::MAX as f32 * db_to_gain(-30.0)) as i16;
+        let mut samples: Vec<i16> =
+            [&[loud; 200][..], &[quiet; 1_000]].concat().iter().flat_map(|&s| [s, s]).collect();
+        compressor.process(&mut samples);
+        let left: Vec<i16> = samples.iter().step_by(2).copied().collect();
+
+        // The onset overshoots while the envelope catches up, then settles at -16 dBFS
+        assert!(left[0] > left[50], "{} then {}", left[0], left[50]);
+        assert!(left[1..60].windows(2).all(|w| w[0] >= w[1]));
+        assert!((dbfs_of(left[199]) + 16.0).abs() < 0.1, "{}", dbfs_of(left[199]));
+        // After the drop the gain comes back slowly; the envelope passes the threshold
+        // about 220 frames in, and the quiet part is untouched from there on
+        assert!(left[201] < quiet, "{} against {quiet}", left[201]);
+        assert!(left[201..420].windows(2).all(|w| w[0] <= w[1]));
+        assert!(left[380] < quiet, "{} against {quiet}", left[380]);
+        assert!(left[430..].iter().all(|&s| s == quiet));
+    }
+
+    // Level change (dB) of a 10000-peak tone at `freq` through the bands, once settled
+    fn eq_gain_db(specs: &[&str], freq: f64) -> f64 {
+        let bands: Vec<EqBand> = specs.iter().map(|spec| parse_eq_band(spec).unwrap()).collect();
+        let mut eq = Equalizer::new(1, &bands, 48_000);
+        let mut tone = sine(48_000, 1, 2.0 * std::f64::consts::PI * freq / 48_000.0);
+        eq.process(&mut tone);
+        20.0 * (peak(&tone[24_000..]) as f64 / 10_000.0).log10()
+    }
+
+    #[test]
+    fn eq_bands_at_0_db_change_nothing() {
+        let input: Vec<i16> = sine(4_800, 2, 0.3)
+            .iter()
+            .zip(sine(4_800, 2, 0.0123))
+            .map(|(&a, b)| a / 2 + b / 2)
+            .collect();
+        let specs = [
+            "f=100,g=0",
+            "f=1000,g=0,q=4",
+            "f=200,g=0,type=lowshelf",
+            "f=8000,g=0,type=highshelf",
+        ];
+        let bands: Vec<EqBand> = specs.iter().map(|spec| parse_eq_band(spec).unwrap()).collect();
+        let mut eq = Equalizer::new(2, &bands, 48_000);
+        let mut output = input.clone();
+        eq.process(&mut output);
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn eq_peak_band_sets_the_gain_at_its_centre() {
+        for gain in [6.0, -6.0, 3.0, -12.0] {
+            let measured = eq_gain_db(&[&format!("f=1000,g={gain}")], 1_000.0);
+            assert!((measured - gain).abs() < 0.1, "{gain} dB band gave {measured:.2} dB");
+        }
+        // Far from the centre the tone is hardly touched
+        let measured = eq_gain_db(&["f=1000,g=6,q=2"], 100.0);
+        assert!(measured.abs() < 0.2, "{measured:.2} dB at 100 Hz");
+        // Bands add up
+        let measured = eq_gain_db(&["f=1000,g=3", "f=1000,g=3"], 1_000.0);
+        assert!((measured - 6.0).abs() < 0.1, "{measured:.2} dB");
+    }
+
+    #[test]
+    fn eq_shelves_set_the_gain_past_their_corner() {
+        let measured = eq_gain_db(&["f=5000,g=-6,type=lowshelf"], 100.0);
+        assert!((measured + 6.0).abs() < 0.1, "{measured:.2} dB");
+        let measured = eq_gain_db(&["f=500,g=6,type=highshelf"], 12_000.0);
+        assert!((measured - 6.0).abs() < 0.1, "{measured:.2} dB");
+        let measured = eq_gain_db(&["f=5000,g=6,type=highshelf"], 100.0);
+        assert!(measured.abs() < 0.1, "{measured:.2} dB below the corner");
+    }
+
+    // Settled peak of a 10000-peak `freq` tone resampled from `in_rate` to `out_rate`
+    fn resampled_peak(in_rate: u32, out_rate: u32, freq: f64) -> i32 {
+        let mut resampler = LinearResampler::new(1, in_rate, out_rate);
+        let tone = sine(in_rate as usize, 1, 2.0 * std::f64::consts::PI * freq / in_rate as f64);
+        let mut out = Vec::new();
+        for chunk in tone.chunks(480) {
+            resampler.process_into(chunk, &mut out);
+        }
+        peak(&out[out.len() / 2..])
+    }
+
+    #[test]
+    fn downsampling_removes_content_above_the_new_nyquist() {
+        // Without the filter these would fold back to 12 kHz and 12 kHz at close to full level
+        let folded = resampled_peak(96_000, 48_000, 36_000.0);
+        assert!(folded <= 3, "36 kHz at 96 -> 48 kHz: {folded}");
+        let folded = resampled_peak(48_000, 32_000, 20_000.0);
+        assert!(folded <= 100, "20 kHz at 48 -> 32 kHz: {folded}");
+    }
+
+    #[test]
+    fn downsampling_keeps_the_passband() {
+        for (in_rate, out_rate) in [(96_000, 48_000), (48_000, 32_000), (48_000, 44_100)] {
+            let kept = resampled_peak(in_rate, out_rate, 5_000.0);
+            assert!((9_700..=10_100).contains(&kept), "{in_rate} -> {out_rate}: {kept}");
+        }
+        // Upsampling has no filter to get in the way
+        assert!(LinearResampler::new(2, 44_100, 48_000).anti_alias.is_none());
+    }
+}
@@ -0,0 +1,41 @@
+//! Hand-written JSON output helpers (no JSON library is used).
+
+use crate::dsp::to_dbfs;
+
+// A JSON array of already formatted values
+pub(crate) fn json_list(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(", "))
+}
+
+// Quote and escape a string for JSON output.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// A peak (absolute i16) in dBFS for JSON; null for digital silence
+pub(crate) fn peak_json(peak_abs: i32) -> String {
+    dbfs_json(to_dbfs(peak_abs as f64))
+}
+
+// A level for JSON: null for digital silence
+pub(crate) fn dbfs_json(dbfs: f64) -> String {
+    if dbfs.is_finite() {
+        format!("{dbfs:.2}")
+    } else {
+        "null".to_string()
+    }
+}
@@ -0,0 +1,54 @@
+//! WASAPI loopback and microphone recording to MP3, WAV, FLAC or raw PCM, as used by
+//! the `ARec` command line tool.
+//!
+//! [`Recorder`] runs a recording with the same settings as `ARec record`
+//! ([`RecordArgs`]), writing the `--out` files and optionally a [`Sink`] of your own.
+//! [`list_devices`] and [`probe_device`] are the `list` and `probe` commands.
+
+// Where the messages of one recording go. Each recording has its own, handed to the
+// sinks and threads that report anything, so recordings in one process don't share it.
+#[derive(Clone, Copy, Debug, Default)]
+struct Console {
+    // `record --quiet`: info! drops the progress chatter
+    quiet: bool,
+    // `--out -` or a network stream: stdout carries the audio, so text goes to stderr
+    stdout_audio: bool,
+}
+
+// println! for recording messages, moved to stderr while stdout carries the audio.
+macro_rules! report {
+    ($console:expr, $($arg:tt)*) => {
+        if $console.stdout_audio {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+// report! for progress messages that --quiet suppresses.
+macro_rules! info {
+    ($console:expr, $($arg:tt)*) => {
+        if !$console.quiet {
+            report!($console, $($arg)*);
+        }
+    };
+}
+
+mod args;
+mod capture;
+mod checksum;
+mod commands;
+mod control;
+mod dsp;
+mod json;
+mod net;
+mod record;
+mod sinks;
+mod time;
+
+pub use args::{BenchArgs, ListArgs, ProbeArgs, RecordArgs, SelfTestArgs};
+pub use capture::{CaptureRead, CaptureSource, ReplaySource, StreamFormat};
+pub use commands::{bench_encoder, list_devices, probe_device, self_test};
+pub use record::{Recorder, RecordingHandle, SamplesCallback, SinkFactory, StopHandle};
+pub use sinks::{SegmentSpan, Sink};
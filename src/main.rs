@@ -1,58 +1,135 @@
 use anyhow::{anyhow, bail, Context, Result};
-use clap::{Parser, Subcommand};
-use crossbeam_channel::{bounded, Receiver};
-use shine_rs::{Mp3Encoder, Mp3EncoderConfig, StereoMode, SUPPORTED_BITRATES, SUPPORTED_SAMPLE_RATES};
+use arec::{
+    bench_encoder, list_devices, probe_device, self_test, BenchArgs, ListArgs, ProbeArgs,
+    RecordArgs, Recorder, SelfTestArgs, StopHandle,
+};
+use clap::{
+    parser::ValueSource, ArgAction, ArgMatches, Args, CommandFactory, FromArgMatches, Parser,
+    Subcommand,
+};
 use std::{
-    fs::File,
-    io::{self, Write},
+    ffi::{c_void, OsString},
+    fs::OpenOptions,
+    os::windows::io::AsRawHandle,
     path::Path,
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicPtr, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Instant,
 };
-use wasapi::{
-    initialize_mta, Device, DeviceEnumerator, Direction, SampleType, StreamMode,
-    WaveFormat,
+use wasapi::initialize_mta;
+use windows::{
+    core::{w, BOOL, PWSTR},
+    Win32::{
+        Foundation::{
+            ERROR_CALL_NOT_IMPLEMENTED, ERROR_FAILED_SERVICE_CONTROLLER_CONNECT,
+            ERROR_SERVICE_SPECIFIC_ERROR, HANDLE, NO_ERROR,
+        },
+        System::{
+            Console::{
+                SetConsoleCtrlHandler, SetStdHandle, CTRL_BREAK_EVENT, CTRL_CLOSE_EVENT,
+                CTRL_LOGOFF_EVENT, CTRL_SHUTDOWN_EVENT, STD_ERROR_HANDLE, STD_OUTPUT_HANDLE,
+            },
+            Services::{
+                RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+                SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_CONTROL_INTERROGATE,
+                SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP, SERVICE_RUNNING,
+                SERVICE_START_PENDING, SERVICE_STATUS, SERVICE_STATUS_CURRENT_STATE,
+                SERVICE_STATUS_HANDLE, SERVICE_STOPPED, SERVICE_STOP_PENDING,
+                SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+            },
+        },
+    },
 };
 
 #[derive(Parser, Debug)]
 #[command(name = "ARec")]
-#[command(about = "Record Windows speaker output (WASAPI loopback) to MP3 (pure Rust encoder).", long_about = None)]
+#[command(about = "Record Windows speaker output (WASAPI loopback) to MP3 (pure Rust encoder), WAV or FLAC.", long_about = None)]
 struct Cli {
     #[command(subcommand)]
     cmd: Command,
+
+    /// Log what the recorder does on stderr: -v steps, -vv decisions and drops,
+    /// -vvv every packet
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
 }
 
 #[derive(Subcommand, Debug)]
 enum Command {
-    /// List active playback (render) devices
-    List,
-
-    /// Record speaker output to an MP3 file
-    Record {
-        /// Output mp3 path
-        #[arg(short, long, default_value = "output.mp3")]
-        out: String,
-
-        /// Record duration seconds (0 = until Ctrl+C)
-        #[arg(short = 't', long, default_value_t = 10)]
-        seconds: u64,
-
-        /// Select device by substring match on friendly name (case-insensitive).
-        /// If omitted, uses default playback device.
-        #[arg(short, long)]
-        device: Option<String>,
-
-        /// MP3 bitrate in kbps (must be supported by shine_rs)
-        #[arg(short, long, default_value_t = 192)]
-        kbps: u32,
-
-        /// Force stereo output even if device has >2 channels (downmix).
-        #[arg(long, default_value_t = true)]
-        downmix_to_stereo: bool,
-    },
+    /// List active playback (render) or recording (capture) devices
+    List(ListArgs),
+
+    /// Record speaker output (or a microphone) to an MP3, WAV or FLAC file, or raw PCM
+    Record(Box<RecordArgs>),
+
+    /// Show a device's mix format and which capture and MP3 settings work with it
+    Probe(ProbeArgs),
+
+    /// Record one second from the default device into a temporary MP3 to check that
+    /// capture and encoding work
+    Selftest(SelfTestArgs),
+
+    /// Encode synthetic audio as fast as possible and report the throughput (no device used)
+    Bench(BenchArgs),
+
+    /// Work with settings files for `record --config`
+    #[command(subcommand)]
+    Config(ConfigCommand),
+
+    /// Record as a Windows service (or headless in the foreground) with the `record`
+    /// settings of a `--config` file, logging to a file
+    Service(ServiceArgs),
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Write a commented settings template
+    Init(ConfigInitArgs),
+}
+
+#[derive(Args, Debug)]
+struct ConfigInitArgs {
+    /// Where to write the template
+    #[arg(default_value = "arec.toml")]
+    path: String,
+
+    /// Replace the file if it already exists
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(Args, Debug)]
+struct ServiceArgs {
+    /// Settings file with the `record` options (see `config init`); relative paths in it
+    /// are taken from its folder
+    #[arg(long, value_name = "FILE")]
+    config: String,
+
+    /// Where to append the log and the summaries [default: arec-service.log next to the
+    /// settings file]
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<String>,
+
+    /// Control pipe that takes stop and status (and the other commands), unless the
+    /// settings name one
+    #[arg(long, value_name = "NAME", default_value = "arec")]
+    control_pipe: String,
 }
 
 fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let cli = parse_cli()?;
+    init_logging(cli.verbose);
+
+    // These don't touch audio devices, so they run before COM is set up
+    match &cli.cmd {
+        Command::Config(ConfigCommand::Init(args)) => return write_config_template(args),
+        Command::Bench(args) => return bench_encoder(args),
+        // The recording thread initializes COM for itself
+        Command::Service(args) => return run_service(args, cli.verbose),
+        _ => {}
+    }
 
     // WASAPI requires COM; don't do this on a UI thread.
     // wasapi::initialize_mta returns an HRESULT, not a Result.
@@ -62,397 +139,843 @@ fn main() -> Result<()> {
     }
 
     match cli.cmd {
-        Command::List => list_devices(),
-        Command::Record {
-            out,
-            seconds,
-            device,
-            kbps,
-            downmix_to_stereo,
-        } => record_loopback_to_mp3(&out, seconds, device.as_deref(), kbps, downmix_to_stereo),
+        Command::List(args) => list_devices(&args),
+        Command::Probe(args) => probe_device(&args),
+        Command::Selftest(args) => self_test(&args),
+        Command::Record(args) => record_in_console(*args),
+        Command::Config(_) | Command::Bench(_) | Command::Service(_) => Ok(()),
     }
 }
 
-fn list_devices() -> Result<()> {
-    let enumerator = DeviceEnumerator::new()?;
-
-    let default = enumerator.get_default_device(&Direction::Render)?;
-    let default_id = default.get_id().unwrap_or_default();
-
-    println!("Default render device (will be recorded if you don't pass --device):");
-    println!(
-        "  {}",
-        default
-            .get_friendlyname()
-            .unwrap_or_else(|_| "<unknown>".to_string())
-    );
-    println!("  id: {default_id}");
-    println!();
-
-    let collection = enumerator.get_device_collection(&Direction::Render)?;
-    println!("Render (playback) devices:");
-    let count = collection.get_nbr_devices()?;
-    for i in 0..count {
-        let dev = collection.get_device_at_index(i)?;
-        let name = dev.get_friendlyname().unwrap_or_else(|_| "<unknown>".to_string());
-        let id = dev.get_id().unwrap_or_else(|_| "<unknown>".to_string());
-
-        let mark = if id == default_id { "*" } else { " " };
-        println!("  {mark}[{i}] {name}");
-        println!("       id: {id}");
-    }
-
-    Ok(())
+// Record until the recording ends by itself, Ctrl+C, or a console event
+fn record_in_console(args: RecordArgs) -> Result<()> {
+    let recording = Recorder::new(args).start()?;
+    let stop = recording.stop_handle();
+    ctrlc::set_handler(move || stop.stop()).context("failed to set Ctrl+C handler")?;
+    let _ = CONSOLE_STOP.set(recording.stop_handle());
+    // Registered after ctrlc, so it is asked first and passes Ctrl+C on
+    unsafe { SetConsoleCtrlHandler(Some(console_handler), true) }
+        .context("failed to set the console control handler")?;
+    recording.wait()
 }
 
+// The recording that Ctrl+Break or closing the console stops
+static CONSOLE_STOP: OnceLock<StopHandle> = OnceLock::new();
 
-
-
-fn record_loopback_to_mp3(
-    out_path: &str,
-    seconds: u64,
-    device_substring: Option<&str>,
-    kbps: u32,
-    downmix_to_stereo: bool,
-) -> Result<()> {
-    // Validate requested bitrate vs shine_rs supported list
-    if !SUPPORTED_BITRATES.contains(&kbps) {
-        bail!(
-            "Unsupported bitrate {kbps} kbps for shine_rs. Supported: {:?}",
-            SUPPORTED_BITRATES
-        );
-    }
-
-    let enumerator = DeviceEnumerator::new()?;
-    let device = select_render_device(&enumerator, device_substring)?;
-
-    let device_name = device
-        .get_friendlyname()
-        .unwrap_or_else(|_| "<unknown>".to_string());
-    println!("Using device: {device_name}");
-
-    // Activate AudioClient on the chosen render device.
-    // wasapi 0.22: get_iaudioclient (not get_audioclient).
-    let mut audio_client = device.get_iaudioclient()?;
-
-    // For loopback, the device mix format is always valid in shared mode.
-    let mix = audio_client.get_mixformat()?;
-
-    let mix_rate = mix.get_samplespersec() as usize;
-    let mix_channels = mix.get_nchannels() as usize;
-
-
-    // shine_rs only supports certain sample rates; make sure mix_rate is supported.
-    if !SUPPORTED_SAMPLE_RATES.contains(&(mix_rate as u32)) {
-        bail!(
-            "Device mix sample rate {mix_rate} Hz not supported by shine_rs. Supported: {:?}",
-            SUPPORTED_SAMPLE_RATES
-        );
+// Ctrl+Break stops the recording like Ctrl+C. Closing the console window, logging off or
+// shutting down ends the process as soon as the handler returns, so after asking the
+// recording to stop it never returns: main finishes the files and exits the process
+// (Windows allows about 5 seconds before it ends the process anyway).
+unsafe extern "system" fn console_handler(event: u32) -> BOOL {
+    let Some(stop) = CONSOLE_STOP.get() else {
+        return false.into();
+    };
+    match event {
+        CTRL_BREAK_EVENT => {
+            stop.stop();
+            true.into()
+        }
+        CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT => {
+            stop.stop();
+            loop {
+                std::thread::park();
+            }
+        }
+        // Ctrl+C goes on to the ctrlc handler
+        _ => false.into(),
     }
+}
 
-    // We'll capture as 16-bit PCM interleaved to feed the MP3 encoder.
-    // Keep the sample rate the same; optionally downmix to stereo in software.
-    let target_channels = if downmix_to_stereo { 2 } else { mix_channels.min(2) };
-    let desired = WaveFormat::new(
-        16,               // storebits
-        16,               // validbits
-        &SampleType::Int, // i16
-        mix_rate,
-        mix_channels, // capture in device channel count; we can downmix later
-        None,
-    );
-
-    // Shared, event-driven. Autoconvert lets the audio engine convert from endpoint format if needed.
-    let buffer_duration_hns = 200_000; // 20ms
-    let mode = StreamMode::EventsShared {
-        autoconvert: true,
-        buffer_duration_hns,
+// `service`: record with the settings file's options, logging to a file, until the
+// recording ends by itself, the control pipe says `stop`, or the service is stopped.
+// Started by the service control manager it reports its state there; started any other
+// way it records in the foreground as `record` does.
+fn run_service(args: &ServiceArgs, verbose: u8) -> Result<()> {
+    let config = std::path::absolute(&args.config)
+        .with_context(|| format!("bad --config path {}", args.config))?;
+    let folder = config.parent().map(Path::to_path_buf).unwrap_or_default();
+    let log_path = match &args.log_file {
+        Some(path) => std::path::absolute(path)
+            .with_context(|| format!("bad --log-file path {path}"))?,
+        None => folder.join("arec-service.log"),
     };
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .with_context(|| format!("failed to open the log file {}", log_path.display()))?;
+    // A service has no console: stdout and stderr (progress, warnings, the summary and the
+    // log) all go to the file from here on, which stays open until the process exits
+    let handle = HANDLE(log.as_raw_handle());
+    for std_handle in [STD_OUTPUT_HANDLE, STD_ERROR_HANDLE] {
+        unsafe { SetStdHandle(std_handle, handle) }
+            .context("failed to send output to the log file")?;
+    }
+    std::mem::forget(log);
+    init_logging(verbose.max(1));
+    log::info!("Service starting with {}", config.display());
+
+    let record = service_record_args(&config, &args.control_pipe)?;
+    // Services start in System32; --out, --out-dir and the like are meant from the file
+    std::env::set_current_dir(&folder)
+        .with_context(|| format!("failed to change to {}", folder.display()))?;
+    *SERVICE_RECORD.lock().unwrap() = Some(record);
+
+    // With SERVICE_WIN32_OWN_PROCESS the name isn't checked, so any installed name works
+    let mut name: Vec<u16> = "ARec".encode_utf16().chain(Some(0)).collect();
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: PWSTR(name.as_mut_ptr()),
+            lpServiceProc: Some(service_main),
+        },
+        SERVICE_TABLE_ENTRYW::default(),
+    ];
+    // Returns once service_main has reported SERVICE_STOPPED
+    match unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } {
+        Ok(()) => SERVICE_RESULT.lock().unwrap().take().unwrap_or(Ok(())),
+        Err(e) if e.code() == ERROR_FAILED_SERVICE_CONTROLLER_CONNECT.to_hresult() => {
+            log::info!("Not started as a service; recording in the foreground");
+            let record = SERVICE_RECORD.lock().unwrap().take();
+            record_in_console(record.expect("service settings are stored before dispatch"))
+        }
+        Err(e) => Err(e).context("StartServiceCtrlDispatcherW failed"),
+    }
+}
 
-    // Loopback capture: initialize a CAPTURE stream on a RENDER endpoint.
-    audio_client
-        .initialize_client(&desired, &Direction::Capture, &mode)
-        .context("initialize_client (loopback) failed")?;
-
-    let capture = audio_client.get_audiocaptureclient()?;
-    let h_event = audio_client.set_get_eventhandle()?;
-
-    // Prepare MP3 encoder
-    let stereo_mode = if target_channels == 1 {
-        StereoMode::Mono
-    } else {
-        StereoMode::Stereo
+// The `record` options for `service`: the settings file and AREC_* variables as for
+// `record --config`, with the console's space bar and progress output off, and the
+// control pipe on unless the settings name one.
+fn service_record_args(config: &Path, control_pipe: &str) -> Result<RecordArgs> {
+    let mut argv: Vec<OsString> = ["ARec", "record", "--quiet", "--no-pause-key", "--config"]
+        .into_iter()
+        .map(OsString::from)
+        .chain(Some(config.into()))
+        .collect();
+    let mut cmd = Cli::command();
+    let mut matches = parse_settings(&mut cmd, argv.clone())?;
+    if let Some(("record", record)) = matches.subcommand()
+        && record.get_one::<String>("control_pipe").is_none()
+    {
+        argv.push(format!("--control-pipe={control_pipe}").into());
+        matches = parse_settings(&mut cmd, argv)?;
+    }
+    let Some(("record", record)) = matches.subcommand() else {
+        unreachable!("the arguments start with record");
     };
+    RecordArgs::from_arg_matches(record).map_err(|e| anyhow!(clap_message(&e)))
+}
 
-    // shine-rs 0.1.3 fields: sample_rate, bitrate, channels, stereo_mode, ...
-    let enc_cfg = Mp3EncoderConfig {
-        sample_rate: mix_rate as u32,
-        bitrate: kbps, // kbps
-        channels: target_channels as u8,
-        stereo_mode,
-        ..Default::default()
+// State shared by run_service, service_main and service_control, which Windows calls
+// without a context of ours
+static SERVICE_RECORD: Mutex<Option<RecordArgs>> = Mutex::new(None);
+static SERVICE_RESULT: Mutex<Option<Result<()>>> = Mutex::new(None);
+static SERVICE_STATUS: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+static SERVICE_STOP: OnceLock<StopHandle> = OnceLock::new();
+
+// The service's main function, on a thread of the service control dispatcher
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut PWSTR) {
+    let registered =
+        unsafe { RegisterServiceCtrlHandlerExW(w!("ARec"), Some(service_control), None) };
+    let status = match registered {
+        Ok(status) => status,
+        Err(e) => {
+            let e = anyhow!(e).context("RegisterServiceCtrlHandlerExW failed");
+            *SERVICE_RESULT.lock().unwrap() = Some(Err(e));
+            return;
+        }
     };
+    SERVICE_STATUS.store(status.0, Ordering::Relaxed);
+    set_service_state(SERVICE_START_PENDING, false);
+    let record = SERVICE_RECORD.lock().unwrap().take();
+    let record = record.expect("service settings are stored before dispatch");
+    let result = Recorder::new(record).start().and_then(|recording| {
+        let _ = SERVICE_STOP.set(recording.stop_handle());
+        set_service_state(SERVICE_RUNNING, false);
+        recording.wait()
+    });
+    let failed = result.is_err();
+    *SERVICE_RESULT.lock().unwrap() = Some(result);
+    log::info!("Service stopped");
+    set_service_state(SERVICE_STOPPED, failed);
+}
 
-    let mut encoder = Mp3Encoder::new(enc_cfg).map_err(|e| anyhow!("mp3 encoder init: {e:?}"))?;
-
-    let mut out = File::create(out_path).with_context(|| format!("create {out_path}"))?;
-
-    // Ctrl+C handling
-    let (stop_tx, stop_rx) = bounded::<()>(1);
-    ctrlc::set_handler(move || {
-        let _ = stop_tx.try_send(());
-    })
-    .context("failed to set Ctrl+C handler")?;
+// Stop and shutdown requests end the recording as Ctrl+C would; service_main reports
+// SERVICE_STOPPED once the files are finished
+unsafe extern "system" fn service_control(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut c_void,
+    _context: *mut c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            log::info!("Service stop requested");
+            set_service_state(SERVICE_STOP_PENDING, false);
+            if let Some(stop) = SERVICE_STOP.get() {
+                stop.stop();
+            }
+            NO_ERROR.0
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR.0,
+        _ => ERROR_CALL_NOT_IMPLEMENTED.0,
+    }
+}
 
-    println!(
-        "Recording started. Output: {out_path} | bitrate: {kbps} kbps | Ctrl+C to stop{}",
-        if seconds == 0 { "" } else { "" }
-    );
+fn set_service_state(state: SERVICE_STATUS_CURRENT_STATE, failed: bool) {
+    let pending = state == SERVICE_START_PENDING || state == SERVICE_STOP_PENDING;
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        // Stop is only taken once the StopHandle is stored
+        dwControlsAccepted: if state == SERVICE_RUNNING {
+            SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+        } else {
+            0
+        },
+        dwWin32ExitCode: if failed { ERROR_SERVICE_SPECIFIC_ERROR.0 } else { NO_ERROR.0 },
+        dwServiceSpecificExitCode: u32::from(failed),
+        dwCheckPoint: 0,
+        // Finishing the files (or normalizing them) can take a while after a stop
+        dwWaitHint: if pending { 30_000 } else { 0 },
+    };
+    let handle = SERVICE_STATUS_HANDLE(SERVICE_STATUS.load(Ordering::Relaxed));
+    if let Err(e) = unsafe { SetServiceStatus(handle, &status) } {
+        log::warn!("SetServiceStatus failed: {e}");
+    }
+}
 
+// -v/-vv/-vvv logging: ARec's own messages and those of the wasapi crate, with the time
+// since start. Without -v nothing is logged.
+struct StderrLogger {
+    start: Instant,
+}
 
-    audio_client.start_stream()?;
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
 
-    let start = Instant::now();
-    let mut last_ui = Instant::now();
-    let mut stop_reason = "completed";
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "[{:>8.3} {:<5} {}] {}",
+                self.start.elapsed().as_secs_f64(),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
 
+    fn flush(&self) {}
+}
 
-    // Reusable buffers to avoid per-packet allocations (important for long recordings)
-    let bytes_per_sample = 2usize; // i16
-    let bytes_per_frame = mix_channels * bytes_per_sample;
+fn init_logging(verbose: u8) {
+    let level = match verbose {
+        0 => return,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    let logger = StderrLogger {
+        start: Instant::now(),
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(level);
+    }
+}
 
-    // Raw bytes read from WASAPI
-    let mut raw_buf: Vec<u8> = Vec::with_capacity(bytes_per_frame * 4096);
+// Parse the command line. For `record`, options the command line doesn't set (or conflict
+// with) come from AREC_* environment variables, then from a `--config <file>`: their
+// values are appended as `--key=value` and everything is parsed again, so they go through
+// the normal checks. Appended variables count as given on the command line when the file
+// is merged, which is what puts them ahead of it.
+fn parse_cli() -> Result<Cli> {
+    let mut cmd = Cli::command();
+    let matches = parse_settings(&mut cmd, std::env::args_os().collect())?;
+    Ok(Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
+}
 
-    // Decoded i16 samples (mix_channels interleaved)
-    let mut pcm_buf: Vec<i16> = Vec::with_capacity(mix_channels * 4096);
+// Parse `argv`, and for `record` fill in the options it doesn't set from AREC_* variables
+// and then the --config file
+fn parse_settings(cmd: &mut clap::Command, argv: Vec<OsString>) -> Result<ArgMatches> {
+    let mut matches = cmd
+        .try_get_matches_from_mut(&argv)
+        .unwrap_or_else(|e| e.exit());
+    let Some(("record", record_matches)) = matches.subcommand() else {
+        return Ok(matches);
+    };
+    let record = cmd
+        .find_subcommand("record")
+        .expect("record subcommand is defined");
+    let from_env = env_args(record, record_matches)?;
+    let mut full = argv;
+    if !from_env.args.is_empty() {
+        full.extend(from_env.args.iter().map(|(arg, _)| arg.into()));
+        matches = cmd
+            .try_get_matches_from_mut(&full)
+            .map_err(|e| explain_env(&e, &from_env.args))?;
+    }
+    if let Some(("record", record_matches)) = matches.subcommand()
+        && let Some(path) = record_matches.get_one::<String>("config")
+    {
+        let settings = Settings::load(path)?;
+        let record = cmd
+            .find_subcommand("record")
+            .expect("record subcommand is defined");
+        let extra = settings.to_args(record, record_matches, &from_env.ids)?;
+        full.extend(extra.iter().map(|(arg, _)| arg.into()));
+        matches = cmd
+            .try_get_matches_from_mut(full)
+            .map_err(|e| settings.explain(&e, &extra))?;
+    }
+    Ok(matches)
+}
 
-    // Final samples given to encoder (target_channels interleaved)
-    let mut enc_buf: Vec<i16> = Vec::with_capacity(target_channels * 4096);
+// What env_args found in the AREC_* variables
+struct EnvArgs {
+    // `--long=value` arguments, each paired with its variable's name
+    args: Vec<(String, String)>,
+    // Ids of the options the environment sets, including switches it turns off
+    ids: Vec<String>,
+}
 
-    // Main loop (Outer Loop)
-    loop {
-        if seconds != 0 && start.elapsed() >= Duration::from_secs(seconds) {
-            stop_reason = "time limit reached";
-            break;
+// `record` arguments from the environment: AREC_ plus the option's long name in capitals
+// with `_` for `-` (AREC_KBPS, AREC_OUT, AREC_SPLIT_SECONDS), each paired with the
+// variable's name. Options the command line sets, or conflicts with, are left alone, as
+// are empty variables. Switches take 1/true/yes or 0/false/no; one turned off adds no
+// argument, but the --config file can't turn it on either.
+fn env_args(record: &clap::Command, matches: &ArgMatches) -> Result<EnvArgs> {
+    let on_cli = |arg: &clap::Arg| {
+        matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+    };
+    let mut out = Vec::new();
+    let mut ids = Vec::new();
+    for arg in record.get_arguments() {
+        let Some(long) = arg.get_long().filter(|&long| !matches!(long, "help" | "verbose"))
+        else {
+            continue;
+        };
+        let name = format!("AREC_{}", long.to_ascii_uppercase().replace('-', "_"));
+        let Some(value) = std::env::var_os(&name).filter(|value| !value.is_empty()) else {
+            continue;
+        };
+        let Some(value) = value.to_str() else {
+            bail!("{name} is not valid Unicode");
+        };
+        if on_cli(arg) || record.get_arg_conflicts_with(arg).into_iter().any(on_cli) {
+            continue;
         }
-        if stop_requested(&stop_rx) {
-            stop_reason = "interrupted (Ctrl+C)";
-            break;
+        ids.push(arg.get_id().to_string());
+        if matches!(arg.get_action(), ArgAction::SetTrue) {
+            match value.to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" => out.push((format!("--{long}"), name)),
+                "0" | "false" | "no" => {}
+                _ => bail!("{name} is a switch; use 1 or 0 (got `{value}`)"),
+            }
+        } else {
+            out.push((format!("--{long}={value}"), name));
         }
+    }
+    Ok(EnvArgs { args: out, ids })
+}
 
-        // Update UI once per second
-        if last_ui.elapsed() >= Duration::from_secs(1) {
-            let elapsed = start.elapsed().as_secs();
-            if seconds == 0 {
-                print_status_line(&format!("Recording... elapsed {elapsed}s (Ctrl+C to stop)"))?;
-            } else {
-                let remaining = seconds.saturating_sub(elapsed);
-                print_status_line(&format!(
-                    "Recording... remaining {remaining}s / total {seconds}s (Ctrl+C to stop)"
-                ))?;
-            }
-            last_ui = Instant::now();
+// Turn a parse error caused by an environment value into one that names the variable
+fn explain_env(err: &clap::Error, from_env: &[(String, String)]) -> anyhow::Error {
+    let message = clap_message(err);
+    let culprit = match err.get(clap::error::ContextKind::InvalidArg) {
+        Some(clap::error::ContextValue::String(arg)) => {
+            let long = arg.split([' ', '=']).next().unwrap_or_default();
+            from_env.iter().find(|(a, _)| a.split('=').next() == Some(long))
         }
+        _ => None,
+    };
+    match culprit {
+        Some((_, name)) => anyhow!("{name}: {message}"),
+        None => anyhow!("in the AREC_* environment variables: {message}"),
+    }
+}
 
-        // Wait for event that indicates data is available
-        h_event.wait_for_event(1000)?; // timeout ms
+// A `--config` file: `key = value` lines, where each key is the long name of a `record`
+// option. This is the subset of TOML that flat settings need: strings ("basic" with
+// escapes, or 'literal'), integers, floats, booleans, one-line arrays, and # comments.
+struct Settings {
+    path: String,
+    entries: Vec<Setting>,
+}
 
-        // Drain all available packets
-        loop {
-            let next = capture.get_next_packet_size()?;
-            let Some(frames_available) = next else { break; };
-            if frames_available == 0 {
-                break;
-            }
+struct Setting {
+    line: usize,
+    key: String,
+    value: SettingValue,
+}
 
-            let needed = frames_available as usize * bytes_per_frame;
+#[derive(Debug, PartialEq)]
+enum SettingValue {
+    Bool(bool),
+    // Strings and numbers, as they will be passed to the option
+    Text(String),
+    List(Vec<SettingValue>),
+}
 
-            // Ensure raw_buf is large enough, then read into it (no new allocation each time)
-            if raw_buf.capacity() < needed {
-                raw_buf.reserve(needed - raw_buf.capacity());
+impl Settings {
+    fn load(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {path}"))?;
+        let mut entries: Vec<Setting> = Vec::new();
+        for (i, raw) in text.lines().enumerate() {
+            let line = i + 1;
+            let trimmed = raw.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
             }
-            raw_buf.clear();
-            raw_buf.resize(needed, 0u8);
-
-            let (frames_read, _info) = capture
-                .read_from_device(&mut raw_buf)
-                .context("read_from_device failed")?;
-
-            if frames_read == 0 {
-                break;
+            if trimmed.starts_with('[') {
+                bail!(
+                    "{path}:{line}: tables are not supported; put every setting at the top level"
+                );
             }
-
-            let used_bytes = frames_read as usize * bytes_per_frame;
-
-            // Decode bytes -> i16 into pcm_buf (reuse)
-            let sample_count = frames_read as usize * mix_channels;
-            if pcm_buf.capacity() < sample_count {
-                pcm_buf.reserve(sample_count - pcm_buf.capacity());
+            let Some((key, value)) = trimmed.split_once('=') else {
+                bail!("{path}:{line}: expected `key = value`");
+            };
+            let key = key.trim();
+            if key.is_empty()
+                || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                bail!("{path}:{line}: invalid key `{key}`");
             }
-            pcm_buf.clear();
-
-            for chunk in raw_buf[..used_bytes].chunks_exact(2) {
-                pcm_buf.push(i16::from_le_bytes([chunk[0], chunk[1]]));
+            let value = parse_setting_value(value)
+                .map_err(|e| anyhow!("{path}:{line}: `{key}`: {e}"))?;
+            if let Some(first) = entries.iter().find(|e| e.key == key) {
+                bail!("{path}:{line}: `{key}` is already set on line {}", first.line);
             }
+            entries.push(Setting {
+                line,
+                key: key.to_string(),
+                value,
+            });
+        }
+        Ok(Self {
+            path: path.to_string(),
+            entries,
+        })
+    }
 
-            // Prepare encoder input into enc_buf (reuse)
-            enc_buf.clear();
-
-            if mix_channels == target_channels {
-                // Fast path: no downmix, just copy
-                enc_buf.extend_from_slice(&pcm_buf);
-            } else if downmix_to_stereo && target_channels == 2 {
-                // Downmix into enc_buf without allocating a new Vec each time
-                downmix_n_to_stereo_into(&pcm_buf, mix_channels, &mut enc_buf);
-            } else if target_channels == 1 {
-                downmix_n_to_mono_into(&pcm_buf, mix_channels, &mut enc_buf);
-            } else {
-                take_first_two_channels_into(&pcm_buf, mix_channels, &mut enc_buf);
+    // Command-line arguments for the entries the command line and the environment (`env_ids`)
+    // leave open, each paired with the index of its entry (for error messages).
+    fn to_args(
+        &self,
+        record: &clap::Command,
+        matches: &ArgMatches,
+        env_ids: &[String],
+    ) -> Result<Vec<(String, usize)>> {
+        let on_cli = |arg: &clap::Arg| {
+            matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+        };
+        let mut out = Vec::new();
+        for (index, entry) in self.entries.iter().enumerate() {
+            let long = entry.key.replace('_', "-");
+            let arg = record.get_arguments().find(|a| {
+                let named = a.get_long() == Some(long.as_str())
+                    || a.get_all_aliases().is_some_and(|aliases| aliases.contains(&long.as_str()));
+                named && !matches!(long.as_str(), "config" | "help" | "verbose")
+            });
+            let Some(arg) = arg else {
+                bail!(
+                    "{}:{}: unknown key `{}` (keys are `record` option names, such as `kbps`)",
+                    self.path,
+                    entry.line,
+                    entry.key
+                );
+            };
+            // An alias such as `seconds` is passed on under the option's own name
+            let long = arg.get_long().unwrap_or(&long).to_string();
+            let from_env = env_ids.iter().any(|id| id == arg.get_id().as_str());
+            if from_env
+                || on_cli(arg)
+                || record.get_arg_conflicts_with(arg).into_iter().any(on_cli)
+            {
+                continue;
             }
-
-            // Encode MP3
-            let chunks = encoder
-                .encode_interleaved(&enc_buf)
-                .map_err(|e| anyhow!("encode error: {e:?}"))?;
-
-            for c in chunks {
-                out.write_all(&c)?;
+            let is_flag = matches!(arg.get_action(), ArgAction::SetTrue);
+            let values = match &entry.value {
+                SettingValue::List(items) => items.iter().collect(),
+                value => vec![value],
+            };
+            let (path, line, key) = (&self.path, entry.line, &entry.key);
+            for value in values {
+                match value {
+                    SettingValue::Bool(true) if is_flag => out.push((format!("--{long}"), index)),
+                    SettingValue::Bool(false) if is_flag => {}
+                    _ if is_flag => bail!("{path}:{line}: `{key}` is a switch; use true or false"),
+                    SettingValue::Bool(b) => out.push((format!("--{long}={b}"), index)),
+                    SettingValue::Text(text) => out.push((format!("--{long}={text}"), index)),
+                    SettingValue::List(_) => {
+                        bail!("{path}:{line}: `{key}`: nested arrays are not supported")
+                    }
+                }
             }
         }
-
+        Ok(out)
     }
 
-    audio_client.stop_stream()?;
-
-    // Flush encoder tail
-    let tail = encoder.finish().map_err(|e| anyhow!("finish error: {e:?}"))?;
-    out.write_all(&tail)?;
-    out.flush()?;
-
-    // Clear the live status line and print a newline
-    print_status_line("")?;
-    println!();
-
-    let recorded_secs = start.elapsed().as_secs().max(1); // avoid div-by-zero
-    let size_bytes = std::fs::metadata(Path::new(out_path))
-        .map(|m| m.len())
-        .unwrap_or(0);
-
-    let avg_kbps = (size_bytes as f64 * 8.0 / recorded_secs as f64) / 1000.0;
-
-    println!("Stop reason: {stop_reason}");
-    println!("Recorded: {recorded_secs} s");
-    println!("File: {out_path}");
-    println!("Size: {}", human_bytes(size_bytes));
-    println!("Average bitrate (approx): {:.1} kbps", avg_kbps);
-
-
-    println!("Saved: {out_path}");
-    Ok(())
+    // Turn a parse error caused by a file value into one that names the file, line and key
+    fn explain(&self, err: &clap::Error, extra: &[(String, usize)]) -> anyhow::Error {
+        let message = clap_message(err);
+        let culprit = match err.get(clap::error::ContextKind::InvalidArg) {
+            Some(clap::error::ContextValue::String(arg)) => {
+                let long = arg.split([' ', '=']).next().unwrap_or_default();
+                extra
+                    .iter()
+                    .find(|(a, _)| a.split('=').next() == Some(long))
+                    .map(|&(_, index)| &self.entries[index])
+            }
+            _ => None,
+        };
+        match culprit {
+            Some(entry) => anyhow!("{}:{}: `{}`: {message}", self.path, entry.line, entry.key),
+            None => anyhow!("{}: {message}", self.path),
+        }
+    }
 }
 
-fn stop_requested(rx: &Receiver<()>) -> bool {
-    rx.try_recv().is_ok()
+// clap's message on one line, without the usage and help hint that follow the first blank
+// line
+fn clap_message(err: &clap::Error) -> String {
+    let rendered = err.render().to_string();
+    let message: Vec<&str> = rendered
+        .lines()
+        .take_while(|l| !l.trim().is_empty())
+        .map(str::trim)
+        .collect();
+    let message = message.join(" ");
+    message.strip_prefix("error: ").unwrap_or(&message).to_string()
 }
 
-fn select_render_device(enumerator: &DeviceEnumerator, needle: Option<&str>) -> Result<Device> {
-    if let Some(needle) = needle {
-        let needle = needle.to_lowercase();
-        let collection = enumerator.get_device_collection(&Direction::Render)?;
-        let count = collection.get_nbr_devices()?;
-        for i in 0..count {
-            let dev = collection.get_device_at_index(i)?;
-            let name = dev.get_friendlyname().unwrap_or_default().to_lowercase();
-            if name.contains(&needle) {
-                return Ok(dev);
-            }
-        }
-        bail!("No render device matched substring: {needle}");
+fn parse_setting_value(text: &str) -> Result<SettingValue, String> {
+    let mut chars = text.trim_start().chars().peekable();
+    let value = parse_setting_item(&mut chars)?;
+    let rest: String = chars.collect();
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(format!("unexpected `{rest}` after the value"));
     }
-
-    Ok(enumerator.get_default_device(&Direction::Render)?)
+    Ok(value)
 }
 
-fn downmix_n_to_stereo_into(input: &[i16], channels: usize, out: &mut Vec<i16>) {
-    let frames = input.len() / channels;
-    out.reserve(frames * 2);
-
-    for f in 0..frames {
-        let base = f * channels;
-        let mut l_acc: i32 = 0;
-        let mut r_acc: i32 = 0;
-        let mut l_n: i32 = 0;
-        let mut r_n: i32 = 0;
-
-        for ch in 0..channels {
-            let s = input[base + ch] as i32;
-            if ch % 2 == 0 {
-                l_acc += s;
-                l_n += 1;
-            } else {
-                r_acc += s;
-                r_n += 1;
+fn parse_setting_item(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+) -> Result<SettingValue, String> {
+    match chars.peek().copied() {
+        None => Err("missing value".to_string()),
+        Some('"') => {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    None => return Err("unterminated string".to_string()),
+                    Some('"') => return Ok(SettingValue::Text(s)),
+                    Some('\\') => match chars.next() {
+                        Some('"') => s.push('"'),
+                        Some('\\') => s.push('\\'),
+                        Some('n') => s.push('\n'),
+                        Some('t') => s.push('\t'),
+                        Some('r') => s.push('\r'),
+                        Some('u') => {
+                            let hex: String = chars.by_ref().take(4).collect();
+                            let c = u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| format!("invalid escape \\u{hex}"))?;
+                            s.push(c);
+                        }
+                        Some(c) => return Err(format!("invalid escape \\{c}")),
+                        None => return Err("unterminated string".to_string()),
+                    },
+                    Some(c) => s.push(c),
+                }
+            }
+        }
+        Some('\'') => {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    None => return Err("unterminated string".to_string()),
+                    Some('\'') => return Ok(SettingValue::Text(s)),
+                    Some(c) => s.push(c),
+                }
+            }
+        }
+        Some('[') => {
+            chars.next();
+            let mut items = Vec::new();
+            loop {
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                if chars.next_if_eq(&']').is_some() {
+                    return Ok(SettingValue::List(items));
+                }
+                items.push(parse_setting_item(chars)?);
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                match chars.next() {
+                    Some(',') => {}
+                    Some(']') => return Ok(SettingValue::List(items)),
+                    _ => return Err("expected `,` or `]` in array".to_string()),
+                }
+            }
+        }
+        Some(_) => {
+            let mut word = String::new();
+            while let Some(c) =
+                chars.next_if(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.' | '_'))
+            {
+                word.push(c);
+            }
+            match word.as_str() {
+                "true" => Ok(SettingValue::Bool(true)),
+                "false" => Ok(SettingValue::Bool(false)),
+                _ => {
+                    // Integers and floats; TOML allows `_` between digits
+                    let number = word.replace('_', "");
+                    if number.parse::<f64>().is_ok_and(f64::is_finite) {
+                        Ok(SettingValue::Text(number))
+                    } else if word.is_empty() {
+                        Err("expected a string, number, boolean or array".to_string())
+                    } else {
+                        Err(format!(
+                            "`{word}` is not a string, number or boolean (strings need quotes)"
+                        ))
+                    }
+                }
             }
         }
-
-        let l = (l_acc / l_n.max(1)).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
-        let r = (r_acc / r_n.max(1)).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
-
-        out.push(l);
-        out.push(r);
     }
 }
 
-fn downmix_n_to_mono_into(input: &[i16], channels: usize, out: &mut Vec<i16>) {
-    let frames = input.len() / channels;
-    out.reserve(frames);
-
-    for f in 0..frames {
-        let base = f * channels;
-        let mut acc: i32 = 0;
-        for ch in 0..channels {
-            acc += input[base + ch] as i32;
-        }
-        let m = (acc / channels as i32).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
-        out.push(m);
+const CONFIG_TEMPLATE: &str = r#"# ARec settings. Use with: ARec record --config <this file>
+#
+# Each key is the long name of a `record` option (see `ARec record --help`), with
+# `-` or `_` between words. Options given on the command line win over this file, and
+# so do AREC_* environment variables (AREC_KBPS=192 for `kbps`).
+# Remove the leading `#` to use a setting.
+
+# Capture: "loopback" (speaker output) or "mic"
+# source = "loopback"
+
+# Device: substring of the friendly name (or use device_index / device_id)
+# device = "Headphones"
+# or choose it from a menu each time
+# pick = true
+# Without a device: the default "console", "multimedia" or "communications" device
+# role = "communications"
+# Or only one program's audio, by name or PID (Windows 10 2004 or later)
+# process = "firefox"
+# or everything but one program
+# exclude_process = "Discord"
+# Stop when this program exits
+# until_process_exits = "game.exe"
+# Milliseconds of audio to discard when capture starts (default 50; 0 keeps it all)
+# skip_warmup = 200
+# Longest wait for capture data before the loop checks the stop conditions again
+# event_timeout_ms = 1000
+
+# Output path; may contain {date}, {time}, {device} and {n}
+# out = "rec-{date}-{time}.mp3"
+# Or a folder for the files, named {device}-{date}-{time}.<ext> without out
+# out_dir = 'D:\Recordings'
+# overwrite = false
+# append = false  (continue an existing WAV, MP3 or raw PCM file)
+
+# "mp3", "wav", "flac" or "pcm" (raw samples, little-endian unless pcm_be)
+# format = "mp3"
+# Several outputs at once: one format per path
+# out = ["share.mp3", "master.wav"]
+# format = ["mp3", "wav"]
+# flac_level = 5
+# pcm_be = false
+# Seconds between flushes to disk and WAV header updates while recording (0 = only at the end)
+# flush_interval = 5
+
+# Duration: seconds, "HH:MM:SS" or "1h30m" (0 = until Ctrl+C)
+# duration = 10
+
+# MP3 bitrate (CBR), or VBR quality 0-9
+# kbps = 192
+# allow_nearest_bitrate = true
+# vbr = 2
+# stereo_mode = "joint"
+# mp3_copyright = false
+# mp3_copy = false
+# mp3_emphasis = "none"
+
+# Processing
+# resample = 48000
+# gain = 0.0
+# highpass = 20
+# lowpass = 11000  (0 = off; automatic for MP3 below 128 kbps)
+# eq = ["f=200,g=-6,q=1.4", "type=highshelf,f=5000,g=3"]
+# compress = "3:-20"
+# compress_attack = 10
+# compress_release = 200
+# compress_makeup = 0.0
+# limiter = -1.0
+# gate = -50
+# gate_attack = 5
+# gate_release = 150
+# dither = "tpdf"
+# gain_ch = ["2=-3", "3=-3"]
+# stereo_width = 1.2
+# swap_lr = false
+# invert_phase = "R"
+# no_downmix = false  (keep all channels of a surround device; not for MP3)
+# mono = false  (one channel; LFE left out)
+# mono_coeffs = "1,1,0.7,0,0.5,0.5"
+# auto_mono = false  (one channel when the first second is identical on both)
+
+# Stop after this many seconds of silence below silence_threshold (dBFS)
+# silence_timeout = 30
+# silence_threshold = -60
+
+# Voice-activated: only write audio above silence_threshold, plus vox_hang ms after it
+# vox = false
+# vox_hang = 1000
+# vox_log = "vox.json"
+# preroll = 500
+
+# Split into files every N seconds or N MiB
+# split_seconds = 600
+# split_mb = 100
+# Or one file per track, cut after silence_min ms of silence
+# split_on_silence = true
+# silence_min = 2000
+# CUE sheet of the files, or of tracks marked in one file when cue_markers is set
+# cue = "playlist.cue"
+# cue_markers = false
+# Command run for each finished file, with {file} replaced by its path
+# on_complete = 'ffmpeg -y -i "{file}" "{file}.opus"'
+# Write <out>.json with the device, format, times, levels and encoder settings
+# sidecar = false
+# With a digest of the samples, the same for every format: "sha256" or "crc32"
+# checksum = "sha256"
+
+# Live MP3 stream to an Icecast server (in addition to the files)
+# icecast = "http://radio.example.com:8000/live"
+# icecast_password = "hackme"
+# Or serve it to browsers and players: http://<this PC>:8080/
+# serve = ":8080"
+
+# Progress output
+# no_meter = false
+# no_pause_key = false
+# progress = "text"  (or "json": JSON lines on stderr for a parent process)
+# Spectrum bands as JSON lines on stderr, for a visualizer
+# spectrum = 16
+# quiet = false
+
+# Remote control: stop, status, split, pause, resume and spectrum on \\.\pipe\arec
+# control_pipe = "arec"
+# Start and stop writing from any program; the recording waits for the first press
+# hotkey = "Ctrl+Alt+R"
+"#;
+
+fn write_config_template(args: &ConfigInitArgs) -> Result<()> {
+    if !args.force && Path::new(&args.path).exists() {
+        bail!("{} already exists (use --force to replace it)", args.path);
     }
+    std::fs::write(&args.path, CONFIG_TEMPLATE)
+        .with_context(|| format!("failed to write {}", args.path))?;
+    println!("Wrote settings template to {}", args.path);
+    Ok(())
 }
 
-fn take_first_two_channels_into(input: &[i16], channels: usize, out: &mut Vec<i16>) {
-    let frames = input.len() / channels;
-    out.reserve(frames * 2);
-
-    for f in 0..frames {
-        let base = f * channels;
-        let l = input[base];
-        let r = input[base + 1.min(channels - 1)];
-        out.push(l);
-        out.push(r);
+#[cfg(test)]
+mod tests {
+    use clap::{ArgMatches, CommandFactory};
+    use std::ffi::OsString;
+
+    use super::{parse_settings, Cli};
+
+    fn set_env(vars: &[(&str, &str)]) {
+        for name in [
+            "AREC_KBPS",
+            "AREC_OUT",
+            "AREC_NO_METER",
+            "AREC_DURATION",
+            "AREC_QUIET",
+            "AREC_VERBOSE",
+        ] {
+            // Only this test touches the environment
+            unsafe { std::env::remove_var(name) };
+        }
+        for (name, value) in vars {
+            unsafe { std::env::set_var(name, value) };
+        }
     }
-}
 
-fn human_bytes(bytes: u64) -> String {
-    const KB: f64 = 1024.0;
-    const MB: f64 = KB * 1024.0;
-    const GB: f64 = MB * 1024.0;
-    let b = bytes as f64;
-
-    if b >= GB {
-        format!("{:.2} GiB", b / GB)
-    } else if b >= MB {
-        format!("{:.2} MiB", b / MB)
-    } else if b >= KB {
-        format!("{:.2} KiB", b / KB)
-    } else {
-        format!("{bytes} B")
+    fn record_matches(argv: &[&str]) -> anyhow::Result<ArgMatches> {
+        let argv: Vec<OsString> = argv.iter().map(OsString::from).collect();
+        let matches = parse_settings(&mut Cli::command(), argv)?;
+        let (_, record) = matches.subcommand().expect("a subcommand");
+        Ok(record.clone())
     }
-}
 
-fn print_status_line(s: &str) -> Result<()> {
-    // \r = carriage return (return to start of line), no newline.
-    // Pad with spaces to overwrite leftovers from previous longer line.
-    print!("\r{s:<80}");
-    io::stdout().flush()?;
-    Ok(())
+    #[test]
+    fn command_line_beats_environment_beats_config() {
+        let path = std::env::temp_dir().join(format!("arec-settings-{}.toml", std::process::id()));
+        std::fs::write(&path, "kbps = 128\nout = \"config.mp3\"\nseconds = 90\nquiet = true\n")
+            .unwrap();
+        let config = path.to_str().unwrap();
+        let kbps = |m: &ArgMatches| m.get_one::<u32>("kbps").copied();
+        let out = |m: &ArgMatches| -> Vec<String> {
+            m.get_many::<String>("out").unwrap().cloned().collect()
+        };
+
+        // --kbps on all three levels, --out in the environment and the file, --no-meter only
+        // in the environment, the duration (as its alias) and --quiet only in the file
+        set_env(&[
+            ("AREC_KBPS", "192"),
+            ("AREC_OUT", "env.mp3"),
+            ("AREC_NO_METER", "yes"),
+            ("AREC_DURATION", ""),
+        ]);
+        let m = record_matches(&["arec", "record", "--kbps", "320", "--config", config]).unwrap();
+        assert_eq!(kbps(&m), Some(320));
+        assert_eq!(out(&m), ["env.mp3"]);
+        assert!(m.get_flag("no_meter"));
+        assert_eq!(m.get_one::<u64>("duration"), Some(&90));
+        assert!(m.get_flag("quiet"));
+
+        let m = record_matches(&["arec", "record", "--config", config]).unwrap();
+        assert_eq!(kbps(&m), Some(192));
+
+        // A switch turned off in the environment also wins over the file
+        set_env(&[("AREC_QUIET", "0")]);
+        let m = record_matches(&["arec", "record", "--config", config]).unwrap();
+        assert_eq!(kbps(&m), Some(128));
+        assert_eq!(out(&m), ["config.mp3"]);
+        assert!(!m.get_flag("no_meter"));
+        assert!(!m.get_flag("quiet"));
+
+        // The global --verbose only comes from the command line
+        set_env(&[("AREC_VERBOSE", "1")]);
+        let m = record_matches(&["arec", "record", "--config", config]).unwrap();
+        assert_eq!(m.get_count("verbose"), 0);
+        assert!(m.get_flag("quiet"));
+
+        // Without a config file only the environment fills in
+        set_env(&[("AREC_KBPS", "256")]);
+        let m = record_matches(&["arec", "record"]).unwrap();
+        assert_eq!(kbps(&m), Some(256));
+        assert_eq!(m.get_one::<u64>("duration"), Some(&10));
+
+        // A bad value names the variable it came from
+        set_env(&[("AREC_KBPS", "loud")]);
+        let err = record_matches(&["arec", "record", "--config", config]).unwrap_err();
+        assert!(err.to_string().starts_with("AREC_KBPS: "), "{err}");
+        set_env(&[("AREC_NO_METER", "maybe")]);
+        let err = record_matches(&["arec", "record"]).unwrap_err();
+        assert!(err.to_string().contains("AREC_NO_METER is a switch"), "{err}");
+
+        set_env(&[]);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,495 @@
+//! Network outputs: `tcp://`, Icecast and the `--serve` HTTP server.
+
+use anyhow::{anyhow, bail, Context, Result};
+use crossbeam_channel::{bounded, Sender, TrySendError};
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::args::RecordArgs;
+use crate::Console;
+
+// Where --icecast streams to, from the URL and the --icecast-* options
+pub(crate) struct IcecastTarget {
+    pub(crate) address: String, // host:port
+    mount: String,
+    user: String,
+    password: String,
+}
+
+impl IcecastTarget {
+    pub(crate) fn label(&self) -> String {
+        format!("{ICECAST_PREFIX}{}{}", self.address, self.mount)
+    }
+}
+
+// http://[user[:password]@]host[:port][/mount]; the port defaults to 8000 and the user to
+// `source`, as in Icecast's own configuration
+pub(crate) fn icecast_target(args: &RecordArgs) -> Result<Option<IcecastTarget>> {
+    let Some(url) = args.icecast.as_deref() else {
+        return Ok(None);
+    };
+    if url.starts_with("https://") {
+        bail!("--icecast {url}: HTTPS isn't supported; use the server's plain http:// port");
+    }
+    let rest = url.strip_prefix("http://").unwrap_or(url);
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let (userinfo, host) = match authority.rsplit_once('@') {
+        Some((userinfo, host)) => (Some(userinfo), host),
+        None => (None, authority),
+    };
+    let (user, url_password) = match userinfo.map(|info| info.split_once(':').unwrap_or((info, ""))) {
+        Some((user, password)) => (user, (!password.is_empty()).then_some(password)),
+        None => ("source", None),
+    };
+    if host.is_empty() {
+        bail!("--icecast {url}: no server name");
+    }
+    let address = if host.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) {
+        host.to_string()
+    } else {
+        format!("{host}:8000")
+    };
+    let mount = args.icecast_mount.as_deref().unwrap_or(path);
+    if mount.is_empty() || mount == "/" {
+        bail!("--icecast needs a mount point, such as http://{host}/live or --icecast-mount /live");
+    }
+    let mount = if mount.starts_with('/') {
+        mount.to_string()
+    } else {
+        format!("/{mount}")
+    };
+    let Some(password) = args.icecast_password.as_deref().or(url_password) else {
+        bail!("--icecast needs the source password: --icecast-password, or user:password@ in the URL");
+    };
+    Ok(Some(IcecastTarget {
+        address,
+        mount,
+        user: user.to_string(),
+        password: password.to_string(),
+    }))
+}
+
+#[derive(Clone, Copy)]
+enum IcecastMethod {
+    Put,
+    Source,
+}
+
+// The source client side of the Icecast protocol: an HTTP PUT (Icecast 2.4 and later) or
+// the older SOURCE request with the stream's format, then the MP3 bytes
+pub(crate) struct IcecastSource {
+    pub(crate) target: IcecastTarget,
+    name: String,
+    audio_info: String,
+    kbps: Option<u32>,
+}
+
+impl IcecastSource {
+    pub(crate) fn new(
+        target: IcecastTarget,
+        args: &RecordArgs,
+        sample_rate: u32,
+        kbps: u32,
+        channels: usize,
+    ) -> Self {
+        // VBR has no single bitrate to announce
+        let kbps = args.vbr.is_none().then_some(kbps);
+        let mut audio_info = format!("ice-samplerate={sample_rate};ice-channels={channels}");
+        if let Some(kbps) = kbps {
+            audio_info.push_str(&format!(";ice-bitrate={kbps}"));
+        }
+        Self {
+            target,
+            // A line break would end the header early
+            name: args.tags.title.as_deref().unwrap_or("ARec").replace(['\r', '\n'], " "),
+            audio_info,
+            kbps,
+        }
+    }
+
+    // Sends the request and reads the server's answer. Unsupported means the server didn't
+    // understand the method, so the other one is worth a try.
+    fn handshake(&self, mut stream: &TcpStream, method: IcecastMethod) -> io::Result<()> {
+        let target = &self.target;
+        let credentials = base64_encode(format!("{}:{}", target.user, target.password).as_bytes());
+        let mut request = match method {
+            IcecastMethod::Put => format!("PUT {} HTTP/1.1\r\nHost: {}\r\n", target.mount, target.address),
+            IcecastMethod::Source => format!("SOURCE {} ICE/1.0\r\n", target.mount),
+        };
+        request.push_str(&format!(
+            "Authorization: Basic {credentials}\r\nUser-Agent: ARec/{}\r\nContent-Type: audio/mpeg\r\n\
+             Ice-Name: {}\r\nIce-Public: 0\r\nIce-Audio-Info: {}\r\n",
+            env!("CARGO_PKG_VERSION"),
+            self.name,
+            self.audio_info
+        ));
+        if let Some(kbps) = self.kbps {
+            request.push_str(&format!("Ice-Bitrate: {kbps}\r\n"));
+        }
+        if let IcecastMethod::Put = method {
+            request.push_str("Expect: 100-continue\r\n");
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        // The answer is a status line and headers; the audio starts after them
+        stream.set_read_timeout(Some(TCP_WRITE_TIMEOUT))?;
+        let mut answer = Vec::new();
+        let mut byte = [0u8; 1];
+        while !answer.ends_with(b"\r\n\r\n") && answer.len() < 8192 {
+            if stream.read(&mut byte)? == 0 {
+                break;
+            }
+            answer.push(byte[0]);
+        }
+        let answer = String::from_utf8_lossy(&answer);
+        let status_line = answer.lines().next().unwrap_or("").trim();
+        let code = status_line.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok());
+        let error = |kind, what: &str| io::Error::new(kind, format!("{what} ({status_line})"));
+        match code {
+            Some(100 | 200) => Ok(()),
+            Some(401) => Err(error(
+                io::ErrorKind::PermissionDenied,
+                "the server rejected the user name or password",
+            )),
+            Some(403) => Err(error(
+                io::ErrorKind::PermissionDenied,
+                "the server refused the mount point (already in use, or not allowed)",
+            )),
+            Some(400 | 405 | 501) if matches!(method, IcecastMethod::Put) => {
+                Err(error(io::ErrorKind::Unsupported, "the server doesn't take PUT"))
+            }
+            _ if status_line.is_empty() => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "the server closed the connection without answering",
+            )),
+            _ => Err(error(io::ErrorKind::Other, "the server refused the stream")),
+        }
+    }
+}
+
+// Standard base64 with padding, for the Basic authorization header
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (chunk.get(1).copied().unwrap_or(0) as u32) << 8
+            | chunk.get(2).copied().unwrap_or(0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+pub(crate) const TCP_PREFIX: &str = "tcp://";
+
+// How an --icecast output is shown; the password never appears in it
+pub(crate) const ICECAST_PREFIX: &str = "icecast://";
+
+const ICECAST_MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// How a --serve output is shown
+pub(crate) const SERVE_PREFIX: &str = "serve://";
+
+// Chunks (MP3 frames, about 25 ms each) a listener may fall behind before it is dropped
+const LISTENER_QUEUE_LEN: usize = 200;
+
+// A listener that stops reading counts as gone after this long, rather than stalling capture
+const TCP_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// `--out -`, `--out tcp://...`, --icecast and --serve stream instead of writing a file
+pub(crate) fn is_stream_out(template: &str) -> bool {
+    template == "-"
+        || [TCP_PREFIX, ICECAST_PREFIX, SERVE_PREFIX]
+            .iter()
+            .any(|prefix| template.starts_with(prefix))
+}
+
+// --serve :8080 listens on all interfaces; a bare port means the same
+pub(crate) fn serve_address(address: &str) -> String {
+    if address.starts_with(':') {
+        format!("0.0.0.0{address}")
+    } else if address.parse::<u16>().is_ok() {
+        format!("0.0.0.0:{address}")
+    } else {
+        address.to_string()
+    }
+}
+
+// --serve: a one-mount HTTP server for the live MP3. An accept thread answers each GET
+// with audio headers and hands the connection to a thread of its own; `write` gives every
+// connected listener a copy of each chunk through its queue. A listener whose queue is
+// full is too slow and gets disconnected, so one bad connection can't hold up the others
+// or the recording. Each chunk is one MP3 frame, so a new listener starts on a frame.
+pub(crate) struct StreamServer {
+    listeners: Arc<Mutex<Vec<Listener>>>,
+    closed: Arc<AtomicBool>,
+    accept: Option<std::thread::JoinHandle<()>>,
+    console: Console,
+}
+
+struct Listener {
+    peer: String,
+    chunks: Sender<Arc<[u8]>>,
+}
+
+impl StreamServer {
+    pub(crate) fn bind(address: &str, console: Console) -> Result<Self> {
+        let socket = std::net::TcpListener::bind(address)
+            .with_context(|| format!("--serve: can't listen on {address}"))?;
+        Self::listen(socket, console)
+    }
+
+    // Serves on a socket that is already bound (port 0 picks a free one)
+    pub(crate) fn listen(socket: std::net::TcpListener, console: Console) -> Result<Self> {
+        // Polled, so the thread notices when the recording ends
+        socket.set_nonblocking(true)?;
+        info!(console, "Serving the live stream on http://{}/", socket.local_addr()?);
+        let listeners = Arc::new(Mutex::new(Vec::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let accept = {
+            let listeners = listeners.clone();
+            let closed = closed.clone();
+            std::thread::Builder::new()
+                .name("serve".to_string())
+                .spawn(move || accept_listeners(socket, listeners, closed, console))
+                .context("failed to start the --serve thread")?
+        };
+        Ok(Self {
+            listeners,
+            closed,
+            accept: Some(accept),
+            console,
+        })
+    }
+
+    pub(crate) fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk: Arc<[u8]> = Arc::from(buf);
+        let mut listeners = self.listeners.lock().unwrap_or_else(|e| e.into_inner());
+        listeners.retain(|listener| match listener.chunks.try_send(chunk.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                info!(self.console, "\nListener {} dropped (not keeping up)", listener.peer);
+                false
+            }
+            // Its connection already ended
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        Ok(buf.len())
+    }
+}
+
+impl Drop for StreamServer {
+    // Listeners get what is queued, then their connections close
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.listeners.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        if let Some(accept) = self.accept.take() {
+            let _ = accept.join();
+        }
+    }
+}
+
+fn accept_listeners(
+    socket: std::net::TcpListener,
+    listeners: Arc<Mutex<Vec<Listener>>>,
+    closed: Arc<AtomicBool>,
+    console: Console,
+) {
+    while !closed.load(Ordering::Relaxed) {
+        let (stream, peer) = match socket.accept() {
+            Ok(connection) => connection,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            Err(_) => continue,
+        };
+        let (chunks, chunk_rx) = bounded::<Arc<[u8]>>(LISTENER_QUEUE_LEN);
+        let listeners = listeners.clone();
+        let _ = std::thread::Builder::new()
+            .name("listener".to_string())
+            .spawn(move || {
+                let peer = peer.to_string();
+                if let Ok(true) = answer_listener(&stream) {
+                    // Registered only after the headers, so audio starts right after them
+                    listeners
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push(Listener { peer: peer.clone(), chunks });
+                    info!(console, "\nListener connected: {peer}");
+                    let mut stream = &stream;
+                    for chunk in chunk_rx {
+                        if stream.write_all(&chunk).is_err() {
+                            break;
+                        }
+                    }
+                    info!(console, "\nListener disconnected: {peer}");
+                }
+            });
+    }
+}
+
+// Reads the request and answers it; true when the audio should follow (GET)
+fn answer_listener(mut stream: &std::net::TcpStream) -> io::Result<bool> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(TCP_WRITE_TIMEOUT))?;
+    stream.set_write_timeout(Some(TCP_WRITE_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut byte = [0u8; 1];
+    while !request.ends_with(b"\r\n\r\n") && request.len() < 8192 {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(false);
+        }
+        request.push(byte[0]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let method = request.split_whitespace().next().unwrap_or("");
+    if method != "GET" && method != "HEAD" {
+        stream.write_all(b"HTTP/1.0 405 Method Not Allowed\r\nAllow: GET, HEAD\r\nConnection: close\r\n\r\n")?;
+        return Ok(false);
+    }
+    stream.write_all(
+        b"HTTP/1.0 200 OK\r\nContent-Type: audio/mpeg\r\nCache-Control: no-cache, no-store\r\n\
+          Connection: close\r\n\r\n",
+    )?;
+    Ok(method == "GET")
+}
+
+// --out tcp://host:port, and the connection to an Icecast server. A closed or failed
+// connection doesn't stop the recording: the audio for this output is dropped from then
+// on, and with --reconnect a new connection is tried once a second (Icecast always
+// reconnects, waiting twice as long after each failed attempt). MP3 frames are
+// self-contained, so a listener can start decoding at any frame.
+pub(crate) struct TcpOutput {
+    address: String,
+    // Sent after connecting, before any audio
+    icecast: Option<IcecastSource>,
+    stream: Option<TcpStream>,
+    reconnect: bool,
+    last_attempt: Instant,
+    retry_delay: Duration,
+}
+
+impl TcpOutput {
+    // The first connection has to work, except for a plain TCP stream with --reconnect
+    pub(crate) fn connect(
+        address: &str,
+        reconnect: bool,
+        icecast: Option<IcecastSource>,
+    ) -> Result<Self> {
+        let mut output = Self {
+            address: address.to_string(),
+            icecast,
+            stream: None,
+            reconnect,
+            last_attempt: Instant::now(),
+            retry_delay: Duration::from_secs(1),
+        };
+        match output.open() {
+            Ok(stream) => output.stream = Some(stream),
+            Err(e) if reconnect && output.icecast.is_none() => {
+                eprintln!("Warning: can't connect to {} ({e}); retrying while recording", output.label());
+            }
+            Err(e) => return Err(anyhow!(e).context(format!("connect to {}", output.label()))),
+        }
+        Ok(output)
+    }
+
+    fn label(&self) -> String {
+        match &self.icecast {
+            Some(source) => source.target.label(),
+            None => format!("{TCP_PREFIX}{}", self.address),
+        }
+    }
+
+    fn open(&mut self) -> io::Result<TcpStream> {
+        self.last_attempt = Instant::now();
+        let stream = connect_tcp(&self.address)?;
+        if let Some(source) = &self.icecast
+            && let Err(e) = source.handshake(&stream, IcecastMethod::Put)
+        {
+            // Servers before Icecast 2.4 only know the SOURCE method
+            if e.kind() != io::ErrorKind::Unsupported {
+                return Err(e);
+            }
+            let stream = connect_tcp(&self.address)?;
+            source.handshake(&stream, IcecastMethod::Source)?;
+            return Ok(stream);
+        }
+        Ok(stream)
+    }
+}
+
+fn connect_tcp(address: &str) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address found");
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, Duration::from_secs(1)) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(TCP_WRITE_TIMEOUT))?;
+                // Frames are small and should arrive as they are encoded
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+impl Write for TcpOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.stream.is_none() && self.reconnect && self.last_attempt.elapsed() >= self.retry_delay {
+            match self.open() {
+                Ok(stream) => {
+                    eprintln!("\nConnected to {}", self.label());
+                    self.stream = Some(stream);
+                    self.retry_delay = Duration::from_secs(1);
+                }
+                Err(e) if self.icecast.is_some() => {
+                    self.retry_delay = (self.retry_delay * 2).min(ICECAST_MAX_RETRY_DELAY);
+                    eprintln!(
+                        "\nWarning: can't reconnect to {} ({e}); next try in {} s",
+                        self.label(),
+                        self.retry_delay.as_secs()
+                    );
+                }
+                Err(_) => {}
+            }
+        }
+        let Some(stream) = &mut self.stream else {
+            return Ok(buf.len());
+        };
+        match stream.write(buf) {
+            Ok(written) if written > 0 => Ok(written),
+            result => {
+                let reason = result.err().map_or_else(|| "closed".to_string(), |e| e.to_string());
+                let next = if self.reconnect { "reconnecting" } else { "no more audio is sent" };
+                eprintln!("\nWarning: connection to {} lost ({reason}); {next}", self.label());
+                self.stream = None;
+                self.last_attempt = Instant::now();
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stream {
+            Some(stream) => stream.flush(),
+            None => Ok(()),
+        }
+    }
+}
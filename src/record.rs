@@ -0,0 +1,2640 @@
+//! The recording itself: [`Recorder`] and the capture loop behind `ARec record`.
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use shine_rs::{SUPPORTED_BITRATES, SUPPORTED_SAMPLE_RATES};
+use std::{
+    io::{self, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use wasapi::{initialize_mta, DeviceEnumerator};
+
+use crate::args::{
+    ChannelMode, Mp3Emphasis, OutputFormat, ProgressFormat, RecordArgs, Source, DEFAULT_FLAC_LEVEL,
+    DEFAULT_KBPS,
+};
+use crate::capture::{
+    channels_match, is_device_lost, probe_mono, select_device, CaptureRead, CaptureSource,
+    DeviceSelector, ProcessTarget, StreamFormat, WasapiSource,
+};
+use crate::checksum::Checksum;
+use crate::commands::pick_device;
+use crate::control::{
+    control_error, ControlCommand, ControlPipe, GlobalHotkey, PauseKeys, ProcessWatch,
+};
+use crate::dsp::{
+    apply_channel_gains, auto_lowpass_hz, count_clipped, db_to_gain, downmix_matrix_to_stereo_into,
+    downmix_n_to_stereo_into, downmix_to_mono_into, mono_coefficients, mono_downmix_weights,
+    remap_channels_into, rms_dbfs, scale_samples, speaker_names, standard_channel_mask,
+    stereo_downmix_matrix, to_dbfs, ChannelMap, Compressor, DriftTracker, Equalizer, HighPass,
+    Limiter, LinearResampler, LowPass, NoiseGate, Spectrum, StereoTools, Vox, SPECTRUM_INTERVAL,
+};
+use crate::json::{dbfs_json, json_string, peak_json};
+use crate::net::{icecast_target, is_stream_out, serve_address, SERVE_PREFIX};
+use crate::sinks::{
+    check_distinct_outputs, confirm_overwrite, cue_sheet, nearest_supported, open_output,
+    pcm_sample_format, resolve_outputs, run_encoder, sanitize_filename, CompletionCommands,
+    EncoderJob, EncoderOutput, EncoderResult, EncoderThread, Mp3Header, OutputNamer, OutputTarget,
+    SegmentSpan, Sink,
+};
+use crate::time::{format_position, format_wait, local_now, LocalTime};
+use crate::Console;
+
+// Chunks (about one WASAPI packet each, ~10 ms) the capture thread may queue for the encoder
+const ENCODER_QUEUE_LEN: usize = 256;
+
+// How long past --duration the wall clock waits for the audio that completes it
+const DURATION_GRACE: Duration = Duration::from_secs(1);
+
+/// Creates a custom sink once the stream format is known. It is called on the encoder
+/// thread, so the sink itself doesn't need to be `Send`.
+pub type SinkFactory = Box<dyn FnOnce(StreamFormat) -> Result<Box<dyn Sink>> + Send>;
+
+/// Called on the capture thread with each processed block of interleaved samples, the
+/// channel count and the sample rate. It holds up capture while it runs, so heavy work
+/// belongs on another thread (or in a [`Sink`], which has its own).
+pub type SamplesCallback = Box<dyn FnMut(&[i16], usize, u32) + Send>;
+
+// Name of the custom sink in messages and the summary; `<` can't appear in a file name
+const CUSTOM_SINK_NAME: &str = "<sink>";
+
+/// Runs a recording in the background, like `ARec record` with the same settings.
+///
+/// ```no_run
+/// let config = arec::RecordArgs::parse_from(["--seconds", "0", "--out", "rec.flac"])?;
+/// let recording = arec::Recorder::new(config).start()?;
+/// std::thread::sleep(std::time::Duration::from_secs(10));
+/// recording.stop()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// Progress and the summary are printed as by the command line tool (`--quiet` and
+/// `--no-meter` turn them down).
+pub struct Recorder {
+    config: RecordArgs,
+    source: Option<Box<dyn CaptureSource + Send>>,
+    sink: Option<SinkFactory>,
+    on_samples: Option<SamplesCallback>,
+}
+
+impl Recorder {
+    pub fn new(config: RecordArgs) -> Self {
+        Self {
+            config,
+            source: None,
+            sink: None,
+            on_samples: None,
+        }
+    }
+
+    /// Records from `source` instead of a WASAPI device. The device options (`--device`,
+    /// `--pick`, `--follow-default`, `--reconnect`, `--mix-mic`, `--exclusive`, ...) are
+    /// then not used.
+    pub fn source(mut self, source: impl CaptureSource + Send + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// Calls `callback` with every block of samples as it is captured, before any
+    /// encoder sees it (see [`SamplesCallback`]). Without `--out`, `--format` or a
+    /// [`Recorder::sink`], nothing else is written and no encoder runs.
+    pub fn on_samples(mut self, callback: impl FnMut(&[i16], usize, u32) + Send + 'static) -> Self {
+        self.on_samples = Some(Box::new(callback));
+        self
+    }
+
+    /// Also writes the recording to a sink created by `factory`. When the settings name
+    /// no `--out` or `--format`, this sink is the only output.
+    pub fn sink(
+        mut self,
+        factory: impl FnOnce(StreamFormat) -> Result<Box<dyn Sink>> + Send + 'static,
+    ) -> Self {
+        self.sink = Some(Box::new(factory));
+        self
+    }
+
+    /// Starts the recording on its own thread (which initializes COM for itself).
+    /// Setup errors, such as a device that can't be found, are returned by
+    /// [`RecordingHandle::stop`] or [`RecordingHandle::wait`].
+    pub fn start(self) -> Result<RecordingHandle> {
+        let (stop_tx, stop_rx) = bounded::<()>(1);
+        let thread = std::thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || {
+                // wasapi::initialize_mta returns an HRESULT, not a Result
+                let hr = initialize_mta();
+                if hr.is_err() {
+                    bail!("initialize_mta failed: HRESULT={hr:?}");
+                }
+                let source = self.source.map(|source| source as Box<dyn CaptureSource>);
+                record_loopback_to_mp3(&self.config, source, self.sink, self.on_samples, &stop_rx)
+            })
+            .context("failed to start recording thread")?;
+        Ok(RecordingHandle {
+            stop: StopHandle(stop_tx),
+            thread: Some(thread),
+        })
+    }
+}
+
+/// A running recording. Dropping it stops the recording and waits for the files to be
+/// finished, like [`RecordingHandle::stop`] without the result.
+pub struct RecordingHandle {
+    stop: StopHandle,
+    thread: Option<std::thread::JoinHandle<Result<()>>>,
+}
+
+impl RecordingHandle {
+    /// Ends the recording as Ctrl+C does in `ARec record`, waits until every output is
+    /// finished, and returns how the recording went.
+    pub fn stop(self) -> Result<()> {
+        self.stop.stop();
+        self.wait()
+    }
+
+    /// Waits for the recording to end by itself (`--duration`, `--silence-timeout`, a
+    /// lost device) or through a [`StopHandle`].
+    pub fn wait(mut self) -> Result<()> {
+        let thread = self.thread.take().expect("recording thread is joined once");
+        thread.join().map_err(|_| anyhow!("recording thread panicked"))?
+    }
+
+    /// Whether the recording has ended, so [`RecordingHandle::wait`] won't block
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(|thread| thread.is_finished())
+    }
+
+    /// A handle that can stop the recording from elsewhere, e.g. a Ctrl+C handler
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+}
+
+impl Drop for RecordingHandle {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop.stop();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Asks a recording to stop; see [`RecordingHandle::stop_handle`].
+#[derive(Clone, Debug)]
+pub struct StopHandle(Sender<()>);
+
+impl StopHandle {
+    pub fn stop(&self) {
+        let _ = self.0.try_send(());
+    }
+}
+
+// The recording behind `ARec record` and Recorder: the selected device (or a custom
+// source) into the --out files plus the optional custom sink and samples callback, until
+// stop_rx, --duration or another stop condition ends it.
+fn record_loopback_to_mp3(
+    args: &RecordArgs,
+    custom_source: Option<Box<dyn CaptureSource>>,
+    custom_sink: Option<SinkFactory>,
+    on_samples: Option<SamplesCallback>,
+    stop_rx: &Receiver<()>,
+) -> Result<()> {
+    let recording = Recording::start(args, custom_source, custom_sink, on_samples, stop_rx)?;
+    let Some(mut recording) = recording else {
+        return Ok(());
+    };
+    let stop_reason = recording.capture(stop_rx)?;
+    recording.finish(stop_reason)
+}
+
+// A running recording: the started source and encoder threads, the processing chain, and
+// what the capture loop and the summary keep track of.
+struct Recording<'a> {
+    args: &'a RecordArgs,
+    console: Console,
+    source: Box<dyn CaptureSource>,
+    // A custom source rather than a device (no warm-up, may run faster than real time)
+    replayed: bool,
+    device_name: String,
+    device_id: Option<String>,
+    mix_rate: usize,
+    mix_channels: usize,
+    out_rate: u32,
+    target_channels: usize,
+
+    // Per output, in encoder order: the reported path, the mode, and the --sidecar file
+    // (path, described file, encoder JSON). The custom sink comes last.
+    out_paths: Vec<String>,
+    mode_descs: Vec<String>,
+    sidecars: Vec<Option<(String, String, String)>>,
+    cue_format: Option<OutputFormat>,
+    on_complete: Option<Arc<CompletionCommands>>,
+    on_samples: Option<SamplesCallback>,
+    encoders: Vec<EncoderThread>,
+    // Emptied chunk buffers coming back from the encoder threads
+    free_rx: Receiver<Vec<i16>>,
+    // Bytes written by all outputs so far, for --progress json
+    written: Arc<AtomicU64>,
+
+    control: Option<ControlPipe>,
+    hotkey: Option<GlobalHotkey>,
+    process_watch: Option<ProcessWatch>,
+    pause_keys: Option<PauseKeys>,
+    // A split command has something to cut
+    splittable: bool,
+    // Ctrl+C during the countdown
+    cancelled: bool,
+
+    // Decoded i16 samples (mix_channels interleaved)
+    pcm_buf: Vec<i16>,
+    // Final samples given to encoder (target_channels interleaved); sent to the encoder
+    // thread whole and replaced by a recycled buffer
+    enc_buf: Vec<i16>,
+    processing: Processing,
+    // --vox: chunks below the threshold (after the hang time) are not written
+    vox: Option<Vox>,
+    // Live level meter of what goes to the encoder (None with --no-meter, or while JSON
+    // lines go to stderr)
+    meter: Option<LevelMeter>,
+    spectrum: Option<Spectrum>,
+    stats: RecordingStats,
+    // Space bar or --control-pipe pause: capture goes on, but nothing is written
+    pause: Pause,
+
+    start: Instant,
+    started_at: LocalTime,
+    last_ui: Instant,
+    last_spectrum: Instant,
+    // Last time enc_buf rose above --silence-threshold (no packets at all also counts as
+    // silence)
+    last_loud: Instant,
+    // --duration in output samples: the block that reaches it is cut at that exact frame.
+    // The wall clock only stops the recording a second later, in case less audio arrives
+    // (paused, --vox, loopback with nothing playing).
+    limit_samples: u64,
+    time_limit: Duration,
+    // --skip-warmup: device frames still to discard since the stream (re)started
+    warmup_frames: u64,
+    warmup_left: u64,
+
+    // Capture error seen in the loop, handled at the top of the next pass
+    lost: Option<anyhow::Error>,
+    // A capture error that ended the recording; returned once the file is saved
+    capture_error: Option<anyhow::Error>,
+}
+
+impl<'a> Recording<'a> {
+    // Checks the options, opens the source and the outputs, waits for --start-at and
+    // --countdown, and starts the capture. None when it was cancelled before that.
+    fn start(
+        args: &'a RecordArgs,
+        custom_source: Option<Box<dyn CaptureSource>>,
+        custom_sink: Option<SinkFactory>,
+        on_samples: Option<SamplesCallback>,
+        stop_rx: &Receiver<()>,
+    ) -> Result<Option<Self>> {
+        // A custom sink, callback, Icecast or HTTP stream can stand alone; otherwise --out
+        // defaults to output.mp3. The streams are more MP3 outputs after the --out ones.
+        let icecast = icecast_target(args)?;
+        let serve = args.serve.as_deref().map(serve_address);
+        let consumer =
+            custom_sink.is_some() || on_samples.is_some() || icecast.is_some() || serve.is_some();
+        let mut outputs = if consumer && args.out.is_empty() && args.format.is_empty() {
+            Vec::new()
+        } else {
+            resolve_outputs(&args.out, &args.format, args.out_dir.as_deref())?
+        };
+        if let Some(target) = &icecast {
+            outputs.push((target.label(), OutputFormat::Mp3));
+        }
+        if let Some(address) = &serve {
+            outputs.push((format!("{SERVE_PREFIX}{address}"), OutputFormat::Mp3));
+        }
+        let has_format = |format: OutputFormat| outputs.iter().any(|(_, f)| *f == format);
+        let (has_mp3, has_flac, has_pcm) = (
+            has_format(OutputFormat::Mp3),
+            has_format(OutputFormat::Flac),
+            has_format(OutputFormat::Pcm),
+        );
+        let vbr = args.vbr;
+
+        // `--out -` streams MP3 or raw PCM to stdout (e.g. into ffplay), `--out tcp://host:port`
+        // to a listener. Nothing can be seeked or reopened there, so the Xing header is
+        // skipped and file-based options are refused. Text goes to stderr in both cases.
+        let streaming = outputs.iter().any(|(template, _)| is_stream_out(template));
+        let console = Console { quiet: args.quiet, stdout_audio: streaming };
+        validate_outputs(args, &outputs, streaming)?;
+        let kbps = args.kbps.unwrap_or(DEFAULT_KBPS);
+
+        // Resolve --start-at now so a time in the past fails before any device is opened
+        let start_at = match args.start_at {
+            Some(at) => Some(at.resolve(&local_now(), args.start_next_day)?),
+            None => None,
+        };
+
+        // Validate requested bitrate vs shine_rs supported list (only matters for CBR MP3)
+        let kbps = if has_mp3 && vbr.is_none() && !SUPPORTED_BITRATES.contains(&kbps) {
+            let nearest = nearest_supported(SUPPORTED_BITRATES, kbps);
+            if !args.allow_nearest_bitrate {
+                bail!(
+                    "Unsupported bitrate {kbps} kbps for shine_rs. Supported: {:?} \
+                     (the nearest is {nearest}; --allow-nearest-bitrate uses it)",
+                    SUPPORTED_BITRATES
+                );
+            }
+            eprintln!("Warning: {kbps} kbps isn't supported by shine_rs; using {nearest} kbps");
+            nearest
+        } else {
+            kbps
+        };
+
+        if args.mix_mic.is_some() && args.source == Source::Mic {
+            bail!("--mix-mic mixes a microphone into loopback capture; it can't be used with --source mic");
+        }
+        let picks_process = args.process.is_some() || args.exclude_process.is_some();
+        if picks_process && args.source == Source::Mic {
+            bail!(
+                "--process and --exclude-process pick programs' playback; they can't be used with \
+                 --source mic"
+            );
+        }
+        if args.exclusive && args.source == Source::Loopback {
+            eprintln!("Warning: loopback capture has no exclusive mode; --exclusive ignored, using shared mode");
+        }
+
+        let replayed = custom_source.is_some();
+        let mut source: Box<dyn CaptureSource> = match custom_source {
+            Some(source) => source,
+            None => {
+                let enumerator = DeviceEnumerator::new()?;
+                let selector = if args.pick {
+                    DeviceSelector::Index(pick_device(&enumerator, args.source, args.role)?)
+                } else {
+                    DeviceSelector::from_options(
+                        args.device_index,
+                        args.device_id.as_deref(),
+                        args.device.as_deref(),
+                    )
+                };
+                let direction = args.source.direction();
+                let device = select_device(&enumerator, direction, selector, args.role)?;
+                let process = match (&args.process, &args.exclude_process) {
+                    (Some(spec), _) => Some(ProcessTarget::find(spec, false, console)?),
+                    (None, Some(spec)) => Some(ProcessTarget::find(spec, true, console)?),
+                    (None, None) => None,
+                };
+                Box::new(WasapiSource::new(enumerator, device, process, args, console)?)
+            }
+        };
+
+        let device_name = source.name();
+        let device_id = source.id();
+        info!(console, "Using device: {device_name}");
+
+        // Output names: --out tokens are expanded when each file is created. Two outputs
+        // can't share a file, which is checked now rather than after a scheduled start.
+        let split = args.splits();
+        let outputs: Vec<(OutputNamer, OutputFormat)> = outputs
+            .into_iter()
+            .map(|(template, format)| {
+                let namer = OutputNamer {
+                    template,
+                    device: sanitize_filename(&device_name),
+                    overwrite: args.overwrite || args.append,
+                };
+                (namer, format)
+            })
+            .collect();
+        let first_names: Vec<(&str, String)> = outputs
+            .iter()
+            .map(|(namer, _)| match namer.template.as_str() {
+                template if is_stream_out(template) => (template, template.to_string()),
+                template => (template, namer.path(split.then_some(0))),
+            })
+            .collect();
+        check_distinct_outputs(&first_names)?;
+        // An existing file is only numbered for a {n}, {date} or {time} template. Anything
+        // else is replaced only after asking, so ask now rather than once recording starts.
+        for ((namer, _), (template, path)) in outputs.iter().zip(&first_names) {
+            if !is_stream_out(template) && !namer.numbers_existing(split) {
+                confirm_overwrite("--out", path, namer.overwrite)?;
+            }
+        }
+        for (option, path) in [("--cue", &args.cue), ("--vox-log", &args.vox_log)] {
+            if let Some(path) = path {
+                confirm_overwrite(option, path, args.overwrite)?;
+            }
+        }
+
+        // The device mix format (always valid in shared mode), or a custom source's format
+        let mix = source.format();
+        if mix.channels == 0 || mix.sample_rate == 0 {
+            bail!(
+                "{device_name} reports {} channels at {} Hz; both must be above 0",
+                mix.channels,
+                mix.sample_rate
+            );
+        }
+        let mix_rate = mix.sample_rate as usize;
+        let mix_channels = mix.channels;
+
+        // Output rate: the device rate, unless --resample asks for another one.
+        let out_rate = args.resample.unwrap_or(mix_rate as u32);
+        if out_rate == 0 {
+            bail!("--resample rate must be greater than 0");
+        }
+
+        // shine_rs only supports certain sample rates; make sure out_rate is supported.
+        // WAV can store any rate, so this only applies to MP3.
+        if has_mp3 && !SUPPORTED_SAMPLE_RATES.contains(&out_rate) {
+            if args.resample.is_some() {
+                bail!(
+                    "Resample rate {out_rate} Hz not supported by shine_rs. Supported: {:?}",
+                    SUPPORTED_SAMPLE_RATES
+                );
+            }
+            bail!(
+                "Device mix sample rate {mix_rate} Hz not supported by shine_rs. Supported: {:?} \
+                 (use --resample {} to convert)",
+                SUPPORTED_SAMPLE_RATES,
+                nearest_supported(SUPPORTED_SAMPLE_RATES, mix_rate as u32)
+            );
+        }
+
+        // We'll capture as 16-bit PCM interleaved to feed the encoder.
+        // Keep the sample rate the same; downmix to stereo in software unless --no-downmix.
+        // Mono devices (typically microphones) stay mono rather than gaining a silent right
+        // channel.
+        let keep_all_channels = args.no_downmix && mix_channels > 2;
+        // --auto-mono settles the channel count from the audio itself, so the source is
+        // opened (and started for a second) here rather than after the remaining checks
+        let auto_mono = args.auto_mono && mix_channels == 2 && !replayed;
+        if auto_mono {
+            source.open()?;
+        }
+        let probed_mono = if auto_mono {
+            match probe_mono(source.as_mut(), mix_rate as u32, stop_rx, console)? {
+                Some(mono) => mono,
+                None => {
+                    info!(console, "Cancelled during the --auto-mono check; nothing was recorded.");
+                    return Ok(None);
+                }
+            }
+        } else {
+            false
+        };
+        let mono = args.mono || probed_mono;
+        if keep_all_channels && has_mp3 {
+            bail!(
+                "--no-downmix: the device has {mix_channels} channels, but MP3 holds at most two. \
+                 Drop --no-downmix to downmix to stereo, pick two channels with --channel-map, \
+                 or record to --format wav, flac or pcm to keep them all"
+            );
+        }
+        if keep_all_channels && has_flac && mix_channels > 8 {
+            bail!("--no-downmix: the device has {mix_channels} channels, but FLAC holds at most 8");
+        }
+        let target_channels = if mono {
+            1
+        } else if args.channel_map.is_some() || (mix_channels > 2 && !keep_all_channels) {
+            2
+        } else {
+            mix_channels
+        };
+        // Speaker positions for the multichannel header. A mask that doesn't describe every
+        // channel would leave players guessing, so the standard layout replaces it.
+        let output_mask = if keep_all_channels {
+            let mix_mask = mix.channel_mask;
+            let mask = if mix_mask.count_ones() as usize == mix_channels {
+                mix_mask
+            } else {
+                let standard = standard_channel_mask(mix_channels);
+                let layout = if standard == 0 {
+                    "no speaker layout".to_string()
+                } else {
+                    format!("the standard {mix_channels}-channel layout")
+                };
+                eprintln!(
+                    "Warning: the device's channel mask {mix_mask:#x} doesn't describe its \
+                     {mix_channels} channels; writing {layout}"
+                );
+                standard
+            };
+            if mask != 0 {
+                info!(console, "Channel layout: {}", speaker_names(mask).join(" "));
+            }
+            mask
+        } else {
+            0
+        };
+
+        // Low-bitrate MP3 spends bits on highs it can't code cleanly; without lossless
+        // outputs or other consumers of the audio, band-limit it like encoders do by default
+        let only_mp3 = !outputs.is_empty()
+            && outputs.iter().all(|(_, f)| *f == OutputFormat::Mp3)
+            && custom_sink.is_none()
+            && on_samples.is_none();
+        let auto_lowpass = if only_mp3 && vbr.is_none() {
+            auto_lowpass_hz(kbps, target_channels, out_rate)
+        } else {
+            None
+        };
+        let processing =
+            Processing::new(args, mix, out_rate, target_channels, probed_mono, auto_lowpass)?;
+        if args.lowpass.is_none()
+            && let Some(hz) = auto_lowpass
+        {
+            info!(console, "Low-pass: {hz} Hz for {kbps} kbps (--lowpass 0 turns it off)");
+        }
+
+        let channel_mode = ChannelMode::resolve(args.stereo_mode, target_channels);
+        if has_mp3 {
+            match (channel_mode, target_channels) {
+                (ChannelMode::Mono, 1) => {}
+                (ChannelMode::Mono, n) => bail!(
+                    "--stereo-mode mono needs a one-channel target, but the output has {n} \
+                     channels (the device records {mix_channels})"
+                ),
+                (mode, 1) => bail!(
+                    "--stereo-mode {} needs two channels, but the device records mono",
+                    mode.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default()
+                ),
+                _ => {}
+            }
+        }
+
+        if !auto_mono {
+            source.open()?;
+        }
+
+        // Scheduled start: the clients are initialized (so device errors show up right
+        // away), but no output is opened until the time arrives, so {date}/{time} name the
+        // recording.
+        if let Some(target) = start_at {
+            let wait = target.wait_from(&local_now());
+            info!(console,
+                "Scheduled start at {target}, waiting {} (Ctrl+C to cancel)",
+                format_wait(wait)
+            );
+            if wait_until(&target, stop_rx) {
+                info!(console, "Cancelled before the scheduled start; nothing was recorded.");
+                return Ok(None);
+            }
+        }
+
+        // With splitting, report the template; each segment's real name is listed at the
+        // end. The custom sink comes last, after the --out files.
+        let mut out_paths: Vec<String> = outputs
+            .iter()
+            .map(|(namer, _)| {
+                if split || is_stream_out(&namer.template) {
+                    namer.template.clone()
+                } else {
+                    namer.path(None)
+                }
+            })
+            .collect();
+
+        let mp3_header = Mp3Header::new(args, target_channels);
+        let mode_desc = |format: OutputFormat| match (format, vbr) {
+            (OutputFormat::Mp3, Some(q)) => {
+                format!("MP3 VBR quality {q}, {}{}", channel_mode.label(), mp3_header.describe())
+            }
+            (OutputFormat::Mp3, None) => {
+                format!("MP3 CBR {kbps} kbps, {}{}", channel_mode.label(), mp3_header.describe())
+            }
+            (OutputFormat::Wav, _) => "WAV 16-bit PCM".to_string(),
+            (OutputFormat::Flac, _) => format!(
+                "FLAC 16-bit, level {}",
+                args.flac_level.unwrap_or(DEFAULT_FLAC_LEVEL)
+            ),
+            (OutputFormat::Pcm, _) => format!("raw PCM {}", pcm_sample_format(args.pcm_be)),
+        };
+        let mut mode_descs: Vec<String> =
+            outputs.iter().map(|(_, format)| mode_desc(*format)).collect();
+        // --sidecar: the settings behind each mode, as fields; a split recording's sidecar
+        // is named after --out without a segment number. The custom sink gets none.
+        let encoder_json = |format: OutputFormat, mode: &str| {
+            let settings = match format {
+                OutputFormat::Mp3 => format!(
+                    "\"codec\": \"mp3\", \"bitrate_kbps\": {}, \"vbr_quality\": {}, \
+                     \"channel_mode\": {}",
+                    vbr.map_or(kbps.to_string(), |_| "null".to_string()),
+                    vbr.map_or("null".to_string(), |q| q.to_string()),
+                    json_string(
+                        &channel_mode
+                            .to_possible_value()
+                            .map(|v| v.get_name().to_string())
+                            .unwrap_or_default()
+                    )
+                ),
+                OutputFormat::Wav => "\"codec\": \"wav\", \"bits\": 16".to_string(),
+                OutputFormat::Flac => format!(
+                    "\"codec\": \"flac\", \"bits\": 16, \"level\": {}",
+                    args.flac_level.unwrap_or(DEFAULT_FLAC_LEVEL)
+                ),
+                OutputFormat::Pcm => format!(
+                    "\"codec\": \"pcm\", \"bits\": 16, \"big_endian\": {}",
+                    args.pcm_be
+                ),
+            };
+            format!("{{{settings}, \"mode\": {}}}", json_string(mode))
+        };
+        let sidecars: Vec<Option<(String, String, String)>> = outputs
+            .iter()
+            .zip(&out_paths)
+            .zip(&mode_descs)
+            .map(|(((namer, format), path), mode)| {
+                let file = if split { namer.path(None) } else { path.clone() };
+                (args.sidecar && !is_stream_out(path))
+                    .then(|| (format!("{file}.json"), file, encoder_json(*format, mode)))
+            })
+            .collect();
+        for (path, _, _) in sidecars.iter().flatten() {
+            confirm_overwrite("--sidecar", path, args.overwrite)?;
+        }
+        let cue_format = outputs.first().map(|(_, format)| *format);
+        let on_complete = args
+            .on_complete
+            .clone()
+            .map(|template| Arc::new(CompletionCommands::new(template, console)));
+        let mut jobs: Vec<EncoderJob> = outputs
+            .into_iter()
+            .zip(&out_paths)
+            .map(|((namer, format), path)| {
+                let on_complete = on_complete.clone();
+                EncoderJob::File(OutputTarget { namer, format, on_complete }, path.clone())
+            })
+            .collect();
+        if let Some(factory) = custom_sink {
+            out_paths.push(CUSTOM_SINK_NAME.to_string());
+            mode_descs.push("custom sink".to_string());
+            jobs.push(EncoderJob::Custom(factory));
+        }
+
+        // --control-pipe listens before any output is opened, so a name in use fails early
+        let control = args.control_pipe.as_deref().map(ControlPipe::start).transpose()?;
+        // --hotkey too, so a combination another program holds fails before anything is
+        // written
+        let hotkey = args.hotkey.as_ref().map(GlobalHotkey::start).transpose()?;
+        // --until-process-exits: a process that isn't running is an error before any output
+        let process_watch = args
+            .until_process_exits
+            .as_deref()
+            .map(|spec| ProcessWatch::start(spec, console))
+            .transpose()?;
+        let splittable = args.has_split()
+            || args.cue.is_some()
+            || jobs.iter().any(|job| matches!(job, EncoderJob::Custom(_)));
+
+        let stream = StreamFormat {
+            sample_rate: out_rate,
+            channels: target_channels,
+            channel_mask: output_mask,
+        };
+        let written = Arc::new(AtomicU64::new(0));
+        let (encoders, free_rx) = spawn_encoders(args, jobs, stream, kbps, &written, console)?;
+
+        // Raw PCM carries no header, so tell the consumer what the bytes are (even with
+        // --quiet)
+        if has_pcm {
+            eprintln!(
+                "Raw PCM: {out_rate} Hz, {target_channels} channel{}, {}",
+                if target_channels == 1 { "" } else { "s" },
+                pcm_sample_format(args.pcm_be)
+            );
+        }
+        // Ctrl+C during the countdown ends the recording before any audio is captured
+        let cancelled = countdown(args.countdown, stop_rx)?;
+        if args.beep && !cancelled {
+            eprint!("\x07");
+            io::stderr().flush()?;
+        }
+
+        let mut started: Vec<String> = out_paths
+            .iter()
+            .zip(&mode_descs)
+            .map(|(path, mode)| format!("{path} | {mode}"))
+            .collect();
+        if on_samples.is_some() {
+            started.push("samples callback".to_string());
+        }
+        // The space bar pauses and resumes when ARec runs in a console
+        let pause_keys = (!args.no_pause_key).then(PauseKeys::open).flatten();
+        info!(console,
+            "Recording started. Output{}: {} | {}{}Ctrl+C to stop",
+            if started.len() == 1 { "" } else { "s" },
+            started.join("; "),
+            match &args.hotkey {
+                Some(hotkey) => format!("{} to start and stop, ", hotkey.text),
+                None => String::new(),
+            },
+            if pause_keys.is_some() { "Space to pause, " } else { "" }
+        );
+
+        source.start()?;
+        log::info!("Capture started");
+
+        if out_rate as usize != mix_rate {
+            info!(console, "Resampling {mix_rate} Hz -> {out_rate} Hz");
+        }
+        let progress_json = args.progress == ProgressFormat::Json;
+        let json_lines = progress_json || args.spectrum.is_some();
+        let meter = (!args.no_meter && !args.quiet && !json_lines)
+            .then(|| LevelMeter::new(target_channels));
+
+        // With --hotkey nothing is written until the first press
+        let mut pause = Pause::default();
+        if hotkey.is_some() {
+            pause.set(true);
+        }
+
+        // A custom source has no driver warm-up, so its first samples are kept
+        let warmup_ms = if replayed { 0 } else { args.skip_warmup };
+        let warmup_frames = u64::from(warmup_ms) * mix_rate as u64 / 1000;
+
+        Ok(Some(Recording {
+            args,
+            console,
+            source,
+            replayed,
+            device_name,
+            device_id,
+            mix_rate,
+            mix_channels,
+            out_rate,
+            target_channels,
+            out_paths,
+            mode_descs,
+            sidecars,
+            cue_format,
+            on_complete,
+            on_samples,
+            encoders,
+            free_rx,
+            written,
+            control,
+            hotkey,
+            process_watch,
+            pause_keys,
+            splittable,
+            cancelled,
+            pcm_buf: Vec::with_capacity(mix_channels * 4096),
+            enc_buf: Vec::with_capacity(target_channels * 4096),
+            processing,
+            vox: args.vox.then(|| {
+                Vox::new(
+                    target_channels,
+                    args.silence_threshold,
+                    args.vox_hang,
+                    args.preroll,
+                    out_rate,
+                )
+            }),
+            meter,
+            spectrum: args.spectrum.map(|bands| Spectrum::new(bands, out_rate, target_channels)),
+            stats: RecordingStats::new(args, mix_rate as u32, mix_channels, target_channels),
+            pause,
+            start: Instant::now(),
+            started_at: local_now(),
+            last_ui: Instant::now(),
+            last_spectrum: Instant::now(),
+            last_loud: Instant::now(),
+            limit_samples: args.duration * out_rate as u64 * target_channels as u64,
+            time_limit: Duration::from_secs(args.duration) + DURATION_GRACE,
+            warmup_frames,
+            warmup_left: warmup_frames,
+            lost: None,
+            capture_error: None,
+        }))
+    }
+
+    // The capture loop: reads and processes blocks until a stop condition, and returns
+    // why it stopped.
+    fn capture(&mut self, stop_rx: &Receiver<()>) -> Result<&'static str> {
+        let args = self.args;
+        let seconds = args.duration;
+        let stop_reason = loop {
+            if seconds != 0 && self.start.elapsed() >= self.time_limit {
+                break "time limit reached";
+            }
+            if self.cancelled || stop_requested(stop_rx) {
+                break "interrupted (Ctrl+C)";
+            }
+            if self.process_watch.as_ref().is_some_and(ProcessWatch::exited) {
+                break "watched process exited";
+            }
+            if let Some(timeout) = args.silence_timeout
+                && self.last_loud.elapsed() >= Duration::from_secs(timeout)
+            {
+                break "silence timeout";
+            }
+            if let Some(max) = args.max_drops
+                && self.stats.drops() > max
+            {
+                self.capture_error =
+                    Some(anyhow!("{} drops exceeded --max-drops {max}", self.stats.drops()));
+                break "too many drops";
+            }
+
+            if let Some(reason) = self.answer_control() {
+                break reason;
+            }
+            // Each space bar or --hotkey press toggles the pause
+            let presses = self.pause_keys.as_ref().map_or(0, PauseKeys::presses)
+                + self.hotkey.as_ref().map_or(0, GlobalHotkey::presses);
+            if presses % 2 == 1 {
+                self.pause.set(!self.pause.is_paused());
+                log::info!("{}", if self.pause.is_paused() { "Paused" } else { "Resumed" });
+            }
+            // A pause isn't silence for --silence-timeout
+            if self.pause.is_paused() {
+                self.last_loud = Instant::now();
+            }
+
+            if let Some(reason) = self.follow_device(stop_rx) {
+                break reason;
+            }
+            self.update_ui()?;
+
+            // The next block from the device (decoded, with any gap silence in front and the
+            // --mix-mic audio summed in). No packets for a while is normal for loopback.
+            self.pcm_buf.clear();
+            let (frames, lost_frames, timestamp) = match self.source.read(&mut self.pcm_buf) {
+                Ok(CaptureRead::Block {
+                    frames,
+                    lost_frames,
+                    short,
+                    timestamp,
+                }) => {
+                    if short {
+                        self.stats.short_reads += 1;
+                        log::debug!("Short read: {frames} frames, less than the packet announced");
+                    }
+                    (frames, lost_frames, timestamp)
+                }
+                Ok(CaptureRead::Idle { expected }) => {
+                    if expected {
+                        self.stats.event_timeouts += 1;
+                        log::debug!("No capture event within {} ms", args.event_timeout_ms);
+                    }
+                    continue;
+                }
+                Ok(CaptureRead::Ended) => break "end of source",
+                Err(e) => {
+                    log::debug!("Capture read failed: {e:#}");
+                    self.lost = Some(e);
+                    continue;
+                }
+            };
+            if let Some(reason) = self.process_packet(frames, lost_frames, timestamp) {
+                break reason;
+            }
+        };
+        log::info!(
+            "Capture stopped: {stop_reason} ({} frames, {} glitches, {} short reads, {} event \
+             timeouts)",
+            self.stats.frames_captured,
+            self.stats.glitches,
+            self.stats.short_reads,
+            self.stats.event_timeouts
+        );
+        Ok(stop_reason)
+    }
+
+    // --control-pipe commands, answered with the state as of this pass. Some(reason) when
+    // one ends the recording.
+    fn answer_control(&mut self) -> Option<&'static str> {
+        let control = self.control.as_ref()?;
+        while let Ok(request) = control.requests.try_recv() {
+            let reply = match request.command {
+                ControlCommand::Stop => "{\"ok\": true, \"state\": \"stopping\"}".to_string(),
+                ControlCommand::Status => format!(
+                    "{{\"ok\": true, \"state\": \"{}\", \"elapsed_secs\": {:.2}, \
+                     \"paused_secs\": {:.2}, \"frames\": {}, \
+                     \"bytes_written\": {}, \"peak_dbfs\": {}, \"drops\": {}}}",
+                    self.pause.state(),
+                    self.start.elapsed().as_secs_f64(),
+                    self.pause.total().as_secs_f64(),
+                    self.stats.frames_captured,
+                    self.written.load(Ordering::Relaxed),
+                    peak_json(self.stats.peak_abs),
+                    self.stats.drops()
+                ),
+                ControlCommand::Spectrum => match &self.spectrum {
+                    Some(spectrum) => {
+                        format!("{{\"ok\": true, \"spectrum_dbfs\": {}}}", spectrum.to_json())
+                    }
+                    None => control_error("spectrum needs --spectrum"),
+                },
+                ControlCommand::Split if !self.splittable => control_error(
+                    "split needs --split-seconds, --split-mb, --split-on-silence, or --cue",
+                ),
+                ControlCommand::Split => {
+                    // Queued behind the audio sent so far, so the cut lands right here
+                    for encoder in &self.encoders {
+                        if encoder.chunks().send(Vec::new()).is_err() {
+                            return Some("encoder stopped");
+                        }
+                    }
+                    format!("{{\"ok\": true, \"state\": \"{}\"}}", self.pause.state())
+                }
+                ControlCommand::Pause | ControlCommand::Resume => {
+                    self.pause.set(request.command == ControlCommand::Pause);
+                    format!("{{\"ok\": true, \"state\": \"{}\"}}", self.pause.state())
+                }
+            };
+            let _ = request.reply.send(reply);
+            if request.command == ControlCommand::Stop {
+                return Some("stopped by --control-pipe");
+            }
+        }
+        None
+    }
+
+    // A lost device (unplugged, disabled, audio service restarted) ends the recording, or
+    // with --reconnect waits for a default device to take over. Other capture errors also
+    // end it; the file is saved either way and the error is returned afterwards. Without
+    // an error, --follow-default may switch to a new default device. Some(reason) when the
+    // recording ends here.
+    fn follow_device(&mut self, stop_rx: &Receiver<()>) -> Option<&'static str> {
+        let switched = if let Some(e) = self.lost.take() {
+            if !is_device_lost(&e) {
+                self.capture_error = Some(e);
+                return Some("capture error");
+            }
+            if !self.args.reconnect {
+                eprintln!("\nWarning: the device was disconnected; saving what was recorded");
+                return Some("device disconnected");
+            }
+            eprintln!("\nDevice disconnected; waiting for a default device (Ctrl+C to stop)");
+            log::info!("Device lost: {e:#}");
+            let seconds = self.args.duration;
+            let name = loop {
+                if seconds != 0 && self.start.elapsed() >= self.time_limit {
+                    return Some("time limit reached");
+                }
+                if let Ok(Some(name)) = self.source.reopen() {
+                    break name;
+                }
+                if stop_rx.recv_timeout(Duration::from_secs(1)).is_ok() {
+                    return Some("interrupted (Ctrl+C)");
+                }
+                if self.process_watch.as_ref().is_some_and(ProcessWatch::exited) {
+                    return Some("watched process exited");
+                }
+            };
+            self.stats.reconnects += 1;
+            (name, "Reconnected")
+        } else {
+            match self.source.follow_default() {
+                Ok(Some(name)) => {
+                    self.stats.device_switches += 1;
+                    (name, "Default device changed")
+                }
+                Ok(None) => return None,
+                Err(e) => {
+                    eprintln!("\nWarning: can't record the new default device: {e:#}");
+                    return Some("default device switch failed");
+                }
+            }
+        };
+        let (name, what) = switched;
+        eprintln!("\n{what}; now recording: {name}");
+        self.warmup_left = self.warmup_frames;
+        None
+    }
+
+    // The status line, level meter or --progress json line, and the --spectrum line
+    fn update_ui(&mut self) -> Result<()> {
+        let args = self.args;
+        let seconds = args.duration;
+        let progress_json = args.progress == ProgressFormat::Json;
+        let json_lines = progress_json || args.spectrum.is_some();
+        // Update UI once per second (~10x/sec when the level meter is on, 4x with
+        // --progress json)
+        let ui_interval = if progress_json {
+            Duration::from_millis(250)
+        } else if self.meter.is_some() {
+            Duration::from_millis(100)
+        } else {
+            Duration::from_secs(1)
+        };
+        if self.last_ui.elapsed() >= ui_interval {
+            let elapsed = self.start.elapsed().as_secs();
+            let paused = self.pause.is_paused();
+            match &mut self.meter {
+                // Whole lines on stderr, so they can't mix with audio on stdout (--out -)
+                _ if progress_json => {
+                    eprintln!(
+                        "{{\"elapsed_secs\": {:.2}, \"bytes_written\": {}, \"peak_dbfs\": {}, \
+                         \"drops\": {}, \"paused\": {paused}}}",
+                        self.start.elapsed().as_secs_f64(),
+                        self.written.load(Ordering::Relaxed),
+                        peak_json(self.stats.progress_peak),
+                        self.stats.drops()
+                    );
+                    self.stats.progress_peak = 0;
+                }
+                Some(meter) => {
+                    let time = if seconds == 0 {
+                        format!("{elapsed}s")
+                    } else {
+                        format!("{}s left", seconds.saturating_sub(elapsed))
+                    };
+                    let label = if paused {
+                        "PAUSE"
+                    } else if self.vox.as_ref().is_some_and(|v| !v.is_active()) {
+                        "WAIT"
+                    } else {
+                        "REC"
+                    };
+                    print_meter_line(&format!("{label} {time:>8} {}", meter.take_line()))?;
+                }
+                None if args.quiet || json_lines => {}
+                None => {
+                    let what = if paused { "Paused" } else { "Recording" };
+                    let time = if seconds == 0 {
+                        format!("elapsed {elapsed}s")
+                    } else {
+                        let remaining = seconds.saturating_sub(elapsed);
+                        format!("remaining {remaining}s / total {seconds}s")
+                    };
+                    let line = format!("{what}... {time} (Ctrl+C to stop)");
+                    print_status_line(&line, self.console)?;
+                }
+            }
+            self.last_ui = Instant::now();
+        }
+        if let Some(spectrum) = &self.spectrum
+            && self.last_spectrum.elapsed() >= SPECTRUM_INTERVAL
+        {
+            eprintln!(
+                "{{\"elapsed_secs\": {:.2}, \"spectrum_dbfs\": {}}}",
+                self.start.elapsed().as_secs_f64(),
+                spectrum.to_json()
+            );
+            self.last_spectrum = Instant::now();
+        }
+        Ok(())
+    }
+
+    // One block read into pcm_buf: counted, processed, and handed to the encoders.
+    // Some(reason) when the recording ends with it.
+    fn process_packet(
+        &mut self,
+        frames: u64,
+        lost_frames: Option<u64>,
+        timestamp: Option<Duration>,
+    ) -> Option<&'static str> {
+        let args = self.args;
+        let mix_channels = self.mix_channels;
+        self.stats.frames_captured += frames;
+        if let Some(gap_frames) = lost_frames {
+            log::debug!("Discontinuity: {gap_frames} frames lost before this packet");
+            self.stats.glitches += 1;
+            if args.fill_gaps {
+                self.stats.gap_frames_filled += gap_frames;
+            }
+        }
+        self.stats.drift.track(&mut self.pcm_buf, frames, timestamp, args.drift_correct);
+
+        // The drift tracker has seen the warm-up, but nothing after it does
+        if self.warmup_left > 0 {
+            let skip = self.warmup_left.min((self.pcm_buf.len() / mix_channels) as u64);
+            self.pcm_buf.drain(..skip as usize * mix_channels);
+            self.warmup_left -= skip;
+            if self.warmup_left == 0 {
+                log::debug!("Skipped {} ms of warm-up", args.skip_warmup);
+            }
+            if self.pcm_buf.is_empty() {
+                return None;
+            }
+        }
+
+        self.processing.process(&mut self.pcm_buf, &mut self.enc_buf);
+
+        // Encode / write to the output sink
+        if self.enc_buf.is_empty() {
+            return None;
+        }
+        if let Some(meter) = &mut self.meter {
+            meter.update(&self.enc_buf);
+        }
+        if let Some(spectrum) = &mut self.spectrum {
+            spectrum.update(&self.enc_buf);
+        }
+        // Paused: the meter and spectrum keep showing the input, but nothing is written
+        if self.pause.is_paused() {
+            return None;
+        }
+        if args.silence_timeout.is_some()
+            && rms_dbfs(&self.enc_buf) >= args.silence_threshold as f64
+        {
+            self.last_loud = Instant::now();
+        }
+        // --vox: quiet chunks are dropped here rather than written
+        if let Some(vox) = &mut self.vox
+            && !vox.keep(&mut self.enc_buf)
+        {
+            return None;
+        }
+        let samples_written = self.stats.samples_written;
+        let limit_reached = self.limit_samples != 0
+            && self.enc_buf.len() as u64 >= self.limit_samples - samples_written;
+        if limit_reached {
+            self.enc_buf.truncate((self.limit_samples - samples_written) as usize);
+        }
+        self.stats.count_written(&self.enc_buf);
+        if let Some(callback) = &mut self.on_samples {
+            callback(&self.enc_buf, self.target_channels, self.out_rate);
+        }
+
+        if !self.send_to_encoders() {
+            return Some("encoder stopped");
+        }
+        limit_reached.then_some("time limit reached")
+    }
+
+    // Hands enc_buf to each encoder thread: copies for all but the last, which gets
+    // enc_buf itself. False when an encoder stopped.
+    fn send_to_encoders(&mut self) -> bool {
+        let last = self.encoders.len().saturating_sub(1);
+        for (i, encoder) in self.encoders.iter().enumerate() {
+            let mut next_buf = self
+                .free_rx
+                .try_recv()
+                .unwrap_or_else(|_| Vec::with_capacity(self.target_channels * 4096));
+            let chunk = if i == last {
+                std::mem::replace(&mut self.enc_buf, next_buf)
+            } else {
+                next_buf.clear();
+                next_buf.extend_from_slice(&self.enc_buf);
+                next_buf
+            };
+            match encoder.chunks().try_send(chunk) {
+                Ok(()) => {}
+                Err(TrySendError::Full(chunk)) => {
+                    if self.stats.queue_full_events == 0 {
+                        eprintln!("\nWarning: encoder can't keep up; capture is waiting on it");
+                    }
+                    self.stats.queue_full_events += 1;
+                    log::debug!("Encoder {i} queue full; capture waits for it");
+                    if encoder.chunks().send(chunk).is_err() {
+                        return false;
+                    }
+                }
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        }
+        true
+    }
+
+    // Stops the source, finishes every output, writes the cue sheet, the --vox-log and the
+    // sidecars, and prints the summary. Returns the capture error that ended the recording,
+    // if any.
+    fn finish(mut self, stop_reason: &'static str) -> Result<()> {
+        let ended_at = local_now();
+
+        // Stopping a disconnected device fails; the recording is saved regardless
+        self.source.stop();
+
+        // The limiter still holds its lookahead; send it as the last chunk
+        if let Some(limiter) = &mut self.processing.limiter {
+            let mut tail = Vec::new();
+            limiter.flush_into(&mut tail);
+            if self.limit_samples != 0 {
+                tail.truncate((self.limit_samples - self.stats.samples_written) as usize);
+            }
+            if let Some(vox) = &mut self.vox
+                && !tail.is_empty()
+                && !vox.keep(&mut tail)
+            {
+                tail.clear();
+            }
+            self.stats.count_written(&tail);
+            if let Some(callback) = &mut self.on_samples
+                && !tail.is_empty()
+            {
+                callback(&tail, self.target_channels, self.out_rate);
+            }
+            if !tail.is_empty() {
+                // A send error means the encoder stopped; its error is reported by the join
+                // below
+                for encoder in &self.encoders {
+                    let _ = encoder.chunks().send(tail.clone());
+                }
+            }
+        }
+
+        let Recording {
+            args,
+            console,
+            source,
+            replayed,
+            device_name,
+            device_id,
+            mix_rate,
+            mix_channels,
+            out_rate,
+            target_channels,
+            out_paths,
+            mode_descs,
+            sidecars,
+            cue_format,
+            on_complete,
+            encoders,
+            processing,
+            mut vox,
+            meter,
+            stats,
+            pause,
+            start,
+            started_at,
+            capture_error,
+            ..
+        } = self;
+        let RecordingStats {
+            frames_captured,
+            peak_abs,
+            clipped_samples,
+            sum_squares,
+            samples_written,
+            checksum,
+            queue_full_events,
+            glitches,
+            short_reads,
+            event_timeouts,
+            gap_frames_filled,
+            device_switches,
+            reconnects,
+            drift,
+            ..
+        } = stats;
+        let Processing { limiter, gate, .. } = processing;
+        let limited_samples = limiter.as_ref().map_or(0, |l| l.limited);
+        let gated_secs = gate.as_ref().map_or(0.0, |g| g.gated_frames as f64 / out_rate as f64);
+        let paused_secs = pause.total().as_secs_f64();
+        let json_lines = args.progress == ProgressFormat::Json || args.spectrum.is_some();
+
+        // Closing the channels lets each encoder drain, flush the encoder tail / patch the
+        // WAV header, and report the segments written (or why it stopped early). Every
+        // output is finished before the first error is returned.
+        let results: Vec<EncoderResult> = encoders.into_iter().map(EncoderThread::join).collect();
+        let mut saved = Vec::with_capacity(results.len());
+        for result in results {
+            saved.push(result?);
+        }
+        // --cue describes the first output
+        if let (Some(cue_path), Some(cue_format)) = (&args.cue, cue_format) {
+            let sheet = cue_sheet(
+                args,
+                cue_format,
+                cue_path,
+                &out_paths[0],
+                &saved[0],
+                out_rate as u64 * target_channels as u64,
+            );
+            std::fs::write(cue_path, sheet).with_context(|| format!("failed to write {cue_path}"))?;
+        }
+        if let Some(vox) = &mut vox {
+            vox.finish();
+            if let Some(path) = &args.vox_log {
+                std::fs::write(path, vox.to_json(&started_at))
+                    .with_context(|| format!("failed to write {path}"))?;
+            }
+        }
+
+        // Clear the live status (or meter) line and print a newline
+        if meter.is_some() {
+            print_meter_line("")?;
+            eprintln!();
+        } else if !args.quiet && !json_lines {
+            print_status_line("", console)?;
+            report!(console, );
+        }
+
+        // A device delivers in real time; a custom source may be read faster than that
+        let recorded_secs = if replayed {
+            frames_captured as f64 / mix_rate as f64
+        } else {
+            start.elapsed().as_secs_f64()
+        }
+        .max(0.001); // avoid div-by-zero
+        let file_size =
+            |path: &str| std::fs::metadata(Path::new(path)).map(|m| m.len()).unwrap_or(0);
+        let output_sizes: Vec<u64> = out_paths
+            .iter()
+            .zip(&saved)
+            .map(|(out_path, output)| {
+                if is_stream_out(out_path) || out_path == CUSTOM_SINK_NAME {
+                    output.bytes_written
+                } else if output.segments.is_empty() {
+                    file_size(out_path)
+                } else {
+                    output.segments.iter().map(|p| file_size(p)).sum()
+                }
+            })
+            .collect();
+        let size_bytes: u64 = output_sizes.iter().sum();
+
+        let avg_kbps = (size_bytes as f64 * 8.0 / recorded_secs) / 1000.0;
+        let peak_dbfs = to_dbfs(peak_abs as f64);
+        let drops = glitches + short_reads + event_timeouts;
+        let clipped_total: u64 = clipped_samples.iter().sum();
+        let clipped = clipped_total > 0;
+        let float_overs = source.float_overs();
+
+        info!(console, "Stop reason: {stop_reason}");
+        // Segment spans are in samples of the output; shown as time since the start
+        let span_secs = |samples: u64| samples as f64 / (out_rate as f64 * target_channels as f64);
+        let span_times = |span: &SegmentSpan| {
+            format!(
+                "{} - {} ({})",
+                format_position(span_secs(span.start)),
+                format_position(span_secs(span.end)),
+                format_position(span_secs(span.end - span.start))
+            )
+        };
+        for ((out_path, mode_desc), output) in out_paths.iter().zip(&mode_descs).zip(&saved) {
+            info!(console, "Mode: {mode_desc}");
+            if out_path == "-" {
+                info!(console, "Streamed to stdout");
+            } else if is_stream_out(out_path) {
+                info!(console, "Streamed to {out_path}");
+            } else if out_path == CUSTOM_SINK_NAME {
+                info!(console, "Written to the custom sink");
+            } else if output.segments.is_empty() {
+                info!(console, "Saved: {out_path}");
+                if args.cue_markers {
+                    for (i, span) in output.spans.iter().enumerate() {
+                        info!(console, "  Track {:02}  {}", i + 1, span_times(span));
+                    }
+                }
+            } else if args.split_on_silence {
+                info!(console, "Saved {} tracks:", output.segments.len());
+                for (path, span) in output.segments.iter().zip(&output.spans) {
+                    info!(console, "  {path}  {}", span_times(span));
+                }
+            } else {
+                info!(console, "Saved {} segments:", output.segments.len());
+                for path in &output.segments {
+                    info!(console, "  {path}");
+                }
+            }
+        }
+        if let Some(cue_path) = &args.cue {
+            info!(console, "Cue sheet: {cue_path}");
+        }
+        let segments_json = |out_path: &str, output: &EncoderOutput| {
+            let mut json = String::new();
+            if !output.segments.is_empty() {
+                let list: Vec<String> = output.segments.iter().map(|p| json_string(p)).collect();
+                json = format!(", \"segments\": [{}]", list.join(", "));
+            }
+            // Tracks are files with --split-on-silence, or parts of one file with --cue-markers
+            let single = output.segments.is_empty() && args.cue_markers;
+            if (args.split_on_silence && !output.segments.is_empty()) || single {
+                let tracks: Vec<String> = output
+                    .spans
+                    .iter()
+                    .enumerate()
+                    .map(|(i, span)| {
+                        let path = output.segments.get(i).map_or(out_path, String::as_str);
+                        format!(
+                            "{{\"file\": {}, \"start_secs\": {:.3}, \"end_secs\": {:.3}}}",
+                            json_string(path),
+                            span_secs(span.start),
+                            span_secs(span.end)
+                        )
+                    })
+                    .collect();
+                json.push_str(&format!(", \"tracks\": [{}]", tracks.join(", ")));
+            }
+            json
+        };
+        let checksum_json = checksum.as_ref().map_or(String::new(), |checksum| {
+            let (kind, digest) = (checksum.name(), checksum.hex());
+            format!(", \"checksum_type\": \"{kind}\", \"checksum\": \"{digest}\"")
+        });
+        let rms_json = dbfs_json(to_dbfs((sum_squares / samples_written.max(1) as f64).sqrt()));
+        for ((sidecar, out_path), (output, size)) in
+            sidecars.iter().zip(&out_paths).zip(saved.iter().zip(&output_sizes))
+        {
+            let Some((path, file, encoder)) = sidecar else { continue };
+            let json = format!(
+                "{{\"schema\": \"arec-sidecar\", \"version\": 1, \"file\": {}, \
+                 \"device\": {{\"name\": {}, \"id\": {}}}, \
+                 \"format\": {{\"input_rate\": {mix_rate}, \"input_channels\": {mix_channels}, \
+                 \"sample_rate\": {out_rate}, \"channels\": {target_channels}}}, \
+                 \"encoder\": {encoder}, \
+                 \"started_at\": {}, \"ended_at\": {}, \"duration_secs\": {recorded_secs:.3}, \
+                 \"size_bytes\": {size}, \"peak_dbfs\": {}, \"rms_dbfs\": {rms_json}, \
+                 \"clipped_samples\": {clipped_total}, \"drops\": {drops}, \"stop_reason\": {}\
+                 {checksum_json}{}}}\n",
+                json_string(file),
+                json_string(&device_name),
+                device_id.as_deref().map_or("null".to_string(), json_string),
+                json_string(&started_at.iso8601()),
+                json_string(&ended_at.iso8601()),
+                dbfs_json(peak_dbfs),
+                json_string(stop_reason),
+                segments_json(out_path, output)
+            );
+            std::fs::write(path, json).with_context(|| format!("failed to write {path}"))?;
+            info!(console, "Sidecar: {path}");
+        }
+        let failed_commands = on_complete.map_or(0, |commands| commands.wait());
+
+        if args.no_summary {
+        } else if args.json {
+            let drift_json = drift
+                .ppm()
+                .map_or_else(|| "null".to_string(), |ppm| format!("{ppm:.1}"));
+            let peak_json = if peak_dbfs.is_finite() {
+                format!("{peak_dbfs:.2}")
+            } else {
+                "null".to_string()
+            };
+            // The first output is reported at the top level (null with only a samples
+            // callback); several are also listed one by one
+            let first_segments = match (out_paths.first(), saved.first()) {
+                (Some(path), Some(output)) => segments_json(path, output),
+                _ => String::new(),
+            };
+            let json_or_null =
+                |s: Option<&String>| s.map_or("null".to_string(), |s| json_string(s));
+            let outputs_json = if out_paths.len() <= 1 {
+                String::new()
+            } else {
+                let list: Vec<String> = out_paths
+                    .iter()
+                    .zip(&mode_descs)
+                    .zip(saved.iter().zip(&output_sizes))
+                    .map(|((path, mode), (output, size))| {
+                        format!(
+                            "{{\"file\": {}, \"mode\": {}, \"size_bytes\": {size}{}}}",
+                            json_string(path),
+                            json_string(mode),
+                            segments_json(path, output)
+                        )
+                    })
+                    .collect();
+                format!(", \"outputs\": [{}]", list.join(", "))
+            };
+            let vox_json = match &vox {
+                Some(vox) => format!(
+                    ", \"vox_segments\": {}, \"vox_written_secs\": {:.3}",
+                    vox.segments.len(),
+                    vox.secs(vox.written)
+                ),
+                None => String::new(),
+            };
+            report!(console,
+                "{{\"file\": {}, \"mode\": {}, \"stop_reason\": {}, \"duration_secs\": {recorded_secs:.3}, \
+                 \"frames\": {frames_captured}, \"size_bytes\": {size_bytes}, \"avg_kbps\": {avg_kbps:.1}, \
+                 \"peak_dbfs\": {peak_json}, \"clipped\": {clipped}, \"clipped_samples\": [{}], \"float_overs\": {float_overs}, \"encoder_queue_full\": {queue_full_events}, \
+                 \"glitches\": {glitches}, \"drops\": {drops}, \"short_reads\": {short_reads}, \
+                 \"event_timeouts\": {event_timeouts}, \"gap_frames_filled\": {gap_frames_filled}, \"limited_samples\": {limited_samples}, \
+                 \"gated_secs\": {gated_secs:.3}, \"paused_secs\": {paused_secs:.3}, \"device_switches\": {device_switches}, \"reconnects\": {reconnects}, \"drift_ppm\": {drift_json}, \
+                 \"drift_frames_inserted\": {}, \"drift_frames_dropped\": {}{vox_json}{checksum_json}{first_segments}{outputs_json}}}",
+                json_or_null(out_paths.first()),
+                json_or_null(mode_descs.first()),
+                json_string(stop_reason),
+                clipped_samples.iter().map(u64::to_string).collect::<Vec<_>>().join(", "),
+                drift.inserted,
+                drift.dropped,
+            );
+        } else {
+            report!(console, "Summary:");
+            report!(console, "  Duration: {recorded_secs:.2} s");
+            report!(console, "  Frames captured: {frames_captured}");
+            if out_paths.len() == 1 {
+                report!(console, "  File size: {}", human_bytes(size_bytes));
+            } else if !out_paths.is_empty() {
+                let each: Vec<String> = out_paths
+                    .iter()
+                    .zip(&output_sizes)
+                    .map(|(path, &size)| format!("{path} {}", human_bytes(size)))
+                    .collect();
+                report!(console, "  File size: {} ({})", human_bytes(size_bytes), each.join(", "));
+            }
+            if !out_paths.is_empty() {
+                report!(console, "  Average data rate (approx): {avg_kbps:.1} kbps");
+            }
+            report!(console, "  Peak: {peak_dbfs:.1} dBFS");
+            if clipped {
+                let per_channel: Vec<String> = clipped_samples
+                    .iter()
+                    .enumerate()
+                    .map(|(ch, count)| format!("ch {}: {count}", ch + 1))
+                    .collect();
+                let per_channel = per_channel.join(", ");
+                report!(console, "  Clipping: yes, {clipped_total} samples ({per_channel})");
+            } else {
+                report!(console, "  Clipping: no");
+            }
+            if let Some(checksum) = &checksum {
+                let (kind, digest) = (checksum.name(), checksum.hex());
+                report!(console, "  Checksum ({kind} of the samples): {digest}");
+            }
+            if float_overs > 0 {
+                report!(console,
+                    "  Source overs: {float_overs} float samples at or above 0 dBFS before conversion"
+                );
+            }
+            if gap_frames_filled > 0 {
+                let filled_ms = gap_frames_filled * 1000 / mix_rate as u64;
+                report!(console, "  Glitches: {glitches} ({filled_ms} ms of silence inserted)");
+            } else {
+                report!(console, "  Glitches: {glitches}");
+            }
+            report!(console,
+                "  Drops: {drops} ({glitches} glitches, {short_reads} short reads, {event_timeouts} event timeouts)"
+            );
+            if limiter.is_some() {
+                report!(console, "  Limited samples: {limited_samples}");
+            }
+            if gate.is_some() {
+                report!(console, "  Gated: {gated_secs:.1} s");
+            }
+            if paused_secs > 0.0 {
+                report!(console, "  Paused: {paused_secs:.1} s (not in the file)");
+            }
+            if let Some(vox) = &vox {
+                report!(console,
+                    "  VOX: {} active stretch{}, {:.1} s of {:.1} s written",
+                    vox.segments.len(),
+                    if vox.segments.len() == 1 { "" } else { "es" },
+                    vox.secs(vox.written),
+                    vox.secs(vox.position)
+                );
+            }
+            if queue_full_events > 0 {
+                report!(console, "  Encoder backlog stalls: {queue_full_events}");
+            }
+            if device_switches > 0 {
+                report!(console, "  Device switches: {device_switches}");
+            }
+            if reconnects > 0 {
+                report!(console, "  Reconnects: {reconnects}");
+            }
+            if let Some(ppm) = drift.ppm() {
+                report!(console, "  Clock drift: {ppm:+.1} ppm (device against system clock)");
+            }
+            if args.drift_correct {
+                report!(console,
+                    "  Drift correction: {} frames inserted, {} dropped",
+                    drift.inserted,
+                    drift.dropped
+                );
+            }
+        }
+        // Overs at the source are clamped before --gain, so only a lower volume there helps
+        if float_overs > 0 {
+            eprintln!(
+                "Warning: the source went over full scale {float_overs} times before conversion; \
+                 turn down the volume of the playing program or device"
+            );
+        } else if clipped {
+            let suggested = args.gain.unwrap_or(0.0) - 3.0;
+            eprintln!(
+                "Warning: {clipped_total} samples clipped; try --gain {suggested} or --limiter -1"
+            );
+        }
+        match capture_error {
+            Some(e) => Err(e),
+            None if failed_commands > 0 => bail!(
+                "{failed_commands} --on-complete command{} failed",
+                if failed_commands == 1 { "" } else { "s" }
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+// The output checks and warnings that don't need the device: stream and file-only
+// options, and options for formats that aren't being written.
+fn validate_outputs(
+    args: &RecordArgs,
+    outputs: &[(String, OutputFormat)],
+    streaming: bool,
+) -> Result<()> {
+    let has_format = |format: OutputFormat| outputs.iter().any(|(_, f)| *f == format);
+    let (has_mp3, has_flac, has_pcm) = (
+        has_format(OutputFormat::Mp3),
+        has_format(OutputFormat::Flac),
+        has_format(OutputFormat::Pcm),
+    );
+    if streaming {
+        if let Some((template, _)) = outputs
+            .iter()
+            .find(|(template, f)| is_stream_out(template) && !matches!(f, OutputFormat::Mp3 | OutputFormat::Pcm))
+        {
+            bail!("--out {template} needs --format mp3 or pcm (WAV and FLAC headers are patched when the file is finished)");
+        }
+        if args.splits() {
+            bail!("--split-seconds, --split-mb and --split-on-silence write several files; they can't be used with --out - or tcp://");
+        }
+    }
+    if args.cue.is_some() && outputs.first().is_none_or(|(template, _)| is_stream_out(template)) {
+        bail!("--cue describes the first --out, which needs to be a file (not stdout, TCP, or only a custom sink)");
+    }
+    if args.cue_markers && args.split_mb.is_some() {
+        bail!("--cue-markers keeps one file, so --split-mb has nothing to cut; use --split-seconds or --split-on-silence");
+    }
+
+    if args.vbr.is_some() && args.kbps.is_some() {
+        eprintln!("Warning: --vbr is set; ignoring --kbps");
+    }
+
+    if let Some(target) = args.normalize_peak {
+        if let Some((template, f)) = outputs
+            .iter()
+            .find(|(_, f)| !matches!(f, OutputFormat::Wav | OutputFormat::Flac))
+        {
+            bail!(
+                "--normalize-peak needs --format wav or flac for every output, but {template} is {} \
+                 (MP3 and raw PCM are written as they stream, so they can't be rescaled afterwards)",
+                f.extension()
+            );
+        }
+        if !(target <= 0.0 && target.is_finite()) {
+            bail!("--normalize-peak must be a level at or below 0 dBFS, got {target}");
+        }
+    }
+
+    if args.trim_silence
+        && let Some((template, f)) = outputs.iter().find(|(_, f)| *f != OutputFormat::Wav)
+    {
+        bail!(
+            "--trim-silence needs --format wav for every output, but {template} is {} \
+             (trailing silence is cut when the file is finished)",
+            f.extension()
+        );
+    }
+
+    if !has_mp3 && args.stereo_mode.is_some() {
+        eprintln!("Warning: --stereo-mode only applies to MP3; ignoring it");
+    }
+
+    let mp3_flags = args.mp3_copyright || args.mp3_copy || args.mp3_emphasis != Mp3Emphasis::None;
+    if !has_mp3 && mp3_flags {
+        eprintln!(
+            "Warning: --mp3-copyright, --mp3-copy and --mp3-emphasis only apply to MP3; ignoring them"
+        );
+    }
+
+    if !has_mp3 && !has_flac && args.cue.is_none() && !args.tags.is_empty() {
+        eprintln!("Warning: tags are only written to MP3 and FLAC; ignoring --title/--artist/...");
+    }
+
+    if !has_flac && args.flac_level.is_some() {
+        eprintln!("Warning: --flac-level only applies to FLAC; ignoring it");
+    }
+
+    if !has_pcm && (args.pcm_le || args.pcm_be) {
+        eprintln!("Warning: --pcm-le and --pcm-be only apply to raw PCM; ignoring them");
+    }
+    Ok(())
+}
+
+// Encoding and file writing run on their own thread per output so a slow encode can't
+// delay the next wait_for_event. Chunks go over a bounded channel (backpressure) and
+// emptied buffers come back on a shared one for reuse. The encoder isn't Send, so the
+// sink is created on that thread; `ready` reports whether that worked. Returns the
+// threads and the receiver of the emptied buffers; `written` counts the bytes written.
+fn spawn_encoders(
+    args: &RecordArgs,
+    jobs: Vec<EncoderJob>,
+    stream: StreamFormat,
+    kbps: u32,
+    written: &Arc<AtomicU64>,
+    console: Console,
+) -> Result<(Vec<EncoderThread>, Receiver<Vec<i16>>)> {
+    let (free_tx, free_rx) = bounded::<Vec<i16>>(ENCODER_QUEUE_LEN * jobs.len());
+    let mut encoders = Vec::with_capacity(jobs.len());
+    let flush_every = (args.flush_interval > 0).then(|| Duration::from_secs(args.flush_interval));
+    for job in jobs {
+        let (chunk_tx, chunk_rx) = bounded::<Vec<i16>>(ENCODER_QUEUE_LEN);
+        let (ready_tx, ready_rx) = bounded::<Result<()>>(1);
+        let free_tx = free_tx.clone();
+        let sink_args = args.clone();
+        let written = written.clone();
+        let handle = std::thread::Builder::new()
+            .name("encoder".to_string())
+            .spawn(move || {
+                let sink = match job {
+                    EncoderJob::File(target, path) => {
+                        open_output(&sink_args, target, &path, stream, kbps, console)
+                    }
+                    EncoderJob::Custom(factory) => factory(stream),
+                };
+                match sink {
+                    Ok(sink) => {
+                        let _ = ready_tx.send(Ok(()));
+                        run_encoder(sink, chunk_rx, free_tx, flush_every, &written)
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        Ok(EncoderOutput::default())
+                    }
+                }
+            })
+            .context("failed to start encoder thread")?;
+        encoders.push(EncoderThread {
+            chunks: Some(chunk_tx),
+            handle: Some(handle),
+        });
+        ready_rx
+            .recv()
+            .map_err(|_| anyhow!("encoder thread exited before opening the output"))??;
+    }
+    Ok((encoders, free_rx))
+}
+
+// The processing chain from the decoded device audio to what the encoders get: --gain,
+// resampling, the filters and gate on every device channel, --gain-ch, the channel
+// selection or downmix, then the stereo tools, compressor and limiter.
+struct Processing {
+    pre_gain: Option<f64>,
+    // Optional sample-rate conversion of pcm_buf (output swapped back into pcm_buf)
+    resampler: Option<LinearResampler>,
+    resample_buf: Vec<i16>,
+    highpass: Option<HighPass>,
+    lowpass: Option<LowPass>,
+    equalizer: Option<Equalizer>,
+    gate: Option<NoiseGate>,
+    channel_gains: Option<Vec<f32>>,
+    channel_map: Option<ChannelMap>,
+    mono_weights: Option<Vec<i32>>,
+    // Speaker-aware stereo downmix coefficients (None = unknown layout, use averaging)
+    stereo_matrix: Option<Vec<[i32; 2]>>,
+    stereo_tools: Option<StereoTools>,
+    compressor: Option<Compressor>,
+    // Optional peak limiter on enc_buf; its output is swapped back into enc_buf
+    limiter: Option<Limiter>,
+    limit_buf: Vec<i16>,
+    mix_channels: usize,
+    target_channels: usize,
+    // --auto-mono chose mono; warn once if the channels part later
+    mono_watch: bool,
+}
+
+impl Processing {
+    // Checks the processing options against the device and output formats. auto_lowpass
+    // is the band limit used when --lowpass isn't given.
+    fn new(
+        args: &RecordArgs,
+        mix: StreamFormat,
+        out_rate: u32,
+        target_channels: usize,
+        probed_mono: bool,
+        auto_lowpass: Option<f32>,
+    ) -> Result<Self> {
+        let mix_rate = mix.sample_rate;
+        let mix_channels = mix.channels;
+        let mono = args.mono || probed_mono;
+
+        // --channel-map and --gain-ch name device channels; make sure they exist
+        let highest = mix_channels - 1;
+        if let Some(map) = &args.channel_map
+            && map.left.max(map.right) > highest
+        {
+            bail!(
+                "--channel-map L={},R={}: the device has {mix_channels} channels (0-{highest})",
+                map.left,
+                map.right
+            );
+        }
+        let mut channel_gains = vec![1.0f32; mix_channels];
+        for &(channel, db) in &args.gain_ch {
+            if channel > highest {
+                bail!(
+                    "--gain-ch {channel}={db}: the device has {mix_channels} channels (0-{highest})"
+                );
+            }
+            channel_gains[channel] *= db_to_gain(db);
+        }
+        let channel_gains = (!args.gain_ch.is_empty()).then_some(channel_gains);
+
+        // --mono weights: given, or from the speaker layout
+        let mono_weights = if args.mono_coeffs.is_empty() {
+            mono_downmix_weights(mix.channel_mask, mix_channels)
+        } else {
+            if args.mono_coeffs.len() != mix_channels {
+                bail!(
+                    "--mono-coeffs: got {} weights, but the device has {mix_channels} channels",
+                    args.mono_coeffs.len()
+                );
+            }
+            if let Some(w) = args.mono_coeffs.iter().find(|w| !w.is_finite()) {
+                bail!("--mono-coeffs: '{w}' is not a weight");
+            }
+            args.mono_coeffs.clone()
+        };
+        let mono_weights = mono.then(|| mono_coefficients(&mono_weights));
+
+        if let Some(hz) = args.highpass
+            && !(hz > 0.0 && hz < out_rate as f32 / 2.0)
+        {
+            bail!(
+                "--highpass must be between 0 and {} Hz (half the output rate), got {hz}",
+                out_rate / 2
+            );
+        }
+
+        let lowpass_hz = match args.lowpass {
+            Some(0.0) => None,
+            Some(hz) if !(hz > 0.0 && hz < out_rate as f32 / 2.0) => {
+                bail!(
+                    "--lowpass must be between 0 and {} Hz (half the output rate), got {hz}",
+                    out_rate / 2
+                )
+            }
+            Some(hz) => Some(hz),
+            None => auto_lowpass,
+        };
+
+        if let Some(band) = args.eq.iter().find(|band| band.freq_hz >= out_rate as f32 / 2.0) {
+            bail!(
+                "--eq band at {} Hz must be below {} Hz (half the output rate)",
+                band.freq_hz,
+                out_rate / 2
+            );
+        }
+
+        if let Some(db) = args.gain
+            && !db.is_finite()
+        {
+            bail!("--gain must be a number of dB, got {db}");
+        }
+
+        if let Some(width) = args.stereo_width
+            && !(0.0..=2.0).contains(&width)
+        {
+            bail!("--stereo-width must be between 0.0 and 2.0, got {width}");
+        }
+        let stereo_tools = StereoTools::new(args.stereo_width, args.swap_lr, args.invert_phase);
+        if stereo_tools.is_some() && target_channels != 2 {
+            bail!(
+                "--stereo-width, --swap-lr and --invert-phase need a two-channel output (this one has {target_channels})"
+            );
+        }
+
+        if let Some(threshold) = args.gate
+            && !(threshold < 0.0 && threshold.is_finite())
+        {
+            bail!("--gate must be a threshold below 0 dBFS, got {threshold}");
+        }
+
+        if !args.compress_makeup.is_finite() {
+            bail!("--compress-makeup must be a number of dB, got {}", args.compress_makeup);
+        }
+
+        if let Some(ceiling) = args.limiter
+            && !(ceiling <= 0.0 && ceiling.is_finite())
+        {
+            bail!("--limiter must be a ceiling at or below 0 dBFS, got {ceiling}");
+        }
+
+        Ok(Processing {
+            pre_gain: args.gain.map(|db| db_to_gain(db) as f64),
+            resampler: (out_rate != mix_rate)
+                .then(|| LinearResampler::new(mix_channels, mix_rate, out_rate)),
+            resample_buf: Vec::new(),
+            // Optional DC-blocking high-pass, run at the output rate before downmix
+            highpass: args.highpass.map(|hz| HighPass::new(mix_channels, hz, out_rate)),
+            // Optional band limit (--lowpass, or automatic for low-bitrate MP3), after the
+            // high-pass
+            lowpass: lowpass_hz.map(|hz| LowPass::new(mix_channels, hz, out_rate)),
+            // Optional --eq bands, after the band limits
+            equalizer: (!args.eq.is_empty())
+                .then(|| Equalizer::new(mix_channels, &args.eq, out_rate)),
+            // Optional noise gate on every device channel, after the filters
+            gate: args.gate.map(|threshold| {
+                let (attack, release) = (args.gate_attack, args.gate_release);
+                NoiseGate::new(mix_channels, threshold, attack, release, out_rate)
+            }),
+            channel_gains,
+            channel_map: args.channel_map,
+            mono_weights,
+            stereo_matrix: stereo_downmix_matrix(mix.channel_mask, mix_channels),
+            stereo_tools,
+            // Optional compressor on enc_buf, ahead of the limiter
+            compressor: args.compress.map(|compression| {
+                Compressor::new(
+                    target_channels,
+                    compression,
+                    args.compress_attack,
+                    args.compress_release,
+                    args.compress_makeup,
+                    out_rate,
+                )
+            }),
+            limiter: args.limiter.map(|ceiling| Limiter::new(target_channels, ceiling, out_rate)),
+            limit_buf: Vec::new(),
+            mix_channels,
+            target_channels,
+            mono_watch: probed_mono,
+        })
+    }
+
+    // Runs one block of decoded audio (pcm_buf, device channels) through the chain into
+    // enc_buf (output channels). enc_buf may stay empty while the limiter fills its
+    // lookahead.
+    fn process(&mut self, pcm_buf: &mut Vec<i16>, enc_buf: &mut Vec<i16>) {
+        let mix_channels = self.mix_channels;
+
+        // --gain on the decoded (and mixed) audio, ahead of filters and the limiter
+        if let Some(gain) = self.pre_gain {
+            scale_samples(pcm_buf, gain);
+        }
+
+        if let Some(resampler) = &mut self.resampler {
+            self.resample_buf.clear();
+            resampler.process_into(pcm_buf, &mut self.resample_buf);
+            std::mem::swap(pcm_buf, &mut self.resample_buf);
+        }
+
+        if let Some(filter) = &mut self.highpass {
+            filter.process(pcm_buf);
+        }
+        if let Some(filter) = &mut self.lowpass {
+            filter.process(pcm_buf);
+        }
+        if let Some(equalizer) = &mut self.equalizer {
+            equalizer.process(pcm_buf);
+        }
+        if let Some(gate) = &mut self.gate {
+            gate.process(pcm_buf);
+        }
+
+        // Per-channel trim (--gain-ch) before the channels are combined
+        if let Some(gains) = &self.channel_gains {
+            apply_channel_gains(pcm_buf, gains);
+        }
+
+        // --auto-mono chose mono from the first second; say so once if the channels part
+        if self.mono_watch && !channels_match(pcm_buf) {
+            eprintln!(
+                "\nWarning: the channels differ now, but --auto-mono chose mono; the recording \
+                 stays mono"
+            );
+            self.mono_watch = false;
+        }
+
+        // Prepare encoder input into enc_buf (reuse)
+        enc_buf.clear();
+
+        if let Some(map) = &self.channel_map {
+            // Explicit channel selection replaces the downmix
+            remap_channels_into(pcm_buf, mix_channels, map, enc_buf);
+        } else if let Some(weights) = &self.mono_weights
+            && mix_channels > 1
+        {
+            downmix_to_mono_into(pcm_buf, weights, enc_buf);
+        } else if mix_channels == self.target_channels {
+            // Fast path: no downmix, just copy
+            enc_buf.extend_from_slice(pcm_buf);
+        } else {
+            // Downmix into enc_buf without allocating a new Vec each time
+            match &self.stereo_matrix {
+                Some(matrix) => downmix_matrix_to_stereo_into(pcm_buf, matrix, enc_buf),
+                None => downmix_n_to_stereo_into(pcm_buf, mix_channels, enc_buf),
+            }
+        }
+
+        if let Some(tools) = &self.stereo_tools {
+            tools.process(enc_buf);
+        }
+        if let Some(compressor) = &mut self.compressor {
+            compressor.process(enc_buf);
+        }
+
+        // --limiter output lags by the lookahead; what's left is flushed by finish
+        if let Some(limiter) = &mut self.limiter {
+            self.limit_buf.clear();
+            limiter.process_into(enc_buf, &mut self.limit_buf);
+            std::mem::swap(enc_buf, &mut self.limit_buf);
+        }
+    }
+}
+
+// Totals for the end-of-recording summary
+struct RecordingStats {
+    frames_captured: u64,
+    peak_abs: i32,
+    // Peak since the last --progress json line
+    progress_peak: i32,
+    // Written samples at full scale, per output channel
+    clipped_samples: Vec<u64>,
+    // For the overall RMS level (--sidecar)
+    sum_squares: f64,
+    samples_written: u64,
+    checksum: Option<Checksum>,
+    queue_full_events: u64,
+    glitches: u64,
+    // Potential drops the device doesn't flag: packets read short, and event timeouts on a
+    // capture device (loopback is silent while nothing plays, so timeouts are normal there)
+    short_reads: u64,
+    event_timeouts: u64,
+    gap_frames_filled: u64,
+    device_switches: u64,
+    reconnects: u64,
+    drift: DriftTracker,
+}
+
+impl RecordingStats {
+    fn new(args: &RecordArgs, mix_rate: u32, mix_channels: usize, target_channels: usize) -> Self {
+        RecordingStats {
+            frames_captured: 0,
+            peak_abs: 0,
+            progress_peak: 0,
+            clipped_samples: vec![0; target_channels],
+            sum_squares: 0.0,
+            samples_written: 0,
+            checksum: args.checksum.map(Checksum::new),
+            queue_full_events: 0,
+            glitches: 0,
+            short_reads: 0,
+            event_timeouts: 0,
+            gap_frames_filled: 0,
+            device_switches: 0,
+            reconnects: 0,
+            drift: DriftTracker::new(mix_rate, mix_channels),
+        }
+    }
+
+    fn drops(&self) -> u64 {
+        self.glitches + self.short_reads + self.event_timeouts
+    }
+
+    // Samples about to be written: peak, clipping, RMS and checksum
+    fn count_written(&mut self, samples: &[i16]) {
+        for &s in samples {
+            self.peak_abs = self.peak_abs.max((s as i32).abs());
+            self.progress_peak = self.progress_peak.max((s as i32).abs());
+        }
+        count_clipped(samples, &mut self.clipped_samples);
+        self.sum_squares += samples.iter().map(|&s| s as f64 * s as f64).sum::<f64>();
+        self.samples_written += samples.len() as u64;
+        if let Some(checksum) = &mut self.checksum {
+            checksum.update(samples);
+        }
+    }
+}
+
+pub(crate) fn stop_requested(rx: &Receiver<()>) -> bool {
+    rx.try_recv().is_ok()
+}
+
+// Whether the recording is paused (space bar or --control-pipe), and for how long in all
+#[derive(Default)]
+pub(crate) struct Pause {
+    since: Option<Instant>,
+    total: Duration,
+}
+
+impl Pause {
+    fn is_paused(&self) -> bool {
+        self.since.is_some()
+    }
+
+    fn set(&mut self, paused: bool) {
+        match (paused, self.since) {
+            (true, None) => self.since = Some(Instant::now()),
+            (false, Some(since)) => {
+                self.total += since.elapsed();
+                self.since = None;
+            }
+            _ => {}
+        }
+    }
+
+    // Paused time so far, including a pause still going on
+    fn total(&self) -> Duration {
+        self.total + self.since.map_or(Duration::ZERO, |since| since.elapsed())
+    }
+
+    fn state(&self) -> &'static str {
+        if self.is_paused() { "paused" } else { "recording" }
+    }
+}
+
+// Sleeps until the local clock reaches `target`; returns true if Ctrl+C came first.
+// The clock is re-read at least once a second, so sleep/hibernate and clock changes
+// (including daylight saving) don't shift the start.
+fn wait_until(target: &LocalTime, stop_rx: &Receiver<()>) -> bool {
+    loop {
+        let wait = target.wait_from(&local_now());
+        if wait.is_zero() {
+            return false;
+        }
+        if stop_rx.recv_timeout(wait.min(Duration::from_secs(1))).is_ok() {
+            return true;
+        }
+    }
+}
+
+// Prints "Starting in N..." once per second on stderr; returns true if Ctrl+C cut it short
+pub(crate) fn countdown(secs: u64, stop_rx: &Receiver<()>) -> Result<bool> {
+    for n in (1..=secs).rev() {
+        eprint!("\rStarting in {n}...{:<10}", "");
+        io::stderr().flush()?;
+        if stop_rx.recv_timeout(Duration::from_secs(1)).is_ok() {
+            eprintln!();
+            return Ok(true);
+        }
+    }
+    if secs > 0 {
+        eprint!("\r{:<30}\r", "");
+    }
+    Ok(false)
+}
+
+pub(crate) fn human_bytes(bytes: u64) -> String {
+    const KB: f64 = 1024.0;
+    const MB: f64 = KB * 1024.0;
+    const GB: f64 = MB * 1024.0;
+    let b = bytes as f64;
+
+    if b >= GB {
+        format!("{:.2} GiB", b / GB)
+    } else if b >= MB {
+        format!("{:.2} MiB", b / MB)
+    } else if b >= KB {
+        format!("{:.2} KiB", b / KB)
+    } else {
+        format!("{bytes} B")
+    }
+}
+
+// Per-channel peak/RMS over the samples seen since the last redraw.
+struct LevelMeter {
+    channels: usize,
+    peak: Vec<i32>,
+    sum_sq: Vec<f64>,
+    frames: usize,
+    clipped: bool,
+}
+
+const METER_WIDTH: usize = 16;
+
+const METER_FLOOR_DB: f64 = -48.0;
+
+impl LevelMeter {
+    fn new(channels: usize) -> Self {
+        Self {
+            channels,
+            peak: vec![0; channels],
+            sum_sq: vec![0.0; channels],
+            frames: 0,
+            clipped: false,
+        }
+    }
+
+    fn update(&mut self, samples: &[i16]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (ch, &s) in frame.iter().enumerate() {
+                let a = (s as i32).abs();
+                self.peak[ch] = self.peak[ch].max(a);
+                self.sum_sq[ch] += (s as f64) * (s as f64);
+            }
+        }
+        self.frames += samples.len() / self.channels;
+        self.clipped |= self.peak.iter().any(|&p| p >= i16::MAX as i32);
+    }
+
+    // Render e.g. "L [=========|   ]  -6.0 dB  R [...]" and reset for the next window.
+    // More than two channels leave no room for bars, so those show each channel's peak.
+    fn take_line(&mut self) -> String {
+        let mut line = String::new();
+        if self.channels > 2 {
+            for (ch, &peak) in self.peak.iter().enumerate() {
+                line.push_str(&format!(" {} {:.1}", ch + 1, to_dbfs(peak as f64)));
+            }
+        } else {
+            let labels: &[&str] = if self.channels == 2 { &["L", "R"] } else { &["M"] };
+            for (ch, label) in labels.iter().enumerate() {
+                let rms = if self.frames > 0 {
+                    (self.sum_sq[ch] / self.frames as f64).sqrt()
+                } else {
+                    0.0
+                };
+                let peak_db = to_dbfs(self.peak[ch] as f64);
+                let rms_cells = meter_cells(to_dbfs(rms));
+                let peak_cell = meter_cells(peak_db);
+
+                let bar: String = (0..METER_WIDTH)
+                    .map(|i| {
+                        if peak_cell > 0 && i == peak_cell - 1 {
+                            '|'
+                        } else if i < rms_cells {
+                            '='
+                        } else {
+                            ' '
+                        }
+                    })
+                    .collect();
+                line.push_str(&format!(" {label} [{bar}] {peak_db:>6.1} dB"));
+            }
+        }
+        if self.clipped {
+            line.push_str("  CLIP");
+        }
+
+        self.peak.fill(0);
+        self.sum_sq.fill(0.0);
+        self.frames = 0;
+        self.clipped = false;
+        line
+    }
+}
+
+// Number of filled meter cells for a dBFS level (METER_FLOOR_DB..0 dB).
+fn meter_cells(db: f64) -> usize {
+    let frac = ((db - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0);
+    (frac * METER_WIDTH as f64).round() as usize
+}
+
+fn print_meter_line(s: &str) -> Result<()> {
+    // Same \r trick as print_status_line, on stderr so stdout stays clean for scripts.
+    eprint!("\r{s:<80}");
+    io::stderr().flush()?;
+    Ok(())
+}
+
+fn print_status_line(s: &str, console: Console) -> Result<()> {
+    // \r = carriage return (return to start of line), no newline.
+    // Pad with spaces to overwrite leftovers from previous longer line.
+    if console.stdout_audio {
+        eprint!("\r{s:<80}");
+        return Ok(());
+    }
+    print!("\r{s:<80}");
+    io::stdout().flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{bail, Result};
+    use std::sync::{Arc, Mutex};
+
+    use crate::args::RecordArgs;
+    use crate::capture::{CaptureRead, CaptureSource, ReplaySource, StreamFormat};
+    use crate::args::OutputFormat;
+    use crate::sinks::{create_sink, Sink};
+    use crate::Console;
+
+    use super::Recorder;
+
+    // What a CollectSink saw: the stream format, every sample, and how often finish ran
+    #[derive(Default)]
+    struct Collected {
+        format: Option<StreamFormat>,
+        samples: Vec<i16>,
+        finished: usize,
+    }
+
+    struct CollectSink(Arc<Mutex<Collected>>);
+
+    impl Sink for CollectSink {
+        fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+            self.0.lock().unwrap().samples.extend_from_slice(samples);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<()> {
+            self.0.lock().unwrap().finished += 1;
+            Ok(())
+        }
+
+        fn bytes_written(&self) -> u64 {
+            self.0.lock().unwrap().samples.len() as u64 * 2
+        }
+    }
+
+    // Settings from `options`, printing nothing
+    fn quiet_args(options: &[&str]) -> RecordArgs {
+        let quiet = ["--quiet", "--no-meter", "--no-pause-key"];
+        let mut config = RecordArgs::parse_from(quiet.iter().chain(options)).unwrap();
+        config.no_summary = true;
+        config
+    }
+
+    // Records `source` into a CollectSink with `options` (no files)
+    fn record(
+        options: &[&str],
+        source: impl CaptureSource + Send + 'static,
+    ) -> (Result<()>, Collected) {
+        let config = quiet_args(options);
+        let collected = Arc::new(Mutex::new(Collected::default()));
+        let sink_state = collected.clone();
+        let result = Recorder::new(config)
+            .source(source)
+            .sink(move |format| {
+                sink_state.lock().unwrap().format = Some(format);
+                Ok(Box::new(CollectSink(sink_state)) as Box<dyn Sink>)
+            })
+            .start()
+            .and_then(|recording| recording.wait());
+        let collected = std::mem::take(&mut *collected.lock().unwrap());
+        (result, collected)
+    }
+
+    // A 1 kHz-ish tone with a different offset on each channel
+    fn tone_sample(frame: usize, channel: usize) -> i16 {
+        ((frame as f32 * 0.13).sin() * 8000.0) as i16 + channel as i16 * 100
+    }
+
+    fn tone(frames: usize, channels: usize) -> Vec<i16> {
+        (0..frames * channels).map(|i| tone_sample(i / channels, i % channels)).collect()
+    }
+
+    #[test]
+    fn replayed_samples_reach_the_sink_unchanged() {
+        let input = tone(48_000, 2);
+        let (result, collected) =
+            record(&["--duration", "0"], ReplaySource::new(input.clone(), 2, 48_000));
+        result.unwrap();
+        let format = collected.format.unwrap();
+        assert_eq!((format.sample_rate, format.channels), (48_000, 2));
+        assert_eq!(collected.samples.len(), input.len());
+        assert!(collected.samples == input);
+        // The source ran out, the recording ended without an error and finished the sink once
+        assert_eq!(collected.finished, 1);
+    }
+
+    #[test]
+    fn surround_source_is_downmixed_to_stereo() {
+        let input = tone(4_800, 6);
+        let (result, collected) =
+            record(&["--duration", "0"], ReplaySource::new(input, 6, 48_000));
+        result.unwrap();
+        assert_eq!(collected.format.unwrap().channels, 2);
+        assert_eq!(collected.samples.len(), 4_800 * 2);
+        assert!(collected.samples.iter().any(|&s| s != 0));
+    }
+
+    #[test]
+    fn resampled_recording_keeps_its_length() {
+        let input = tone(48_000, 2);
+        let options = ["--duration", "0", "--resample", "44100"];
+        let (result, collected) = record(&options, ReplaySource::new(input, 2, 48_000));
+        result.unwrap();
+        assert_eq!(collected.format.unwrap().sample_rate, 44_100);
+        let frames = collected.samples.len() / 2;
+        assert!(frames.abs_diff(44_100) <= 1, "{frames} frames");
+    }
+
+    struct FailingSink;
+
+    impl Sink for FailingSink {
+        fn write_samples(&mut self, _samples: &[i16]) -> Result<()> {
+            bail!("disk full");
+        }
+
+        fn finish(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn bytes_written(&self) -> u64 {
+            0
+        }
+    }
+
+    #[test]
+    fn sink_error_ends_the_recording_with_that_error() {
+        let error = Recorder::new(quiet_args(&["--duration", "0"]))
+            .source(ReplaySource::new(tone(48_000, 2), 2, 48_000))
+            .sink(|_| Ok(Box::new(FailingSink) as Box<dyn Sink>))
+            .start()
+            .and_then(|recording| recording.wait())
+            .unwrap_err();
+        assert_eq!(error.to_string(), "disk full");
+    }
+
+    #[test]
+    fn source_without_channels_or_rate_is_refused() {
+        for (channels, rate) in [(0, 48_000), (2, 0)] {
+            let (result, collected) =
+                record(&["--duration", "1"], ReplaySource::new(vec![0; 960], channels, rate));
+            let error = result.unwrap_err().to_string();
+            assert_eq!(
+                error,
+                format!("replay reports {channels} channels at {rate} Hz; both must be above 0")
+            );
+            assert!(collected.samples.is_empty());
+        }
+    }
+
+    // A ReplaySource whose every read is preceded by `idle_reads` waits that time out, as
+    // a microphone that delivers nothing would
+    struct IdleSource {
+        replay: ReplaySource,
+        idle_reads: usize,
+        idle_left: usize,
+    }
+
+    impl CaptureSource for IdleSource {
+        fn name(&self) -> String {
+            self.replay.name()
+        }
+
+        fn format(&self) -> StreamFormat {
+            self.replay.format()
+        }
+
+        fn read(&mut self, out: &mut Vec<i16>) -> Result<CaptureRead> {
+            if self.idle_left > 0 {
+                self.idle_left -= 1;
+                return Ok(CaptureRead::Idle { expected: true });
+            }
+            self.idle_left = self.idle_reads;
+            self.replay.read(out)
+        }
+    }
+
+    fn idle_source(input: Vec<i16>, idle_reads: usize) -> IdleSource {
+        IdleSource {
+            replay: ReplaySource::new(input, 2, 48_000),
+            idle_reads,
+            idle_left: idle_reads,
+        }
+    }
+
+    #[test]
+    fn event_timeouts_dont_end_the_recording() {
+        let input = tone(48_000, 2);
+        let (result, collected) = record(&["--duration", "0"], idle_source(input.clone(), 3));
+        result.unwrap();
+        assert!(collected.samples == input);
+        assert_eq!(collected.finished, 1);
+    }
+
+    #[test]
+    fn event_timeouts_count_as_drops() {
+        let options = ["--duration", "0", "--max-drops", "5"];
+        let (result, collected) = record(&options, idle_source(tone(48_000, 2), 3));
+        let error = result.unwrap_err().to_string();
+        assert_eq!(error, "6 drops exceeded --max-drops 5");
+        // Three timeouts, a 10 ms block, three more: the block is still written and finished
+        assert_eq!(collected.samples.len(), 480 * 2);
+        assert_eq!(collected.finished, 1);
+    }
+
+    // Frames the sink got for a 1 s --duration of a 3 s source, with `options` added
+    fn one_second_frames(options: &[&str]) -> (usize, u32) {
+        let options: Vec<&str> = ["--duration", "1"].iter().chain(options).copied().collect();
+        let source = ReplaySource::new(tone(3 * 48_000, 2), 2, 48_000);
+        let (result, collected) = record(&options, source);
+        result.unwrap();
+        (collected.samples.len() / 2, collected.format.unwrap().sample_rate)
+    }
+
+    #[test]
+    fn duration_is_cut_to_within_one_packet() {
+        // A packet of ReplaySource is 10 ms
+        for options in [&[][..], &["--resample", "44100"], &["--limiter", "-1"]] {
+            let (frames, rate) = one_second_frames(options);
+            let packet = rate as usize / 100;
+            assert!(frames.abs_diff(rate as usize) <= packet, "{options:?}: {frames} frames");
+        }
+    }
+
+    // An endless tone delivered in real time, 10 ms per read, like a device
+    struct LiveSource {
+        frame: usize,
+    }
+
+    impl CaptureSource for LiveSource {
+        fn name(&self) -> String {
+            "live".to_string()
+        }
+
+        fn format(&self) -> StreamFormat {
+            ReplaySource::new(Vec::new(), 2, 48_000).format()
+        }
+
+        fn read(&mut self, out: &mut Vec<i16>) -> Result<CaptureRead> {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            let frames = self.frame..self.frame + 480;
+            out.extend(frames.flat_map(|f| [tone_sample(f, 0), tone_sample(f, 1)]));
+            self.frame += 480;
+            Ok(CaptureRead::Block {
+                frames: 480,
+                lost_frames: None,
+                short: false,
+                timestamp: None,
+            })
+        }
+    }
+
+    // Sizes of a WAV file's RIFF chunk and data chunk, checked against the file length
+    fn check_wav(bytes: &[u8]) -> usize {
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(u32_at(4) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[8..12], b"WAVE");
+        let mut at = 12;
+        while &bytes[at..at + 4] != b"data" {
+            at += 8 + u32_at(at + 4) as usize;
+        }
+        let data = u32_at(at + 4) as usize;
+        assert_eq!(at + 8 + data, bytes.len());
+        data
+    }
+
+    // Walks the MPEG-1 Layer III frames after the ID3v2 tag; they have to end at the end
+    // of the file. Returns the frame count.
+    fn check_mp3(bytes: &[u8]) -> usize {
+        const KBPS: [usize; 15] =
+            [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+        let mut at = 0;
+        if bytes.starts_with(b"ID3") {
+            let size = bytes[6..10].iter().fold(0, |size, &b| size << 7 | b as usize);
+            at = 10 + size;
+        }
+        let mut frames = 0;
+        while at < bytes.len() {
+            let head = &bytes[at..at + 4];
+            assert!(head[0] == 0xFF && head[1] & 0xFE == 0xFA, "no frame at byte {at}");
+            assert_eq!(head[2] >> 2 & 3, 1, "48 kHz");
+            let kbps = KBPS[(head[2] >> 4) as usize];
+            at += 144 * kbps * 1000 / 48_000 + (head[2] >> 1 & 1) as usize;
+            frames += 1;
+        }
+        assert_eq!(at, bytes.len());
+        frames
+    }
+
+    #[test]
+    fn stopped_recording_leaves_valid_files() {
+        let dir =
+            std::env::temp_dir().join(format!("arec-stopped-recording-{}", std::process::id()));
+        let dir_name = dir.to_string_lossy().into_owned();
+        let options = [
+            "--duration", "0", "--out-dir", &dir_name, "--overwrite",
+            "--out", "stopped.wav", "--format", "wav", "--out", "stopped.mp3", "--format", "mp3",
+        ];
+        let recording = Recorder::new(quiet_args(&options))
+            .source(LiveSource { frame: 0 })
+            .start()
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        recording.stop_handle().stop();
+        recording.wait().unwrap();
+
+        let wav = std::fs::read(dir.join("stopped.wav")).unwrap();
+        let mp3 = std::fs::read(dir.join("stopped.mp3")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let data = check_wav(&wav);
+        assert!(data > 0 && data.is_multiple_of(4), "{data} bytes of samples");
+        // The Info frame, then about 42 frames a second of audio
+        let frames = check_mp3(&mp3);
+        assert!(frames > 5, "{frames} frames");
+        assert!(mp3.windows(4).any(|w| w == b"Info"));
+    }
+
+    #[test]
+    fn dropped_sinks_leave_valid_files() {
+        let dir = std::env::temp_dir().join(format!("arec-dropped-sinks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let args = quiet_args(&[]);
+        let stream = StreamFormat { sample_rate: 48_000, channels: 2, channel_mask: 3 };
+        let console = Console { quiet: true, stdout_audio: false };
+        // Half a second of a 1 kHz tone, written in capture-sized pieces
+        let samples: Vec<i16> = (0..48_000)
+            .map(|i| ((i / 2) as f32 * std::f32::consts::TAU / 48.0).sin())
+            .map(|x| (x * 8000.0) as i16)
+            .collect();
+
+        let mut files = Vec::new();
+        let outputs = [(OutputFormat::Wav, "dropped.wav"), (OutputFormat::Mp3, "dropped.mp3")];
+        for (format, name) in outputs {
+            let path = dir.join(name).to_string_lossy().into_owned();
+            let mut sink = create_sink(&args, format, &path, stream, 128, console).unwrap();
+            for piece in samples.chunks(960) {
+                sink.write_samples(piece).unwrap();
+            }
+            // No finish(): the sink's Drop has to leave the file playable
+            drop(sink);
+            files.push(std::fs::read(&path).unwrap());
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(check_wav(&files[0]), samples.len() * 2);
+        // The Info frame, then the whole frames encoded before the drop
+        let frames = check_mp3(&files[1]);
+        assert!(frames > 10, "{frames} frames");
+        assert!(files[1].windows(4).any(|w| w == b"Info"));
+    }
+}
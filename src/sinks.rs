@@ -0,0 +1,1608 @@
+//! Output sinks: the [`Sink`] trait, the wrappers around the encoders, output naming and
+//! the encoder threads.
+
+use anyhow::{anyhow, bail, Context, Result};
+use crossbeam_channel::{Receiver, Sender};
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, IsTerminal, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::args::{OutputFormat, RecordArgs, DEFAULT_FLAC_LEVEL};
+use crate::capture::StreamFormat;
+use crate::dsp::{normalize_gain, rms_dbfs, scale_samples, standard_channel_mask};
+use crate::net::{
+    icecast_target, is_stream_out, IcecastSource, StreamServer, TcpOutput, ICECAST_PREFIX,
+    SERVE_PREFIX, TCP_PREFIX,
+};
+use crate::record::SinkFactory;
+use crate::time::{local_now, LocalTime};
+use crate::Console;
+use flac::FlacSink;
+use mp3::{mp3_samples_per_frame, now_playing, Mp3Sink};
+use wav::WavSink;
+
+mod flac;
+mod mp3;
+mod wav;
+
+pub(crate) use mp3::{nearest_supported, valid_bitrates, Mp3Header};
+
+// The output sink (MP3 encoder, WAV or FLAC writer), optionally split into segments.
+pub(crate) fn open_output<'a>(
+    args: &'a RecordArgs,
+    target: OutputTarget,
+    out_path: &str,
+    stream: StreamFormat,
+    kbps: u32,
+    console: Console,
+) -> Result<Box<dyn Sink + 'a>> {
+    let StreamFormat { sample_rate: out_rate, channels: target_channels, .. } = stream;
+    // --cue needs the track boundaries, so it goes through SegmentedSink even for one file
+    let sink: Box<dyn Sink + 'a> = if args.has_split() || args.cue.is_some() {
+        let markers_only = !args.splits();
+        let single_path = out_path.to_string();
+        // Cut on an MP3 frame boundary so no segment ends with a padded partial frame
+        let frame_len = match target.format {
+            OutputFormat::Mp3 => mp3_samples_per_frame(out_rate) as u64,
+            OutputFormat::Wav | OutputFormat::Flac | OutputFormat::Pcm => 1,
+        };
+        let segment_samples = args.split_seconds.map(|secs| {
+            let frames = secs * out_rate as u64;
+            (frames / frame_len).max(1) * frame_len * target_channels as u64
+        });
+        Box::new(SegmentedSink::new(
+            segment_samples,
+            args.split_mb.map(|mb| mb * 1024 * 1024),
+            frame_len * target_channels as u64,
+            args.split_on_silence.then(|| {
+                SilenceSplit::new(args.silence_threshold, args.silence_min, out_rate, target_channels)
+            }),
+            markers_only,
+            args.cue.is_some() && args.tags.auto_tags,
+            move |index| {
+                let path = if markers_only {
+                    single_path.clone()
+                } else {
+                    target.namer.path(Some(index))
+                };
+                let sink = create_sink(args, target.format, &path, stream, kbps, console)?;
+                Ok((path.clone(), target.completing(sink, &path)))
+            },
+        )?)
+    } else {
+        let sink = create_sink(args, target.format, out_path, stream, kbps, console)?;
+        target.completing(sink, out_path)
+    };
+
+    // Fades cover the whole recording, so they wrap the segments rather than each file
+    let ms_to_frames = |ms: Option<u32>| (out_rate as u64 * ms.unwrap_or(0) as u64 / 1000) as usize;
+    let fade_in_frames = ms_to_frames(args.fade_in);
+    let fade_out_frames = ms_to_frames(args.fade_out);
+    if fade_in_frames == 0 && fade_out_frames == 0 {
+        return Ok(sink);
+    }
+    Ok(Box::new(FadeSink::new(
+        sink,
+        target_channels,
+        fade_in_frames,
+        fade_out_frames,
+    )))
+}
+
+// Builds the output sink for one file: MP3 encoder, WAV/FLAC writer with the optional
+// normalize/trim wrappers, or raw PCM.
+pub(crate) fn create_sink(
+    args: &RecordArgs,
+    format: OutputFormat,
+    path: &str,
+    stream: StreamFormat,
+    kbps: u32,
+    console: Console,
+) -> Result<Box<dyn Sink>> {
+    let StreamFormat { sample_rate, channels, channel_mask } = stream;
+    log::debug!(
+        "Creating {} output {path}: {sample_rate} Hz, {channels} channel(s)",
+        format.extension()
+    );
+    if args.append && !is_stream_out(path) && Path::new(path).exists() {
+        return append_sink(args, format, path, sample_rate, channels, kbps, console);
+    }
+    // `--out -` is only accepted for MP3 and raw PCM (checked before recording starts)
+    // A stream is decoded from any frame, so it gets no ID3 tag
+    let untagged = path.starts_with(ICECAST_PREFIX) || path.starts_with(SERVE_PREFIX);
+    let output = if path == "-" {
+        MpegOutput::Stdout(io::stdout().lock())
+    } else if let Some(address) = path.strip_prefix(TCP_PREFIX) {
+        MpegOutput::Tcp(TcpOutput::connect(address, args.reconnect, None)?)
+    } else if let Some(address) = path.strip_prefix(SERVE_PREFIX) {
+        MpegOutput::Serve(StreamServer::bind(address, console)?)
+    } else if path.starts_with(ICECAST_PREFIX) {
+        let Some(target) = icecast_target(args)? else {
+            bail!("{path}: use --icecast to stream to an Icecast server");
+        };
+        let source = IcecastSource::new(target, args, sample_rate, kbps, channels);
+        MpegOutput::Tcp(TcpOutput::connect(&source.target.address.clone(), true, Some(source))?)
+    } else {
+        // --out-dir (and any folder its tokens expand to) is created with the first file in it
+        if args.out_dir.is_some()
+            && let Some(parent) = Path::new(path).parent()
+        {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        MpegOutput::File(BufWriter::new(
+            File::create(path).with_context(|| format!("create {path}"))?,
+        ))
+    };
+    let sink: Box<dyn Sink> = match format {
+        OutputFormat::Mp3 => Box::new(Mp3Sink::new(
+            output,
+            sample_rate,
+            kbps,
+            channels,
+            Mp3Header::new(args, channels),
+            args.vbr,
+            &if untagged { Vec::new() } else { args.tags.resolve(console).to_id3v2() },
+        )?),
+        OutputFormat::Wav => {
+            let MpegOutput::File(file) = output else {
+                bail!("WAV can't be written to stdout or TCP");
+            };
+            let wav = WavSink::new(
+                file,
+                sample_rate,
+                channels as u16,
+                channel_mask,
+            )?;
+            let trim = args.trim_silence.then(|| {
+                let padding_frames = (sample_rate as u64 * args.trim_padding as u64 / 1000) as usize;
+                (args.silence_threshold, padding_frames)
+            });
+            match (args.normalize_peak, trim) {
+                (Some(target_dbfs), trim) => {
+                    let temp_path = format!("{path}.part");
+                    let normalizing =
+                        NormalizingSink::new(Box::new(wav), temp_path, target_dbfs, console)?;
+                    match trim {
+                        Some((threshold, padding)) => Box::new(TrimSilenceSink::new(
+                            normalizing,
+                            channels,
+                            threshold,
+                            padding,
+                        )),
+                        None => Box::new(normalizing),
+                    }
+                }
+                (None, Some((threshold, padding))) => {
+                    Box::new(TrimSilenceSink::new(wav, channels, threshold, padding))
+                }
+                (None, None) => Box::new(wav),
+            }
+        }
+        OutputFormat::Flac => {
+            let MpegOutput::File(file) = output else {
+                bail!("FLAC can't be written to stdout or TCP");
+            };
+            // FLAC implies the standard layout; any other one is stored as a tag
+            let mask_tag = (channel_mask != standard_channel_mask(channels)).then_some(channel_mask);
+            let flac = FlacSink::new(
+                file,
+                sample_rate,
+                channels,
+                args.flac_level.unwrap_or(DEFAULT_FLAC_LEVEL),
+                &args.tags.resolve(console).to_vorbis_comment(mask_tag),
+            )?;
+            match args.normalize_peak {
+                Some(target_dbfs) => Box::new(NormalizingSink::new(
+                    Box::new(flac),
+                    format!("{path}.part"),
+                    target_dbfs,
+                    console,
+                )?),
+                None => Box::new(flac),
+            }
+        }
+        OutputFormat::Pcm => Box::new(PcmSink {
+            out: output,
+            big_endian: args.pcm_be,
+            bytes_written: 0,
+        }),
+    };
+    Ok(sink)
+}
+
+// --append: continues the existing file at `path` after checking that its format is the
+// recording's. The ID3 tag of an MP3 and the FLAC-only wrappers don't apply.
+fn append_sink(
+    args: &RecordArgs,
+    format: OutputFormat,
+    path: &str,
+    sample_rate: u32,
+    channels: usize,
+    kbps: u32,
+    console: Console,
+) -> Result<Box<dyn Sink>> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("open {path}"))?;
+    let sink: Box<dyn Sink> = match format {
+        OutputFormat::Mp3 => Box::new(Mp3Sink::append(
+            file,
+            path,
+            sample_rate,
+            kbps,
+            channels,
+            Mp3Header::new(args, channels),
+            args.vbr,
+        )?),
+        OutputFormat::Wav => Box::new(WavSink::append(file, path, sample_rate, channels as u16)?),
+        OutputFormat::Flac => {
+            bail!("--append: {path} is FLAC, which can't be continued; use WAV, MP3 or raw PCM")
+        }
+        OutputFormat::Pcm => {
+            let end = file.seek(SeekFrom::End(0))?;
+            if end % (channels as u64 * 2) != 0 {
+                bail!("--append: {path} doesn't hold whole {channels}-channel 16-bit frames");
+            }
+            Box::new(PcmSink {
+                out: MpegOutput::File(BufWriter::new(file)),
+                big_endian: args.pcm_be,
+                bytes_written: end,
+            })
+        }
+    };
+    info!(console, "Appending to {path}");
+    Ok(sink)
+}
+
+// --on-complete: the command is started for each file as soon as it is finished, so it
+// runs while the recording goes on, and the recording waits for all of them at the end.
+pub(crate) struct CompletionCommands {
+    template: String,
+    console: Console,
+    // Each file with its command, or why the command couldn't start
+    started: Mutex<Vec<(String, io::Result<Child>)>>,
+}
+
+impl CompletionCommands {
+    pub(crate) fn new(template: String, console: Console) -> Self {
+        Self {
+            template,
+            console,
+            started: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn start(&self, path: &str) {
+        let line = self.template.replace("{file}", path);
+        let mut command = shell_command(&line);
+        // Nothing may end up in an MP3 or PCM stream on stdout
+        command.stdin(Stdio::null());
+        if self.console.stdout_audio {
+            command.stdout(io::stderr());
+        }
+        let child = command.spawn();
+        self.started
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((path.to_string(), child));
+    }
+
+    // Waits for every command and reports the ones that failed; returns how many did
+    pub(crate) fn wait(&self) -> usize {
+        let started = std::mem::take(&mut *self.started.lock().unwrap_or_else(|e| e.into_inner()));
+        let mut failed = 0;
+        for (path, child) in started {
+            let status = child.and_then(|mut child| child.wait());
+            match status {
+                Ok(status) if status.success() => info!(self.console, "On complete: {path} done"),
+                Ok(status) => {
+                    eprintln!("Warning: --on-complete for {path} failed ({status})");
+                    failed += 1;
+                }
+                Err(e) => {
+                    eprintln!("Warning: --on-complete for {path} could not run: {e}");
+                    failed += 1;
+                }
+            }
+        }
+        failed
+    }
+}
+
+// The command line goes to the shell as it is, so quotes and pipes work as typed
+#[cfg(windows)]
+fn shell_command(line: &str) -> Command {
+    use std::os::windows::process::CommandExt;
+    let mut command = Command::new("cmd");
+    command.arg("/C").raw_arg(line);
+    command
+}
+
+#[cfg(not(windows))]
+fn shell_command(line: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(line);
+    command
+}
+
+// Finishes and closes the file before its --on-complete command starts, so the command
+// sees the patched header and all the data
+struct CompletionSink {
+    inner: Option<Box<dyn Sink>>,
+    path: String,
+    bytes_written: u64,
+    commands: Arc<CompletionCommands>,
+}
+
+impl Sink for CompletionSink {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        match &mut self.inner {
+            Some(inner) => inner.write_samples(samples),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if let Some(mut inner) = self.inner.take() {
+            inner.finish()?;
+            self.bytes_written = inner.bytes_written();
+            drop(inner);
+            self.commands.start(&self.path);
+        }
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.inner.as_ref().map_or(self.bytes_written, |inner| inner.bytes_written())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match &mut self.inner {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Rotating output for --split-seconds / --split-mb / --split-on-silence: once a segment
+// reaches its length or size cap, or a track ends in silence, the current sink is
+// finished and a new one opened, so each file stands on its own. Size-triggered cuts wait
+// for the next `align_samples` boundary (a whole MP3 frame), so no segment ends with a
+// padded partial frame. With `markers_only` (--cue-markers, or --cue without splitting)
+// there is one file, and a cut only starts a new track in `spans`.
+struct SegmentedSink<F: FnMut(usize) -> Result<(String, Box<dyn Sink>)>> {
+    open: F,
+    segment_samples: Option<u64>,
+    max_bytes: Option<u64>,
+    align_samples: u64,
+    silence: Option<SilenceSplit>,
+    markers_only: bool,
+    cut_pending: bool, // markers_only: the next write starts a new track
+    track_titles: bool, // ask the media session for each track's title (--auto-tags)
+    current: Option<Box<dyn Sink>>,
+    in_segment: u64,
+    finished_bytes: u64,
+    paths: Vec<String>,
+    position: u64, // samples received, including skipped silence
+    spans: Vec<SegmentSpan>,
+}
+
+/// Where a segment (or `--cue-markers` track) starts and ends, and what was playing
+/// when it started.
+#[derive(Clone, Debug)]
+pub struct SegmentSpan {
+    /// First sample, counted in interleaved samples since the recording started
+    pub start: u64,
+    /// One past the last sample
+    pub end: u64,
+    /// Media session title when the span started (`--cue` with `--auto-tags`)
+    pub title: Option<String>,
+    /// Media session artist when the span started
+    pub performer: Option<String>,
+}
+
+impl SegmentSpan {
+    fn new(position: u64, track_titles: bool) -> Self {
+        let (title, performer) = match track_titles.then(now_playing).flatten() {
+            Some(np) => (np.title, np.artist),
+            None => (None, None),
+        };
+        Self {
+            start: position,
+            end: position,
+            title,
+            performer,
+        }
+    }
+}
+
+// --split-on-silence: a quiet run of `min_samples` ends the track, and silence is skipped
+// until the next sound starts a new one.
+struct SilenceSplit {
+    threshold_dbfs: f64,
+    min_samples: u64,
+    quiet_run: u64,
+    in_track: bool, // sound arrived since the last cut
+}
+
+impl SilenceSplit {
+    fn new(threshold_dbfs: f32, min_ms: u32, sample_rate: u32, channels: usize) -> Self {
+        Self {
+            threshold_dbfs: threshold_dbfs as f64,
+            min_samples: sample_rate as u64 * min_ms as u64 / 1000 * channels as u64,
+            quiet_run: 0,
+            in_track: false,
+        }
+    }
+}
+
+impl<F: FnMut(usize) -> Result<(String, Box<dyn Sink>)>> SegmentedSink<F> {
+    fn new(
+        segment_samples: Option<u64>,
+        max_bytes: Option<u64>,
+        align_samples: u64,
+        silence: Option<SilenceSplit>,
+        markers_only: bool,
+        track_titles: bool,
+        open: F,
+    ) -> Result<Self> {
+        let mut sink = Self {
+            open,
+            segment_samples,
+            max_bytes,
+            align_samples,
+            silence,
+            markers_only,
+            cut_pending: false,
+            track_titles,
+            current: None,
+            in_segment: 0,
+            finished_bytes: 0,
+            paths: Vec::new(),
+            position: 0,
+            spans: Vec::new(),
+        };
+        // Open the first file up front so a recording always produces one
+        sink.open_next()?;
+        Ok(sink)
+    }
+
+    fn open_next(&mut self) -> Result<()> {
+        // With markers_only the one file stays open and only a new track starts
+        if !self.markers_only || self.current.is_none() {
+            let (path, sink) = (self.open)(self.paths.len())?;
+            self.current = Some(sink);
+            self.paths.push(path);
+        }
+        self.cut_pending = false;
+        self.spans.push(SegmentSpan::new(self.position, self.track_titles));
+        self.in_segment = 0;
+        Ok(())
+    }
+
+    // End the current segment: finish its file, or just mark the track boundary
+    fn cut(&mut self) -> Result<()> {
+        if self.markers_only {
+            self.cut_pending = true;
+            Ok(())
+        } else {
+            self.finish_current()
+        }
+    }
+
+    fn over_size_cap(&self) -> bool {
+        match (self.max_bytes, &self.current) {
+            (Some(max), Some(current)) => current.bytes_written() >= max,
+            _ => false,
+        }
+    }
+
+    fn finish_current(&mut self) -> Result<()> {
+        if let Some(mut done) = self.current.take() {
+            done.finish()?;
+            self.finished_bytes += done.bytes_written();
+        }
+        Ok(())
+    }
+
+    // Write through the length and size cuts
+    fn write_split(&mut self, mut samples: &[i16]) -> Result<()> {
+        while !samples.is_empty() {
+            if self.current.is_none() || self.cut_pending {
+                self.open_next()?;
+            }
+            let mut room = self
+                .segment_samples
+                .map_or(usize::MAX, |n| (n - self.in_segment) as usize);
+            if self.over_size_cap() {
+                // Over the cap: write up to the next frame boundary, then cut
+                let into_frame = self.in_segment % self.align_samples;
+                room = room.min((self.align_samples - into_frame) as usize);
+            }
+            let (now, rest) = samples.split_at(room.min(samples.len()));
+            if let Some(current) = &mut self.current {
+                current.write_samples(now)?;
+            }
+            if let Some(span) = self.spans.last_mut() {
+                // The first file is opened up front; its span starts with its first sample
+                if self.in_segment == 0 {
+                    span.start = self.position;
+                }
+                span.end = self.position + now.len() as u64;
+            }
+            self.position += now.len() as u64;
+            self.in_segment += now.len() as u64;
+            samples = rest;
+
+            // Segment full: finish it now; the next one opens when more audio arrives
+            let time_full = self.segment_samples == Some(self.in_segment);
+            let size_full = self.in_segment.is_multiple_of(self.align_samples) && self.over_size_cap();
+            if time_full || size_full {
+                self.cut()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<F: FnMut(usize) -> Result<(String, Box<dyn Sink>)>> Sink for SegmentedSink<F> {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        let mut track_done = false;
+        if let Some(silence) = &mut self.silence {
+            if rms_dbfs(samples) < silence.threshold_dbfs {
+                if !silence.in_track {
+                    // Silence before the first track or between tracks isn't stored in
+                    // its own files; a single file keeps it ahead of the next track
+                    if self.markers_only
+                        && let Some(current) = &mut self.current
+                    {
+                        current.write_samples(samples)?;
+                    }
+                    self.position += samples.len() as u64;
+                    return Ok(());
+                }
+                silence.quiet_run += samples.len() as u64;
+                track_done = silence.quiet_run >= silence.min_samples;
+            } else {
+                silence.quiet_run = 0;
+                silence.in_track = true;
+            }
+        }
+        self.write_split(samples)?;
+        if track_done && let Some(silence) = &mut self.silence {
+            silence.in_track = false;
+            silence.quiet_run = 0;
+            self.cut()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finish_current()
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.finished_bytes + self.current.as_ref().map_or(0, |c| c.bytes_written())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match &mut self.current {
+            Some(current) => current.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn split(&mut self) -> Result<()> {
+        if self.current.is_some() && self.in_segment > 0 {
+            self.cut()?;
+        }
+        Ok(())
+    }
+
+    fn segment_paths(&self) -> &[String] {
+        if self.markers_only { &[] } else { &self.paths }
+    }
+
+    fn segment_spans(&self) -> &[SegmentSpan] {
+        &self.spans
+    }
+}
+
+// One --out with its --format; the namer expands the template when files are created.
+pub(crate) struct OutputTarget {
+    pub(crate) namer: OutputNamer,
+    pub(crate) format: OutputFormat,
+    pub(crate) on_complete: Option<Arc<CompletionCommands>>,
+}
+
+impl OutputTarget {
+    // A file sink that runs --on-complete once it is finished; stdout has no file to hand over
+    fn completing(&self, sink: Box<dyn Sink>, path: &str) -> Box<dyn Sink> {
+        match &self.on_complete {
+            Some(commands) if !is_stream_out(path) => Box::new(CompletionSink {
+                inner: Some(sink),
+                path: path.to_string(),
+                bytes_written: 0,
+                commands: commands.clone(),
+            }),
+            _ => sink,
+        }
+    }
+}
+
+// Pairs each --out with its --format. The Nth format goes with the Nth output and later
+// outputs reuse the last one; without --out there's one output per format, named
+// output.<ext> (or {device}-{date}-{time}.<ext> in --out-dir).
+pub(crate) fn resolve_outputs(
+    outs: &[String],
+    formats: &[OutputFormat],
+    out_dir: Option<&str>,
+) -> Result<Vec<(String, OutputFormat)>> {
+    if let Some(dir) = out_dir {
+        return resolve_outputs(outs, formats, None)?
+            .into_iter()
+            .map(|(template, format)| {
+                let template = if outs.is_empty() {
+                    format!("{{device}}-{{date}}-{{time}}.{}", format.extension())
+                } else {
+                    template
+                };
+                if is_stream_out(&template) {
+                    return Ok((template, format));
+                }
+                if Path::new(&template).has_root() {
+                    bail!("--out {template} is a full path; give --out-dir a file name or relative path instead");
+                }
+                Ok((Path::new(dir).join(template).to_string_lossy().into_owned(), format))
+            })
+            .collect();
+    }
+    if !outs.is_empty() && formats.len() > outs.len() {
+        bail!(
+            "{} --format values for {} --out paths; give each --format its own --out",
+            formats.len(),
+            outs.len()
+        );
+    }
+    let format_at = |i: usize| {
+        formats
+            .get(i)
+            .or(formats.last())
+            .copied()
+            .unwrap_or(OutputFormat::Mp3)
+    };
+    if outs.is_empty() {
+        let count = formats.len().max(1);
+        return Ok((0..count)
+            .map(|i| (format!("output.{}", format_at(i).extension()), format_at(i)))
+            .collect());
+    }
+    Ok(outs
+        .iter()
+        .enumerate()
+        .map(|(i, out)| (out.clone(), format_at(i)))
+        .collect())
+}
+
+// Two outputs writing one file would corrupt it. Names are compared the way Windows
+// does: as absolute paths, ignoring case.
+// `paths` holds each --out template with the first file name it expands to.
+pub(crate) fn check_distinct_outputs(paths: &[(&str, String)]) -> Result<()> {
+    let mut seen: Vec<(String, &str)> = Vec::new();
+    for &(template, ref path) in paths {
+        let key = std::path::absolute(path)
+            .map(|p| p.to_string_lossy().to_lowercase())
+            .unwrap_or_else(|_| path.to_lowercase());
+        if let Some((_, first)) = seen.iter().find(|(k, _)| *k == key) {
+            bail!("--out {first} and --out {template} resolve to the same file ({path}); give each output its own path");
+        }
+        seen.push((key, template));
+    }
+    Ok(())
+}
+
+// A file written under a fixed name (--out without a numbering token, --cue, --vox-log,
+// --sidecar) that already exists is only replaced with --overwrite or after a y at the
+// prompt; without a terminal to ask on, that's an error.
+pub(crate) fn confirm_overwrite(option: &str, path: &str, overwrite: bool) -> Result<()> {
+    if overwrite || !Path::new(path).exists() {
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+        bail!("{option} {path} already exists (use --overwrite to replace it)");
+    }
+    eprint!("{path} already exists. Overwrite it? [y/N] ");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).context("failed to read the answer")?;
+    if !matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes") {
+        bail!("{option} {path} already exists; not replaced");
+    }
+    Ok(())
+}
+
+// CUE sheet for --cue: a FILE per segment of a split recording, or one FILE with a TRACK
+// per span (--cue-markers). `samples_per_sec` converts span positions to time.
+pub(crate) fn cue_sheet(
+    args: &RecordArgs,
+    format: OutputFormat,
+    cue_path: &str,
+    out_path: &str,
+    output: &EncoderOutput,
+    samples_per_sec: u64,
+) -> String {
+    let file_type = match format {
+        OutputFormat::Mp3 => "MP3",
+        OutputFormat::Wav | OutputFormat::Flac => "WAVE",
+        OutputFormat::Pcm if args.pcm_be => "MOTOROLA",
+        OutputFormat::Pcm => "BINARY",
+    };
+    // Cue strings can't contain double quotes
+    let quoted = |text: &str| format!("\"{}\"", text.replace('"', "'"));
+    // Files next to the cue sheet are named without a directory, others by absolute path
+    let cue_dir = std::path::absolute(cue_path)
+        .ok()
+        .and_then(|p| p.parent().map(Path::to_path_buf));
+    let reference = |path: &str| {
+        let absolute = std::path::absolute(path).unwrap_or_else(|_| PathBuf::from(path));
+        match (&cue_dir, absolute.parent(), absolute.file_name()) {
+            (Some(dir), Some(parent), Some(name)) if dir == parent => {
+                name.to_string_lossy().into_owned()
+            }
+            _ => absolute.to_string_lossy().into_owned(),
+        }
+    };
+
+    let mut cue = String::new();
+    if let Some(genre) = &args.tags.genre {
+        cue.push_str(&format!("REM GENRE {}\n", quoted(genre)));
+    }
+    if let Some(year) = args.tags.year {
+        cue.push_str(&format!("REM DATE {year}\n"));
+    }
+    if let Some(artist) = &args.tags.artist {
+        cue.push_str(&format!("PERFORMER {}\n", quoted(artist)));
+    }
+    if let Some(title) = args.tags.album.as_ref().or(args.tags.title.as_ref()) {
+        cue.push_str(&format!("TITLE {}\n", quoted(title)));
+    }
+    let track = |cue: &mut String, number: usize, span: &SegmentSpan, index: u64| {
+        cue.push_str(&format!("  TRACK {number:02} AUDIO\n"));
+        if let Some(title) = &span.title {
+            cue.push_str(&format!("    TITLE {}\n", quoted(title)));
+        }
+        if let Some(performer) = &span.performer {
+            cue.push_str(&format!("    PERFORMER {}\n", quoted(performer)));
+        }
+        cue.push_str(&format!("    INDEX 01 {}\n", cue_time(index, samples_per_sec)));
+    };
+    if output.segments.is_empty() {
+        cue.push_str(&format!("FILE {} {file_type}\n", quoted(&reference(out_path))));
+        for (i, span) in output.spans.iter().enumerate() {
+            // The first track starts with the file, including any silence before it
+            let index = if i == 0 { 0 } else { span.start };
+            track(&mut cue, i + 1, span, index);
+        }
+    } else {
+        for (i, (path, span)) in output.segments.iter().zip(&output.spans).enumerate() {
+            cue.push_str(&format!("FILE {} {file_type}\n", quoted(&reference(path))));
+            track(&mut cue, i + 1, span, 0);
+        }
+    }
+    cue
+}
+
+// MM:SS:FF with 75 frames per second (minutes may exceed 99)
+fn cue_time(samples: u64, samples_per_sec: u64) -> String {
+    let frames = samples * 75 / samples_per_sec.max(1);
+    format!("{:02}:{:02}:{:02}", frames / 75 / 60, frames / 75 % 60, frames % 75)
+}
+
+// Expands --out templates into concrete file names.
+// Tokens: {date} (YYYY-MM-DD), {time} (HH-MM-SS, local), {device} (sanitized
+// friendly name), {n} (segment number, 000 without splitting).
+pub(crate) struct OutputNamer {
+    pub(crate) template: String,
+    pub(crate) device: String,
+    pub(crate) overwrite: bool,
+}
+
+impl OutputNamer {
+    // Path for a single file (None) or for segment `index` of a split recording.
+    pub(crate) fn path(&self, segment: Option<usize>) -> String {
+        let now = local_now();
+        let n = segment.unwrap_or(0);
+        let mut path = expand_out_template(&self.template, &now, &self.device, n);
+        // Without {n}, segments get a numbered suffix: out.mp3 -> out-000.mp3
+        if let Some(index) = segment
+            && !self.template.contains("{n}")
+        {
+            path = segment_path(&path, index);
+        }
+        if self.overwrite || !self.numbers_existing(segment.is_some()) {
+            path
+        } else {
+            unique_path(&path)
+        }
+    }
+
+    // Whether an existing file gets a new numbered name rather than going through
+    // confirm_overwrite: the template has a {n}, {date} or {time} token, or the file is a
+    // segment of a split recording (numbered as if the template ended in {n}).
+    pub(crate) fn numbers_existing(&self, split: bool) -> bool {
+        split || ["{n}", "{date}", "{time}"].iter().any(|token| self.template.contains(token))
+    }
+}
+
+fn expand_out_template(template: &str, now: &LocalTime, device: &str, n: usize) -> String {
+    template
+        .replace(
+            "{date}",
+            &format!("{:04}-{:02}-{:02}", now.year, now.month, now.day),
+        )
+        .replace(
+            "{time}",
+            &format!("{:02}-{:02}-{:02}", now.hour, now.minute, now.second),
+        )
+        .replace("{device}", device)
+        .replace("{n}", &format!("{n:03}"))
+}
+
+// Keep letters, digits, '-', '_' and '.'; everything else becomes a single '_'.
+// "Speakers (Realtek(R) Audio)" -> "Speakers_Realtek_R_Audio"
+pub(crate) fn sanitize_filename(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') {
+            out.push(c);
+        } else if !out.ends_with('_') {
+            out.push('_');
+        }
+    }
+    let out = out.trim_matches(|c| c == '_' || c == '.');
+    if out.is_empty() {
+        "device".to_string()
+    } else {
+        out.to_string()
+    }
+}
+
+// out.mp3 -> out-000.mp3, out-001.mp3, ... (same directory)
+fn segment_path(out_path: &str, index: usize) -> String {
+    numbered_path(out_path, &format!("{index:03}"))
+}
+
+// First of path, path-1, path-2, ... (before the extension) that doesn't exist yet.
+fn unique_path(path: &str) -> String {
+    if !Path::new(path).exists() {
+        return path.to_string();
+    }
+    (1..)
+        .map(|i: u32| numbered_path(path, &i.to_string()))
+        .find(|candidate| !Path::new(candidate).exists())
+        .expect("unbounded search")
+}
+
+fn numbered_path(out_path: &str, suffix: &str) -> String {
+    let path = Path::new(out_path);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("output");
+    let name = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{stem}-{suffix}.{ext}"),
+        None => format!("{stem}-{suffix}"),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+// Encoder thread body: write every chunk, recycle its buffer, and finish the sink
+// once the capture side closes the channel.
+pub(crate) fn run_encoder(
+    mut sink: Box<dyn Sink + '_>,
+    chunks: Receiver<Vec<i16>>,
+    free: Sender<Vec<i16>>,
+    flush_every: Option<Duration>,
+    written: &AtomicU64,
+) -> EncoderResult {
+    let mut last_flush = Instant::now();
+    let mut reported = 0;
+    for chunk in chunks {
+        // The capture loop never sends empty audio; an empty chunk asks for a split
+        if chunk.is_empty() {
+            sink.split()?;
+            continue;
+        }
+        sink.write_samples(&chunk)?;
+        let _ = free.try_send(chunk);
+        let bytes = sink.bytes_written();
+        written.fetch_add(bytes.saturating_sub(reported), Ordering::Relaxed);
+        reported = reported.max(bytes);
+        if let Some(every) = flush_every
+            && last_flush.elapsed() >= every
+        {
+            sink.flush()?;
+            last_flush = Instant::now();
+        }
+    }
+    sink.finish()?;
+    Ok(EncoderOutput {
+        segments: sink.segment_paths().to_vec(),
+        spans: sink.segment_spans().to_vec(),
+        bytes_written: sink.bytes_written(),
+    })
+}
+
+// What an encoder thread writes to: an --out file (with its name as reported), or a
+// Recorder::sink
+pub(crate) enum EncoderJob {
+    File(OutputTarget, String),
+    Custom(SinkFactory),
+}
+
+// What an encoder thread wrote, or why it stopped
+pub(crate) type EncoderResult = Result<EncoderOutput>;
+
+#[derive(Default)]
+pub(crate) struct EncoderOutput {
+    pub(crate) segments: Vec<String>,  // files of a split recording (empty for a single file)
+    pub(crate) spans: Vec<SegmentSpan>, // where each segment or track starts and ends
+    pub(crate) bytes_written: u64,
+}
+
+// The encoder thread and the sending side of its chunk channel. If capture ends early
+// (an error returned with `?`, or a panic), dropping this closes the channel and waits for
+// the thread, so the sink is finished (or dropped) before the process exits.
+pub(crate) struct EncoderThread {
+    pub(crate) chunks: Option<Sender<Vec<i16>>>,
+    pub(crate) handle: Option<std::thread::JoinHandle<EncoderResult>>,
+}
+
+impl EncoderThread {
+    pub(crate) fn chunks(&self) -> &Sender<Vec<i16>> {
+        self.chunks.as_ref().expect("chunk channel is open until join")
+    }
+
+    // Closing the channel lets the encoder drain and finish the sink; returns its result
+    pub(crate) fn join(mut self) -> EncoderResult {
+        self.chunks = None;
+        let handle = self.handle.take().expect("encoder thread is joined once");
+        handle.join().map_err(|_| anyhow!("encoder thread panicked"))?
+    }
+}
+
+impl Drop for EncoderThread {
+    fn drop(&mut self) {
+        self.chunks = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Where a recording's samples go: the MP3, WAV, FLAC and raw PCM writers, or a sink
+/// of your own given to [`Recorder::sink`].
+pub trait Sink {
+    /// Called on the encoder thread with each block of interleaved samples
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()>;
+
+    /// Called once after the last block; an error here is the recording's result
+    fn finish(&mut self) -> Result<()>;
+
+    /// Bytes written to the output so far (headers included), for the summary
+    fn bytes_written(&self) -> u64;
+
+    /// Called on the encoder thread every `--flush-interval` to push buffered output to
+    /// disk, so a crash loses at most that much audio
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called on the encoder thread when `--control-pipe` asks for a split: end the current
+    /// segment (or track) so the next samples start a new one. Unsplit outputs ignore it.
+    fn split(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Files written when the output is split into segments (empty for a single file)
+    fn segment_paths(&self) -> &[String] {
+        &[]
+    }
+
+    /// Start and end of each segment (or track), in samples since the recording started
+    fn segment_spans(&self) -> &[SegmentSpan] {
+        &[]
+    }
+}
+
+// Where MP3 frames (or raw PCM) go: a file, stdout for `--out -`, or a TCP connection for
+// `--out tcp://host:port` (the last two aren't seekable, so no Xing header)
+pub(crate) enum MpegOutput {
+    File(BufWriter<File>),
+    Stdout(io::StdoutLock<'static>),
+    Tcp(TcpOutput),
+    Serve(StreamServer),
+}
+
+impl Write for MpegOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MpegOutput::File(file) => file.write(buf),
+            MpegOutput::Stdout(stdout) => stdout.write(buf),
+            MpegOutput::Tcp(tcp) => tcp.write(buf),
+            MpegOutput::Serve(server) => server.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MpegOutput::File(file) => file.flush(),
+            MpegOutput::Stdout(stdout) => stdout.flush(),
+            MpegOutput::Tcp(tcp) => tcp.flush(),
+            MpegOutput::Serve(_) => Ok(()),
+        }
+    }
+}
+
+// Headerless interleaved 16-bit samples for piping into other tools (`--format pcm`).
+struct PcmSink {
+    out: MpegOutput,
+    big_endian: bool,
+    bytes_written: u64,
+}
+
+impl Sink for PcmSink {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        let bytes: Vec<u8> = if self.big_endian {
+            samples.iter().flat_map(|s| s.to_be_bytes()).collect()
+        } else {
+            samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+        };
+        self.out.write_all(&bytes)?;
+        self.bytes_written += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+// Sample format of `--format pcm` in the names ffmpeg and sox use (`-f s16le`)
+pub(crate) fn pcm_sample_format(big_endian: bool) -> &'static str {
+    if big_endian {
+        "16-bit signed big-endian (s16be)"
+    } else {
+        "16-bit signed little-endian (s16le)"
+    }
+}
+
+// Sinks that can drop already-written samples from the end (for --trim-silence).
+pub(crate) trait TruncateSink: Sink {
+    // Keep only the first `samples` interleaved samples written so far.
+    fn truncate_samples(&mut self, samples: u64) -> Result<()>;
+}
+
+// Two-pass peak normalization for the lossless formats. Samples go to a raw temp file
+// while the global peak is tracked; finish() rescales the temp data into the real sink.
+struct NormalizingSink {
+    inner: Box<dyn Sink>,
+    temp_path: String,
+    temp: BufWriter<File>,
+    temp_bytes: u64,
+    peak: i32,
+    target_dbfs: f32,
+    console: Console,
+}
+
+impl NormalizingSink {
+    fn new(
+        inner: Box<dyn Sink>,
+        temp_path: String,
+        target_dbfs: f32,
+        console: Console,
+    ) -> Result<Self> {
+        let temp = File::create(&temp_path).with_context(|| format!("create {temp_path}"))?;
+        Ok(Self {
+            inner,
+            temp_path,
+            temp: BufWriter::new(temp),
+            temp_bytes: 0,
+            peak: 0,
+            target_dbfs,
+            console,
+        })
+    }
+}
+
+impl Sink for NormalizingSink {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        for &s in samples {
+            self.peak = self.peak.max((s as i32).abs());
+            self.temp.write_all(&s.to_le_bytes())?;
+        }
+        self.temp_bytes += samples.len() as u64 * 2;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.temp.flush()?;
+        let gain = normalize_gain(self.peak, self.target_dbfs);
+        info!(self.console,
+            "Normalizing peak to {:.1} dBFS (gain {:+.1} dB)",
+            self.target_dbfs,
+            20.0 * gain.log10()
+        );
+
+        let mut reader = io::BufReader::new(
+            File::open(&self.temp_path).with_context(|| format!("open {}", self.temp_path))?,
+        );
+        let mut bytes = vec![0u8; 64 * 1024];
+        let mut samples: Vec<i16> = Vec::with_capacity(bytes.len() / 2);
+        loop {
+            let n = read_full(&mut reader, &mut bytes)?;
+            if n == 0 {
+                break;
+            }
+            samples.clear();
+            for chunk in bytes[..n].chunks_exact(2) {
+                samples.push(i16::from_le_bytes([chunk[0], chunk[1]]));
+            }
+            scale_samples(&mut samples, gain);
+            self.inner.write_samples(&samples)?;
+        }
+        drop(reader);
+        self.temp_bytes = 0;
+
+        self.inner.finish()?;
+        std::fs::remove_file(&self.temp_path)
+            .with_context(|| format!("remove {}", self.temp_path))?;
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        // Spooled samples end up in the output once finish() copies them over
+        self.inner.bytes_written() + self.temp_bytes
+    }
+
+    // The output is only written in finish(); until then the audio lives in the spool file
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.temp.flush()?)
+    }
+}
+
+// Dropped without finish(): normalize what was spooled so far into the output
+impl Drop for NormalizingSink {
+    fn drop(&mut self) {
+        if self.temp_bytes > 0 {
+            let _ = self.finish();
+        }
+    }
+}
+
+impl TruncateSink for NormalizingSink {
+    fn truncate_samples(&mut self, samples: u64) -> Result<()> {
+        self.temp.flush()?;
+        let len = self.temp.get_ref().metadata()?.len();
+        if samples * 2 < len {
+            self.temp.get_ref().set_len(samples * 2)?;
+            self.temp.seek(SeekFrom::End(0))?;
+            self.temp_bytes = samples * 2;
+        }
+        Ok(())
+    }
+}
+
+// Drops silence before the first and after the last sample at or above the threshold,
+// keeping `padding_frames` on each side. Leading silence is held in a small ring buffer;
+// trailing silence is written normally and cut from the inner sink in finish().
+struct TrimSilenceSink<S: TruncateSink> {
+    inner: S,
+    channels: usize,
+    threshold: i32,
+    padding_frames: usize,
+    started: bool,
+    lead: VecDeque<i16>,
+    written: u64,   // samples passed to `inner`
+    last_loud: u64, // sample count just after the last loud frame
+}
+
+impl<S: TruncateSink> TrimSilenceSink<S> {
+    fn new(inner: S, channels: usize, threshold_dbfs: f32, padding_frames: usize) -> Self {
+        let threshold = (i16::MAX as f64 * 10f64.powf(threshold_dbfs as f64 / 20.0)).round() as i32;
+        Self {
+            inner,
+            channels,
+            threshold,
+            padding_frames,
+            started: false,
+            lead: VecDeque::with_capacity(padding_frames * channels),
+            written: 0,
+            last_loud: 0,
+        }
+    }
+
+    fn is_loud(&self, frame: &[i16]) -> bool {
+        frame.iter().any(|&s| (s as i32).abs() >= self.threshold)
+    }
+}
+
+impl<S: TruncateSink> Sink for TrimSilenceSink<S> {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        let mut offset = 0;
+        if !self.started {
+            // Find the first loud frame; everything before it only feeds the lead-in buffer
+            let first_loud = samples
+                .chunks_exact(self.channels)
+                .position(|frame| self.is_loud(frame));
+            let silent_len = first_loud.map_or(samples.len(), |f| f * self.channels);
+
+            self.lead.extend(&samples[..silent_len]);
+            let max_lead = self.padding_frames * self.channels;
+            if self.lead.len() > max_lead {
+                let excess = self.lead.len() - max_lead;
+                self.lead.drain(..excess);
+            }
+            if first_loud.is_none() {
+                return Ok(());
+            }
+
+            self.started = true;
+            let lead: Vec<i16> = self.lead.drain(..).collect();
+            self.inner.write_samples(&lead)?;
+            self.written += lead.len() as u64;
+            offset = silent_len;
+        }
+
+        let rest = &samples[offset..];
+        if let Some(last) = rest
+            .chunks_exact(self.channels)
+            .rposition(|frame| self.is_loud(frame))
+        {
+            self.last_loud = self.written + ((last + 1) * self.channels) as u64;
+        }
+        self.inner.write_samples(rest)?;
+        self.written += rest.len() as u64;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.started {
+            let keep = self.last_loud + (self.padding_frames * self.channels) as u64;
+            self.inner.truncate_samples(keep.min(self.written))?;
+        }
+        self.inner.finish()
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.inner.bytes_written()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Linear fade-in over the first `in_frames` and fade-out over the last `out_frames`.
+// The fade-out can't know where the recording ends, so the last `out_frames` are held
+// back and only written, ramped down, in finish(). First and last frame are silent.
+struct FadeSink<'a> {
+    inner: Box<dyn Sink + 'a>,
+    channels: usize,
+    in_frames: usize,
+    out_frames: usize,
+    frames_seen: usize, // frames received so far, for the fade-in ramp
+    tail: VecDeque<i16>,
+    scratch: Vec<i16>,
+}
+
+impl<'a> FadeSink<'a> {
+    fn new(inner: Box<dyn Sink + 'a>, channels: usize, in_frames: usize, out_frames: usize) -> Self {
+        Self {
+            inner,
+            channels,
+            in_frames,
+            out_frames,
+            frames_seen: 0,
+            tail: VecDeque::with_capacity(out_frames * channels),
+            scratch: Vec::new(),
+        }
+    }
+
+    // Hand samples on, except the last `out_frames`, which stay in `tail`
+    fn push(&mut self, samples: &[i16]) -> Result<()> {
+        self.tail.extend(samples);
+        let keep = self.out_frames * self.channels;
+        if self.tail.len() <= keep {
+            return Ok(());
+        }
+        let excess = self.tail.len() - keep;
+        let (front, back) = self.tail.as_slices();
+        let from_front = excess.min(front.len());
+        self.inner.write_samples(&front[..from_front])?;
+        if excess > from_front {
+            self.inner.write_samples(&back[..excess - from_front])?;
+        }
+        self.tail.drain(..excess);
+        Ok(())
+    }
+}
+
+impl Sink for FadeSink<'_> {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        if self.frames_seen >= self.in_frames {
+            return self.push(samples);
+        }
+
+        let mut scratch = std::mem::take(&mut self.scratch);
+        scratch.clear();
+        scratch.extend_from_slice(samples);
+        for (i, frame) in scratch.chunks_exact_mut(self.channels).enumerate() {
+            let pos = self.frames_seen + i;
+            if pos >= self.in_frames {
+                break;
+            }
+            scale_samples(frame, pos as f64 / self.in_frames as f64);
+        }
+        self.frames_seen += samples.len() / self.channels;
+        let result = self.push(&scratch);
+        self.scratch = scratch;
+        result
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        // Ramp whatever is held back down to silence: the last frame gets gain 0
+        let tail = self.tail.make_contiguous();
+        let frames = tail.len() / self.channels;
+        for (i, frame) in tail.chunks_exact_mut(self.channels).enumerate() {
+            scale_samples(frame, (frames - 1 - i) as f64 / self.out_frames as f64);
+        }
+        if !tail.is_empty() {
+            self.inner.write_samples(tail)?;
+        }
+        self.tail.clear();
+        self.inner.finish()
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.inner.bytes_written()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn split(&mut self) -> Result<()> {
+        // The audio held back for the fade-out belongs to the segment that ends here
+        let tail = self.tail.make_contiguous();
+        if !tail.is_empty() {
+            self.inner.write_samples(tail)?;
+        }
+        self.tail.clear();
+        self.inner.split()
+    }
+
+    fn segment_paths(&self) -> &[String] {
+        self.inner.segment_paths()
+    }
+
+    fn segment_spans(&self) -> &[SegmentSpan] {
+        self.inner.segment_spans()
+    }
+}
+
+// Read until `buf` is full or EOF, so 2-byte samples never straddle two reads.
+fn read_full(reader: &mut impl io::Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    // Collects samples in memory for the wrapper sinks to write into
+    #[derive(Clone, Default)]
+    struct MemorySink {
+        samples: Rc<RefCell<Vec<i16>>>,
+    }
+
+    impl Sink for MemorySink {
+        fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+            self.samples.borrow_mut().extend_from_slice(samples);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn bytes_written(&self) -> u64 {
+            self.samples.borrow().len() as u64 * 2
+        }
+    }
+
+    impl TruncateSink for MemorySink {
+        fn truncate_samples(&mut self, samples: u64) -> Result<()> {
+            self.samples.borrow_mut().truncate(samples as usize);
+            Ok(())
+        }
+    }
+
+    // `samples` through `sink` in blocks of `block` samples, then finish
+    fn write_all(sink: &mut dyn Sink, samples: &[i16], block: usize) {
+        for chunk in samples.chunks(block) {
+            sink.write_samples(chunk).unwrap();
+        }
+        sink.finish().unwrap();
+    }
+
+    #[test]
+    fn trim_silence_drops_the_silence_around_the_sound() {
+        // -40 dBFS is 328; 100 is silence, 1000 is sound
+        let sound = [1000, 100, 0, 0, -1000];
+        let input: Vec<i16> = [&[0; 10][..], &sound, &[100; 10]].concat();
+        // Block sizes that cut through the lead-in, the sound and the tail
+        for block in [1, 3, 7, input.len()] {
+            let memory = MemorySink::default();
+            let mut trim = TrimSilenceSink::new(memory.clone(), 1, -40.0, 2);
+            write_all(&mut trim, &input, block);
+            // Two frames of padding kept on each side, the silence inside kept whole
+            let expected: Vec<i16> = [&[0, 0][..], &sound, &[100, 100]].concat();
+            assert_eq!(*memory.samples.borrow(), expected, "blocks of {block}");
+        }
+    }
+
+    #[test]
+    fn trim_silence_looks_at_every_channel_of_a_frame() {
+        let input = [0, 0, 0, 0, 0, 1000, 0, 0, 0, 0];
+        let memory = MemorySink::default();
+        let mut trim = TrimSilenceSink::new(memory.clone(), 2, -40.0, 0);
+        write_all(&mut trim, &input, 4);
+        assert_eq!(*memory.samples.borrow(), [0, 1000]);
+    }
+
+    #[test]
+    fn trim_silence_writes_nothing_for_a_silent_recording() {
+        let memory = MemorySink::default();
+        let mut trim = TrimSilenceSink::new(memory.clone(), 1, -40.0, 5);
+        write_all(&mut trim, &[10; 100], 16);
+        assert!(memory.samples.borrow().is_empty());
+    }
+
+    fn march_first() -> LocalTime {
+        LocalTime {
+            year: 2026,
+            month: 3,
+            day: 1,
+            hour: 14,
+            minute: 5,
+            second: 9,
+            millisecond: 250,
+        }
+    }
+
+    #[test]
+    fn out_template_expands_each_placeholder() {
+        let now = march_first();
+        let expand = |template| expand_out_template(template, &now, "Speakers", 7);
+        assert_eq!(expand("{date}"), "2026-03-01");
+        assert_eq!(expand("{time}"), "14-05-09");
+        assert_eq!(expand("{device}"), "Speakers");
+        assert_eq!(expand("{n}"), "007");
+        assert_eq!(
+            expand("rec/{device}/{date}_{time}-{n}.mp3"),
+            "rec/Speakers/2026-03-01_14-05-09-007.mp3"
+        );
+        assert_eq!(expand("{n}{n}"), "007007");
+        assert_eq!(expand_out_template("{n}", &now, "", 1234), "1234");
+    }
+
+    #[test]
+    fn out_template_leaves_other_text_alone() {
+        let now = march_first();
+        let expand = |template| expand_out_template(template, &now, "Speakers", 1);
+        assert_eq!(expand("plain.wav"), "plain.wav");
+        assert_eq!(expand("{year}-{DATE}.wav"), "{year}-{DATE}.wav");
+        assert_eq!(expand("{{n}}.wav"), "{001}.wav");
+        assert_eq!(expand("{n.wav"), "{n.wav");
+    }
+
+    #[test]
+    fn device_names_cannot_add_placeholders_or_folders() {
+        let now = march_first();
+        let device = sanitize_filename("Mic {n} / USB");
+        assert_eq!(device, "Mic_n_USB");
+        assert_eq!(expand_out_template("{device}.wav", &now, &device, 1), "Mic_n_USB.wav");
+        assert_eq!(sanitize_filename("Speakers (Realtek(R) Audio)"), "Speakers_Realtek_R_Audio");
+        assert_eq!(sanitize_filename("..\\..\\{date}"), "date");
+        assert_eq!(sanitize_filename("().."), "device");
+    }
+
+    #[test]
+    fn only_numbering_templates_rename_an_existing_file() {
+        let dir = std::env::temp_dir().join(format!("arec-output-namer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["take.mp3", "take-000.mp3"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let in_dir = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let namer = |template: &str, overwrite| OutputNamer {
+            template: in_dir(template),
+            device: "Speakers".to_string(),
+            overwrite,
+        };
+        let plain = namer("take.mp3", false);
+        let numbered = namer("take-{n}.mp3", false);
+        let paths = [
+            plain.path(None),
+            plain.path(Some(0)),
+            numbered.path(None),
+            namer("take-{n}.mp3", true).path(None),
+            namer("{device}.mp3", false).path(None),
+        ];
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // A plain name is kept for confirm_overwrite; {n} and split segments are numbered
+        assert!(!plain.numbers_existing(false) && plain.numbers_existing(true));
+        assert!(numbered.numbers_existing(false));
+        assert!(namer("{date}-{time}.mp3", false).numbers_existing(false));
+        assert!(!namer("{device}.mp3", false).numbers_existing(false));
+        assert_eq!(paths[0], in_dir("take.mp3"));
+        assert_eq!(paths[1], in_dir("take-000-1.mp3"));
+        assert_eq!(paths[2], in_dir("take-000-1.mp3"));
+        assert_eq!(paths[3], in_dir("take-000.mp3"));
+        assert_eq!(paths[4], in_dir("Speakers.mp3"));
+    }
+
+    fn faded(input: &[i16], channels: usize, in_frames: usize, out_frames: usize) -> Vec<i16> {
+        let memory = MemorySink::default();
+        let mut fade = FadeSink::new(Box::new(memory.clone()), channels, in_frames, out_frames);
+        write_all(&mut fade, input, 5);
+        memory.samples.take()
+    }
+
+    #[test]
+    fn fade_ramps_from_and_to_silence() {
+        assert_eq!(
+            faded(&[1000; 12], 1, 4, 4),
+            [0, 250, 500, 750, 1000, 1000, 1000, 1000, 750, 500, 250, 0]
+        );
+        // Both channels of a frame get the same gain
+        assert_eq!(
+            faded(&[1000, -1000].repeat(6), 2, 2, 2),
+            [0, 0, 500, -500, 1000, -1000, 1000, -1000, 500, -500, 0, 0]
+        );
+    }
+
+    #[test]
+    fn fade_longer_than_the_recording_keeps_its_length() {
+        // Fade-in gains 0, 0.1, 0.2, 0.3, then the fade-out gains 0.3, 0.2, 0.1, 0 on top
+        assert_eq!(faded(&[1000; 4], 1, 10, 10), [0, 20, 20, 0]);
+        assert!(faded(&[], 1, 10, 10).is_empty());
+    }
+}
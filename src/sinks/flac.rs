@@ -0,0 +1,1077 @@
+//! The FLAC encoder (fixed and LPC subframes, Rice coding) and the MD5 of the samples.
+
+use anyhow::{bail, Result};
+use std::io::{Seek, SeekFrom, Write};
+
+use crate::sinks::Sink;
+
+// FLAC level presets, modeled on the reference encoder's: block size, highest LPC order
+// (0 = fixed predictors only), highest Rice partition order, whether stereo is tried as
+// left/side, side/right and mid/side too, and whether every LPC order is tried instead of
+// only the one the prediction error suggests.
+struct FlacParams {
+    block_size: usize,
+    max_lpc_order: usize,
+    max_partition_order: u32,
+    stereo: bool,
+    exhaustive: bool,
+}
+
+impl FlacParams {
+    fn for_level(level: u8) -> Self {
+        let (block_size, max_lpc_order, max_partition_order, stereo, exhaustive) = match level {
+            0 => (1152, 0, 3, false, false),
+            1 => (1152, 0, 3, true, false),
+            2 => (1152, 0, 4, true, false),
+            3 => (4096, 6, 4, false, false),
+            4 => (4096, 8, 4, true, false),
+            5 => (4096, 8, 5, true, false),
+            6 => (4096, 8, 6, true, false),
+            7 => (4096, 12, 6, true, true),
+            _ => (4096, 12, 8, true, true),
+        };
+        Self {
+            block_size,
+            max_lpc_order,
+            max_partition_order,
+            stereo,
+            exhaustive,
+        }
+    }
+}
+
+const FLAC_MAX_FIXED_ORDER: usize = 4;
+
+const FLAC_MAX_RICE_PARAMETER: u32 = 14;
+
+// Quantized LPC coefficient precision (bits) and the largest shift the format can store
+const FLAC_LPC_PRECISION: u32 = 12;
+
+const FLAC_MAX_LPC_SHIFT: i32 = 15;
+
+// 16-bit FLAC writer with a fixed block size. Samples are buffered until a whole block is
+// available; each block becomes one frame, choosing per channel the cheapest of constant,
+// verbatim, fixed and LPC subframes. STREAMINFO is written up front with the sample count
+// and MD5 unknown (0) and patched in finish(), like the WAV header.
+pub(crate) struct FlacSink<W: Write + Seek> {
+    out: W,
+    params: FlacParams,
+    sample_rate: u32,
+    channels: usize,
+    // Interleaved samples waiting for a whole block
+    pending: Vec<i16>,
+    frame_number: u64,
+    // Samples per channel in the frames written so far
+    frames: u64,
+    min_frame_bytes: u32,
+    max_frame_bytes: u32,
+    md5: Md5,
+    bytes_written: u64,
+    finished: bool,
+}
+
+impl<W: Write + Seek> FlacSink<W> {
+    pub(crate) fn new(
+        out: W,
+        sample_rate: u32,
+        channels: usize,
+        level: u8,
+        vorbis_comment: &[u8],
+    ) -> Result<Self> {
+        if !(1..=8).contains(&channels) {
+            bail!("FLAC stores 1 to 8 channels, got {channels}");
+        }
+        if sample_rate == 0 || sample_rate >= 1 << 20 {
+            bail!("FLAC can't store a sample rate of {sample_rate} Hz");
+        }
+        let mut sink = Self {
+            out,
+            params: FlacParams::for_level(level),
+            sample_rate,
+            channels,
+            pending: Vec::new(),
+            frame_number: 0,
+            frames: 0,
+            min_frame_bytes: 0,
+            max_frame_bytes: 0,
+            md5: Md5::new(),
+            bytes_written: 0,
+            finished: false,
+        };
+
+        let has_comment = !vorbis_comment.is_empty();
+        let streaminfo = sink.streaminfo();
+        sink.out.write_all(b"fLaC")?;
+        sink.out.write_all(&flac_block_header(0, streaminfo.len(), !has_comment))?;
+        sink.out.write_all(&streaminfo)?;
+        sink.bytes_written = 8 + streaminfo.len() as u64;
+        if has_comment {
+            sink.out.write_all(&flac_block_header(4, vorbis_comment.len(), true))?;
+            sink.out.write_all(vorbis_comment)?;
+            sink.bytes_written += 4 + vorbis_comment.len() as u64;
+        }
+        Ok(sink)
+    }
+
+    // 34-byte STREAMINFO body; min frame size, sample count and MD5 stay 0 (unknown) until
+    // the first frame is written.
+    fn streaminfo(&self) -> Vec<u8> {
+        let block_size = self.params.block_size as u64;
+        let mut w = BitWriter::default();
+        w.write(block_size, 16); // min block size
+        w.write(block_size, 16); // max block size
+        w.write(self.min_frame_bytes as u64, 24);
+        w.write(self.max_frame_bytes as u64, 24);
+        w.write(self.sample_rate as u64, 20);
+        w.write(self.channels as u64 - 1, 3);
+        w.write(15, 5); // bits per sample - 1
+        w.write(self.frames, 36);
+        let mut bytes = w.bytes;
+        if self.frames == 0 {
+            bytes.extend_from_slice(&[0; 16]);
+        } else {
+            bytes.extend_from_slice(&self.md5.digest());
+        }
+        bytes
+    }
+
+    fn patch_streaminfo(&mut self) -> Result<()> {
+        let streaminfo = self.streaminfo();
+        self.out.seek(SeekFrom::Start(8))?; // after "fLaC" and the block header
+        self.out.write_all(&streaminfo)?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    // Encodes one block of interleaved samples as a frame.
+    fn write_frame(&mut self, block: &[i16]) -> Result<()> {
+        let frames = block.len() / self.channels;
+        let bytes: Vec<u8> = block.iter().flat_map(|s| s.to_le_bytes()).collect();
+        self.md5.update(&bytes);
+
+        let channels: Vec<Vec<i32>> = (0..self.channels)
+            .map(|c| block.iter().skip(c).step_by(self.channels).map(|&s| s as i32).collect())
+            .collect();
+        let (assignment, subframes) = if self.channels == 2 && self.params.stereo {
+            let (left, right) = (&channels[0], &channels[1]);
+            let mid: Vec<i32> = left.iter().zip(right).map(|(&l, &r)| (l + r) >> 1).collect();
+            let side: Vec<i32> = left.iter().zip(right).map(|(&l, &r)| l - r).collect();
+            let left = encode_flac_subframe(left, 16, &self.params);
+            let right = encode_flac_subframe(right, 16, &self.params);
+            let mid = encode_flac_subframe(&mid, 16, &self.params);
+            // The difference channel needs one more bit
+            let side = encode_flac_subframe(&side, 17, &self.params);
+            let sizes = [
+                left.bit_len() + right.bit_len(),
+                left.bit_len() + side.bit_len(),
+                side.bit_len() + right.bit_len(),
+                mid.bit_len() + side.bit_len(),
+            ];
+            let smallest = (0..sizes.len()).min_by_key(|&i| sizes[i]).unwrap_or(0);
+            match smallest {
+                0 => (0b0001, vec![left, right]),
+                1 => (0b1000, vec![left, side]),
+                2 => (0b1001, vec![side, right]),
+                _ => (0b1010, vec![mid, side]),
+            }
+        } else {
+            let subframes = channels
+                .iter()
+                .map(|c| encode_flac_subframe(c, 16, &self.params))
+                .collect();
+            (self.channels as u64 - 1, subframes)
+        };
+
+        let mut w = BitWriter::default();
+        w.write(0xFFF8, 16); // sync code, fixed block size
+        let (size_code, size_extra) = flac_block_size_code(frames);
+        let (rate_code, rate_extra) = flac_sample_rate_code(self.sample_rate);
+        w.write(size_code, 4);
+        w.write(rate_code, 4);
+        w.write(assignment, 4);
+        w.write(0b1000, 4); // 16 bits per sample, reserved bit
+        write_flac_utf8(&mut w, self.frame_number);
+        for (value, bits) in size_extra.into_iter().chain(rate_extra) {
+            w.write(value, bits);
+        }
+        let crc = flac_crc8(&w.bytes);
+        w.write(crc as u64, 8);
+        for subframe in &subframes {
+            w.append(subframe);
+        }
+        w.align();
+        let crc = flac_crc16(&w.bytes);
+        w.write(crc as u64, 16);
+
+        self.out.write_all(&w.bytes)?;
+        let len = w.bytes.len() as u32;
+        self.bytes_written += len as u64;
+        if self.min_frame_bytes == 0 || len < self.min_frame_bytes {
+            self.min_frame_bytes = len;
+        }
+        self.max_frame_bytes = self.max_frame_bytes.max(len);
+        self.frame_number += 1;
+        self.frames += frames as u64;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Sink for FlacSink<W> {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        self.pending.extend_from_slice(samples);
+        let block_len = self.params.block_size * self.channels;
+        if self.pending.len() < block_len {
+            return Ok(());
+        }
+        let mut pending = std::mem::take(&mut self.pending);
+        let mut blocks = pending.chunks_exact(block_len);
+        for block in &mut blocks {
+            self.write_frame(block)?;
+        }
+        let done = pending.len() - blocks.remainder().len();
+        pending.drain(..done);
+        self.pending = pending;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        // The last block may be shorter than the rest
+        if !self.pending.is_empty() {
+            let pending = std::mem::take(&mut self.pending);
+            self.write_frame(&pending)?;
+        }
+        self.patch_streaminfo()?;
+        self.finished = true;
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+    // Only whole frames reach the disk; the partial block is still in `pending`
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+// Dropped without finish(): patch STREAMINFO so the frames written so far play.
+// The partial block still buffered is lost.
+impl<W: Write + Seek> Drop for FlacSink<W> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.patch_streaminfo();
+        }
+    }
+}
+
+// Metadata block header: last-block flag, block type, 24-bit length
+fn flac_block_header(block_type: u8, len: usize, last: bool) -> [u8; 4] {
+    [
+        (last as u8) << 7 | block_type,
+        (len >> 16) as u8,
+        (len >> 8) as u8,
+        len as u8,
+    ]
+}
+
+// Frame header block size code, plus the explicit size for uncommon (last-block) sizes
+fn flac_block_size_code(size: usize) -> (u64, Option<(u64, u32)>) {
+    match size {
+        192 => (1, None),
+        576 => (2, None),
+        1152 => (3, None),
+        2304 => (4, None),
+        4608 => (5, None),
+        256 => (8, None),
+        512 => (9, None),
+        1024 => (10, None),
+        2048 => (11, None),
+        4096 => (12, None),
+        8192 => (13, None),
+        16384 => (14, None),
+        32768 => (15, None),
+        n if n <= 256 => (6, Some((n as u64 - 1, 8))),
+        n => (7, Some((n as u64 - 1, 16))),
+    }
+}
+
+// Frame header sample rate code, plus the explicit rate when there's no code for it
+// (0 means "see STREAMINFO")
+fn flac_sample_rate_code(rate: u32) -> (u64, Option<(u64, u32)>) {
+    match rate {
+        88200 => (1, None),
+        176400 => (2, None),
+        192000 => (3, None),
+        8000 => (4, None),
+        16000 => (5, None),
+        22050 => (6, None),
+        24000 => (7, None),
+        32000 => (8, None),
+        44100 => (9, None),
+        48000 => (10, None),
+        96000 => (11, None),
+        r if r % 1000 == 0 && r / 1000 <= 255 => (12, Some((r as u64 / 1000, 8))),
+        r if r <= 65535 => (13, Some((r as u64, 16))),
+        r if r % 10 == 0 && r / 10 <= 65535 => (14, Some((r as u64 / 10, 16))),
+        _ => (0, None),
+    }
+}
+
+// Frame numbers use the UTF-8 style variable-length coding (up to 36 bits)
+fn write_flac_utf8(w: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        w.write(value, 8);
+        return;
+    }
+    let len = match value {
+        0..0x800 => 2,
+        0x800..0x1_0000 => 3,
+        0x1_0000..0x20_0000 => 4,
+        0x20_0000..0x400_0000 => 5,
+        0x400_0000..0x8000_0000 => 6,
+        _ => 7,
+    };
+    let lead = (0xFF00u64 >> len) & 0xFF;
+    w.write(lead | value >> (6 * (len - 1)), 8);
+    for i in (0..len - 1).rev() {
+        w.write(0x80 | (value >> (6 * i) & 0x3F), 8);
+    }
+}
+
+// CRC-8 (polynomial 0x07) over the frame header
+fn flac_crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &b in data {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+// CRC-16 (polynomial 0x8005) over the whole frame
+fn flac_crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+// One channel of a block as the smallest subframe that codes it exactly
+fn encode_flac_subframe(samples: &[i32], bps: u32, params: &FlacParams) -> BitWriter {
+    let n = samples.len();
+    if samples.iter().all(|&s| s == samples[0]) {
+        let mut w = BitWriter::default();
+        w.write(0, 8); // SUBFRAME_CONSTANT
+        w.write_signed(samples[0], bps);
+        return w;
+    }
+
+    let mut best = BitWriter::default();
+    best.write(0b000001 << 1, 8); // SUBFRAME_VERBATIM
+    for &s in samples {
+        best.write_signed(s, bps);
+    }
+
+    let mut residual = Vec::with_capacity(n);
+    for order in 0..=FLAC_MAX_FIXED_ORDER.min(n - 1) {
+        flac_fixed_residual(samples, order, &mut residual);
+        let mut w = BitWriter::default();
+        w.write((0b001000 | order as u64) << 1, 8); // SUBFRAME_FIXED
+        for &s in &samples[..order] {
+            w.write_signed(s, bps);
+        }
+        write_flac_residual(&mut w, &residual, n, order, params.max_partition_order);
+        if w.bit_len() < best.bit_len() {
+            best = w;
+        }
+    }
+
+    let max_order = params.max_lpc_order.min(n - 1);
+    if max_order == 0 {
+        return best;
+    }
+    let autoc = flac_autocorrelation(samples, max_order);
+    let candidates = flac_lpc_coefficients(&autoc, max_order);
+    let orders = if params.exhaustive {
+        1..=candidates.len()
+    } else {
+        let order = flac_estimate_lpc_order(&candidates, n, bps);
+        order..=order
+    };
+    for order in orders.filter(|&o| o > 0) {
+        let Some((coefs, shift)) = flac_quantize_lpc(&candidates[order - 1].0) else {
+            continue;
+        };
+        if !flac_lpc_residual(samples, &coefs, shift, &mut residual) {
+            continue;
+        }
+        let mut w = BitWriter::default();
+        w.write((0b100000 | (order as u64 - 1)) << 1, 8); // SUBFRAME_LPC
+        for &s in &samples[..order] {
+            w.write_signed(s, bps);
+        }
+        w.write(FLAC_LPC_PRECISION as u64 - 1, 4);
+        w.write(shift as u64, 5);
+        for &c in &coefs {
+            w.write_signed(c, FLAC_LPC_PRECISION);
+        }
+        write_flac_residual(&mut w, &residual, n, order, params.max_partition_order);
+        if w.bit_len() < best.bit_len() {
+            best = w;
+        }
+    }
+    best
+}
+
+// Residual of the fixed polynomial predictor of the given order (0-4)
+fn flac_fixed_residual(samples: &[i32], order: usize, out: &mut Vec<i32>) {
+    out.clear();
+    out.extend((order..samples.len()).map(|i| {
+        let x = |back: usize| samples[i - back] as i64;
+        let residual = match order {
+            0 => x(0),
+            1 => x(0) - x(1),
+            2 => x(0) - 2 * x(1) + x(2),
+            3 => x(0) - 3 * x(1) + 3 * x(2) - x(3),
+            _ => x(0) - 4 * x(1) + 6 * x(2) - 4 * x(3) + x(4),
+        };
+        residual as i32
+    }));
+}
+
+// Autocorrelation (lags 0..=max_lag) of the block under a Tukey(0.5) window
+fn flac_autocorrelation(samples: &[i32], max_lag: usize) -> Vec<f64> {
+    let n = samples.len();
+    let taper = (n / 4).max(1);
+    let windowed: Vec<f64> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, &s)| {
+            let edge = i.min(n - 1 - i);
+            let weight = if edge < taper {
+                0.5 - 0.5 * (std::f64::consts::PI * edge as f64 / taper as f64).cos()
+            } else {
+                1.0
+            };
+            s as f64 * weight
+        })
+        .collect();
+    (0..=max_lag)
+        .map(|lag| windowed[lag..].iter().zip(&windowed).map(|(a, b)| a * b).sum())
+        .collect()
+}
+
+// Levinson-Durbin recursion: predictor coefficients (for x[n-1], x[n-2], ...) and the
+// prediction error of every order up to `max_order`, stopping once the error hits 0.
+fn flac_lpc_coefficients(autoc: &[f64], max_order: usize) -> Vec<(Vec<f64>, f64)> {
+    let mut coefs: Vec<f64> = Vec::with_capacity(max_order);
+    let mut error = autoc[0];
+    let mut out = Vec::with_capacity(max_order);
+    for m in 0..max_order {
+        if error <= 0.0 {
+            break;
+        }
+        let mut acc = autoc[m + 1];
+        for (j, c) in coefs.iter().enumerate() {
+            acc -= c * autoc[m - j];
+        }
+        let k = acc / error;
+        let previous = coefs.clone();
+        for (j, c) in coefs.iter_mut().enumerate() {
+            *c -= k * previous[m - 1 - j];
+        }
+        coefs.push(k);
+        error *= 1.0 - k * k;
+        out.push((coefs.clone(), error));
+    }
+    out
+}
+
+// LPC order with the fewest estimated bits (residual plus warm-up and coefficients),
+// from each order's prediction error; 0 if there are no candidates.
+fn flac_estimate_lpc_order(candidates: &[(Vec<f64>, f64)], n: usize, bps: u32) -> usize {
+    let overhead_per_order = (bps + FLAC_LPC_PRECISION) as f64;
+    let bits = |order: usize, error: f64| {
+        let per_sample = if error > 0.0 {
+            (0.5 * (0.5 * error / n as f64).log2()).max(0.0)
+        } else {
+            0.0
+        };
+        per_sample * (n - order) as f64 + overhead_per_order * order as f64
+    };
+    candidates
+        .iter()
+        .enumerate()
+        .map(|(i, (_, error))| (i + 1, bits(i + 1, *error)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(0, |(order, _)| order)
+}
+
+// Coefficients rounded to FLAC_LPC_PRECISION-bit integers (with error feedback) and the
+// shift applied to the prediction. None if they can't be represented.
+fn flac_quantize_lpc(coefs: &[f64]) -> Option<(Vec<i32>, u32)> {
+    let cmax = coefs.iter().fold(0.0f64, |m, c| m.max(c.abs()));
+    if !(cmax > 0.0 && cmax.is_finite()) {
+        return None;
+    }
+    let exponent = cmax.log2().floor() as i32 + 1;
+    let shift = (FLAC_LPC_PRECISION as i32 - 1 - exponent).min(FLAC_MAX_LPC_SHIFT);
+    if shift < 0 {
+        return None;
+    }
+    let qmax = (1i64 << (FLAC_LPC_PRECISION - 1)) - 1;
+    let scale = (1i64 << shift) as f64;
+    let mut error = 0.0;
+    let quantized = coefs
+        .iter()
+        .map(|&c| {
+            error += c * scale;
+            let q = (error.round() as i64).clamp(-qmax - 1, qmax);
+            error -= q as f64;
+            q as i32
+        })
+        .collect();
+    Some((quantized, shift as u32))
+}
+
+// Residual of the quantized LPC predictor; false if it doesn't fit in 32 bits.
+fn flac_lpc_residual(samples: &[i32], coefs: &[i32], shift: u32, out: &mut Vec<i32>) -> bool {
+    out.clear();
+    let order = coefs.len();
+    for i in order..samples.len() {
+        let prediction: i64 = coefs
+            .iter()
+            .zip(samples[i - order..i].iter().rev())
+            .map(|(&c, &s)| c as i64 * s as i64)
+            .sum();
+        let residual = samples[i] as i64 - (prediction >> shift);
+        if residual.abs() > i32::MAX as i64 {
+            return false;
+        }
+        out.push(residual as i32);
+    }
+    true
+}
+
+// Partitioned Rice coding (method 0) of the residual after `predictor_order` warm-up
+// samples. The partition order and each partition's parameter come from bit estimates
+// over prefix sums of the zigzag-folded residual.
+fn write_flac_residual(
+    w: &mut BitWriter,
+    residual: &[i32],
+    block_size: usize,
+    predictor_order: usize,
+    max_partition_order: u32,
+) {
+    let folded: Vec<u64> = residual
+        .iter()
+        .map(|&r| {
+            let r = r as i64;
+            ((r << 1) ^ (r >> 63)) as u64
+        })
+        .collect();
+    let mut prefix = Vec::with_capacity(folded.len() + 1);
+    let mut sum = 0u64;
+    prefix.push(sum);
+    for &u in &folded {
+        sum += u;
+        prefix.push(sum);
+    }
+
+    // (first, end) residual indexes of partition `p` of 2^order
+    let bounds = |order: u32, p: usize| {
+        let size = block_size >> order;
+        let first = if p == 0 { 0 } else { p * size - predictor_order };
+        (first, (p + 1) * size - predictor_order)
+    };
+
+    let mut best: Option<(u64, u32, Vec<u32>)> = None;
+    for order in 0..=max_partition_order {
+        let partitions = 1 << order;
+        if !block_size.is_multiple_of(partitions) || block_size >> order <= predictor_order {
+            break;
+        }
+        let mut bits = 0;
+        let mut parameters = Vec::with_capacity(partitions);
+        for p in 0..partitions {
+            let (first, end) = bounds(order, p);
+            let (k, cost) = flac_rice_parameter(end - first, prefix[end] - prefix[first]);
+            bits += 4 + cost;
+            parameters.push(k);
+        }
+        if best.as_ref().is_none_or(|(b, ..)| bits < *b) {
+            best = Some((bits, order, parameters));
+        }
+    }
+    // Partition order 0 always fits, since the predictor order is below the block size
+    let (_, order, parameters) = best.unwrap_or((0, 0, vec![0]));
+
+    w.write(0, 2); // Rice coding with 4-bit parameters
+    w.write(order as u64, 4);
+    for (p, &k) in parameters.iter().enumerate() {
+        let (first, end) = bounds(order, p);
+        w.write(k as u64, 4);
+        for &u in &folded[first..end] {
+            w.write_unary(u >> k);
+            w.write(u, k);
+        }
+    }
+}
+
+// Rice parameter with the fewest estimated bits for `count` values summing to `sum`
+fn flac_rice_parameter(count: usize, sum: u64) -> (u32, u64) {
+    (0..=FLAC_MAX_RICE_PARAMETER)
+        .map(|k| (k, count as u64 * (k as u64 + 1) + (sum >> k)))
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap_or((0, 0))
+}
+
+// MSB-first bit packer for FLAC frames and STREAMINFO
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    // Bits not yet making up a whole byte (fewer than 8)
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    // Appends the low `bits` bits of `value` (at most 56 at a time)
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        self.acc = (self.acc << bits) | (value & (u64::MAX >> (64 - bits)));
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+        self.acc &= (1 << self.bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i32, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    // `zeros` 0 bits followed by a 1
+    fn write_unary(&mut self, mut zeros: u64) {
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    fn bit_len(&self) -> usize {
+        self.bytes.len() * 8 + self.bits as usize
+    }
+
+    fn append(&mut self, other: &BitWriter) {
+        if self.bits == 0 {
+            self.bytes.extend_from_slice(&other.bytes);
+        } else {
+            for &b in &other.bytes {
+                self.write(b as u64, 8);
+            }
+        }
+        self.write(other.acc, other.bits);
+    }
+
+    // Pads with 0 bits to the next byte boundary
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+// MD5 of the PCM samples (interleaved, little-endian) for STREAMINFO, which decoders
+// use to verify the file.
+#[derive(Clone)]
+struct Md5 {
+    state: [u32; 4],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+const MD5_K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee,
+    0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be,
+    0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa,
+    0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed,
+    0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c,
+    0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05,
+    0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039,
+    0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1,
+    0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+impl Md5 {
+    fn new() -> Self {
+        Self {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        while !data.is_empty() {
+            let n = (64 - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+            if self.block_len == 64 {
+                md5_compress(&mut self.state, &self.block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    fn digest(&self) -> [u8; 16] {
+        let mut md5 = self.clone();
+        let bit_len = self.total_len.wrapping_mul(8);
+        md5.update(&[0x80]);
+        while md5.block_len != 56 {
+            md5.update(&[0]);
+        }
+        md5.update(&bit_len.to_le_bytes());
+        let mut out = [0u8; 16];
+        for (chunk, word) in out.chunks_exact_mut(4).zip(md5.state) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+}
+
+fn md5_compress(state: &mut [u32; 4], block: &[u8; 64]) {
+    let mut m = [0u32; 16];
+    for (word, bytes) in m.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    let [mut a, mut b, mut c, mut d] = *state;
+    for i in 0..64 {
+        let (f, g) = match i / 16 {
+            0 => ((b & c) | (!b & d), i),
+            1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+            2 => (b ^ c ^ d, (3 * i + 5) % 16),
+            _ => (c ^ (b | !d), (7 * i) % 16),
+        };
+        let f = f.wrapping_add(a).wrapping_add(MD5_K[i]).wrapping_add(m[g]);
+        a = d;
+        d = c;
+        c = b;
+        b = b.wrapping_add(f.rotate_left(MD5_SHIFTS[i / 16 * 4 + i % 4]));
+    }
+    for (s, v) in state.iter_mut().zip([a, b, c, d]) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::sinks::Sink;
+
+    use super::{FlacSink, Md5};
+
+    // MSB-first reader over a FLAC stream, for decoding the test output
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        pos: usize, // in bits
+    }
+
+    impl BitReader<'_> {
+        fn read(&mut self, bits: u32) -> u64 {
+            let mut value = 0;
+            for _ in 0..bits {
+                let bit = self.bytes[self.pos / 8] >> (7 - self.pos % 8) & 1;
+                value = value << 1 | bit as u64;
+                self.pos += 1;
+            }
+            value
+        }
+
+        fn read_signed(&mut self, bits: u32) -> i64 {
+            let shift = 64 - bits;
+            ((self.read(bits) << shift) as i64) >> shift
+        }
+
+        fn read_unary(&mut self) -> u64 {
+            let mut zeros = 0;
+            while self.read(1) == 0 {
+                zeros += 1;
+            }
+            zeros
+        }
+
+        fn byte_pos(&self) -> usize {
+            assert_eq!(self.pos % 8, 0, "not on a byte boundary");
+            self.pos / 8
+        }
+
+        fn align(&mut self) {
+            self.pos = self.pos.div_ceil(8) * 8;
+        }
+    }
+
+    struct Decoded {
+        sample_rate: u32,
+        channels: usize,
+        total_frames: u64,
+        md5: [u8; 16],
+        samples: Vec<i16>, // interleaved
+    }
+
+    // Decodes the subset of FLAC the encoder writes, checking both CRCs of every frame
+    fn decode(flac: &[u8]) -> Decoded {
+        assert_eq!(&flac[..4], b"fLaC");
+        let mut r = BitReader { bytes: flac, pos: 32 };
+        let mut decoded = None;
+        loop {
+            let last = r.read(1) == 1;
+            let block_type = r.read(7);
+            let len = r.read(24) as usize;
+            let start = r.byte_pos();
+            if block_type == 0 {
+                assert_eq!(len, 34);
+                r.read(16 + 16 + 24 + 24);
+                let sample_rate = r.read(20) as u32;
+                let channels = r.read(3) as usize + 1;
+                assert_eq!(r.read(5), 15, "16 bits per sample");
+                let total_frames = r.read(36);
+                let mut md5 = [0u8; 16];
+                md5.copy_from_slice(&flac[start + 18..start + 34]);
+                decoded = Some(Decoded {
+                    sample_rate,
+                    channels,
+                    total_frames,
+                    md5,
+                    samples: Vec::new(),
+                });
+            }
+            r.pos = (start + len) * 8;
+            if last {
+                break;
+            }
+        }
+        let mut decoded = decoded.expect("STREAMINFO comes first");
+
+        while r.pos / 8 < flac.len() {
+            let frame_start = r.byte_pos();
+            assert_eq!(r.read(16), 0xFFF8, "frame sync at byte {frame_start}");
+            let size_code = r.read(4);
+            let rate_code = r.read(4);
+            let assignment = r.read(4);
+            assert_eq!(r.read(4), 0b1000);
+            let lead = r.read(8);
+            for _ in 1..(lead as u8).leading_ones().max(1) {
+                assert_eq!(r.read(2), 0b10);
+                r.read(6);
+            }
+            let frames = match size_code {
+                6 => r.read(8) as usize + 1,
+                7 => r.read(16) as usize + 1,
+                1 => 192,
+                2..=5 => 576 << (size_code - 2),
+                _ => 256 << (size_code - 8),
+            };
+            match rate_code {
+                12 => assert_eq!(r.read(8) * 1000, decoded.sample_rate as u64),
+                13 => assert_eq!(r.read(16), decoded.sample_rate as u64),
+                14 => assert_eq!(r.read(16) * 10, decoded.sample_rate as u64),
+                _ => {}
+            }
+            let header_end = r.byte_pos();
+            assert_eq!(
+                r.read(8) as u8,
+                super::flac_crc8(&flac[frame_start..header_end])
+            );
+
+            let channels: Vec<Vec<i64>> = (0..decoded.channels)
+                .map(|c| {
+                    let side = matches!((assignment, c), (8 | 10, 1) | (9, 0));
+                    decode_subframe(&mut r, frames, if side { 17 } else { 16 })
+                })
+                .collect();
+            r.align();
+            let frame_end = r.byte_pos();
+            assert_eq!(r.read(16) as u16, super::flac_crc16(&flac[frame_start..frame_end]));
+
+            for i in 0..frames {
+                let frame: Vec<i64> = match assignment {
+                    8 => vec![channels[0][i], channels[0][i] - channels[1][i]],
+                    9 => vec![channels[1][i] + channels[0][i], channels[1][i]],
+                    10 => {
+                        let side = channels[1][i];
+                        let mid = channels[0][i] << 1 | (side & 1);
+                        vec![(mid + side) >> 1, (mid - side) >> 1]
+                    }
+                    _ => channels.iter().map(|c| c[i]).collect(),
+                };
+                decoded.samples.extend(frame.iter().map(|&s| s as i16));
+            }
+        }
+        decoded
+    }
+
+    fn decode_subframe(r: &mut BitReader, frames: usize, bps: u32) -> Vec<i64> {
+        assert_eq!(r.read(1), 0);
+        let kind = r.read(6);
+        assert_eq!(r.read(1), 0, "no wasted bits");
+        if kind == 0 {
+            return vec![r.read_signed(bps); frames];
+        }
+        if kind == 1 {
+            return (0..frames).map(|_| r.read_signed(bps)).collect();
+        }
+        let is_lpc = kind & 0b100000 != 0;
+        let order = if is_lpc {
+            (kind & 0x1F) as usize + 1
+        } else {
+            assert_eq!(kind & 0b111000, 0b001000, "subframe type {kind:#b}");
+            (kind & 0x7) as usize
+        };
+        let mut samples: Vec<i64> = (0..order).map(|_| r.read_signed(bps)).collect();
+        // LPC: coefficient precision and shift, then the coefficients
+        let lpc = is_lpc.then(|| {
+            let precision = r.read(4) as u32 + 1;
+            let shift = r.read(5) as u32;
+            let coefs: Vec<i64> = (0..order).map(|_| r.read_signed(precision)).collect();
+            (coefs, shift)
+        });
+
+        assert_eq!(r.read(2), 0, "Rice coding with 4-bit parameters");
+        let partition_order = r.read(4);
+        let partitions = 1usize << partition_order;
+        let mut residual = Vec::with_capacity(frames);
+        for p in 0..partitions {
+            let count = (frames >> partition_order) - if p == 0 { order } else { 0 };
+            let k = r.read(4) as u32;
+            assert!(k < 15, "no escaped partitions");
+            for _ in 0..count {
+                let u = r.read_unary() << k | r.read(k);
+                residual.push((u >> 1) as i64 ^ -((u & 1) as i64));
+            }
+        }
+
+        for (i, e) in (order..frames).zip(residual) {
+            let x = |back: usize| samples[i - back];
+            let prediction = match &lpc {
+                Some((coefs, shift)) => {
+                    coefs.iter().enumerate().map(|(j, c)| c * x(j + 1)).sum::<i64>() >> shift
+                }
+                None => match order {
+                    0 => 0,
+                    1 => x(1),
+                    2 => 2 * x(1) - x(2),
+                    3 => 3 * x(1) - 3 * x(2) + x(3),
+                    _ => 4 * x(1) - 6 * x(2) + 4 * x(3) - x(4),
+                },
+            };
+            samples.push(prediction + e);
+        }
+        samples
+    }
+
+    fn md5_hex(data: &[u8]) -> String {
+        let mut md5 = Md5::new();
+        md5.update(data);
+        md5.digest().iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    // A tone with some noise and a DC step, so every subframe kind gets used somewhere
+    fn test_signal(frames: usize, channels: usize) -> Vec<i16> {
+        let mut state = 1u32;
+        (0..frames * channels)
+            .map(|i| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let (frame, channel) = (i / channels, i % channels);
+                let tone = (frame as f64 * 0.03 * (channel + 1) as f64).sin() * 12_000.0;
+                let noise = (state >> 24) as f64 - 128.0;
+                match frame {
+                    0..1000 => 0,
+                    1000..2000 => 5_000,
+                    _ => (tone + noise) as i16,
+                }
+            })
+            .collect()
+    }
+
+    fn encode(input: &[i16], sample_rate: u32, channels: usize, level: u8, block: usize) -> Vec<u8> {
+        let mut sink = FlacSink::new(Cursor::new(Vec::new()), sample_rate, channels, level, &[])
+            .unwrap();
+        for chunk in input.chunks(block * channels) {
+            sink.write_samples(chunk).unwrap();
+        }
+        sink.finish().unwrap();
+        std::mem::take(sink.out.get_mut())
+    }
+
+    #[test]
+    fn flac_round_trips_bit_exact() {
+        // Mono, stereo (decorrelated at level 1 and up) and more channels; the last block
+        // is always a short one, and 44.1/37.8 kHz need a rate code or an explicit rate
+        let cases = [(48_000, 1, 0), (48_000, 2, 0), (44_100, 2, 1), (48_000, 2, 5), (37_800, 3, 8)];
+        for (sample_rate, channels, level) in cases {
+            let input = test_signal(10_000, channels);
+            let flac = encode(&input, sample_rate, channels, level, 333);
+            let decoded = decode(&flac);
+            let case = format!("{channels} channels at level {level}");
+            assert_eq!(decoded.sample_rate, sample_rate, "{case}");
+            assert_eq!(decoded.channels, channels, "{case}");
+            assert_eq!(decoded.total_frames, 10_000, "{case}");
+            assert!(decoded.samples == input, "{case}: decoded samples differ");
+            // STREAMINFO's MD5 is that of the samples, as little-endian 16-bit
+            let bytes: Vec<u8> = input.iter().flat_map(|s| s.to_le_bytes()).collect();
+            let mut md5 = Md5::new();
+            md5.update(&bytes);
+            assert_eq!(decoded.md5, md5.digest(), "{case}");
+            assert!(flac.len() < bytes.len(), "{case}: {} bytes", flac.len());
+        }
+    }
+
+    #[test]
+    fn md5_matches_known_digests() {
+        assert_eq!(md5_hex(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(md5_hex(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            md5_hex(b"The quick brown fox jumps over the lazy dog"),
+            "9e107d9d372bb6826bd81d3542a419d6"
+        );
+        // 80 bytes: more than one 64-byte block
+        let digits = b"1234567890".repeat(8);
+        assert_eq!(md5_hex(&digits), "57edf4a22be3c955ac49da2e2107b67a");
+        // Fed in pieces that don't line up with the blocks
+        let mut md5 = Md5::new();
+        for piece in digits.chunks(7) {
+            md5.update(piece);
+        }
+        assert_eq!(md5.digest(), {
+            let mut whole = Md5::new();
+            whole.update(&digits);
+            whole.digest()
+        });
+    }
+}
@@ -0,0 +1,1011 @@
+//! MP3 output through shine, with ID3 tags, VBR and the Xing header.
+
+use anyhow::{anyhow, bail, Context, Result};
+use shine_rs::{
+    encoder::{shine_check_config, shine_find_bitrate_index},
+    types::GRANULE_SIZE,
+    Mp3Encoder, Mp3EncoderConfig, StereoMode, SUPPORTED_BITRATES,
+};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+use crate::args::{ChannelMode, Mp3Emphasis, RecordArgs, TagArgs, DEFAULT_KBPS};
+use crate::sinks::{MpegOutput, Sink};
+use crate::Console;
+
+// What the MP3 frame headers say besides the bitrate and sample rate
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Mp3Header {
+    stereo_mode: StereoMode,
+    copyright: bool,
+    original: bool,
+    emphasis: Mp3Emphasis,
+}
+
+impl Mp3Header {
+    pub(crate) fn new(args: &RecordArgs, channels: usize) -> Self {
+        Self {
+            stereo_mode: ChannelMode::resolve(args.stereo_mode, channels).stereo_mode(),
+            copyright: args.mp3_copyright,
+            original: !args.mp3_copy,
+            emphasis: args.mp3_emphasis,
+        }
+    }
+
+    // Low bits of the header's fourth byte: copyright, original, emphasis
+    fn flag_bits(self) -> u8 {
+        (self.copyright as u8) << 3 | (self.original as u8) << 2 | self.emphasis.bits()
+    }
+
+    // ", copyright, copy, emphasis 50/15 us" for the Mode line; empty for the defaults
+    pub(crate) fn describe(self) -> String {
+        let mut out = String::new();
+        if self.copyright {
+            out.push_str(", copyright");
+        }
+        if !self.original {
+            out.push_str(", copy");
+        }
+        if self.emphasis != Mp3Emphasis::None {
+            out.push_str(&format!(", emphasis {}", self.emphasis.label()));
+        }
+        out
+    }
+}
+
+pub(crate) struct Mp3Sink {
+    encoder: Mp3Encoder,
+    out: Mp3FrameWriter,
+    sample_rate: u32,
+    channels: usize,
+    header: Mp3Header,
+    xing_kbps: u32,
+    // Whether the stream starts with a Xing/Info frame to fill in (not on stdout or TCP,
+    // nor when --append found none it can rewrite)
+    xing: bool,
+    vbr: Option<VbrControl>,
+    finished: bool,
+}
+
+// Output file plus the bookkeeping needed for a Xing header
+struct Mp3FrameWriter {
+    file: MpegOutput,
+    // Start offset of every audio frame, for the Xing frame count and seek TOC
+    frame_starts: Vec<u64>,
+    // Where the MP3 stream starts (after the ID3 tag); Xing sizes and offsets are relative to it
+    audio_start: u64,
+    bytes_written: u64,
+    // --mp3-emphasis bits, patched into each frame header (shine-rs always writes none)
+    emphasis: u8,
+}
+
+impl Mp3FrameWriter {
+    fn write_frames(&mut self, chunks: Vec<Vec<u8>>) -> Result<()> {
+        for mut c in chunks {
+            if self.emphasis != 0 {
+                c[3] = (c[3] & !0b11) | self.emphasis;
+            }
+            self.frame_starts.push(self.bytes_written);
+            self.file.write_all(&c)?;
+            self.bytes_written += c.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+impl TagArgs {
+    pub(crate) fn is_empty(&self) -> bool {
+        !self.auto_tags
+            && self.title.is_none()
+            && self.artist.is_none()
+            && self.album.is_none()
+            && self.year.is_none()
+            && self.comment.is_none()
+            && self.genre.is_none()
+    }
+
+    // Explicit flags win; --auto-tags fills the gaps from the current media session.
+    pub(crate) fn resolve(&self, console: Console) -> TagArgs {
+        let mut tags = self.clone();
+        if self.auto_tags {
+            match now_playing() {
+                Some(np) => {
+                    info!(console,
+                        "Now playing: {} - {}",
+                        np.artist.as_deref().unwrap_or("<unknown artist>"),
+                        np.title.as_deref().unwrap_or("<unknown title>")
+                    );
+                    tags.title = tags.title.or(np.title);
+                    tags.artist = tags.artist.or(np.artist);
+                    tags.album = tags.album.or(np.album);
+                }
+                None => info!(console, "No active media session; --auto-tags adds nothing"),
+            }
+        }
+        tags
+    }
+
+    // Minimal ID3v2.3 tag (empty if there is nothing to write).
+    pub(crate) fn to_id3v2(&self) -> Vec<u8> {
+        let year = self.year.map(|y| format!("{y:04}"));
+        let text_frames = [
+            (b"TIT2", &self.title),
+            (b"TPE1", &self.artist),
+            (b"TALB", &self.album),
+            (b"TYER", &year),
+            (b"TCON", &self.genre),
+        ];
+        if text_frames.iter().all(|(_, v)| v.is_none()) && self.comment.is_none() {
+            return Vec::new();
+        }
+
+        let mut frames = Vec::new();
+        for (id, value) in text_frames {
+            if let Some(value) = value {
+                id3_frame(&mut frames, id, &id3_text(value, false));
+            }
+        }
+        if let Some(comment) = &self.comment {
+            // COMM: encoding, language, empty short description, then the text
+            let encoded = id3_text(comment, true);
+            let mut body = vec![encoded[0]];
+            body.extend_from_slice(b"eng");
+            body.extend_from_slice(&encoded[1..]);
+            id3_frame(&mut frames, b"COMM", &body);
+        }
+
+        let mut tag = Vec::with_capacity(10 + frames.len());
+        tag.extend_from_slice(b"ID3");
+        tag.extend_from_slice(&[3, 0]); // version 2.3.0
+        tag.push(0); // flags
+        tag.extend_from_slice(&syncsafe(frames.len() as u32));
+        tag.extend_from_slice(&frames);
+        tag
+    }
+
+    // FLAC VORBIS_COMMENT block body (empty if there is nothing to write). A channel mask is
+    // only needed when the layout isn't FLAC's default one for the channel count.
+    pub(crate) fn to_vorbis_comment(&self, channel_mask: Option<u32>) -> Vec<u8> {
+        let year = self.year.map(|y| format!("{y:04}"));
+        let fields = [
+            ("TITLE", &self.title),
+            ("ARTIST", &self.artist),
+            ("ALBUM", &self.album),
+            ("DATE", &year),
+            ("GENRE", &self.genre),
+            ("COMMENT", &self.comment),
+        ];
+        let mut comments: Vec<String> = fields
+            .iter()
+            .filter_map(|(key, value)| value.as_ref().map(|v| format!("{key}={v}")))
+            .collect();
+        if let Some(mask) = channel_mask {
+            comments.push(format!("WAVEFORMATEXTENSIBLE_CHANNEL_MASK={mask:#06x}"));
+        }
+        if comments.is_empty() {
+            return Vec::new();
+        }
+
+        // Little-endian lengths, unlike the rest of FLAC
+        let vendor = b"ARec";
+        let mut body = Vec::new();
+        body.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        body.extend_from_slice(vendor);
+        body.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            body.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            body.extend_from_slice(comment.as_bytes());
+        }
+        body
+    }
+}
+
+// Track info reported by the system media transport controls.
+pub(crate) struct NowPlaying {
+    pub(crate) title: Option<String>,
+    pub(crate) artist: Option<String>,
+    album: Option<String>,
+}
+
+// Ask the Windows media session manager for the current session's track.
+// Any failure (no session, API unavailable) just means no metadata.
+pub(crate) fn now_playing() -> Option<NowPlaying> {
+    use windows::Media::Control::GlobalSystemMediaTransportControlsSessionManager;
+
+    let manager = GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
+        .ok()?
+        .join()
+        .ok()?;
+    let session = manager.GetCurrentSession().ok()?;
+    let props = session.TryGetMediaPropertiesAsync().ok()?.join().ok()?;
+
+    let text = |value: windows::core::Result<windows::core::HSTRING>| {
+        value.ok().map(|s| s.to_string()).filter(|s| !s.is_empty())
+    };
+    Some(NowPlaying {
+        title: text(props.Title()),
+        artist: text(props.Artist()),
+        album: text(props.AlbumTitle()),
+    })
+}
+
+// ID3v2.3 frame: 4-byte ID, 32-bit big-endian size (not syncsafe in 2.3), 2 flag bytes.
+fn id3_frame(out: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(&[0, 0]);
+    out.extend_from_slice(body);
+}
+
+// Encoding byte + text: ISO-8859-1 when possible, otherwise UTF-16 with BOM.
+// With `described`, an empty (terminated) description precedes the text, as COMM needs.
+fn id3_text(text: &str, described: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    if text.chars().all(|c| (c as u32) < 0x100) {
+        out.push(0);
+        if described {
+            out.push(0);
+        }
+        out.extend(text.chars().map(|c| c as u8));
+    } else {
+        out.push(1);
+        if described {
+            out.extend_from_slice(&[0xFF, 0xFE, 0, 0]);
+        }
+        out.extend_from_slice(&[0xFF, 0xFE]);
+        for unit in text.encode_utf16() {
+            out.extend_from_slice(&unit.to_le_bytes());
+        }
+    }
+    out
+}
+
+// 28-bit size as four 7-bit bytes (ID3v2 header).
+fn syncsafe(size: u32) -> [u8; 4] {
+    [
+        ((size >> 21) & 0x7F) as u8,
+        ((size >> 14) & 0x7F) as u8,
+        ((size >> 7) & 0x7F) as u8,
+        (size & 0x7F) as u8,
+    ]
+}
+
+// Per-frame bitrate selection. shine has no native VBR, but it runs without a bit
+// reservoir, so every frame is self-contained and the bitrate can change between frames.
+struct VbrControl {
+    quality: u8,
+    // Valid bitrates for this sample rate within the quality's range, ascending
+    bitrates: Vec<u32>,
+    // Samples waiting for a full frame, so each encode call is exactly one frame
+    pending: Vec<i16>,
+}
+
+impl Mp3Sink {
+    pub(crate) fn new(
+        mut file: MpegOutput,
+        sample_rate: u32,
+        kbps: u32,
+        channels: usize,
+        header: Mp3Header,
+        vbr_quality: Option<u8>,
+        id3_tag: &[u8],
+    ) -> Result<Self> {
+        let (encoder, vbr) = Self::encoder(sample_rate, kbps, channels, header, vbr_quality)?;
+
+        // ID3v2 tag (if any) goes first, before any MP3 frame
+        file.write_all(id3_tag)?;
+        let audio_start = id3_tag.len() as u64;
+        let mut bytes_written = audio_start;
+
+        // A Xing (VBR) or Info (CBR) header lets players show the length and seek
+        // without scanning the file. Reserve its frame now and fill it in finish().
+        // CBR keeps the stream's bitrate for it when the payload fits, so the file
+        // stays constant-bitrate for decoders that skip the header.
+        let xing_kbps = match vbr {
+            None if mp3_frame_len(sample_rate, kbps) >= xing_frame_needed(sample_rate, channels) => {
+                kbps
+            }
+            _ => xing_frame_kbps(sample_rate, channels),
+        };
+        let xing = matches!(file, MpegOutput::File(_));
+        if xing {
+            let reserved = vec![0u8; mp3_frame_len(sample_rate, xing_kbps)];
+            file.write_all(&reserved)?;
+            bytes_written += reserved.len() as u64;
+        }
+
+        Ok(Self {
+            encoder,
+            out: Mp3FrameWriter {
+                file,
+                frame_starts: Vec::new(),
+                audio_start,
+                bytes_written,
+                emphasis: header.emphasis.bits(),
+            },
+            sample_rate,
+            channels,
+            header,
+            xing_kbps,
+            xing,
+            vbr,
+            finished: false,
+        })
+    }
+
+    // --append: continues an existing MP3 of this sample rate and channel count after its
+    // last whole frame (an ID3v1 tag or a cut-off frame there is dropped). Its Xing or Info
+    // frame is rewritten for the whole file at the end, as for a new one.
+    pub(crate) fn append(
+        mut file: File,
+        path: &str,
+        sample_rate: u32,
+        kbps: u32,
+        channels: usize,
+        header: Mp3Header,
+        vbr_quality: Option<u8>,
+    ) -> Result<Self> {
+        let scan = scan_mp3(&mut file).with_context(|| format!("--append: failed to read {path}"))?;
+        let Some((file_rate, file_channels)) = scan.format else {
+            bail!("--append: {path} holds no MP3 frames");
+        };
+        if (file_rate, file_channels) != (sample_rate, channels) {
+            bail!(
+                "--append: {path} is {file_rate} Hz with {file_channels} channel(s), but this \
+                 recording is {sample_rate} Hz with {channels}"
+            );
+        }
+        // The new Xing frame must fill the old one's place exactly
+        let xing_kbps = match scan.xing {
+            Some((kbps, len))
+                if len == mp3_frame_len(sample_rate, kbps)
+                    && len >= xing_frame_needed(sample_rate, channels) =>
+            {
+                Some(kbps)
+            }
+            Some(_) => {
+                eprintln!(
+                    "Warning: {path}'s Xing header can't be rewritten; players may show the \
+                     length it had before"
+                );
+                None
+            }
+            None => None,
+        };
+        file.set_len(scan.end)?;
+        file.seek(SeekFrom::End(0))?;
+        let (encoder, vbr) = Self::encoder(sample_rate, kbps, channels, header, vbr_quality)?;
+        Ok(Self {
+            encoder,
+            out: Mp3FrameWriter {
+                file: MpegOutput::File(BufWriter::new(file)),
+                frame_starts: scan.frame_starts,
+                audio_start: scan.audio_start,
+                bytes_written: scan.end,
+                emphasis: header.emphasis.bits(),
+            },
+            sample_rate,
+            channels,
+            header,
+            xing_kbps: xing_kbps.unwrap_or(kbps),
+            xing: xing_kbps.is_some(),
+            vbr,
+            finished: false,
+        })
+    }
+
+    fn encoder(
+        sample_rate: u32,
+        kbps: u32,
+        channels: usize,
+        header: Mp3Header,
+        vbr_quality: Option<u8>,
+    ) -> Result<(Mp3Encoder, Option<VbrControl>)> {
+        let vbr = vbr_quality.map(|quality| VbrControl {
+            quality,
+            bitrates: vbr_bitrates(sample_rate, quality),
+            pending: Vec::new(),
+        });
+
+        // shine-rs 0.1.3 fields: sample_rate, bitrate, channels, stereo_mode, copyright, original
+        let enc_cfg = Mp3EncoderConfig {
+            sample_rate,
+            // VBR starts at the top of its range and is re-selected per frame
+            bitrate: vbr
+                .as_ref()
+                .and_then(|v| v.bitrates.last().copied())
+                .unwrap_or(kbps),
+            channels: channels as u8,
+            stereo_mode: header.stereo_mode,
+            copyright: header.copyright,
+            original: header.original,
+        };
+
+        let encoder = Mp3Encoder::new(enc_cfg).map_err(|e| anyhow!("mp3 encoder init: {e:?}"))?;
+        Ok((encoder, vbr))
+    }
+
+    fn write_xing_header(&mut self) -> Result<()> {
+        // stdout can't seek back to the reserved frame (none was reserved)
+        let MpegOutput::File(file) = &mut self.out.file else {
+            return Ok(());
+        };
+        if !self.xing {
+            return Ok(());
+        }
+
+        let start = self.out.audio_start;
+        let stream_bytes = self.out.bytes_written - start;
+        let frame_starts: Vec<u64> = self.out.frame_starts.iter().map(|&pos| pos - start).collect();
+        // Xing quality: 0 = best, 100 = worst. CBR writes an Info header without one.
+        let quality = self.vbr.as_ref().map(|vbr| vbr.quality as u32 * 10);
+        let frame = build_xing_frame(
+            self.sample_rate,
+            self.channels,
+            self.header,
+            self.xing_kbps,
+            &frame_starts,
+            stream_bytes,
+            quality,
+        );
+
+        file.seek(SeekFrom::Start(start))?;
+        file.write_all(&frame)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+fn encode_vbr_frame(
+    encoder: &mut Mp3Encoder,
+    out: &mut Mp3FrameWriter,
+    frame: &[i16],
+    kbps: u32,
+) -> Result<()> {
+    set_frame_bitrate(encoder, kbps);
+    let chunks = encoder
+        .encode_interleaved(frame)
+        .map_err(|e| anyhow!("encode error: {e:?}"))?;
+    out.write_frames(chunks)
+}
+
+impl Sink for Mp3Sink {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        let Some(vbr) = &mut self.vbr else {
+            let chunks = self
+                .encoder
+                .encode_interleaved(samples)
+                .map_err(|e| anyhow!("encode error: {e:?}"))?;
+            return self.out.write_frames(chunks);
+        };
+
+        // Encode one frame at a time so each frame gets its own bitrate.
+        let frame_len = self.encoder.samples_per_frame();
+        vbr.pending.extend_from_slice(samples);
+
+        let mut consumed = 0;
+        while vbr.pending.len() - consumed >= frame_len {
+            let frame = &vbr.pending[consumed..consumed + frame_len];
+            let kbps = vbr_frame_bitrate(frame, self.channels, &vbr.bitrates);
+            encode_vbr_frame(&mut self.encoder, &mut self.out, frame, kbps)?;
+            consumed += frame_len;
+        }
+        vbr.pending.drain(..consumed);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        // Pad and encode the last partial VBR frame ourselves so it is counted.
+        if let Some(vbr) = &mut self.vbr
+            && !vbr.pending.is_empty()
+        {
+            vbr.pending.resize(self.encoder.samples_per_frame(), 0);
+            let kbps = vbr_frame_bitrate(&vbr.pending, self.channels, &vbr.bitrates);
+            encode_vbr_frame(&mut self.encoder, &mut self.out, &vbr.pending, kbps)?;
+            vbr.pending.clear();
+        }
+
+        // The tail is the zero-padded last CBR frame, if samples were left over
+        let partial_frame = self.encoder.buffered_samples() > 0;
+        let tail = self.encoder.finish().map_err(|e| anyhow!("finish error: {e:?}"))?;
+        if partial_frame && !tail.is_empty() {
+            self.out.frame_starts.push(self.out.bytes_written);
+        }
+        self.out.file.write_all(&tail)?;
+        self.out.bytes_written += tail.len() as u64;
+
+        self.write_xing_header()?;
+        self.out.file.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.out.bytes_written
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.out.file.flush()?)
+    }
+}
+
+// Dropped without finish() (capture error, panic): keep the frames written so far playable.
+// The encoder isn't touched here, so its buffered partial frame is lost.
+impl Drop for Mp3Sink {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.write_xing_header();
+            let _ = self.out.file.flush();
+        }
+    }
+}
+
+// Layer III bitrate tables (kbps by header bitrate index)
+const MPEG1_L3_BITRATES: [u32; 15] =
+    [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+const MPEG2_L3_BITRATES: [u32; 15] =
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+// (header version bits, sample rate index) for a supported sample rate
+fn mpeg_header_version(sample_rate: u32) -> (u8, u8) {
+    match sample_rate {
+        44100 => (0b11, 0),
+        48000 => (0b11, 1),
+        32000 => (0b11, 2),
+        22050 => (0b10, 0),
+        24000 => (0b10, 1),
+        16000 => (0b10, 2),
+        11025 => (0b00, 0),
+        12000 => (0b00, 1),
+        _ => (0b00, 2), // 8000
+    }
+}
+
+fn is_mpeg1(sample_rate: u32) -> bool {
+    sample_rate >= 32000
+}
+
+// PCM frames per MP3 frame: two granules for MPEG-1, one for MPEG-2/2.5
+pub(crate) fn mp3_samples_per_frame(sample_rate: u32) -> usize {
+    if is_mpeg1(sample_rate) {
+        2 * GRANULE_SIZE
+    } else {
+        GRANULE_SIZE
+    }
+}
+
+// Bytes per Layer III frame (without padding)
+fn mp3_frame_len(sample_rate: u32, kbps: u32) -> usize {
+    let coeff = if is_mpeg1(sample_rate) { 144_000 } else { 72_000 };
+    (coeff * kbps / sample_rate) as usize
+}
+
+fn side_info_len(sample_rate: u32, channels: usize) -> usize {
+    match (is_mpeg1(sample_rate), channels == 1) {
+        (true, true) => 17,
+        (true, false) => 32,
+        (false, true) => 9,
+        (false, false) => 17,
+    }
+}
+
+// The value in `supported` closest to `value`; a tie goes to the lower one
+pub(crate) fn nearest_supported(supported: &[u32], value: u32) -> u32 {
+    let mut sorted = supported.to_vec();
+    sorted.sort_unstable();
+    sorted
+        .into_iter()
+        .min_by_key(|&v| v.abs_diff(value))
+        .unwrap_or(value)
+}
+
+pub(crate) fn valid_bitrates(sample_rate: u32) -> Vec<u32> {
+    SUPPORTED_BITRATES
+        .iter()
+        .copied()
+        .filter(|&br| shine_check_config(sample_rate as i32, br as i32) >= 0)
+        .collect()
+}
+
+// Bitrate range per VBR quality, in MPEG-1 kbps. Lower sample rates scale the range down.
+const VBR_RANGES: [(u32, u32); 10] = [
+    (160, 320),
+    (128, 256),
+    (112, 224),
+    (96, 192),
+    (80, 160),
+    (64, 128),
+    (56, 112),
+    (48, 96),
+    (40, 80),
+    (32, 64),
+];
+
+fn vbr_bitrates(sample_rate: u32, quality: u8) -> Vec<u32> {
+    let (min, max) = VBR_RANGES[quality as usize];
+    let scale = if is_mpeg1(sample_rate) {
+        1.0
+    } else {
+        sample_rate as f64 / 48_000.0
+    };
+    let min = (min as f64 * scale) as u32;
+    let max = (max as f64 * scale) as u32;
+
+    let valid = valid_bitrates(sample_rate);
+    let in_range: Vec<u32> = valid
+        .iter()
+        .copied()
+        .filter(|&br| br >= min && br <= max)
+        .collect();
+    if in_range.is_empty() {
+        // Range fell between valid bitrates; use the closest valid one to the top of it.
+        let nearest = valid
+            .iter()
+            .copied()
+            .min_by_key(|&br| br.abs_diff(max))
+            .unwrap_or(DEFAULT_KBPS);
+        vec![nearest]
+    } else {
+        in_range
+    }
+}
+
+// Pick a bitrate for one frame from a simple signal-complexity estimate:
+// louder frames with more high-frequency energy (large sample-to-sample
+// differences relative to signal energy) get more bits; near-silence gets the minimum.
+fn vbr_frame_bitrate(frame: &[i16], channels: usize, bitrates: &[u32]) -> u32 {
+    let mut energy = 0f64;
+    let mut diff_energy = 0f64;
+    for (i, &s) in frame.iter().enumerate() {
+        let s = s as f64;
+        energy += s * s;
+        if i >= channels {
+            let d = s - frame[i - channels] as f64;
+            diff_energy += d * d;
+        }
+    }
+
+    let n = frame.len().max(1) as f64;
+    let rms_dbfs = 10.0 * (energy / n / (32768.0 * 32768.0)).max(1e-12).log10();
+    if rms_dbfs < -60.0 {
+        return bitrates[0];
+    }
+
+    let loudness = ((rms_dbfs + 60.0) / 60.0).clamp(0.0, 1.0);
+    let brightness = (diff_energy / energy.max(1.0)).sqrt().min(1.0);
+    let complexity = loudness * (0.4 + 0.6 * brightness);
+
+    let idx = (complexity * (bitrates.len() - 1) as f64).round() as usize;
+    bitrates[idx.min(bitrates.len() - 1)]
+}
+
+// Re-derive shine's per-frame slot sizes for a new bitrate (mirrors shine_initialise).
+fn set_frame_bitrate(encoder: &mut Mp3Encoder, kbps: u32) {
+    let cfg = encoder.shine_config();
+    if cfg.mpeg.bitr == kbps as i32 {
+        return;
+    }
+
+    cfg.mpeg.bitr = kbps as i32;
+    cfg.mpeg.bitrate_index = shine_find_bitrate_index(cfg.mpeg.bitr, cfg.mpeg.version);
+
+    let avg_slots_per_frame = (cfg.mpeg.granules_per_frame as f64 * GRANULE_SIZE as f64
+        / cfg.wave.samplerate as f64)
+        * (1000.0 * cfg.mpeg.bitr as f64 / cfg.mpeg.bits_per_slot as f64);
+    cfg.mpeg.whole_slots_per_frame = avg_slots_per_frame as i32;
+    cfg.mpeg.frac_slots_per_frame = avg_slots_per_frame - cfg.mpeg.whole_slots_per_frame as f64;
+    cfg.mpeg.slot_lag = -cfg.mpeg.frac_slots_per_frame;
+    if cfg.mpeg.frac_slots_per_frame == 0.0 {
+        cfg.mpeg.padding = 0;
+    }
+}
+
+// "Xing" + flags + frames + bytes + TOC + quality
+const XING_PAYLOAD_LEN: usize = 4 + 4 + 4 + 4 + 100 + 4;
+
+// Frame bytes the Xing payload needs after header + side info
+fn xing_frame_needed(sample_rate: u32, channels: usize) -> usize {
+    4 + side_info_len(sample_rate, channels) + XING_PAYLOAD_LEN
+}
+
+// Smallest valid frame that can carry the Xing payload
+fn xing_frame_kbps(sample_rate: u32, channels: usize) -> u32 {
+    let needed = xing_frame_needed(sample_rate, channels);
+    let valid = valid_bitrates(sample_rate);
+    valid
+        .iter()
+        .copied()
+        .find(|&br| mp3_frame_len(sample_rate, br) >= needed)
+        .unwrap_or_else(|| valid.last().copied().unwrap_or(DEFAULT_KBPS))
+}
+
+// 100-entry seek table: entry i is the byte position at i% of the duration, scaled to 0..256.
+fn xing_toc(frame_starts: &[u64], total_bytes: u64) -> [u8; 100] {
+    let mut toc = [0u8; 100];
+    if frame_starts.is_empty() || total_bytes == 0 {
+        return toc;
+    }
+    for (i, entry) in toc.iter_mut().enumerate() {
+        let frame = i * frame_starts.len() / 100;
+        *entry = (frame_starts[frame] * 256 / total_bytes).min(255) as u8;
+    }
+    toc
+}
+
+// A silent Layer III frame whose payload is the Xing header ("Xing" with a quality
+// for VBR, "Info" without one for CBR). Decoders that understand it use it for
+// duration/seeking; others just decode one silent frame.
+fn build_xing_frame(
+    sample_rate: u32,
+    channels: usize,
+    header: Mp3Header,
+    kbps: u32,
+    frame_starts: &[u64],
+    stream_bytes: u64,
+    quality: Option<u32>,
+) -> Vec<u8> {
+    let frames = frame_starts.len() as u32;
+    let total_bytes = u32::try_from(stream_bytes).unwrap_or(u32::MAX);
+    let toc = xing_toc(frame_starts, stream_bytes);
+    let (version, sr_index) = mpeg_header_version(sample_rate);
+    let table = if is_mpeg1(sample_rate) {
+        &MPEG1_L3_BITRATES
+    } else {
+        &MPEG2_L3_BITRATES
+    };
+    let br_index = table.iter().position(|&b| b == kbps).unwrap_or(0) as u8;
+    // StereoMode's discriminants are the header's mode bits (stereo, joint, dual, mono)
+    let mode = header.stereo_mode as u8;
+
+    let mut frame = vec![0u8; mp3_frame_len(sample_rate, kbps)];
+    // sync(11) version(2) layer III(01) no CRC(1)
+    frame[0] = 0xFF;
+    frame[1] = 0xE0 | (version << 3) | (0b01 << 1) | 1;
+    frame[2] = (br_index << 4) | (sr_index << 2);
+    frame[3] = (mode << 6) | header.flag_bits(); // the same flags as the audio frames
+
+    let mut pos = 4 + side_info_len(sample_rate, channels);
+    let mut put = |bytes: &[u8]| {
+        frame[pos..pos + bytes.len()].copy_from_slice(bytes);
+        pos += bytes.len();
+    };
+    match quality {
+        Some(_) => {
+            put(b"Xing");
+            put(&0x0Fu32.to_be_bytes()); // frames | bytes | TOC | quality present
+        }
+        None => {
+            put(b"Info");
+            put(&0x07u32.to_be_bytes()); // frames | bytes | TOC present
+        }
+    }
+    put(&frames.to_be_bytes());
+    put(&total_bytes.to_be_bytes());
+    put(&toc);
+    if let Some(quality) = quality {
+        put(&quality.to_be_bytes());
+    }
+    frame
+}
+
+// What --append needs to know about an existing MP3: where the stream starts (after an
+// ID3v2 tag), the bitrate and length of its Xing/Info frame, the start of every audio
+// frame after it, where the last whole frame ends, and the stream's rate and channels
+struct Mp3Scan {
+    audio_start: u64,
+    xing: Option<(u32, usize)>,
+    frame_starts: Vec<u64>,
+    end: u64,
+    format: Option<(u32, usize)>,
+}
+
+fn scan_mp3(file: &mut File) -> Result<Mp3Scan> {
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = io::BufReader::new(file);
+    let mut id3 = [0u8; 10];
+    let mut audio_start = 0;
+    if reader.read_exact(&mut id3).is_ok() && &id3[..3] == b"ID3" {
+        // Syncsafe size, not counting the header (or the footer, flag 0x10)
+        let size = id3[6..10].iter().fold(0u64, |acc, &b| (acc << 7) | (b & 0x7F) as u64);
+        audio_start = 10 + size + if id3[5] & 0x10 != 0 { 10 } else { 0 };
+    }
+
+    let mut scan = Mp3Scan {
+        audio_start,
+        xing: None,
+        frame_starts: Vec::new(),
+        end: audio_start,
+        format: None,
+    };
+    let mut pos = audio_start;
+    reader.seek(SeekFrom::Start(pos))?;
+    while pos + 4 <= len {
+        let mut head = [0u8; 4];
+        reader.read_exact(&mut head)?;
+        let Some((rate, kbps, channels, frame_len)) = parse_mp3_frame_header(head) else {
+            break;
+        };
+        if pos + frame_len as u64 > len {
+            break;
+        }
+        if pos == audio_start {
+            // A Xing or Info tag sits right after the side information of the first frame
+            let mut frame = vec![0u8; frame_len - 4];
+            reader.read_exact(&mut frame)?;
+            let tag = side_info_len(rate, channels);
+            if matches!(frame.get(tag..tag + 4), Some(b"Xing" | b"Info")) {
+                scan.xing = Some((kbps, frame_len));
+            }
+        } else {
+            // Relative seeks keep the read buffer, so the scan reads the file only once
+            reader.seek_relative(frame_len as i64 - 4)?;
+        }
+        if scan.xing.is_none() || pos != audio_start {
+            scan.frame_starts.push(pos);
+        }
+        scan.format.get_or_insert((rate, channels));
+        pos += frame_len as u64;
+        scan.end = pos;
+    }
+    Ok(scan)
+}
+
+// Sample rate, bitrate, channel count and length (with padding) of a Layer III frame
+// header, or None if `head` isn't one
+fn parse_mp3_frame_header(head: [u8; 4]) -> Option<(u32, u32, usize, usize)> {
+    if head[0] != 0xFF || head[1] & 0xE0 != 0xE0 || (head[1] >> 1) & 0b11 != 0b01 {
+        return None;
+    }
+    let rates: [u32; 3] = match (head[1] >> 3) & 0b11 {
+        0b11 => [44100, 48000, 32000],
+        0b10 => [22050, 24000, 16000],
+        0b00 => [11025, 12000, 8000],
+        _ => return None,
+    };
+    let rate = *rates.get(((head[2] >> 2) & 0b11) as usize)?;
+    let table = if is_mpeg1(rate) {
+        &MPEG1_L3_BITRATES
+    } else {
+        &MPEG2_L3_BITRATES
+    };
+    let kbps = match (head[2] >> 4) as usize {
+        0 | 15 => return None,
+        index => table[index],
+    };
+    let channels = if head[3] >> 6 == 0b11 { 1 } else { 2 };
+    let padding = ((head[2] >> 1) & 1) as usize;
+    Some((rate, kbps, channels, mp3_frame_len(rate, kbps) + padding))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        time::{Duration, Instant},
+    };
+
+    use crate::args::RecordArgs;
+    use crate::net::StreamServer;
+    use crate::sinks::{MpegOutput, Sink};
+    use crate::Console;
+
+    use super::{nearest_supported, parse_mp3_frame_header, syncsafe, Mp3Header, Mp3Sink};
+
+    // Sends `request` and reads the response headers, plus `body` bytes after them
+    fn http(address: std::net::SocketAddr, request: &str, body: usize) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+            head.push(byte[0]);
+        }
+        let mut audio = vec![0u8; body];
+        stream.read_exact(&mut audio).unwrap();
+        (String::from_utf8(head).unwrap(), audio)
+    }
+
+    #[test]
+    fn serve_streams_mp3_frames_to_a_get() {
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let console = Console { quiet: true, stdout_audio: false };
+        let server = StreamServer::listen(socket, console).unwrap();
+        let args = RecordArgs::parse_from(Vec::<String>::new()).unwrap();
+        let header = Mp3Header::new(&args, 2);
+        let output = MpegOutput::Serve(server);
+        let mut sink = Mp3Sink::new(output, 48_000, 128, 2, header, None, &[]).unwrap();
+
+        let listener = std::thread::spawn(move || http(address, "GET / HTTP/1.0\r\n\r\n", 4));
+        // Audio only reaches a listener once it is connected, so keep encoding until then
+        let start = Instant::now();
+        while !listener.is_finished() && start.elapsed() < Duration::from_secs(10) {
+            sink.write_samples(&[0; 1152 * 2]).unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let (head, audio) = listener.join().unwrap();
+        assert!(head.starts_with("HTTP/1.0 200 OK\r\n"), "{head}");
+        assert!(head.contains("\r\nContent-Type: audio/mpeg\r\n"), "{head}");
+        let frame = parse_mp3_frame_header(audio.try_into().unwrap());
+        assert!(matches!(frame, Some((48_000, 128, 2, _))), "{frame:?}");
+
+        let (head, _) = http(address, "POST / HTTP/1.0\r\n\r\n", 0);
+        assert!(head.starts_with("HTTP/1.0 405 Method Not Allowed\r\n"), "{head}");
+        sink.finish().unwrap();
+    }
+
+    #[test]
+    fn syncsafe_sizes_use_seven_bits_per_byte() {
+        assert_eq!(syncsafe(0), [0, 0, 0, 0]);
+        assert_eq!(syncsafe(127), [0, 0, 0, 127]);
+        assert_eq!(syncsafe(128), [0, 0, 1, 0]);
+        assert_eq!(syncsafe(257), [0, 0, 2, 1]);
+        assert_eq!(syncsafe(0x0FFF_FFFF), [0x7F; 4]);
+    }
+
+    // The frames of an ID3v2.3 tag as (ID, body), after checking its header
+    fn id3_frames(tag: &[u8]) -> Vec<(String, Vec<u8>)> {
+        assert_eq!(&tag[..6], b"ID3\x03\x00\x00");
+        let size = tag[6..10].iter().fold(0usize, |acc, &b| {
+            assert!(b < 0x80);
+            (acc << 7) | b as usize
+        });
+        assert_eq!(size, tag.len() - 10);
+        let mut frames = Vec::new();
+        let mut rest = &tag[10..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[4..8].try_into().unwrap()) as usize;
+            assert_eq!(&rest[8..10], [0, 0]);
+            let id = String::from_utf8(rest[..4].to_vec()).unwrap();
+            frames.push((id, rest[10..10 + len].to_vec()));
+            rest = &rest[10 + len..];
+        }
+        frames
+    }
+
+    #[test]
+    fn id3_tag_frames_parse_back() {
+        let args = RecordArgs::parse_from([
+            "--title", "Caf\u{e9}", "--artist", "Band", "--year", "734",
+            "--comment", "\u{2615} live",
+        ])
+        .unwrap();
+        let frames = id3_frames(&args.tags.to_id3v2());
+        let ids: Vec<&str> = frames.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, ["TIT2", "TPE1", "TYER", "COMM"]);
+        // ISO-8859-1 where it fits
+        assert_eq!(frames[0].1, b"\x00Caf\xe9");
+        assert_eq!(frames[1].1, b"\x00Band");
+        assert_eq!(frames[2].1, b"\x000734");
+        // UTF-16 otherwise; COMM has a language and an empty description before the text
+        let mut comment = b"\x01eng\xff\xfe\x00\x00\xff\xfe".to_vec();
+        comment.extend("\u{2615} live".encode_utf16().flat_map(u16::to_le_bytes));
+        assert_eq!(frames[3].1, comment);
+    }
+
+    #[test]
+    fn no_tags_write_no_id3_tag() {
+        let args = RecordArgs::parse_from(Vec::<String>::new()).unwrap();
+        assert!(args.tags.to_id3v2().is_empty());
+    }
+
+    #[test]
+    fn nearest_supported_picks_the_closest_value() {
+        let rates = [48_000, 32_000, 44_100, 22_050];
+        assert_eq!(nearest_supported(&rates, 44_100), 44_100);
+        assert_eq!(nearest_supported(&rates, 40_000), 44_100);
+        assert_eq!(nearest_supported(&rates, 47_000), 48_000);
+        assert_eq!(nearest_supported(&rates, 96_000), 48_000);
+        assert_eq!(nearest_supported(&rates, 8_000), 22_050);
+        // A tie goes to the lower value, whatever the order of the list
+        assert_eq!(nearest_supported(&[160, 128], 144), 128);
+        assert_eq!(nearest_supported(&[128, 160], 144), 128);
+        // Nothing to choose from leaves the value as it is
+        assert_eq!(nearest_supported(&[], 44_100), 44_100);
+    }
+}
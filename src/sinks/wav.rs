@@ -0,0 +1,218 @@
+//! 16-bit PCM WAV output.
+
+use anyhow::{anyhow, bail, Result};
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+use crate::sinks::{Sink, TruncateSink};
+
+// Canonical 44-byte-header PCM WAV, or WAVE_FORMAT_EXTENSIBLE (68-byte header) with the
+// speaker mask for more than two channels. The RIFF and data sizes are unknown while
+// recording, so they are written as 0 and patched in finish().
+pub(crate) struct WavSink<W: Write + Seek> {
+    out: W,
+    header_len: u64,
+    data_bytes: u64,
+    // Set by finish(), so Drop doesn't patch the header a second time
+    finished: bool,
+}
+
+// KSDATAFORMAT_SUBTYPE_PCM, as stored in WAVEFORMATEXTENSIBLE
+const WAV_SUBTYPE_PCM: [u8; 16] = [
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
+
+impl<W: Write + Seek> WavSink<W> {
+    pub(crate) fn new(
+        mut out: W,
+        sample_rate: u32,
+        channels: u16,
+        channel_mask: u32,
+    ) -> Result<Self> {
+        let bits_per_sample: u16 = 16;
+        let block_align = channels * bits_per_sample / 8;
+        let byte_rate = sample_rate * block_align as u32;
+        // Windows expects WAVE_FORMAT_EXTENSIBLE for anything beyond stereo
+        let extensible = channels > 2;
+        let fmt_len: u32 = if extensible { 40 } else { 16 };
+
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?; // RIFF size, patched in finish()
+        out.write_all(b"WAVE")?;
+        out.write_all(b"fmt ")?;
+        out.write_all(&fmt_len.to_le_bytes())?;
+        let format_tag: u16 = if extensible { 0xFFFE } else { 1 }; // WAVE_FORMAT_PCM
+        out.write_all(&format_tag.to_le_bytes())?;
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&byte_rate.to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&bits_per_sample.to_le_bytes())?;
+        if extensible {
+            out.write_all(&22u16.to_le_bytes())?; // cbSize
+            out.write_all(&bits_per_sample.to_le_bytes())?; // wValidBitsPerSample
+            out.write_all(&channel_mask.to_le_bytes())?;
+            out.write_all(&WAV_SUBTYPE_PCM)?;
+        }
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?; // data size, patched in finish()
+
+        Ok(Self {
+            out,
+            header_len: 20 + fmt_len as u64 + 8,
+            data_bytes: 0,
+            finished: false,
+        })
+    }
+
+    fn patch_header(&mut self) -> Result<()> {
+        // The RIFF size covers the header after its first 8 bytes too, so it overflows first
+        let riff_size = u32::try_from(self.header_len - 8 + self.data_bytes)
+            .map_err(|_| anyhow!("WAV data exceeds 4 GiB limit ({} bytes)", self.data_bytes))?;
+        let data_size = self.data_bytes as u32;
+
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&riff_size.to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(self.header_len - 4))?;
+        self.out.write_all(&data_size.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl WavSink<BufWriter<File>> {
+    // --append: reads the header of an existing 16-bit PCM WAV, which must have this rate
+    // and channel count and end with its data chunk, and continues after its last whole
+    // frame. A recording that was cut short may declare less data than it holds; the rest
+    // of the file counts as audio then, unless another chunk follows.
+    pub(crate) fn append(
+        mut file: File,
+        path: &str,
+        sample_rate: u32,
+        channels: u16,
+    ) -> Result<Self> {
+        let len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+        let not_wav = || anyhow!("--append: {path} isn't a WAV file");
+        let mut riff = [0u8; 12];
+        file.read_exact(&mut riff).map_err(|_| not_wav())?;
+        if &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+            return Err(not_wav());
+        }
+
+        let mut format = None;
+        let mut pos = 12;
+        let (data_start, declared) = loop {
+            let mut chunk = [0u8; 8];
+            file.read_exact(&mut chunk)
+                .map_err(|_| anyhow!("--append: {path} has no data chunk"))?;
+            let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64;
+            pos += 8;
+            match &chunk[..4] {
+                b"data" => break (pos, size),
+                b"fmt " if size >= 16 => {
+                    let mut fmt = vec![0u8; size.min(40) as usize];
+                    file.read_exact(&mut fmt).map_err(|_| not_wav())?;
+                    let u16_at = |i: usize| u16::from_le_bytes([fmt[i], fmt[i + 1]]);
+                    let tag = u16_at(0);
+                    let pcm = tag == 1
+                        || (tag == 0xFFFE && fmt.get(24..40) == Some(&WAV_SUBTYPE_PCM[..]));
+                    let rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+                    format = Some((pcm && u16_at(14) == 16, u16_at(2), rate));
+                }
+                _ => {}
+            }
+            // Chunks are padded to an even length
+            pos += size + (size & 1);
+            file.seek(SeekFrom::Start(pos))?;
+        };
+        let Some((pcm16, file_channels, file_rate)) = format else {
+            bail!("--append: {path} has no format chunk before its data");
+        };
+        if !pcm16 {
+            bail!("--append: {path} isn't 16-bit PCM");
+        }
+        if (file_rate, file_channels) != (sample_rate, channels) {
+            bail!(
+                "--append: {path} is {file_rate} Hz with {file_channels} channel(s), but this \
+                 recording is {sample_rate} Hz with {channels}"
+            );
+        }
+
+        let held = len.saturating_sub(data_start);
+        let declared_end = data_start + declared + (declared & 1);
+        if declared != 0 && declared_end + 8 <= len {
+            file.seek(SeekFrom::Start(declared_end))?;
+            let mut id = [0u8; 4];
+            file.read_exact(&mut id)?;
+            if id.iter().all(|b| b.is_ascii_alphanumeric() || *b == b' ') {
+                bail!("--append: {path} has more chunks after its audio data");
+            }
+        }
+        let block_align = channels as u64 * 2;
+        let data_bytes = held / block_align * block_align;
+        file.set_len(data_start + data_bytes)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            out: BufWriter::new(file),
+            header_len: data_start,
+            data_bytes,
+            finished: false,
+        })
+    }
+}
+
+impl<W: Write + Seek> Sink for WavSink<W> {
+    fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        for s in samples {
+            self.out.write_all(&s.to_le_bytes())?;
+        }
+        self.data_bytes += samples.len() as u64 * 2;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.patch_header()?;
+        self.finished = true;
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.header_len + self.data_bytes
+    }
+
+    // Keep the sizes current so a killed recording still plays up to the last flush.
+    // Past 4 GiB the header can't hold the size, which finish() reports
+    fn flush(&mut self) -> Result<()> {
+        if self.header_len - 8 + self.data_bytes <= u32::MAX as u64 {
+            self.patch_header()
+        } else {
+            Ok(self.out.flush()?)
+        }
+    }
+}
+
+// Dropped without finish(): patch the sizes so the samples written so far play
+impl<W: Write + Seek> Drop for WavSink<W> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.patch_header();
+        }
+    }
+}
+
+impl TruncateSink for WavSink<BufWriter<File>> {
+    fn truncate_samples(&mut self, samples: u64) -> Result<()> {
+        let bytes = samples * 2;
+        if bytes < self.data_bytes {
+            self.out.flush()?;
+            self.out.get_ref().set_len(self.header_len + bytes)?;
+            self.out.seek(SeekFrom::End(0))?;
+            self.data_bytes = bytes;
+        }
+        Ok(())
+    }
+}
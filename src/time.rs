@@ -0,0 +1,301 @@
+//! Local time, `--start-at` and `--duration` parsing, and time formatting.
+
+use anyhow::{bail, Result};
+use std::time::Duration;
+
+// Local wall-clock time, as broken-down fields.
+pub(crate) struct LocalTime {
+    pub(crate) year: u16,
+    pub(crate) month: u16,
+    pub(crate) day: u16,
+    pub(crate) hour: u16,
+    pub(crate) minute: u16,
+    pub(crate) second: u16,
+    pub(crate) millisecond: u16,
+}
+
+impl LocalTime {
+    // Milliseconds on a local-time axis (days since 1970-01-01 plus time of day).
+    // Not UTC: it only serves to compare and subtract local times.
+    fn local_millis(&self) -> i64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let secs = days * 86_400
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64;
+        secs * 1000 + self.millisecond as i64
+    }
+
+    fn from_local_millis(millis: i64) -> Self {
+        let days = millis.div_euclid(86_400_000);
+        let rem = millis.rem_euclid(86_400_000);
+        let (year, month, day) = civil_from_days(days);
+        LocalTime {
+            year: year as u16,
+            month: month as u16,
+            day: day as u16,
+            hour: (rem / 3_600_000) as u16,
+            minute: (rem / 60_000 % 60) as u16,
+            second: (rem / 1000 % 60) as u16,
+            millisecond: (rem % 1000) as u16,
+        }
+    }
+
+    // Time from `now` until self (zero if self has passed)
+    pub(crate) fn wait_from(&self, now: &LocalTime) -> Duration {
+        let ms = self.local_millis() - now.local_millis();
+        Duration::from_millis(ms.max(0) as u64)
+    }
+
+    // 2026-03-01T14:05:09.250, local time without an offset (--sidecar)
+    pub(crate) fn iso8601(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millisecond
+        )
+    }
+}
+
+impl std::fmt::Display for LocalTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date (Howard Hinnant's algorithm)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Inverse of days_from_civil: (year, month, day)
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+fn days_in_month(year: u16, month: u16) -> u16 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// --start-at value: a time of day (today or tomorrow), or a full local date and time.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct StartAt {
+    date: Option<(u16, u16, u16)>,
+    hour: u16,
+    minute: u16,
+    second: u16,
+}
+
+impl StartAt {
+    // The local time to start at. A time of day that has passed moves to tomorrow with
+    // `next_day`, otherwise it is an error, as is a full date and time in the past.
+    pub(crate) fn resolve(&self, now: &LocalTime, next_day: bool) -> Result<LocalTime> {
+        let (year, month, day) = self.date.unwrap_or((now.year, now.month, now.day));
+        let mut target = LocalTime {
+            year,
+            month,
+            day,
+            hour: self.hour,
+            minute: self.minute,
+            second: self.second,
+            millisecond: 0,
+        };
+        if target.local_millis() <= now.local_millis() {
+            if self.date.is_some() {
+                bail!("--start-at {target} is in the past (it is now {now})");
+            }
+            if !next_day {
+                bail!(
+                    "--start-at {:02}:{:02}:{:02} has already passed today (use --start-next-day to wait for tomorrow)",
+                    self.hour,
+                    self.minute,
+                    self.second
+                );
+            }
+            target = LocalTime::from_local_millis(target.local_millis() + 86_400_000);
+        }
+        Ok(target)
+    }
+}
+
+// --duration: bare seconds, [HH:]MM:SS, or hours, minutes and seconds such as 1h30m
+pub(crate) fn parse_duration(spec: &str) -> Result<u64, String> {
+    let err = || format!("expected seconds, HH:MM:SS or a duration like 1h30m, got '{spec}'");
+    let text = spec.trim().to_ascii_lowercase();
+    if text.is_empty() {
+        return Err(err());
+    }
+    if text.bytes().all(|b| b.is_ascii_digit()) {
+        return text.parse().map_err(|_| err());
+    }
+    let number = |part: &str| -> Result<u64, String> {
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(err());
+        }
+        part.parse().map_err(|_| err())
+    };
+    let total = |parts: &[(u64, u64)]| {
+        parts.iter().try_fold(0u64, |sum, &(value, unit)| {
+            value.checked_mul(unit).and_then(|v| sum.checked_add(v))
+        })
+    };
+    if text.contains(':') {
+        let parts: Vec<&str> = text.split(':').collect();
+        let (hours, minutes, secs) = match parts[..] {
+            [m, s] => ("0", m, s),
+            [h, m, s] => (h, m, s),
+            _ => return Err(err()),
+        };
+        let (hours, minutes, secs) = (number(hours)?, number(minutes)?, number(secs)?);
+        if secs > 59 || (parts.len() == 3 && minutes > 59) {
+            return Err(format!("'{spec}': minutes and seconds must be 0-59"));
+        }
+        return total(&[(hours, 3600), (minutes, 60), (secs, 1)])
+            .ok_or_else(|| format!("'{spec}' is too long"));
+    }
+    // Units in order, each at most once: [<n>h][<n>m][<n>s]
+    let mut parts = Vec::new();
+    let mut rest = text.as_str();
+    let mut units = [('h', 3600), ('m', 60), ('s', 1)].into_iter();
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(err)?;
+        let value = number(&rest[..digits])?;
+        let unit = rest[digits..].chars().next().ok_or_else(err)?;
+        let (_, scale) = units.find(|&(u, _)| u == unit).ok_or_else(err)?;
+        parts.push((value, scale));
+        rest = &rest[digits + unit.len_utf8()..];
+    }
+    total(&parts).ok_or_else(|| format!("'{spec}' is too long"))
+}
+
+// "HH:MM", "HH:MM:SS", or a date in front: "YYYY-MM-DDTHH:MM[:SS]" (a space also works)
+pub(crate) fn parse_start_at(spec: &str) -> Result<StartAt, String> {
+    let err = || format!("expected HH:MM[:SS] or YYYY-MM-DDTHH:MM[:SS], got '{spec}'");
+    let spec = spec.trim();
+    let (date, time) = match spec.split_once(['T', 't', ' ']) {
+        Some((date, time)) => (Some(date), time),
+        None => (None, spec),
+    };
+    let date = match date {
+        Some(date) => {
+            let parts: Vec<&str> = date.split('-').collect();
+            let [year, month, day] = parts[..] else {
+                return Err(err());
+            };
+            let year: u16 = year.parse().map_err(|_| err())?;
+            let month: u16 = month.parse().map_err(|_| err())?;
+            let day: u16 = day.parse().map_err(|_| err())?;
+            if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+                return Err(format!("'{date}' is not a valid date"));
+            }
+            Some((year, month, day))
+        }
+        None => None,
+    };
+    let parts: Vec<&str> = time.split(':').collect();
+    let (hour, minute, second) = match parts[..] {
+        [h, m] => (h, m, "0"),
+        [h, m, s] => (h, m, s),
+        _ => return Err(err()),
+    };
+    let hour: u16 = hour.parse().map_err(|_| err())?;
+    let minute: u16 = minute.parse().map_err(|_| err())?;
+    let second: u16 = second.parse().map_err(|_| err())?;
+    if hour > 23 || minute > 59 || second > 59 {
+        return Err(format!("'{time}' is not a valid time of day"));
+    }
+    Ok(StartAt {
+        date,
+        hour,
+        minute,
+        second,
+    })
+}
+
+// Time within a recording, as M:SS.s or H:MM:SS.s
+pub(crate) fn format_position(secs: f64) -> String {
+    let tenths = (secs * 10.0).round() as u64;
+    let (whole, tenth) = (tenths / 10, tenths % 10);
+    match (whole / 3600, whole / 60 % 60, whole % 60) {
+        (0, m, s) => format!("{m}:{s:02}.{tenth}"),
+        (h, m, s) => format!("{h}:{m:02}:{s:02}.{tenth}"),
+    }
+}
+
+// 3725 s -> "1h 02m 05s"
+pub(crate) fn format_wait(wait: Duration) -> String {
+    let secs = wait.as_secs_f64().ceil() as u64;
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m {s:02}s"),
+        (h, m, s) => format!("{h}h {m:02}m {s:02}s"),
+    }
+}
+
+pub(crate) fn local_now() -> LocalTime {
+    // SAFETY: GetLocalTime only fills in the returned struct.
+    let st = unsafe { windows::Win32::System::SystemInformation::GetLocalTime() };
+    LocalTime {
+        year: st.wYear,
+        month: st.wMonth,
+        day: st.wDay,
+        hour: st.wHour,
+        minute: st.wMinute,
+        second: st.wSecond,
+        millisecond: st.wMilliseconds,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_duration;
+
+    #[test]
+    fn duration_accepts_each_form() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration(" 0 "), Ok(0));
+        assert_eq!(parse_duration("1:30"), Ok(90));
+        assert_eq!(parse_duration("90:00"), Ok(5400));
+        assert_eq!(parse_duration("01:02:03"), Ok(3723));
+        assert_eq!(parse_duration("1h30m"), Ok(5400));
+        assert_eq!(parse_duration("1H30M15S"), Ok(5415));
+        assert_eq!(parse_duration("45s"), Ok(45));
+        assert_eq!(parse_duration("2h5s"), Ok(7205));
+    }
+
+    #[test]
+    fn duration_rejects_malformed_input() {
+        for spec in ["", "  ", "1h-5m", "-5", "1.5", "1:2:3:4", "1:", ":30", "1:60", "1:60:00"] {
+            assert!(parse_duration(spec).is_err(), "'{spec}' was accepted");
+        }
+        // Units out of order, repeated, unknown or missing a number
+        for spec in ["30m1h", "1m1m", "5d", "h", "1h30"] {
+            assert!(parse_duration(spec).is_err(), "'{spec}' was accepted");
+        }
+        assert!(parse_duration("99999999999999999999").is_err());
+        assert!(parse_duration("9999999999999999h").is_err());
+    }
+}